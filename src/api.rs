@@ -1,43 +1,57 @@
-use hyper::{Method, Response, Body, Request, StatusCode};
-use bytes::buf::BufExt;
+use hyper::{header, HeaderMap, Method, Response, Body, Request, StatusCode};
+use hyper::body::HttpBody;
+use percent_encoding::percent_decode_str;
 use crate::schema::*;
 use crate::cluster::Cluster;
 use std::convert::TryFrom;
 use std::sync::Arc;
+use std::collections::BTreeMap;
 use crate::error::AppError;
+use crate::metrics;
+use crate::health::{Health, HealthCheck, HealthReport};
+use crate::auth::{Auth, Principal, Scope};
+use crate::selector::LabelSelector;
+use crate::validation::{validate_tenant, MAX_BODY_SIZE};
+
+const TENANT_HEADER: &str = "x-tenant";
+// Requests carry a single job, whose body once escaped in json can take
+// several times `MAX_BODY_SIZE`.
+const MAX_REQUEST_SIZE: usize = 8 * MAX_BODY_SIZE;
+const MAX_BATCH_REQUEST_SIZE: usize = 16 * 1024 * 1024;
 
 pub async fn request_routes(
     cluster: Arc<Cluster>,
+    health: Arc<Health>,
+    auth: Arc<Auth>,
     request: Request<Body>
 ) -> Result<Response<Body>, AppError> {
-    let parts: Vec<&str> = request
-        .uri()
-        .path()
-        .split("/")
-        .filter(|part| !part.is_empty())
-        .collect();
+    // the scope is picked from the same segments the request is routed
+    // with, and rejected before anything reaches the cluster.
+    let decoded = path_segments(request.uri().path())?;
+    let parts: Vec<&str> = decoded.iter().map(String::as_str).collect();
+    let route = route_pattern(&parts);
+    let principal = authorize(&auth, request.method(), route, request.headers())?;
+    let tenant = request_tenant(&auth, principal.as_ref(), request.headers())?;
     match (request.method(), parts.as_slice()) {
         // {{{ v1
         (&Method::POST, ["scheduler", "api", "cron"]) => {
             info!("POST -> /scheduler/api/cron");
-            let body = hyper::body::aggregate(request).await
-                .map_err(|_| AppError::UnexpectedError)?;
-            let v1_job: V1CronJob = serde_json::from_reader(body.reader())?;
-            let job = Job::try_from(v1_job)?;
+            let body = read_body(request, MAX_REQUEST_SIZE).await?;
+            let v1_job: V1CronJob = serde_json::from_slice(&body)?;
+            let job = Job::try_from(v1_job)?.in_tenant(&tenant);
             cluster.push(job).await?;
             Ok(Response::new(Body::from("{}")))
         },
         (&Method::DELETE, ["scheduler", "api"]) => {
             info!("DELETE -> /scheduler/api");
-            cluster.clear().await?;
+            cluster.clear(&tenant).await?;
             Ok(Response::new(Body::from("{}")))
         },
         (&Method::POST, ["scheduler", "api"]) => {
             info!("POST -> /scheduler/api");
-            let body = hyper::body::aggregate(request).await
-                .map_err(|_| AppError::UnexpectedError)?;
-            let v1_job: V1Job = serde_json::from_reader(body.reader())?;
-            let job = Job::from(v1_job);
+            let body = read_body(request, MAX_REQUEST_SIZE).await?;
+            let v1_job: V1Job = serde_json::from_slice(&body)?;
+            let job = Job::try_from(v1_job)?.in_tenant(&tenant);
             let key = V1JobKey::new(job.id.clone());
 
             cluster.push(job).await?;
@@ -45,7 +59,7 @@ pub async fn request_routes(
         },
         (&Method::DELETE, ["scheduler", "api", id]) => {
             info!("DELETE -> /scheduler/api/{}", id);
-            let removed = cluster.remove(&id).await?;
+            let removed = cluster.remove(&tenant, &id).await?;
             match removed {
                 Some(_) => Ok(Response::new(Body::from("{}"))),
                 None =>
@@ -62,16 +76,16 @@ pub async fn request_routes(
         // {{{ v2
         (&Method::POST, ["api", "job"]) => {
             info!("POST -> /api/job");
-            let body = hyper::body::aggregate(request).await.map_err(|_| AppError::UnexpectedError)?;
-            let v2_job: V1Job = serde_json::from_reader(body.reader())?;
-            let job = Job::from(v2_job);
+            let body = read_body(request, MAX_REQUEST_SIZE).await?;
+            let v2_job: V1Job = serde_json::from_slice(&body)?;
+            let job = Job::try_from(v2_job)?.in_tenant(&tenant);
             let response = serde_json::to_string(&V2JobResponse::from(&job))?;
             cluster.push(job).await?;
             Ok(Response::new(Body::from(response)))
         },
         (&Method::DELETE, ["api", "job"]) => {
             info!("DELETE -> /scheduler/api");
-            cluster.clear().await?;
+            cluster.clear(&tenant).await?;
             Ok(
                 Response::builder()
                     .status(StatusCode::NO_CONTENT)
@@ -81,7 +95,7 @@ pub async fn request_routes(
         },
         (&Method::DELETE, ["api", "job", id]) => {
             info!("DELETE -> /api/job/{}", id);
-            let removed = cluster.remove(&id).await?;
+            let removed = cluster.remove(&tenant, &id).await?;
             match removed {
                 Some(_) => Ok(
                     Response::builder()
@@ -100,21 +114,588 @@ pub async fn request_routes(
         },
         (&Method::POST, ["api", "cron"]) => {
             info!("POST -> /api/cron");
-            let body = hyper::body::aggregate(request).await.map_err(|_| AppError::UnexpectedError)?;
-            let v2_job: V2CronJob = serde_json::from_reader(body.reader())?;
-            let job = Job::try_from(v2_job)?;
+            let body = read_body(request, MAX_REQUEST_SIZE).await?;
+            let v2_job: V2CronJob = serde_json::from_slice(&body)?;
+            let job = Job::try_from(v2_job)?.in_tenant(&tenant);
             let response = serde_json::to_string(&V2CronJobResponse::from(&job))?;
             cluster.push(job).await?;
             Ok(Response::new(Body::from(response)))
         },
+        (&Method::POST, ["api", "job", id, "pause"]) => {
+            info!("POST -> /api/job/{}/pause", id);
+            let paused = cluster.pause(&tenant, id).await?;
+            job_response(paused)
+        },
+        (&Method::POST, ["api", "job", id, "resume"]) => {
+            info!("POST -> /api/job/{}/resume", id);
+            let resumed = cluster.resume(&tenant, id).await?;
+            job_response(resumed)
+        },
+        (&Method::GET, ["api", "job", id, "runs"]) => {
+            info!("GET -> /api/job/{}/runs", id);
+            let runs = cluster.runs(&tenant, id).await?;
+            let runs: Vec<V2JobRun> = runs.into_iter().map(V2JobRun::from).collect();
+            Ok(Response::new(Body::from(serde_json::to_string(&runs)?)))
+        },
+        (&Method::POST, ["api", "jobs", "pause"]) => {
+            info!("POST -> /api/jobs/pause");
+            let body = read_body(request, MAX_REQUEST_SIZE).await?;
+            let filter: JobFilter = serde_json::from_slice(&body)?;
+            let filter = filter.in_tenant(&tenant);
+            let paused = cluster.pause_matching(&filter).await?;
+            let response = serde_json::to_string(&V2JobIds::from(paused.as_slice()))?;
+            Ok(Response::new(Body::from(response)))
+        },
+        (&Method::POST, ["api", "jobs", "resume"]) => {
+            info!("POST -> /api/jobs/resume");
+            let body = read_body(request, MAX_REQUEST_SIZE).await?;
+            let filter: JobFilter = serde_json::from_slice(&body)?;
+            let filter = filter.in_tenant(&tenant);
+            let resumed = cluster.resume_matching(&filter).await?;
+            let response = serde_json::to_string(&V2JobIds::from(resumed.as_slice()))?;
+            Ok(Response::new(Body::from(response)))
+        },
+        (&Method::GET, ["api", "jobs"]) => {
+            info!("GET -> /api/jobs");
+            let filter = query_filter(&request)?.in_tenant(&tenant);
+            let jobs = cluster.list(&filter).await?;
+            let views: Vec<V2JobView> = jobs.iter().map(V2JobView::from).collect();
+            Ok(Response::new(Body::from(serde_json::to_string(&views)?)))
+        },
+        (&Method::DELETE, ["api", "jobs"]) => {
+            info!("DELETE -> /api/jobs");
+            let filter = query_filter(&request)?.in_tenant(&tenant);
+            // clearing everything is done through `DELETE /api/job`.
+            if filter.is_empty() {
+                return Err(AppError::ValidationError {
+                    field: None,
+                    message: "one of url, selector or group is required".to_owned()
+                });
+            }
+            let removed = cluster.remove_matching(&filter).await?;
+            let response = serde_json::to_string(&V2JobIds::from(removed.as_slice()))?;
+            Ok(Response::new(Body::from(response)))
+        },
+        (&Method::GET, ["api", "groups", group, "jobs"]) => {
+            info!("GET -> /api/groups/{}/jobs", group);
+            let filter = JobFilter::group(group).in_tenant(&tenant);
+            let jobs = cluster.list(&filter).await?;
+            let views: Vec<V2JobView> = jobs.iter().map(V2JobView::from).collect();
+            Ok(Response::new(Body::from(serde_json::to_string(&views)?)))
+        },
+        (&Method::GET, ["api", "groups", group, "stats"]) => {
+            info!("GET -> /api/groups/{}/stats", group);
+            let filter = JobFilter::group(group).in_tenant(&tenant);
+            let stats = cluster.stats(&filter).await?;
+            let response = serde_json::to_string(&V2GroupStats { group, stats })?;
+            Ok(Response::new(Body::from(response)))
+        },
+        (&Method::POST, ["api", "groups", group, "pause"]) => {
+            info!("POST -> /api/groups/{}/pause", group);
+            let filter = JobFilter::group(group).in_tenant(&tenant);
+            let paused = cluster.pause_matching(&filter).await?;
+            let response = serde_json::to_string(&V2JobIds::from(paused.as_slice()))?;
+            Ok(Response::new(Body::from(response)))
+        },
+        (&Method::POST, ["api", "groups", group, "resume"]) => {
+            info!("POST -> /api/groups/{}/resume", group);
+            let filter = JobFilter::group(group).in_tenant(&tenant);
+            let resumed = cluster.resume_matching(&filter).await?;
+            let response = serde_json::to_string(&V2JobIds::from(resumed.as_slice()))?;
+            Ok(Response::new(Body::from(response)))
+        },
+        (&Method::DELETE, ["api", "groups", group]) => {
+            info!("DELETE -> /api/groups/{}", group);
+            let filter = JobFilter::group(group).in_tenant(&tenant);
+            let removed = cluster.remove_matching(&filter).await?;
+            let response = serde_json::to_string(&V2JobIds::from(removed.as_slice()))?;
+            Ok(Response::new(Body::from(response)))
+        },
+        (&Method::PUT, ["api", "groups", group, "jobs", name]) => {
+            info!("PUT -> /api/groups/{}/jobs/{}", group, name);
+            let (group, name) = (group.to_string(), name.to_string());
+            let body = read_body(request, MAX_REQUEST_SIZE).await?;
+            let v2_job: V2BatchJob = serde_json::from_slice(&body)?;
+            let mut job = Job::try_from(v2_job.named(&group, &name))?.in_tenant(&tenant);
+            // declaring the job again must not undo a pause.
+            let existing = cluster.get(&tenant, &job.id).await?;
+            if let Some(existing) = &existing {
+                job.paused = existing.paused;
+            }
+            let response = serde_json::to_string(&V2JobView::from(&job))?;
+            cluster.push(job).await?;
+            let status = match existing {
+                Some(_) => StatusCode::OK,
+                None => StatusCode::CREATED
+            };
+            Ok(
+                Response::builder()
+                    .status(status)
+                    .body(Body::from(response))
+                    .unwrap()
+            )
+        },
+        (&Method::GET, ["api", "groups", group, "jobs", name]) => {
+            info!("GET -> /api/groups/{}/jobs/{}", group, name);
+            let job = cluster.get(&tenant, &named_id(group, name)).await?;
+            job_response(job)
+        },
+        (&Method::DELETE, ["api", "groups", group, "jobs", name]) => {
+            info!("DELETE -> /api/groups/{}/jobs/{}", group, name);
+            let removed = cluster.remove(&tenant, &named_id(group, name)).await?;
+            let status = match removed {
+                Some(_) => StatusCode::NO_CONTENT,
+                None => StatusCode::NOT_FOUND
+            };
+            Ok(
+                Response::builder()
+                    .status(status)
+                    .body(Body::from(""))
+                    .unwrap()
+            )
+        },
+        (&Method::POST, ["api", "jobs:batch"]) => {
+            info!("POST -> /api/jobs:batch");
+            let items = batch_items(request).await?;
+            let mut results = Vec::with_capacity(items.len());
+            let mut jobs = Vec::new();
+            let mut positions = Vec::new();
+            for item in items {
+                let job = item
+                    .and_then(|value| Ok(serde_json::from_value::<V2BatchJob>(value)?))
+                    .and_then(Job::try_from)
+                    .map(|job| job.in_tenant(&tenant));
+                match job {
+                    Ok(job) => {
+                        positions.push(results.len());
+                        results.push(V2BatchResult {
+                            id: Some(job.id.clone()),
+                            status: StatusCode::OK.as_u16(),
+                            error: None
+                        });
+                        jobs.push(job);
+                    },
+                    Err(err) => results.push(batch_error(&err))
+                }
+            }
+            for (position, result) in positions.into_iter().zip(cluster.push_batch(jobs).await) {
+                if let Err(err) = result {
+                    results[position] = V2BatchResult {
+                        id: results[position].id.take(),
+                        ..batch_error(&err)
+                    };
+                }
+            }
+            Ok(Response::new(Body::from(serde_json::to_string(&results)?)))
+        },
+        (&Method::DELETE, ["api", "jobs:batch"]) => {
+            info!("DELETE -> /api/jobs:batch");
+            let items = batch_items(request).await?;
+            let mut results = Vec::with_capacity(items.len());
+            let mut ids = Vec::new();
+            let mut positions = Vec::new();
+            for item in items {
+                match item.and_then(|value| Ok(serde_json::from_value::<String>(value)?)) {
+                    Ok(id) => {
+                        positions.push(results.len());
+                        results.push(V2BatchResult {
+                            id: Some(id.clone()),
+                            status: StatusCode::NO_CONTENT.as_u16(),
+                            error: None
+                        });
+                        ids.push(id);
+                    },
+                    Err(err) => results.push(batch_error(&err))
+                }
+            }
+            let removed = cluster.remove_batch(&tenant, &ids).await;
+            for (position, result) in positions.into_iter().zip(removed) {
+                match result {
+                    Ok(Some(_)) => {},
+                    Ok(None) => results[position].status = StatusCode::NOT_FOUND.as_u16(),
+                    Err(err) => {
+                        results[position] = V2BatchResult {
+                            id: results[position].id.take(),
+                            ..batch_error(&err)
+                        };
+                    }
+                }
+            }
+            Ok(Response::new(Body::from(serde_json::to_string(&results)?)))
+        },
         // }}}
+        (&Method::GET, ["api", "quota"]) => {
+            info!("GET -> /api/quota");
+            let report = cluster.quota(&tenant).await?;
+            Ok(Response::new(Body::from(serde_json::to_string(&report)?)))
+        },
+        (&Method::GET, ["api", "shards"]) => {
+            info!("GET -> /api/shards");
+            let statuses = cluster.shard_statuses().await;
+            Ok(Response::new(Body::from(serde_json::to_string(&statuses)?)))
+        },
+        (&Method::GET, ["api", "nodes"]) => {
+            info!("GET -> /api/nodes");
+            let statuses = cluster.node_statuses();
+            Ok(Response::new(Body::from(serde_json::to_string(&statuses)?)))
+        },
+        (&Method::POST, ["api", "shards", "migrations"]) => {
+            info!("POST -> /api/shards/migrations");
+            let body = read_body(request, MAX_REQUEST_SIZE).await?;
+            let migration_request: V2MigrationRequest = serde_json::from_slice(&body)?;
+            let migration = cluster
+                .migrate(migration_request.shards, &migration_request.target)
+                .await?;
+            Ok(
+                Response::builder()
+                    .status(StatusCode::ACCEPTED)
+                    .body(Body::from(serde_json::to_string(&migration)?))
+                    .unwrap()
+            )
+        },
+        (&Method::GET, ["api", "shards", "migrations"]) => {
+            info!("GET -> /api/shards/migrations");
+            let migrations = cluster.migrations();
+            Ok(Response::new(Body::from(serde_json::to_string(&migrations)?)))
+        },
+        (&Method::DELETE, ["api", "shards", shard]) => {
+            info!("DELETE -> /api/shards/{}", shard);
+            let shard: usize = shard.parse().map_err(|err| AppError::invalid("shard", err))?;
+            cluster.clear_shard(shard).await?;
+            Ok(
+                Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::from(""))
+                    .unwrap()
+            )
+        },
+        (&Method::POST, ["api", "keys"]) => {
+            info!("POST -> /api/keys");
+            let body = read_body(request, MAX_REQUEST_SIZE).await?;
+            let key_request: V2ApiKeyRequest = serde_json::from_slice(&body)?;
+            key_request.validate()?;
+            let (api_key, key) = auth.create_key(
+                key_request.name,
+                key_request.scopes,
+                key_request.tenant
+            );
+            let response = serde_json::to_string(&V2CreatedApiKey { api_key, key })?;
+            Ok(
+                Response::builder()
+                    .status(StatusCode::CREATED)
+                    .body(Body::from(response))
+                    .unwrap()
+            )
+        },
+        (&Method::GET, ["api", "keys"]) => {
+            info!("GET -> /api/keys");
+            Ok(Response::new(Body::from(serde_json::to_string(&auth.list_keys())?)))
+        },
+        (&Method::DELETE, ["api", "keys", id]) => {
+            info!("DELETE -> /api/keys/{}", id);
+            let status = match auth.remove_key(id) {
+                Some(_) => StatusCode::NO_CONTENT,
+                None => StatusCode::NOT_FOUND
+            };
+            Ok(Response::builder().status(status).body(Body::from("")).unwrap())
+        },
+        (&Method::GET, ["metrics"]) => {
+            debug!("GET -> /metrics");
+            metrics::QUEUE_SIZE.set(cluster.queue_size().await as i64);
+            Ok(
+                Response::builder()
+                    .header("Content-Type", "text/plain; version=0.0.4")
+                    .body(Body::from(metrics::encode()))
+                    .unwrap()
+            )
+        },
+        (&Method::GET, ["healthz"]) => {
+            debug!("GET -> /healthz");
+            health_response(health.liveness())
+        },
+        (&Method::GET, ["readyz"]) => {
+            debug!("GET -> /readyz");
+            health_response(HealthReport::from(cluster.readiness().await))
+        },
         (method, parts) => {
             info!("{} -> {}: NOT_FOUND", method, parts.join("/"));
+            let error = V2Error {
+                code: "not_found".to_owned(),
+                message: format!("No route for {} /{}", method, parts.join("/")),
+                details: BTreeMap::new()
+            };
             Ok(
                 Response::builder()
                     .status(StatusCode::NOT_FOUND)
                     .header("Content-Type", "application/json")
-                    .body(Body::from("{}"))
+                    .body(Body::from(serde_json::to_string(&error)?))
+                    .unwrap()
+            )
+        }
+    }
+}
+
+// Serializes a single job, using the cron representation when it has a
+// schedule.
+fn job_response(job: Option<Job>) -> Result<Response<Body>, AppError> {
+    match job {
+        Some(job) => {
+            let response = serde_json::to_string(&V2JobView::from(&job))?;
+            Ok(Response::new(Body::from(response)))
+        },
+        None =>
+            Ok(
+                Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::from(""))
+                    .unwrap()
+            )
+    }
+}
+
+fn health_response(report: HealthReport) -> Result<Response<Body>, AppError> {
+    let status = match report.is_ok() {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_string(&report)?))
+            .unwrap()
+    )
+}
+
+// Reads a `JobFilter` from the `url`, `selector` and `group` query
+// parameters.
+fn query_filter(request: &Request<Body>) -> Result<JobFilter, AppError> {
+    let query = request.uri().query().unwrap_or("");
+    let mut filter = JobFilter::default();
+    // empty values would match every job.
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "url" | "selector" | "group" if value.is_empty() =>
+                return Err(AppError::invalid(&key, "must not be empty")),
+            "url" => filter.url = Some(value.into_owned()),
+            "selector" => {
+                let selector: LabelSelector = value.parse()?;
+                if selector.is_empty() {
+                    return Err(AppError::invalid(&key, "must have a requirement"));
+                }
+                filter.selector = Some(selector);
+            },
+            "group" => filter.group = Some(value.into_owned()),
+            _ => return Err(AppError::invalid(&key, "unknown query parameter"))
+        }
+    }
+    Ok(filter)
+}
+
+// Reads the body of the request, refusing it as soon as it's known to be
+// larger than the limit rather than once it's all in memory.
+async fn read_body(request: Request<Body>, limit: usize) -> Result<Vec<u8>, AppError> {
+    let too_large = || AppError::invalid("body", format!("request must be at most {} bytes", limit));
+    let length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if length.is_some_and(|length| length > limit as u64) {
+        return Err(too_large());
+    }
+    let mut body = request.into_body();
+    let mut read = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if read.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+        read.extend_from_slice(&chunk);
+    }
+    Ok(read)
+}
+
+// Batch bodies are either a json array or newline delimited json. Items
+// which can't be parsed are reported individually.
+async fn batch_items(
+    request: Request<Body>
+) -> Result<Vec<Result<serde_json::Value, AppError>>, AppError> {
+    let ndjson = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .map(|value| value.as_bytes().starts_with(b"application/x-ndjson"))
+        .unwrap_or(false);
+    let body = read_body(request, MAX_BATCH_REQUEST_SIZE).await?;
+    if ndjson {
+        Ok(
+            body
+                .split(|byte| *byte == b'\n')
+                .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+                .map(|line| Ok(serde_json::from_slice(line)?))
+                .collect()
+        )
+    } else {
+        let values: Vec<serde_json::Value> = serde_json::from_slice(&body)?;
+        Ok(values.into_iter().map(Ok).collect())
+    }
+}
+
+fn batch_error(err: &AppError) -> V2BatchResult {
+    V2BatchResult {
+        id: None,
+        status: error_status(err).as_u16(),
+        error: Some(V2Error::from(err))
+    }
+}
+
+fn error_status(err: &AppError) -> StatusCode {
+    match err {
+        AppError::ValidationError { .. } => StatusCode::BAD_REQUEST,
+        AppError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        AppError::NodeUnreachable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        AppError::RpcDeserializationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        AppError::UnexpectedRpcError(message) => {
+            error!("RpcError - {}", message);
+            StatusCode::INTERNAL_SERVER_ERROR
+        },
+        AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+        AppError::Forbidden { .. } => StatusCode::FORBIDDEN,
+        AppError::QuotaExceeded { .. } => StatusCode::TOO_MANY_REQUESTS
+    }
+}
+
+// Path of the route matching the request, with the parameters left out so
+// the metrics have a bounded number of labels.
+// Segments are decoded once split, so an encoded `/` stays in its segment.
+fn path_segments(path: &str) -> Result<Vec<String>, AppError> {
+    path
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .map(|part| percent_decode_str(part).decode_utf8().map(|part| part.into_owned()))
+        .collect::<Result<Vec<String>, _>>()
+        .map_err(|err| AppError::invalid("path", err))
+}
+
+fn route_pattern(parts: &[&str]) -> &'static str {
+    match parts {
+        ["scheduler", "api", "cron"] => "/scheduler/api/cron",
+        ["scheduler", "api"] => "/scheduler/api",
+        ["scheduler", "api", _] => "/scheduler/api/:id",
+        ["api", "job"] => "/api/job",
+        ["api", "job", _] => "/api/job/:id",
+        ["api", "cron"] => "/api/cron",
+        ["api", "job", _, "pause"] => "/api/job/:id/pause",
+        ["api", "job", _, "resume"] => "/api/job/:id/resume",
+        ["api", "job", _, "runs"] => "/api/job/:id/runs",
+        ["api", "jobs"] => "/api/jobs",
+        ["api", "jobs", "pause"] => "/api/jobs/pause",
+        ["api", "jobs", "resume"] => "/api/jobs/resume",
+        ["api", "jobs:batch"] => "/api/jobs:batch",
+        ["api", "groups", _] => "/api/groups/:group",
+        ["api", "groups", _, "jobs"] => "/api/groups/:group/jobs",
+        ["api", "groups", _, "stats"] => "/api/groups/:group/stats",
+        ["api", "groups", _, "pause"] => "/api/groups/:group/pause",
+        ["api", "groups", _, "resume"] => "/api/groups/:group/resume",
+        ["api", "groups", _, "jobs", _] => "/api/groups/:group/jobs/:name",
+        ["api", "keys"] => "/api/keys",
+        ["api", "keys", _] => "/api/keys/:id",
+        ["api", "quota"] => "/api/quota",
+        ["api", "nodes"] => "/api/nodes",
+        ["api", "shards"] => "/api/shards",
+        ["api", "shards", "migrations"] => "/api/shards/migrations",
+        ["api", "shards", _] => "/api/shards/:shard",
+        ["metrics"] => "/metrics",
+        ["healthz"] => "/healthz",
+        ["readyz"] => "/readyz",
+        _ => "unknown"
+    }
+}
+
+// Scope needed for the route, the probes are left open for the
+// orchestrators.
+fn required_scope(method: &Method, route: &str) -> Option<Scope> {
+    match (method, route) {
+        (_, "/healthz") | (_, "/readyz") => None,
+        (_, "/api/keys") | (_, "/api/keys/:id") => Some(Scope::Admin),
+        (_, "/api/shards") | (_, "/api/shards/:shard") | (_, "/api/shards/migrations") =>
+            Some(Scope::Admin),
+        (_, "/api/nodes") => Some(Scope::Admin),
+        // clears every job.
+        (&Method::DELETE, "/api/job") | (&Method::DELETE, "/scheduler/api") =>
+            Some(Scope::Admin),
+        (&Method::GET, _) | (&Method::HEAD, _) => Some(Scope::Read),
+        _ => Some(Scope::Write)
+    }
+}
+
+// Returns who made the request, unless the route is open.
+fn authorize(
+    auth: &Auth,
+    method: &Method,
+    route: &str,
+    headers: &HeaderMap
+) -> Result<Option<Principal>, AppError> {
+    match required_scope(method, route) {
+        Some(scope) => {
+            let principal = auth.authenticate(headers)?;
+            principal.require(scope)?;
+            Ok(Some(principal))
+        },
+        None => Ok(None)
+    }
+}
+
+// Tenant the request acts on. Credentials bound to a tenant are confined to
+// it, and only admin keys pick one through the `X-Tenant` header. With auth
+// disabled anyone would be an admin, so the header is refused.
+fn request_tenant(
+    auth: &Auth,
+    principal: Option<&Principal>,
+    headers: &HeaderMap
+) -> Result<String, AppError> {
+    let principal = match principal {
+        Some(principal) => principal,
+        None => return Ok(DEFAULT_TENANT.to_owned())
+    };
+    let tenant = match (&principal.tenant, headers.get(TENANT_HEADER)) {
+        (Some(tenant), _) => tenant.clone(),
+        (None, Some(value)) => {
+            if !auth.enabled() || !principal.scopes.contains(&Scope::Admin) {
+                return Err(AppError::Forbidden { scope: Scope::Admin.name().to_owned() });
+            }
+            value
+                .to_str()
+                .map_err(|err| AppError::invalid("tenant", err))?
+                .to_owned()
+        },
+        (None, None) => DEFAULT_TENANT.to_owned()
+    };
+    validate_tenant(&tenant)?;
+    Ok(tenant)
+}
+
+// Answers while the queue is being rebuilt, before there is a cluster to
+// pass the requests on to.
+fn starting_routes(health: &Health, request: &Request<Body>) -> Result<Response<Body>, AppError> {
+    match request.uri().path() {
+        "/healthz" => health_response(health.liveness()),
+        "/readyz" => {
+            let queue = HealthCheck::failing("queue".to_owned(), "still rebuilding".to_owned());
+            health_response(HealthReport::from(vec![queue]))
+        },
+        path => {
+            info!("{} -> {}: SERVICE_UNAVAILABLE", request.method(), path);
+            let error = V2Error {
+                code: "starting".to_owned(),
+                message: "The node is still rebuilding its queue".to_owned(),
+                details: BTreeMap::new()
+            };
+            Ok(
+                Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(serde_json::to_string(&error)?))
                     .unwrap()
             )
         }
@@ -122,28 +703,35 @@ pub async fn request_routes(
 }
 
 pub async fn handle_request(
-        cluster: Arc<Cluster>,
+        cluster: Option<Arc<Cluster>>,
+        health: Arc<Health>,
+        auth: Arc<Auth>,
         request: Request<Body>
         ) -> Result<Response<Body>, AppError> {
-    let result = request_routes(cluster, request).await;
-    result.or_else(|err| {
+    let method = request.method().clone();
+    let route = match path_segments(request.uri().path()) {
+        Ok(decoded) => route_pattern(&decoded.iter().map(String::as_str).collect::<Vec<&str>>()),
+        Err(_) => "unknown"
+    };
+    let response = match cluster {
+        Some(cluster) => request_routes(cluster, health, auth, request).await,
+        None => starting_routes(&health, &request)
+    };
+    let response = response.or_else(|err| {
         error!("Error: {}", err);
-        let code = match err {
-            AppError::ValidationError => StatusCode::BAD_REQUEST,
-            AppError::UnexpectedError => StatusCode::INTERNAL_SERVER_ERROR,
-            AppError::NodeUnreachable => StatusCode::SERVICE_UNAVAILABLE,
-            AppError::RpcDeserializationError => StatusCode::INTERNAL_SERVER_ERROR,
-            AppError::UnexpectedRpcError(message) => {
-                error!("RpcError - {}", message);
-                StatusCode::INTERNAL_SERVER_ERROR
-            }
-        };
+        let code = error_status(&err);
         Ok(
             Response::builder()
             .status(code)
             .header("Content-Type", "application/json")
-            .body(Body::from("{}"))
+            .body(Body::from(serde_json::to_string(&V2Error::from(&err))?))
             .unwrap()
         )
-    })
+    });
+    if let Ok(response) = &response {
+        metrics::HTTP_REQUESTS
+            .with_label_values(&[method.as_str(), route, response.status().as_str()])
+            .inc();
+    }
+    response
 }
@@ -0,0 +1,475 @@
+// Authentication of the api requests using api keys or JWT bearer tokens.
+// Only a hash of the api keys is stored, the keys themselves are shown once
+// when created.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use hyper::HeaderMap;
+use hyper::header::AUTHORIZATION;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet};
+use rmp_serde::Serializer;
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use sled::Tree;
+use uuid::Uuid;
+use crate::config::{AuthConfig, JwtConfig, JwtKey};
+use crate::error::AppError;
+
+const API_KEY_HEADER: &str = "x-api-key";
+const KEY_PREFIX: &str = "sm8_";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    Read,
+    Write,
+    // clearing all of the jobs of a tenant and managing the keys.
+    Admin
+}
+
+impl Scope {
+    pub fn name(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Admin => "admin"
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Scope> {
+        match name {
+            "read" => Some(Scope::Read),
+            "write" => Some(Scope::Write),
+            "admin" => Some(Scope::Admin),
+            _ => None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    // in milliseconds.
+    pub created: u64,
+    // the key only gives access to the jobs of the tenant when set.
+    #[serde(default)]
+    pub tenant: Option<String>
+}
+
+// Who is making the request, and what they are allowed to do.
+#[derive(Clone, Debug)]
+pub struct Principal {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    // tenant the principal is confined to, taken from the claims of a token
+    // or from the api key.
+    pub tenant: Option<String>
+}
+
+impl Principal {
+    pub fn admin() -> Principal {
+        Principal {
+            name: "admin".to_owned(),
+            scopes: vec![Scope::Admin],
+            tenant: None
+        }
+    }
+
+    // The admin scope grants all of the others.
+    pub fn require(&self, scope: Scope) -> Result<(), AppError> {
+        match self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin) {
+            true => Ok(()),
+            false => Err(AppError::Forbidden { scope: scope.name().to_owned() })
+        }
+    }
+}
+
+enum JwtKeys {
+    Single(DecodingKey),
+    // keyed by their `kid`.
+    Set(HashMap<String, DecodingKey>)
+}
+
+// Verifies the bearer tokens issued by an identity provider, the keys are
+// loaded once at startup.
+struct Jwt {
+    keys: JwtKeys,
+    validation: Validation,
+    tenant_claim: String,
+    permissions_claim: String
+}
+
+fn read_key_file(path: &Path) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|err| panic!("Failed to read {}: {}", path.display(), err))
+}
+
+fn invalid_token(err: jsonwebtoken::errors::Error) -> AppError {
+    AppError::Unauthorized(format!("invalid token: {}", err))
+}
+
+impl Jwt {
+    fn new(config: &JwtConfig) -> Jwt {
+        let (algorithm, keys) = match &config.key {
+            JwtKey::Hs256Secret(path) => {
+                let secret = read_key_file(path);
+                let key = DecodingKey::from_secret(secret.trim_ascii_end());
+                (Algorithm::HS256, JwtKeys::Single(key))
+            },
+            JwtKey::Rs256PublicKey(path) => {
+                let key = DecodingKey::from_rsa_pem(&read_key_file(path))
+                    .unwrap_or_else(|err| panic!("Invalid key in {}: {}", path.display(), err));
+                (Algorithm::RS256, JwtKeys::Single(key))
+            },
+            JwtKey::Jwks(path) => {
+                let set: JwkSet = serde_json::from_slice(&read_key_file(path))
+                    .unwrap_or_else(|err| panic!("Invalid key set in {}: {}", path.display(), err));
+                let keys = set.keys
+                    .iter()
+                    .filter(|jwk| matches!(jwk.algorithm, AlgorithmParameters::RSA(_)))
+                    .filter_map(|jwk| {
+                        let kid = jwk.common.key_id.clone()?;
+                        match DecodingKey::from_jwk(jwk) {
+                            Ok(key) => Some((kid, key)),
+                            Err(err) => {
+                                warn!("Skipping key {} from {}: {}", kid, path.display(), err);
+                                None
+                            }
+                        }
+                    })
+                    .collect();
+                (Algorithm::RS256, JwtKeys::Set(keys))
+            }
+        };
+        // only the algorithm of the key is accepted, so that a public key
+        // can't be used as an HS256 secret.
+        let mut validation = Validation::new(algorithm);
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false
+        }
+        Jwt {
+            keys,
+            validation,
+            tenant_claim: config.tenant_claim.clone(),
+            permissions_claim: config.permissions_claim.clone()
+        }
+    }
+
+    fn verify(&self, token: &str) -> Result<Principal, AppError> {
+        let key = match &self.keys {
+            JwtKeys::Single(key) => key,
+            JwtKeys::Set(keys) => {
+                let kid = decode_header(token)
+                    .map_err(invalid_token)?
+                    .kid
+                    .ok_or_else(|| AppError::Unauthorized("token has no kid".to_owned()))?;
+                keys.get(&kid)
+                    .ok_or_else(|| AppError::Unauthorized(format!("unknown key {}", kid)))?
+            }
+        };
+        let claims = decode::<Map<String, Value>>(token, key, &self.validation)
+            .map_err(invalid_token)?
+            .claims;
+        // the permissions are either a list or a space separated string,
+        // those which aren't scopes are ignored.
+        let scopes = match claims.get(&self.permissions_claim) {
+            Some(Value::String(permissions)) => permissions
+                .split_whitespace()
+                .filter_map(Scope::parse)
+                .collect(),
+            Some(Value::Array(permissions)) => permissions
+                .iter()
+                .filter_map(Value::as_str)
+                .filter_map(Scope::parse)
+                .collect(),
+            _ => Vec::new()
+        };
+        Ok(Principal {
+            name: claims.get("sub").and_then(Value::as_str).unwrap_or("jwt").to_owned(),
+            scopes,
+            tenant: claims.get(&self.tenant_claim).and_then(Value::as_str).map(str::to_owned)
+        })
+    }
+}
+
+// Compact JWTs are made of three base64 parts, which api keys never are.
+fn is_jwt(token: &str) -> bool {
+    token.split('.').count() == 3
+}
+
+pub struct Auth {
+    admin_key: Option<Vec<u8>>,
+    jwt: Option<Jwt>,
+    // keyed by the hash of the api key.
+    keys: Tree
+}
+
+fn hash(key: &str) -> Vec<u8> {
+    Sha256::digest(key.as_bytes()).to_vec()
+}
+
+impl Auth {
+    pub fn new(config: &AuthConfig, keys: Tree) -> Auth {
+        Auth {
+            admin_key: config.admin_key.as_deref().map(hash),
+            jwt: config.jwt.as_ref().map(Jwt::new),
+            keys
+        }
+    }
+
+    // Without an admin key there is no way to create keys, so unless tokens
+    // are accepted the api is left open.
+    pub fn enabled(&self) -> bool {
+        self.admin_key.is_some() || self.jwt.is_some()
+    }
+
+    // Reads the key from the `x-api-key` header or from a bearer token.
+    fn request_key(headers: &HeaderMap) -> Option<&str> {
+        if let Some(key) = headers.get(API_KEY_HEADER) {
+            return key.to_str().ok();
+        }
+        headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim)
+    }
+
+    pub fn authenticate(&self, headers: &HeaderMap) -> Result<Principal, AppError> {
+        if !self.enabled() {
+            return Ok(Principal::admin());
+        }
+        let key = Auth::request_key(headers)
+            .ok_or_else(|| AppError::Unauthorized("missing api key".to_owned()))?;
+        if let Some(jwt) = self.jwt.as_ref().filter(|_| is_jwt(key)) {
+            return jwt.verify(key);
+        }
+        let hashed = hash(key);
+        if self.admin_key.as_ref() == Some(&hashed) {
+            return Ok(Principal::admin());
+        }
+        let stored = self.keys
+            .get(&hashed)
+            .map_err(|err| AppError::UnexpectedError(err.to_string()))?
+            .ok_or_else(|| AppError::Unauthorized("unknown api key".to_owned()))?;
+        let api_key: ApiKey = rmp_serde::decode::from_slice(&stored)
+            .map_err(|err| AppError::UnexpectedError(err.to_string()))?;
+        Ok(Principal {
+            name: api_key.name,
+            scopes: api_key.scopes,
+            tenant: api_key.tenant
+        })
+    }
+
+    // Returns the stored key along with the secret to hand out.
+    pub fn create_key(
+        &self,
+        name: String,
+        scopes: Vec<Scope>,
+        tenant: Option<String>
+    ) -> (ApiKey, String) {
+        let secret = format!(
+            "{}{}{}",
+            KEY_PREFIX,
+            Uuid::new_v4().to_simple(),
+            Uuid::new_v4().to_simple()
+        );
+        let api_key = ApiKey {
+            id: Uuid::new_v4().to_string(),
+            name,
+            scopes,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Error getting system time")
+                .as_millis() as u64,
+            tenant
+        };
+        let mut buffer = Vec::new();
+        api_key
+            .serialize(&mut Serializer::new(&mut buffer))
+            .expect("Failed to serialize api key");
+        self.keys.insert(hash(&secret), buffer).expect("Failed to write api key");
+        (api_key, secret)
+    }
+
+    fn scan(&self) -> impl Iterator<Item = (sled::IVec, ApiKey)> {
+        self.keys.iter().map(|entry| {
+            let (hashed, serialized) = entry.expect("Failed to extract from store");
+            let api_key = rmp_serde::decode::from_slice(&serialized)
+                .expect("Failed to deserialize from store");
+            (hashed, api_key)
+        })
+    }
+
+    pub fn list_keys(&self) -> Vec<ApiKey> {
+        self.scan().map(|(_, api_key)| api_key).collect()
+    }
+
+    pub fn remove_key(&self, id: &str) -> Option<ApiKey> {
+        let (hashed, api_key) = self.scan().find(|(_, api_key)| api_key.id == id)?;
+        self.keys.remove(hashed).expect("Failed to remove api key");
+        Some(api_key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::header::HeaderValue;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn api_keys() {
+        let db = sled::open(".test/api_keys").expect("Failed to open store");
+        let keys = db.open_tree("api_keys").unwrap();
+        keys.clear().unwrap();
+        let config = AuthConfig {
+            admin_key: Some("bootstrap".to_owned()),
+            jwt: None
+        };
+        let auth = Auth::new(&config, keys);
+
+        let admin = auth.authenticate(&headers("x-api-key", "bootstrap")).unwrap();
+        assert!(admin.require(Scope::Admin).is_ok());
+        assert!(auth.authenticate(&HeaderMap::new()).is_err());
+        assert!(auth.authenticate(&headers("x-api-key", "guess")).is_err());
+
+        let tenant = Some("acme".to_owned());
+        let (api_key, secret) = auth.create_key("reader".to_owned(), vec![Scope::Read], tenant);
+        assert!(secret.starts_with(KEY_PREFIX));
+        let bearer = "Bearer ".to_owned() + &secret;
+        let reader = auth.authenticate(&headers("authorization", &bearer)).unwrap();
+        assert_eq!(reader.name, "reader");
+        assert_eq!(reader.tenant.as_deref(), Some("acme"));
+        assert!(reader.require(Scope::Read).is_ok());
+        assert!(reader.require(Scope::Write).is_err());
+
+        assert_eq!(auth.list_keys(), vec![api_key.clone()]);
+        assert_eq!(auth.remove_key(&api_key.id), Some(api_key));
+        assert!(auth.authenticate(&headers("authorization", &bearer)).is_err());
+    }
+
+    const PRIVATE_KEY: &[u8] = include_bytes!("../tests/fixtures/jwt/rsa.pem");
+
+    fn jwt_auth(name: &str, jwt: JwtConfig) -> Auth {
+        let db = sled::open(format!(".test/{}", name)).expect("Failed to open store");
+        let config = AuthConfig { admin_key: None, jwt: Some(jwt) };
+        Auth::new(&config, db.open_tree("api_keys").unwrap())
+    }
+
+    fn claims(permissions: Value, expires_in: i64) -> Value {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        json!({
+            "sub": "billing",
+            "tenant": "acme",
+            "permissions": permissions,
+            "iss": "https://issuer.test",
+            "aud": "schedule-m8",
+            "exp": now + expires_in
+        })
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        headers("authorization", &format!("Bearer {}", token))
+    }
+
+    #[test]
+    fn jwt_hs256() {
+        fs::create_dir_all(".test").unwrap();
+        fs::write(".test/jwt_secret", "secret\n").unwrap();
+        let secret = JwtKey::Hs256Secret(".test/jwt_secret".into());
+        let auth = jwt_auth("jwt_hs256", JwtConfig::new(secret));
+        let key = EncodingKey::from_secret(b"secret");
+
+        let read_write = claims(json!(["read", "write"]), 60);
+        let token = encode(&Header::default(), &read_write, &key).unwrap();
+        let principal = auth.authenticate(&bearer(&token)).unwrap();
+        assert_eq!(principal.name, "billing");
+        assert_eq!(principal.tenant.as_deref(), Some("acme"));
+        assert!(principal.require(Scope::Write).is_ok());
+        assert!(principal.require(Scope::Admin).is_err());
+
+        let token = encode(&Header::default(), &claims(json!("read other"), 60), &key).unwrap();
+        let principal = auth.authenticate(&bearer(&token)).unwrap();
+        assert_eq!(principal.scopes, vec![Scope::Read]);
+
+        let expired = encode(&Header::default(), &claims(json!("read"), -3600), &key).unwrap();
+        assert!(auth.authenticate(&bearer(&expired)).is_err());
+        let forged = EncodingKey::from_secret(b"guess");
+        let token = encode(&Header::default(), &claims(json!("admin"), 60), &forged).unwrap();
+        assert!(auth.authenticate(&bearer(&token)).is_err());
+        assert!(auth.authenticate(&headers("x-api-key", "sm8_unknown")).is_err());
+    }
+
+    #[test]
+    fn jwt_rs256() {
+        let public_key = JwtKey::Rs256PublicKey("tests/fixtures/jwt/rsa.pub.pem".into());
+        let mut config = JwtConfig::new(public_key);
+        config.issuer = Some("https://issuer.test".to_owned());
+        config.audience = Some("schedule-m8".to_owned());
+        let auth = jwt_auth("jwt_rs256", config);
+        let key = EncodingKey::from_rsa_pem(PRIVATE_KEY).unwrap();
+        let header = Header::new(Algorithm::RS256);
+
+        let token = encode(&header, &claims(json!(["admin"]), 60), &key).unwrap();
+        assert!(auth.authenticate(&bearer(&token)).unwrap().require(Scope::Admin).is_ok());
+
+        let mut other_audience = claims(json!(["admin"]), 60);
+        other_audience["aud"] = json!("elsewhere");
+        let token = encode(&header, &other_audience, &key).unwrap();
+        assert!(auth.authenticate(&bearer(&token)).is_err());
+
+        // the public key must not be usable as an HS256 secret.
+        let public_key = include_bytes!("../tests/fixtures/jwt/rsa.pub.pem");
+        let token = encode(
+            &Header::default(),
+            &claims(json!(["admin"]), 60),
+            &EncodingKey::from_secret(public_key)
+        ).unwrap();
+        assert!(auth.authenticate(&bearer(&token)).is_err());
+    }
+
+    #[test]
+    fn jwks() {
+        let key_set = JwtKey::Jwks("tests/fixtures/jwt/jwks.json".into());
+        let auth = jwt_auth("jwks", JwtConfig::new(key_set));
+        let key = EncodingKey::from_rsa_pem(PRIVATE_KEY).unwrap();
+        let mut header = Header::new(Algorithm::RS256);
+
+        header.kid = Some("test-key".to_owned());
+        let token = encode(&header, &claims(json!(["read"]), 60), &key).unwrap();
+        assert!(auth.authenticate(&bearer(&token)).unwrap().require(Scope::Read).is_ok());
+
+        header.kid = Some("rotated".to_owned());
+        let token = encode(&header, &claims(json!(["read"]), 60), &key).unwrap();
+        assert!(auth.authenticate(&bearer(&token)).is_err());
+
+        header.kid = None;
+        let token = encode(&header, &claims(json!(["read"]), 60), &key).unwrap();
+        assert!(auth.authenticate(&bearer(&token)).is_err());
+    }
+
+    #[test]
+    fn disabled() {
+        let db = sled::open(".test/auth_disabled").expect("Failed to open store");
+        let auth = Auth::new(&AuthConfig::default(), db.open_tree("api_keys").unwrap());
+        let principal = auth.authenticate(&HeaderMap::new()).unwrap();
+        assert!(principal.require(Scope::Admin).is_ok());
+    }
+}
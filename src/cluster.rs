@@ -1,64 +1,1805 @@
-use crate::store::Store;
+use crate::store::{Changes, QueueKey, Store};
 use std::sync::Arc;
 use crate::shard::Shard;
-use crate::schema::Job;
+use crate::schema::{
+    shard_index, stable_hash, Job, JobFilter, JobRun, JobStats, JobUsage, Migration,
+    MigrationState, NodeStatus, ShardState, ShardStatus, SHARD_COUNT
+};
 use crate::error::AppError;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::*;
+use crate::health::HealthCheck;
+use crate::quota::{QuotaReport, Quotas};
+use crate::egress::Egress;
+use crate::config::{ClusterConfig, ShardRange};
+use crate::node::client::NodeClient;
+use crate::node::security::NodeSecurity;
+use crate::raft::{ClusterMap, Command, Raft};
+use crate::gossip::{Gossip, MemberState};
+use std::collections::{HashMap, HashSet};
+use futures::future::join_all;
+use sled::Tree;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+// Points each node has on the ring shards are assigned with.
+const VIRTUAL_NODES: usize = 64;
+
+// Shards along with the clients of the other nodes, by id, and the nodes
+// holding each shard.
+type Assignment = (Vec<Shard>, HashMap<String, Arc<NodeClient>>, Vec<Vec<String>>);
+
+// Views the other nodes last answered a heartbeat with, by id, along with
+// when they were asked.
+type Views = HashMap<String, (Instant, View)>;
+
+// Number of jobs sent to the target of a migration at once.
+const MIGRATION_BATCH: usize = 100;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+const REPLICATION_INTERVAL: Duration = Duration::from_millis(250);
+
+// How long the view a node answered a heartbeat with counts towards a
+// majority, which outlasts the wait for the next answer. Without a raft log,
+// replicated shards changing hands only fire once that long passed, when
+// the majority the node they come from relied on is surely gone.
+const QUORUM_LEASE: Duration = Duration::from_millis(2500);
+
+// How often the shards are built again from the raft log once it changed.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(100);
+
+// Time the leader goes without hearing from a node before handing its
+// shards over to one of their replicas.
+const FAILOVER_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Shards handed over to a node only fire once the lease of their previous
+// owner surely ran out, twice the lease.
+const HANDOVER_DELAY: Duration = Duration::from_secs(2);
+
+// How often the jobs kept for unreachable nodes are sent to them, at most
+// that many at once.
+const HANDOFF_INTERVAL: Duration = Duration::from_secs(1);
+const HANDOFF_BATCH: usize = 100;
+
+// How long the jobs of a tenant are trusted once counted on the shards,
+// along with the jobs admitted since.
+const USAGE_TTL: Duration = Duration::from_secs(1);
+
+// Jobs of a tenant as last counted on the shards, plus the jobs admitted
+// since.
+#[derive(Default)]
+struct Reserved {
+    counted: Option<Instant>,
+    usage: JobUsage
+}
+
+// What a node answers heartbeats with: its id, the nodes it considers down
+// and the replicated shards it took over from their owner.
+#[derive(Clone, Debug)]
+pub struct View {
+    pub node: String,
+    pub down: Vec<String>,
+    pub promoted: Vec<usize>
+}
 
 pub struct Cluster {
-    shards: RwLock<Vec<Shard>>
+    shards: RwLock<Vec<Shard>>,
+    // store of each shard on this node, whether or not it owns the shard.
+    stores: Vec<Arc<Store>>,
+    // clients of the other nodes, by id.
+    nodes: HashMap<String, Arc<NodeClient>>,
+    migrations: Mutex<Vec<Migration>>,
+    // shards this node moved to another one, which the node server passes
+    // requests on for.
+    migrated: Mutex<HashSet<usize>>,
+    // jobs a migration failed to send, which the target may have stored
+    // anyway, along with the version sent.
+    unsent: Mutex<HashMap<QueueKey, Job>>,
+    node_id: String,
+    // nodes holding each shard, its owner first, or none when the shards
+    // aren't replicated.
+    members: Vec<Vec<String>>,
+    // nodes which missed some of the changes of the shards they replicate.
+    unsynced: Mutex<HashSet<String>>,
+    // replicated shards this node took over from their owner, along with
+    // the jobs changed since, which the owner takes back.
+    promoted: Mutex<HashMap<usize, HashSet<QueueKey>>>,
+    views: Mutex<Views>,
+    // whether most nodes heard from this one at the last heartbeat.
+    quorate: AtomicBool,
+    // owner of each shard, when it is agreed upon through the raft log
+    // rather than read from the cluster file.
+    raft: Option<Arc<Raft>>,
+    // entries of the raft log the shards were last assigned from.
+    adopted: AtomicUsize,
+    // shards handed over to this node by the raft log, which fire from then
+    // on.
+    handovers: Mutex<HashMap<usize, Instant>>,
+    // where the other nodes are and whether they're alive, when they find
+    // each other through gossip.
+    gossip: Option<Arc<Gossip>>,
+    // credentials of the calls between the nodes.
+    security: Arc<NodeSecurity>,
+    // jobs of the shards of unreachable nodes, which fire here if they're
+    // due before the nodes are back.
+    hints: Option<Arc<Store>>,
+    // when each of the jobs in the hints was kept, so they don't replace
+    // the jobs written on their node since. Jobs kept before this node
+    // started count as kept when it started.
+    hinted: Mutex<HashMap<QueueKey, Instant>>,
+    started: Instant,
+    quotas: Arc<Quotas>,
+    // jobs counted against the quotas of each tenant, which are only
+    // reserved under its lock, so concurrent pushes can't both take the
+    // last of a quota.
+    reserved: Mutex<HashMap<String, Arc<tokio::sync::Mutex<Reserved>>>>,
+    egress: Arc<Egress>
 }
 
 impl Cluster {
-    pub async fn start(store: Arc<Store>) -> Cluster {
-        let mut shards: Vec<Shard> = Vec::with_capacity(128);
-        for _ in 0..127 {
-            shards.push(Shard::Local(store.clone()));
+    // The raft log and the jobs of unreachable nodes are only kept in their
+    // trees when the cluster file enables them.
+    pub async fn start(
+        stores: Vec<Arc<Store>>,
+        log: Option<Tree>,
+        hints: Option<Tree>,
+        quotas: Arc<Quotas>,
+        egress: Arc<Egress>,
+        config: &ClusterConfig
+    ) -> Arc<Cluster> {
+        let security = Arc::new(NodeSecurity::new(config));
+        let (shards, nodes, members) = Cluster::assign(&stores, config, &security)
+            .expect("Invalid cluster configuration");
+        let migrated = (0..shards.len())
+            .filter(|index| matches!(shards[*index], Shard::Remote(_)) && stores[*index].moved_to().is_some())
+            .collect();
+        let unsynced = nodes.keys().cloned().collect();
+        let raft = match (config.raft, log) {
+            (true, Some(log)) if !config.nodes.is_empty() => {
+                let initial = ClusterMap {
+                    nodes: config.nodes
+                        .iter()
+                        .map(|node| (node.id.clone(), node.address.clone()))
+                        .collect(),
+                    owners: Cluster::owners(config)
+                        .expect("Invalid cluster configuration")
+                        .into_iter()
+                        .map(|owner| config.nodes[owner].id.clone())
+                        .collect()
+                };
+                let node_id = config.node_id.as_ref().expect("The node id is missing");
+                Some(Raft::start(node_id, log, nodes.clone(), initial).expect("Invalid cluster configuration"))
+            },
+            _ => None
+        };
+        let hints = match (config.handoff, hints) {
+            (true, Some(hints)) if !config.nodes.is_empty() => Some(Arc::new(Store::new(hints))),
+            _ => None
+        };
+        let gossip = match &config.gossip {
+            Some(gossip) => {
+                let node_id = config.node_id.as_ref().expect("The node id is missing");
+                let address = config.address
+                    .clone()
+                    .or_else(|| {
+                        config.nodes
+                            .iter()
+                            .find(|node| &node.id == node_id && !node.address.is_empty())
+                            .map(|node| node.address.clone())
+                    })
+                    .expect("The address of the node is missing");
+                Some(Gossip::start(node_id, &address, gossip).await)
+            },
+            None => None
+        };
+        let cluster = Arc::new(Cluster {
+            shards: RwLock::new(shards),
+            stores,
+            nodes,
+            migrations: Mutex::new(Vec::new()),
+            migrated: Mutex::new(migrated),
+            unsent: Mutex::new(HashMap::new()),
+            node_id: config.node_id.clone().unwrap_or_default(),
+            members,
+            unsynced: Mutex::new(unsynced),
+            promoted: Mutex::new(HashMap::new()),
+            views: Mutex::new(HashMap::new()),
+            quorate: AtomicBool::new(false),
+            raft,
+            adopted: AtomicUsize::new(0),
+            handovers: Mutex::new(HashMap::new()),
+            gossip,
+            security,
+            hints,
+            hinted: Mutex::new(HashMap::new()),
+            started: Instant::now(),
+            quotas,
+            reserved: Mutex::new(HashMap::new()),
+            egress
+        });
+        let replicated = cluster.members.iter().any(|members| !members.is_empty());
+        if replicated {
+            for store in &cluster.stores {
+                store.track_changes();
+            }
+        }
+        if config.handoff && !config.nodes.is_empty() {
+            for store in &cluster.stores {
+                store.track_touches();
+            }
+        }
+        if !cluster.nodes.is_empty() {
+            Cluster::watch(&cluster, replicated);
         }
-        // hashing is not implemented to handle beyond that value.
-        assert!(shards.len() < usize::MAX);
-        Cluster {
-            shards: RwLock::new(shards)
+        if cluster.raft.is_some() {
+            Cluster::follow(&cluster).await;
         }
+        if cluster.gossip.is_some() {
+            Cluster::discover(&cluster, config);
+        }
+        if cluster.hints.is_some() {
+            Cluster::hand_off(&cluster);
+        }
+        cluster
     }
 
-    fn shard<'a>(shards: &'a Vec<Shard>, id: &str) -> &'a Shard {
-        let mut hasher = DefaultHasher::new();
-        (*id).hash(&mut hasher);
-        let hash = hasher.finish();
-        let index = (hash % shards.len() as u64) as usize;
+    // Points the clients of the other nodes at the addresses gossip found
+    // them at, and stops requests to the nodes it found dead, until the
+    // cluster is dropped. Gossip isn't authenticated, so the addresses of
+    // the cluster file are kept. Nodes missing from the cluster file own no
+    // shard, so they're left out.
+    fn discover(cluster: &Arc<Cluster>, config: &ClusterConfig) {
+        let discovered = Arc::downgrade(cluster);
+        let addressed: HashSet<String> = config.nodes
+            .iter()
+            .filter(|node| !node.address.is_empty())
+            .map(|node| node.id.clone())
+            .collect();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FOLLOW_INTERVAL);
+            let mut seen = 0;
+            let mut strangers: HashSet<String> = HashSet::new();
+            loop {
+                interval.tick().await;
+                let cluster = match discovered.upgrade() {
+                    Some(cluster) => cluster,
+                    None => break
+                };
+                let gossip = cluster.gossip.as_ref().expect("The cluster doesn't gossip");
+                let (members, version) = gossip.members();
+                if version == seen {
+                    continue;
+                }
+                seen = version;
+                for member in members {
+                    match cluster.nodes.get(&member.id) {
+                        Some(client) => {
+                            if !addressed.contains(&member.id) {
+                                client.set_host(&member.address).await;
+                            }
+                            client.set_dead(member.state == MemberState::Dead);
+                        },
+                        None if strangers.insert(member.id.clone()) =>
+                            warn!("Node {} isn't part of the cluster file, it owns no shard", member.id),
+                        None => {}
+                    }
+                }
+            }
+        });
+    }
+
+    pub fn raft(&self) -> Result<&Raft, AppError> {
+        self.raft
+            .as_deref()
+            .ok_or_else(|| AppError::invalid("raft", "the cluster doesn't keep a raft log"))
+    }
+
+    // Index in the configured nodes of the owner of each shard. Every shard
+    // must be owned by exactly one node. When no node lists its shards, they
+    // are spread over the nodes by the ring instead.
+    fn owners(config: &ClusterConfig) -> Result<Vec<usize>, String> {
+        if config.nodes.iter().all(|node| node.shards.is_empty()) {
+            return Ok(Cluster::ring_owners(config));
+        }
+        let mut owners: Vec<Option<usize>> = vec![None; SHARD_COUNT];
+        for (index, node) in config.nodes.iter().enumerate() {
+            for range in &node.shards {
+                if range.end >= SHARD_COUNT {
+                    let message = format!("shard {} of node {} doesn't exist", range.end, node.id);
+                    return Err(message);
+                }
+                let owned = owners.iter_mut().enumerate().take(range.end + 1).skip(range.start);
+                for (shard, owner) in owned {
+                    if let Some(owner) = owner.replace(index) {
+                        let other = &config.nodes[owner].id;
+                        return Err(format!("shard {} is owned by {} and {}", shard, other, node.id));
+                    }
+                }
+            }
+        }
+        owners
+            .into_iter()
+            .enumerate()
+            .map(|(shard, owner)| owner.ok_or_else(|| format!("shard {} has no owner", shard)))
+            .collect()
+    }
+
+    // Each node is placed at several points of a ring by the hash of its id,
+    // and each shard is owned by the node at the first point following the
+    // hash of the shard. Adding a node only takes shards from the points
+    // preceding its own, so only a share of them move.
+    fn ring_owners(config: &ClusterConfig) -> Vec<usize> {
+        let mut ring: Vec<(u64, usize)> = config.nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(index, node)| {
+                (0..VIRTUAL_NODES).map(move |point| {
+                    (stable_hash(&[&node.id, &point.to_string()]), index)
+                })
+            })
+            .collect();
+        ring.sort_unstable();
+        (0..SHARD_COUNT)
+            .map(|shard| {
+                let hash = stable_hash(&["shard", &shard.to_string()]);
+                let next = ring.partition_point(|(point, _)| *point < hash);
+                ring[next % ring.len()].1
+            })
+            .collect()
+    }
+
+    // Shards of this node use the store, the others the node owning them.
+    // Shards this node migrated to another one stay with it, unless the raft
+    // log keeps their owner.
+    fn assign(
+        stores: &[Arc<Store>],
+        config: &ClusterConfig,
+        security: &Arc<NodeSecurity>
+    ) -> Result<Assignment, String> {
+        if config.nodes.is_empty() {
+            let shards = stores.iter().map(|store| Shard::Local(store.clone())).collect();
+            return Ok((shards, HashMap::new(), vec![Vec::new(); SHARD_COUNT]));
+        }
+        let node_id = config.node_id.as_ref().ok_or("the node id is missing")?;
+        if !config.nodes.iter().any(|node| &node.id == node_id) {
+            return Err(format!("node {} is not part of the cluster", node_id));
+        }
+        let unknown = config.nodes.iter().find(|node| node.address.is_empty() && &node.id != node_id);
+        match (unknown, &config.gossip, &config.token, &config.tls) {
+            (Some(node), None, _, _) =>
+                return Err(format!("the address of node {} is missing", node.id)),
+            // anyone could tell where to send the token otherwise.
+            (Some(node), Some(_), Some(_), None) =>
+                return Err(format!("the address of node {} is missing, which gossip can't be trusted with without tls", node.id)),
+            _ => {}
+        }
+        // one client for each node, so shards of the same node share it.
+        let clients: Vec<Option<Arc<NodeClient>>> = config.nodes
+            .iter()
+            .map(|node| match &node.id == node_id {
+                true => None,
+                false => Some(Arc::new(NodeClient::lazy(&node.address, security.clone())))
+            })
+            .collect();
+        let owners = Cluster::owners(config)?;
+        let members = Cluster::members(config, &owners)?;
+        let moved = |store: &Store| {
+            let target = store.moved_to().filter(|_| !config.raft)?;
+            let position = config.nodes.iter().position(|node| node.id == target);
+            if position.is_none() {
+                warn!("Shard migrated to {} which isn't part of the cluster", target);
+            }
+            clients[position?].clone()
+        };
+        let shards = owners
+            .into_iter()
+            .zip(stores)
+            .map(|(owner, store)| match moved(store).or_else(|| clients[owner].clone()) {
+                Some(client) => Shard::Remote(client),
+                None => Shard::Local(store.clone())
+            })
+            .collect();
+        let nodes = config.nodes
+            .iter()
+            .zip(clients)
+            .filter_map(|(node, client)| client.map(|client| (node.id.clone(), client)))
+            .collect();
+        Ok((shards, nodes, members))
+    }
+
+    // Nodes holding each shard: its owner, followed by the replicas which
+    // are the nodes following it in the list.
+    fn members(config: &ClusterConfig, owners: &[usize]) -> Result<Vec<Vec<String>>, String> {
+        if config.replicas == 0 {
+            return Ok(vec![Vec::new(); SHARD_COUNT]);
+        }
+        let count = config.nodes.len();
+        if config.replicas >= count {
+            return Err(format!("{} replicas require more than {} nodes", config.replicas, count));
+        }
+        let members = owners
+            .iter()
+            .map(|owner| {
+                (0..=config.replicas)
+                    .map(|offset| config.nodes[(owner + offset) % count].id.clone())
+                    .collect()
+            })
+            .collect();
+        Ok(members)
+    }
+
+    fn job_shard(job: &Job) -> usize {
+        shard_index(&job.tenant, &job.id)
+    }
+
+    fn shard<'a>(shards: &'a Vec<Shard>, tenant: &str, id: &str) -> &'a Shard {
+        let index = shard_index(tenant, id);
         shards.get(index).expect("Could not find shard at given id")
     }
 
+    // Groups the positions of the given tenants and ids by the shard which
+    // owns them.
+    fn group_by_shard<'a, I>(keys: I) -> HashMap<usize, Vec<usize>>
+    where I: Iterator<Item = (&'a str, &'a str)> {
+        let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (position, (tenant, id)) in keys.enumerate() {
+            groups
+                .entry(shard_index(tenant, id))
+                .or_default()
+                .push(position);
+        }
+        groups
+    }
+
+    // The jobs of nodes which can't be reached are left out while this node
+    // keeps their new jobs, which are counted instead.
+    async fn usage_of(&self, shards: &[Shard], tenant: &str) -> Result<JobUsage, AppError> {
+        let mut usage = JobUsage::default();
+        for shard in Cluster::distinct(shards) {
+            if let Some(counted) = self.reached(&shard, shard.usage(tenant).await)? {
+                usage.merge(counted);
+            }
+        }
+        if let Some(hints) = &self.hints {
+            usage.merge(hints.usage(tenant));
+        }
+        Ok(usage)
+    }
+
+    // Checks where the callbacks go and counts the jobs of a tenant against
+    // its quotas.
+    async fn admit(&self, shards: &[Shard], tenant: &str, jobs: &[&Job]) -> Vec<Result<(), AppError>> {
+        if !self.quotas.limits_jobs() {
+            return jobs
+                .iter()
+                .map(|job| self.egress.check_url(&job.url).and_then(|_| self.quotas.acquire_create(tenant)))
+                .collect();
+        }
+        let reserved = self.reserved_of(tenant);
+        let mut reserved = reserved.lock().await;
+        let mut results = Vec::with_capacity(jobs.len());
+        for job in jobs {
+            results.push(self.reserve(shards, job, &mut reserved).await);
+        }
+        results
+    }
+
+    // The jobs are counted again on the shards once the count is stale or
+    // the job would exceed it, as jobs which fired or were removed since are
+    // only known then. The job it replaces is only looked up when the quota
+    // would otherwise be exceeded.
+    async fn reserve(&self, shards: &[Shard], job: &Job, reserved: &mut Reserved) -> Result<(), AppError> {
+        self.egress.check_url(&job.url)?;
+        let mut usage = reserved.usage;
+        usage.add(job);
+        let stale = reserved.counted.map_or(true, |counted| counted.elapsed() >= USAGE_TTL);
+        if stale || self.quotas.check_usage(&usage).is_err() {
+            reserved.usage = self.usage_of(shards, &job.tenant).await?;
+            reserved.counted = Some(Instant::now());
+            usage = reserved.usage;
+            usage.add(job);
+        }
+        if self.quotas.check_usage(&usage).is_err() {
+            let shard = &shards[Cluster::job_shard(job)];
+            let previous = match self.get_from(shard, &job.tenant, &job.id).await {
+                Err(AppError::NodeUnreachable { .. }) if self.hints_for(shard).is_some() => None,
+                result => result?
+            };
+            if let Some(previous) = previous {
+                usage.remove(&previous);
+            }
+        }
+        self.quotas.check_usage(&usage)?;
+        self.quotas.acquire_create(&job.tenant)?;
+        reserved.usage = usage;
+        Ok(())
+    }
+
+    // Jobs which couldn't be stored give back the create they took, and the
+    // jobs of their tenant are counted again.
+    async fn release(&self, tenant: &str) {
+        self.quotas.release_create(tenant);
+        if self.quotas.limits_jobs() {
+            self.reserved_of(tenant).lock().await.counted = None;
+        }
+    }
+
+    fn reserved_of(&self, tenant: &str) -> Arc<tokio::sync::Mutex<Reserved>> {
+        let mut reserved = self.reserved.lock().expect("Failed to acquire lock");
+        reserved.entry(tenant.to_owned()).or_default().clone()
+    }
+
     pub async fn push(&self, job: Job) -> Result<(), AppError> {
         let shards = self.shards.read().await;
-        let shard = Cluster::shard(&shards, &job.id);
+        self.admit(&shards, &job.tenant, &[&job]).await.remove(0)?;
+        let tenant = job.tenant.clone();
+        let shard = Cluster::shard(&shards, &job.tenant, &job.id);
+
+        let result = match self.hints_for(shard) {
+            Some(hints) => match shard.push(job.clone()).await {
+                Err(AppError::NodeUnreachable { node, .. }) => {
+                    debug!("{} - Keeping job {} until the node is back", node, job.id);
+                    self.keep(hints, vec![job]);
+                    Ok(())
+                },
+                result => result
+            },
+            None => shard.push(job).await
+        };
+        if result.is_err() {
+            self.release(&tenant).await;
+        }
+        result
+    }
+
+    // Store keeping the jobs of the shard while its node can't be reached.
+    fn hints_for(&self, shard: &Shard) -> Option<&Arc<Store>> {
+        match shard {
+            Shard::Remote(_) => self.hints.as_ref(),
+            _ => None
+        }
+    }
+
+    // Remembers when the jobs were kept, so they don't replace the ones
+    // written on their node after.
+    fn keep(&self, hints: &Store, jobs: Vec<Job>) {
+        let now = Instant::now();
+        let mut hinted = self.hinted.lock().expect("Failed to acquire lock");
+        for job in &jobs {
+            hinted.insert(Store::queue_key(job), now);
+        }
+        hints.push_batch(jobs);
+    }
+
+    // Nothing is read from a node which can't be reached while this node
+    // keeps its new jobs.
+    fn reached<T>(&self, shard: &Shard, result: Result<T, AppError>) -> Result<Option<T>, AppError> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(AppError::NodeUnreachable { node, .. }) if self.hints_for(shard).is_some() => {
+                debug!("{} - Leaving the jobs of the node out", node);
+                Ok(None)
+            },
+            Err(err) => Err(err)
+        }
+    }
+
+    fn forget<'a, I>(&self, jobs: I)
+    where I: Iterator<Item = &'a Job> {
+        let mut hinted = self.hinted.lock().expect("Failed to acquire lock");
+        for job in jobs {
+            hinted.remove(&Store::queue_key(job));
+        }
+    }
+
+    // Hints store keeping the job, which is newer than the one its node may
+    // still hold.
+    fn kept_in(&self, shard: &Shard, tenant: &str, id: &str) -> Option<&Arc<Store>> {
+        self.hints_for(shard).filter(|hints| hints.get(tenant, id).is_some())
+    }
+
+    pub async fn get(&self, tenant: &str, id: &str) -> Result<Option<Job>, AppError> {
+        let shards = self.shards.read().await;
+        let shard = Cluster::shard(&shards, tenant, id);
+        self.get_from(shard, tenant, id).await
+    }
+
+    async fn get_from(&self, shard: &Shard, tenant: &str, id: &str) -> Result<Option<Job>, AppError> {
+        match self.kept_in(shard, tenant, id) {
+            Some(hints) => Ok(hints.get(tenant, id)),
+            None => shard.get(tenant, id).await
+        }
+    }
+
+    // Kept jobs fire on this node, which records their runs, so they're
+    // merged with the ones of the node.
+    pub async fn runs(&self, tenant: &str, id: &str) -> Result<Vec<JobRun>, AppError> {
+        let shards = self.shards.read().await;
+        let shard = Cluster::shard(&shards, tenant, id);
+        let hints = match self.hints_for(shard) {
+            Some(hints) => hints,
+            None => return shard.runs(tenant, id).await
+        };
+        let mut runs = hints.runs(tenant, id);
+        if let Some(recorded) = self.reached(shard, shard.runs(tenant, id).await)? {
+            runs.extend(recorded);
+        }
+        runs.sort_by(|a, b| b.fired.cmp(&a.fired));
+        Ok(runs)
+    }
+
+    // Each shard receives its portion of the jobs as a single write. The
+    // result for each job is returned in the order they were given, jobs
+    // over the quotas of their tenant are left out.
+    pub async fn push_batch(&self, jobs: Vec<Job>) -> Vec<Result<(), AppError>> {
+        let shards = self.shards.read().await;
+        let keys = jobs.iter().map(|job| (job.tenant.as_str(), job.id.as_str()));
+        let groups = Cluster::group_by_shard(keys);
+        let mut results: Vec<Result<(), AppError>> = jobs.iter().map(|_| Ok(())).collect();
+        let mut tenants: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (position, job) in jobs.iter().enumerate() {
+            tenants.entry(job.tenant.as_str()).or_default().push(position);
+        }
+        for (tenant, positions) in tenants {
+            let admitted: Vec<&Job> = positions.iter().map(|position| &jobs[*position]).collect();
+            let admitted = self.admit(&shards, tenant, &admitted).await;
+            for (position, result) in positions.into_iter().zip(admitted) {
+                results[position] = result;
+            }
+        }
+        let tenants: Vec<String> = jobs.iter().map(|job| job.tenant.clone()).collect();
+        let mut jobs: Vec<Option<Job>> = jobs
+            .into_iter()
+            .zip(&results)
+            .map(|(job, result)| result.as_ref().ok().map(|_| job))
+            .collect();
+
+        for (index, positions) in groups {
+            let positions: Vec<usize> = positions
+                .into_iter()
+                .filter(|position| jobs[*position].is_some())
+                .collect();
+            if positions.is_empty() {
+                continue;
+            }
+            let portion: Vec<Job> = positions
+                .iter()
+                .filter_map(|position| jobs[*position].take())
+                .collect();
+            let result = match self.hints_for(&shards[index]) {
+                Some(hints) => match shards[index].push_batch(portion.clone()).await {
+                    Err(AppError::NodeUnreachable { .. }) => {
+                        self.keep(hints, portion);
+                        Ok(())
+                    },
+                    result => result
+                },
+                None => shards[index].push_batch(portion).await
+            };
+            if let Err(err) = result {
+                for position in positions {
+                    results[position] = Err(err.clone());
+                    self.release(&tenants[position]).await;
+                }
+            }
+        }
+        results
+    }
+
+    // Kept jobs are removed like in `remove`.
+    pub async fn remove_batch(
+        &self,
+        tenant: &str,
+        ids: &[String]
+    ) -> Vec<Result<Option<Job>, AppError>> {
+        let shards = self.shards.read().await;
+        let keys = ids.iter().map(|id| (tenant, id.as_str()));
+        let groups = Cluster::group_by_shard(keys);
+        let mut results: Vec<Result<Option<Job>, AppError>> = ids.iter().map(|_| Ok(None)).collect();
+
+        for (index, positions) in groups {
+            let portion: Vec<String> = positions
+                .iter()
+                .map(|position| ids[*position].clone())
+                .collect();
+            let kept = match self.hints_for(&shards[index]) {
+                Some(hints) => hints.remove_batch(tenant, &portion),
+                None => portion.iter().map(|_| None).collect()
+            };
+            self.forget(kept.iter().flatten());
+            match shards[index].remove_batch(tenant, &portion).await {
+                Ok(removed) => {
+                    for ((position, kept), job) in positions.into_iter().zip(kept).zip(removed) {
+                        results[position] = Ok(kept.or(job));
+                    }
+                },
+                Err(err) => {
+                    for (position, kept) in positions.into_iter().zip(kept) {
+                        results[position] = match (kept, &err) {
+                            (Some(kept), AppError::NodeUnreachable { .. }) => Ok(Some(kept)),
+                            _ => Err(err.clone())
+                        };
+                    }
+                }
+            }
+        }
+        results
+    }
+
+    // A job kept here is removed from its node as well, which may hold the
+    // job it had before. When the node still can't be reached, that one
+    // is back with it.
+    pub async fn remove(&self, tenant: &str, id: &str) -> Result<Option<Job>, AppError> {
+        let shards = self.shards.read().await;
+        let shard = Cluster::shard(&shards, tenant, id);
+        let kept = self.hints_for(shard).and_then(|hints| hints.remove(tenant, id));
+        match kept {
+            Some(kept) => {
+                self.hinted.lock().expect("Failed to acquire lock").remove(&(tenant.to_owned(), id.to_owned()));
+                match shard.remove(tenant, id).await {
+                    Ok(_) | Err(AppError::NodeUnreachable { .. }) => Ok(Some(kept)),
+                    Err(err) => Err(err)
+                }
+            },
+            None => shard.remove(tenant, id).await
+        }
+    }
+
+    pub async fn pause(&self, tenant: &str, id: &str) -> Result<Option<Job>, AppError> {
+        let shards = self.shards.read().await;
+        let shard = Cluster::shard(&shards, tenant, id);
+        match self.kept_in(shard, tenant, id) {
+            Some(hints) => Ok(hints.pause(tenant, id)),
+            None => shard.pause(tenant, id).await
+        }
+    }
+
+    pub async fn resume(&self, tenant: &str, id: &str) -> Result<Option<Job>, AppError> {
+        let shards = self.shards.read().await;
+        let shard = Cluster::shard(&shards, tenant, id);
+        match self.kept_in(shard, tenant, id) {
+            Some(hints) => Ok(hints.resume(tenant, id)),
+            None => shard.resume(tenant, id).await
+        }
+    }
 
-        shard.push(job).await
+    // Shards which share a backend are only visited once. Migrating shards
+    // are split into their store and node, which other shards may share.
+    fn distinct(shards: &[Shard]) -> Vec<Shard> {
+        let mut distinct: Vec<Shard> = Vec::new();
+        for shard in shards {
+            let backends = match shard {
+                Shard::Migrating(store, client) =>
+                    vec![Shard::Local(store.clone()), Shard::Remote(client.clone())],
+                shard => vec![shard.clone()]
+            };
+            for backend in backends {
+                if !distinct.iter().any(|seen| seen.same_backend(&backend)) {
+                    distinct.push(backend);
+                }
+            }
+        }
+        distinct
     }
 
-    pub async fn remove(&self, id: &str) -> Result<Option<Job>, AppError> {
+    // The kept jobs are changed before the nodes, which still fail the
+    // operation when they can't be reached.
+    pub async fn pause_matching(&self, filter: &JobFilter) -> Result<Vec<Job>, AppError> {
+        let shards = self.shards.read().await;
+        let mut jobs: Vec<Job> = self.hints.iter().flat_map(|hints| hints.pause_matching(filter)).collect();
+        for shard in Cluster::distinct(&shards) {
+            jobs.extend(shard.pause_matching(filter).await?);
+        }
+        Ok(jobs)
+    }
+
+    pub async fn resume_matching(&self, filter: &JobFilter) -> Result<Vec<Job>, AppError> {
+        let shards = self.shards.read().await;
+        let mut jobs: Vec<Job> = self.hints.iter().flat_map(|hints| hints.resume_matching(filter)).collect();
+        for shard in Cluster::distinct(&shards) {
+            jobs.extend(shard.resume_matching(filter).await?);
+        }
+        Ok(jobs)
+    }
+
+    // Kept jobs replace the ones their node may still hold.
+    pub async fn list(&self, filter: &JobFilter) -> Result<Vec<Job>, AppError> {
+        let shards = self.shards.read().await;
+        let mut jobs = Vec::new();
+        for shard in Cluster::distinct(&shards) {
+            if let Some(listed) = self.reached(&shard, shard.list(filter).await)? {
+                jobs.extend(listed);
+            }
+        }
+        if let Some(hints) = &self.hints {
+            jobs.retain(|job| hints.get(&job.tenant, &job.id).is_none());
+            jobs.extend(hints.list(filter));
+        }
+        Ok(jobs)
+    }
+
+    pub async fn remove_matching(&self, filter: &JobFilter) -> Result<Vec<Job>, AppError> {
+        let shards = self.shards.read().await;
+        let mut jobs: Vec<Job> = self.hints.iter().flat_map(|hints| hints.remove_matching(filter)).collect();
+        self.forget(jobs.iter());
+        for shard in Cluster::distinct(&shards) {
+            jobs.extend(shard.remove_matching(filter).await?);
+        }
+        Ok(jobs)
+    }
+
+    // While jobs of the tenant are kept, they're counted from the list
+    // instead, as their node may hold another version of them.
+    pub async fn stats(&self, filter: &JobFilter) -> Result<JobStats, AppError> {
+        let tenant = JobFilter { tenant: filter.tenant.clone(), ..JobFilter::default() };
+        let mut stats = JobStats::default();
+        if self.hints.iter().any(|hints| !hints.list(&tenant).is_empty()) {
+            for job in self.list(filter).await? {
+                stats.add(&job);
+            }
+            return Ok(stats);
+        }
         let shards = self.shards.read().await;
-        let shard = Cluster::shard(&shards, id);
-        shard.remove(id).await
+        for shard in Cluster::distinct(&shards) {
+            if let Some(counted) = self.reached(&shard, shard.stats(filter).await)? {
+                stats.merge(counted);
+            }
+        }
+        Ok(stats)
+    }
+
+    pub async fn usage(&self, tenant: &str) -> Result<JobUsage, AppError> {
+        let shards = self.shards.read().await;
+        self.usage_of(&shards, tenant).await
+    }
+
+    pub async fn quota(&self, tenant: &str) -> Result<QuotaReport, AppError> {
+        let usage = self.usage(tenant).await?;
+        Ok(self.quotas.report(tenant, usage))
+    }
+
+    // The stores of this node are reported as one.
+    pub async fn readiness(&self) -> Vec<HealthCheck> {
+        let shards = self.shards.read().await;
+        let stores = self.stores();
+        let store = stores
+            .iter()
+            .map(|store| store.check())
+            .find(|check| !check.is_ok())
+            .unwrap_or_else(|| HealthCheck::ok("store".to_owned(), None));
+        let queued: usize = stores.iter().map(|store| store.queue_size()).sum();
+        let mut checks = vec![
+            store,
+            HealthCheck::ok("queue".to_owned(), Some(format!("{} jobs queued", queued)))
+        ];
+        // without a leader, ownership can't change and shards don't fire.
+        if let Some(raft) = &self.raft {
+            checks.push(match raft.leader() {
+                Some(leader) => HealthCheck::ok("raft".to_owned(), Some(format!("led by {}", leader))),
+                None => HealthCheck::failing("raft".to_owned(), "no leader is elected".to_owned())
+            });
+        }
+        // the other nodes are asked all at once, each within a timeout.
+        let distinct = Cluster::distinct(&shards);
+        let remote = distinct.iter().filter(|shard| matches!(shard, Shard::Remote(_)));
+        checks.extend(join_all(remote.map(Shard::readiness)).await.into_iter().flatten());
+        checks
+    }
+
+    pub async fn queue_size(&self) -> usize {
+        let shards = self.shards.read().await;
+        Cluster::distinct(&shards).iter().map(Shard::queue_size).sum()
+    }
+
+    pub async fn clear(&self, tenant: &str) -> Result<(), AppError> {
+        let shards = self.shards.read().await;
+        if let Some(hints) = &self.hints {
+            hints.clear(tenant);
+            self.hinted.lock().expect("Failed to acquire lock").retain(|(kept, _), _| kept != tenant);
+        }
+        for shard in Cluster::distinct(&shards) {
+            shard.clear(tenant).await?;
+        }
+        Ok(())
+    }
+
+    // Stores of this node, each once.
+    pub fn stores(&self) -> Vec<Arc<Store>> {
+        let mut stores: Vec<Arc<Store>> = Vec::new();
+        for store in self.stores.iter().chain(&self.hints) {
+            if !stores.iter().any(|seen| Arc::ptr_eq(seen, store)) {
+                stores.push(store.clone());
+            }
+        }
+        stores
+    }
+
+    // State of each shard, along with the jobs stored by this node.
+    pub async fn shard_statuses(&self) -> Vec<ShardStatus> {
+        let shards = self.shards.read().await;
+        let all = JobFilter::default();
+        shards
+            .iter()
+            .enumerate()
+            .map(|(index, shard)| {
+                let (state, node, jobs) = match shard {
+                    Shard::Local(store) => (ShardState::Local, None, Some(store.stats(&all))),
+                    Shard::Remote(client) => (ShardState::Remote, self.node_of(client), None),
+                    Shard::Migrating(store, client) =>
+                        (ShardState::Migrating, self.node_of(client), Some(store.stats(&all)))
+                };
+                ShardStatus { shard: index, state, node, jobs }
+            })
+            .collect()
+    }
+
+    // Health of the other nodes, as seen by the calls made to them.
+    pub fn node_statuses(&self) -> Vec<NodeStatus> {
+        let mut statuses: Vec<NodeStatus> = self.nodes
+            .iter()
+            .map(|(id, client)| client.status(id))
+            .collect();
+        statuses.sort_by(|a, b| a.node.cmp(&b.node));
+        statuses
+    }
+
+    pub fn security(&self) -> Arc<NodeSecurity> {
+        self.security.clone()
+    }
+
+    fn node_of(&self, client: &Arc<NodeClient>) -> Option<String> {
+        self.nodes
+            .iter()
+            .find(|(_, node)| Arc::ptr_eq(node, client))
+            .map(|(id, _)| id.clone())
+    }
+
+    // Drops every job of a shard stored by this node.
+    pub async fn clear_shard(&self, index: usize) -> Result<(), AppError> {
+        let shards = self.shards.read().await;
+        match shards.get(index) {
+            Some(Shard::Local(store)) => {
+                store.clear_all();
+                Ok(())
+            },
+            Some(_) => {
+                let message = format!("shard {} isn't stored on this node", index);
+                Err(AppError::invalid("shard", message))
+            },
+            None => Err(AppError::invalid("shard", format!("shard {} doesn't exist", index)))
+        }
+    }
+
+    fn serving_at(&self, shards: &[Shard], index: usize) -> Shard {
+        match &shards[index] {
+            shard @ Shard::Migrating(..) => shard.clone(),
+            shard @ Shard::Remote(_)
+                if self.migrated.lock().expect("Failed to acquire lock").contains(&index) =>
+                shard.clone(),
+            _ => Shard::Local(self.stores[index].clone())
+        }
+    }
+
+    // Shard the node server handles a job with. Other nodes only send the
+    // jobs of this one, which are stored locally unless their shard moved.
+    pub async fn serving(&self, tenant: &str, id: &str) -> Shard {
+        let shards = self.shards.read().await;
+        self.serving_at(&shards, shard_index(tenant, id))
+    }
+
+    // Groups the positions of the given tenants and ids by the shard the
+    // node server handles them with.
+    pub async fn serving_groups<'a, I>(&self, keys: I) -> Vec<(Shard, Vec<usize>)>
+    where I: Iterator<Item = (&'a str, &'a str)> {
+        let shards = self.shards.read().await;
+        let mut groups: Vec<(Shard, Vec<usize>)> = Vec::new();
+        for (index, positions) in Cluster::group_by_shard(keys) {
+            let shard = self.serving_at(&shards, index);
+            match groups.iter_mut().find(|(seen, _)| seen.same_backend(&shard)) {
+                Some((_, seen)) => seen.extend(positions),
+                None => groups.push((shard, positions))
+            }
+        }
+        groups
+    }
+
+    // Stores of the shards this node fires the jobs of. With a raft log,
+    // none fire while the node can't be sure it still owns them, and the map
+    // the lease covers is adopted first rather than on the next poll. Jobs
+    // kept for unreachable nodes never reached them, so they fire
+    // regardless.
+    pub async fn firing_stores(&self) -> Vec<Arc<Store>> {
+        let mut stores: Vec<Arc<Store>> = self.hints.iter().cloned().collect();
+        if let Some(raft) = &self.raft {
+            if !raft.has_lease() {
+                return stores;
+            }
+            let (map, applied) = raft.map();
+            self.adopt(&map, applied).await;
+        }
+        let shards = self.shards.read().await;
+        let views = self.views.lock().expect("Failed to acquire lock").clone();
+        let down = self.down();
+        let mut handovers = self.handovers.lock().expect("Failed to acquire lock");
+        let now = Instant::now();
+        handovers.retain(|_, since| *since > now);
+        for (index, shard) in shards.iter().enumerate() {
+            if let Shard::Local(store) | Shard::Migrating(store, _) = shard {
+                let seen = stores.iter().any(|seen| Arc::ptr_eq(seen, store));
+                // without a raft log, the nodes agree on who fires the
+                // replicated shards through their heartbeats.
+                let agreed = self.raft.is_some() ||
+                    self.members[index].is_empty() ||
+                    self.may_fire(index, &views, &down, now);
+                if !seen && agreed && !handovers.contains_key(&index) {
+                    stores.push(store.clone());
+                }
+            }
+        }
+        stores
+    }
+
+    // Stores of the shards this node serves, leaving out the replicas of the
+    // shards of other nodes.
+    pub async fn local_stores(&self) -> Vec<Arc<Store>> {
+        let shards = self.shards.read().await;
+        let mut stores: Vec<Arc<Store>> = Vec::new();
+        for shard in shards.iter() {
+            if let Shard::Local(store) | Shard::Migrating(store, _) = shard {
+                if !stores.iter().any(|seen| Arc::ptr_eq(seen, store)) {
+                    stores.push(store.clone());
+                }
+            }
+        }
+        stores
+    }
+
+    // Checks the other nodes and sends the changes of the shards to their
+    // replicas, if any, until the cluster is dropped.
+    fn watch(cluster: &Arc<Cluster>, replicated: bool) {
+        let watched = Arc::downgrade(cluster);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                interval.tick().await;
+                match watched.upgrade() {
+                    Some(cluster) => cluster.heartbeat().await,
+                    None => break
+                }
+            }
+        });
+        if !replicated {
+            return;
+        }
+        let watched = Arc::downgrade(cluster);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REPLICATION_INTERVAL);
+            loop {
+                interval.tick().await;
+                match watched.upgrade() {
+                    Some(cluster) => cluster.replicate().await,
+                    None => break
+                }
+            }
+        });
+    }
+
+    // Sends a heartbeat to every other node, keeping the views they answer
+    // with. Nodes are down once enough calls to them failed in a row, the
+    // heartbeats included.
+    async fn heartbeat(&self) {
+        let asked = Instant::now();
+        let answers = join_all(self.nodes.iter().map(|(id, client)| client.heartbeat(&self.node_id, id))).await;
+        for (id, answer) in self.nodes.keys().zip(answers) {
+            if let Ok(view) = answer {
+                self.views.lock().expect("Failed to acquire lock").insert(id.clone(), (asked, view));
+            }
+        }
+        // with a raft log, the shards only change hands through the log.
+        if self.raft.is_none() {
+            self.elect().await;
+            self.take_back().await;
+        }
+    }
+
+    fn down(&self) -> HashSet<String> {
+        self.nodes
+            .iter()
+            .filter(|(_, client)| client.is_down())
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    // Id of this node, along with the nodes it considers down and the shards
+    // it took over.
+    pub fn view(&self) -> View {
+        let mut down: Vec<String> = self.down().into_iter().collect();
+        down.sort();
+        let mut promoted: Vec<usize> = self.promoted.lock().expect("Failed to acquire lock").keys().cloned().collect();
+        promoted.sort_unstable();
+        View { node: self.node_id.clone(), down, promoted }
+    }
+
+    fn majority(&self, count: usize) -> bool {
+        count * 2 > self.nodes.len() + 1
+    }
+
+    fn fresh(asked: &Instant, now: Instant) -> bool {
+        now.duration_since(*asked) < QUORUM_LEASE
+    }
+
+    // Whether most nodes, this one included, heard from it lately.
+    fn quorate_at(&self, views: &Views, now: Instant) -> bool {
+        let hearing = views
+            .values()
+            .filter(|(asked, view)| Cluster::fresh(asked, now) && !view.down.contains(&self.node_id))
+            .count();
+        self.majority(hearing + 1)
+    }
+
+    // Whether most nodes, this one included, lately considered the node down.
+    fn agreed_down(
+        &self,
+        node: &str,
+        views: &Views,
+        down: &HashSet<String>,
+        now: Instant
+    ) -> bool {
+        let agreeing = views
+            .values()
+            .filter(|(asked, view)| Cluster::fresh(asked, now) && view.down.iter().any(|id| id == node))
+            .count();
+        self.majority(agreeing + down.contains(node) as usize)
+    }
+
+    // Whether this node may serve a replicated shard: it owns the shard, or
+    // most nodes agree the nodes before it are down.
+    fn eligible(
+        &self,
+        index: usize,
+        views: &Views,
+        down: &HashSet<String>,
+        now: Instant
+    ) -> bool {
+        let members = &self.members[index];
+        match members.iter().position(|member| *member == self.node_id) {
+            Some(position) => members[..position]
+                .iter()
+                .all(|member| self.agreed_down(member, views, down, now)),
+            None => false
+        }
+    }
+
+    // Node after this one among those holding the shard which lately
+    // answered it took the shard over, and wasn't taken it back from yet.
+    fn holder<'a>(&self, index: usize, views: &'a Views, now: Instant) -> Option<&'a String> {
+        let members = &self.members[index];
+        let position = members.iter().position(|member| *member == self.node_id)?;
+        members[position + 1..]
+            .iter()
+            .filter_map(|member| views.get_key_value(member))
+            .find(|(_, (asked, view))| Cluster::fresh(asked, now) && view.promoted.contains(&index))
+            .map(|(id, _)| id)
     }
 
-    pub async fn clear(&self) -> Result<(), AppError> {
-        for shard in self.shards.read().await.iter() {
-            shard.clear().await?;
+    // Whether the jobs of a replicated shard this node serves may fire here:
+    // only while most nodes hear from it and agree it may serve the shard,
+    // and once the jobs changed on the node which took the shard over while
+    // this one was away are taken back.
+    fn may_fire(
+        &self,
+        index: usize,
+        views: &Views,
+        down: &HashSet<String>,
+        now: Instant
+    ) -> bool {
+        self.quorate_at(views, now) &&
+            self.eligible(index, views, down, now) &&
+            self.holder(index, views, now).is_none()
+    }
+
+    // Each replicated shard is served by the first of its nodes which most
+    // nodes don't consider down, so a replica only takes over from an owner
+    // the majority lost, and hands the shard back once the owner is heard
+    // from again. Nodes not serving a shard send its jobs to the first of its
+    // nodes they consider up, or to the one holding it while cut off from
+    // most nodes themselves.
+    async fn elect(&self) {
+        let now = Instant::now();
+        let views = self.views.lock().expect("Failed to acquire lock").clone();
+        let down = self.down();
+        let quorate = self.quorate_at(&views, now);
+        // the shards of a node which was cut off from most nodes may have
+        // been taken over meanwhile, they only fire once the nodes which
+        // did surely stopped.
+        if quorate && !self.quorate.swap(true, Ordering::SeqCst) {
+            let mut handovers = self.handovers.lock().expect("Failed to acquire lock");
+            for (index, members) in self.members.iter().enumerate() {
+                if !members.is_empty() {
+                    handovers.insert(index, now + QUORUM_LEASE);
+                }
+            }
+        }
+        if !quorate && self.quorate.swap(false, Ordering::SeqCst) {
+            warn!("Most nodes don't hear from this one, its replicated shards stop firing");
+        }
+        let targets: Vec<Option<Option<&String>>> = self.members
+            .iter()
+            .enumerate()
+            .map(|(index, members)| {
+                if members.is_empty() {
+                    return None;
+                }
+                let holder = self.holder(index, &views, now);
+                Some(match self.eligible(index, &views, &down, now) {
+                    true if quorate || holder.is_none() => None,
+                    _ if holder.is_some() => holder,
+                    _ => members
+                        .iter()
+                        .find(|member| **member != self.node_id && !down.contains(*member))
+                        .or_else(|| members.first())
+                })
+            })
+            .collect();
+        let serves = |shards: &[Shard], index: usize, target: Option<&String>| match (&shards[index], target) {
+            (Shard::Local(_), None) => true,
+            (Shard::Remote(client), Some(node)) => Arc::ptr_eq(client, &self.nodes[node]),
+            _ => false
+        };
+        let changed = {
+            let shards = self.shards.read().await;
+            targets
+                .iter()
+                .enumerate()
+                .any(|(index, target)| matches!(target, Some(target) if !serves(&shards, index, *target)))
+        };
+        if !changed {
+            return;
+        }
+        let mut shards = self.shards.write().await;
+        let migrated = self.migrated.lock().expect("Failed to acquire lock").clone();
+        let mut promoted = self.promoted.lock().expect("Failed to acquire lock");
+        let mut handovers = self.handovers.lock().expect("Failed to acquire lock");
+        for (index, target) in targets.into_iter().enumerate() {
+            let target = match target {
+                Some(target) => target,
+                None => continue
+            };
+            let moving = matches!(shards[index], Shard::Migrating(..)) || migrated.contains(&index);
+            if moving || serves(&shards, index, target) {
+                continue;
+            }
+            shards[index] = match target {
+                None => {
+                    let owner = &self.members[index][0];
+                    if *owner != self.node_id {
+                        info!("Taking over shard {} from {}", index, owner);
+                        promoted.entry(index).or_default();
+                        handovers.insert(index, now + QUORUM_LEASE);
+                    }
+                    Shard::Local(self.stores[index].clone())
+                },
+                Some(node) => Shard::Remote(self.nodes[node].clone())
+            };
+        }
+    }
+
+    // Takes back the jobs changed on the shards a node after this one took
+    // over while this one was away, once most nodes hear from it again. The
+    // node is then sent every job of the shards, which ends its hold on them.
+    async fn take_back(&self) {
+        let now = Instant::now();
+        let views = self.views.lock().expect("Failed to acquire lock").clone();
+        let down = self.down();
+        if !self.quorate_at(&views, now) {
+            return;
+        }
+        for (index, members) in self.members.iter().enumerate() {
+            if members.is_empty() || !self.eligible(index, &views, &down, now) {
+                continue;
+            }
+            let holder = match self.holder(index, &views, now) {
+                Some(holder) => holder,
+                None => continue
+            };
+            match timeout(HEARTBEAT_INTERVAL, self.nodes[holder].snapshot(index)).await {
+                Ok(Ok(snapshot)) => {
+                    if let Some((jobs, removed)) = snapshot {
+                        info!("Taking back shard {} from {}", index, holder);
+                        self.stores[index].apply(jobs, &removed, false);
+                        self.handovers
+                            .lock()
+                            .expect("Failed to acquire lock")
+                            .insert(index, Instant::now() + QUORUM_LEASE);
+                        self.unsynced.lock().expect("Failed to acquire lock").insert(holder.clone());
+                    }
+                    if let Some((_, view)) = self.views.lock().expect("Failed to acquire lock").get_mut(holder) {
+                        view.promoted.retain(|shard| *shard != index);
+                    }
+                },
+                Ok(Err(err)) => warn!("Failed to take back shard {} from {} - {}", index, holder, err),
+                Err(_) => warn!("Failed to take back shard {} from {} - the call timed out", index, holder)
+            }
+        }
+    }
+
+    // Builds the shards again whenever entries of the raft log are
+    // committed, and hands the shards of unreachable nodes over to their
+    // replicas while this node leads, until the cluster is dropped.
+    async fn follow(cluster: &Arc<Cluster>) {
+        let (map, applied) = cluster.raft().expect("The cluster doesn't keep a raft log").map();
+        cluster.adopt(&map, applied).await;
+        let followed = Arc::downgrade(cluster);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FOLLOW_INTERVAL);
+            loop {
+                interval.tick().await;
+                let cluster = match followed.upgrade() {
+                    Some(cluster) => cluster,
+                    None => break
+                };
+                let raft = cluster.raft().expect("The cluster doesn't keep a raft log");
+                let (map, applied) = raft.map();
+                cluster.adopt(&map, applied).await;
+                if raft.is_leader() {
+                    cluster.failover(raft, &map).await;
+                }
+            }
+        });
+    }
+
+    // Shards owned by this node use their store, the others the node owning
+    // them. Migrating shards are left alone until their migration completes.
+    // Maps built from as many entries as the adopted one, or fewer, are
+    // left alone too.
+    async fn adopt(&self, map: &ClusterMap, applied: usize) {
+        if self.adopted.load(Ordering::SeqCst) >= applied {
+            return;
         }
+        let mut shards = self.shards.write().await;
+        if self.adopted.load(Ordering::SeqCst) >= applied {
+            return;
+        }
+        let mut handovers = self.handovers.lock().expect("Failed to acquire lock");
+        for (index, owner) in map.owners.iter().enumerate() {
+            match &shards[index] {
+                Shard::Migrating(..) => continue,
+                Shard::Local(_) if owner == &self.node_id => continue,
+                _ => {}
+            }
+            shards[index] = match self.nodes.get(owner) {
+                Some(client) => Shard::Remote(client.clone()),
+                None => {
+                    info!("Shard {} was handed over to this node", index);
+                    handovers.insert(index, Instant::now() + HANDOVER_DELAY);
+                    Shard::Local(self.stores[index].clone())
+                }
+            };
+        }
+        self.adopted.store(applied, Ordering::SeqCst);
+    }
+
+    // The leader hands the shards of nodes it didn't hear from for a while
+    // over to the first of their other nodes it still hears from. Shards
+    // without replicas stay with their owner, as their jobs are only stored
+    // there.
+    async fn failover(&self, raft: &Raft, map: &ClusterMap) {
+        let unreachable = raft.unreachable(FAILOVER_TIMEOUT);
+        if unreachable.is_empty() {
+            return;
+        }
+        let mut moves: HashMap<String, Vec<usize>> = HashMap::new();
+        for (index, owner) in map.owners.iter().enumerate() {
+            if !unreachable.contains(owner) {
+                continue;
+            }
+            let target = self.members[index]
+                .iter()
+                .find(|member| *member != owner && !unreachable.contains(member));
+            if let Some(target) = target {
+                moves.entry(target.clone()).or_default().push(index);
+            }
+        }
+        for (node, shards) in moves {
+            warn!("Handing shards {:?} over to {}", shards, node);
+            if let Err(err) = raft.propose(Command::Assign { shards, node }).await {
+                error!("Failed to hand shards over - {}", err);
+            }
+        }
+    }
+
+    // Sends the jobs which changed in the shards this node serves to their
+    // other nodes. Nodes which missed some of the changes are sent every job
+    // of the shards instead.
+    async fn replicate(&self) {
+        let serving: Vec<bool> = {
+            let shards = self.shards.read().await;
+            shards.iter().map(|shard| matches!(shard, Shard::Local(_))).collect()
+        };
+        let down = self.down();
+        let unsynced = self.unsynced.lock().expect("Failed to acquire lock").clone();
+        let views = self.views.lock().expect("Failed to acquire lock").clone();
+        let mut failed: HashSet<String> = HashSet::new();
+        for (index, members) in self.members.iter().enumerate() {
+            let store = &self.stores[index];
+            // changes of the shards served by other nodes are theirs to send.
+            let (jobs, removed) = store.take_changes();
+            if let Some(changed) = self.promoted.lock().expect("Failed to acquire lock").get_mut(&index) {
+                changed.extend(jobs.iter().map(Store::queue_key));
+                changed.extend(removed.iter().cloned());
+            }
+            if members.is_empty() || !serving[index] {
+                continue;
+            }
+            let peers = members
+                .iter()
+                .filter(|member| **member != self.node_id && !down.contains(*member));
+            for peer in peers {
+                // a node holding changes of its own is only sent the shard
+                // once they're taken back, which its answer to a heartbeat
+                // tells.
+                let holding = views
+                    .get(peer)
+                    .map_or(true, |(_, view)| view.promoted.contains(&index));
+                if holding {
+                    failed.insert(peer.clone());
+                    continue;
+                }
+                let client = &self.nodes[peer];
+                let sent = match unsynced.contains(peer) {
+                    true => client.replicate(index, store.list(&JobFilter::default()), vec![], true).await,
+                    false if jobs.is_empty() && removed.is_empty() => Ok(()),
+                    false => client.replicate(index, jobs.clone(), removed.clone(), false).await
+                };
+                if let Err(err) = sent {
+                    warn!("Failed to replicate shard {} to {} - {}", index, peer, err);
+                    failed.insert(peer.clone());
+                }
+            }
+        }
+        // nodes which are down miss the changes sent meanwhile.
+        let mut current = self.unsynced.lock().expect("Failed to acquire lock");
+        for peer in self.nodes.keys() {
+            if failed.contains(peer) || down.contains(peer) {
+                current.insert(peer.clone());
+            } else if unsynced.contains(peer) {
+                current.remove(peer);
+            }
+        }
+    }
+
+    // Sends the jobs kept for unreachable nodes to the nodes serving their
+    // shards once they're back, until the cluster is dropped.
+    fn hand_off(cluster: &Arc<Cluster>) {
+        let handing = Arc::downgrade(cluster);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HANDOFF_INTERVAL);
+            loop {
+                interval.tick().await;
+                match handing.upgrade() {
+                    Some(cluster) => cluster.forward_hints().await,
+                    None => break
+                }
+            }
+        });
+    }
+
+    // The jobs are taken out of the store before they are sent, so they
+    // can't fire on both nodes, and put back when sending them fails. Their
+    // shards are looked up first, so the calls don't hold up other writes.
+    async fn forward_hints(&self) {
+        let hints = match &self.hints {
+            Some(hints) => hints,
+            None => return
+        };
+        let (jobs, targets) = {
+            let shards = self.shards.read().await;
+            let jobs = hints.take(HANDOFF_BATCH, |job| match &shards[Cluster::job_shard(job)] {
+                Shard::Remote(client) => client.is_up(),
+                _ => true
+            });
+            let targets: HashMap<usize, Shard> = jobs
+                .iter()
+                .map(Cluster::job_shard)
+                .map(|index| (index, shards[index].clone()))
+                .collect();
+            (jobs, targets)
+        };
+        // the jobs which fired or were removed here are forgotten once
+        // there's nothing to send.
+        if jobs.is_empty() {
+            self.hinted
+                .lock()
+                .expect("Failed to acquire lock")
+                .retain(|(tenant, id), _| hints.get(tenant, id).is_some());
+            return;
+        }
+        let keys = jobs.iter().map(|job| (job.tenant.as_str(), job.id.as_str()));
+        let groups = Cluster::group_by_shard(keys);
+        let mut jobs: Vec<Option<Job>> = jobs.into_iter().map(Some).collect();
+        for (index, positions) in groups {
+            let portion: Vec<Job> = positions
+                .into_iter()
+                .filter_map(|position| jobs[position].take())
+                .collect();
+            let count = portion.len();
+            let aged: Vec<(Job, Duration)> = {
+                let hinted = self.hinted.lock().expect("Failed to acquire lock");
+                portion
+                    .iter()
+                    .map(|job| {
+                        let kept = hinted.get(&Store::queue_key(job)).copied().unwrap_or(self.started);
+                        (job.clone(), kept.elapsed())
+                    })
+                    .collect()
+            };
+            match targets[&index].hand_off(aged).await {
+                Ok(()) => {
+                    let mut hinted = self.hinted.lock().expect("Failed to acquire lock");
+                    for job in &portion {
+                        hinted.remove(&Store::queue_key(job));
+                    }
+                    info!("Handed {} jobs of shard {} off", count, index);
+                },
+                Err(err) => {
+                    warn!("Failed to hand the jobs of shard {} off: {}", index, err);
+                    hints.push_batch(portion);
+                }
+            }
+        }
+    }
+
+    // Applies the jobs of a shard sent by the node serving it. Every job
+    // being sent means this node no longer serves it.
+    pub fn apply_replica(
+        &self,
+        shard: usize,
+        jobs: Vec<Job>,
+        removed: Vec<(String, String)>,
+        replace: bool
+    ) -> Result<(), AppError> {
+        let store = self.stores
+            .get(shard)
+            .ok_or_else(|| AppError::invalid("shard", format!("shard {} doesn't exist", shard)))?;
+        if replace {
+            self.promoted.lock().expect("Failed to acquire lock").remove(&shard);
+        }
+        store.apply(jobs, &removed, replace);
         Ok(())
     }
+
+    // Jobs changed on a shard since this node took it over, for the node
+    // before it to take back.
+    pub fn snapshot(&self, shard: usize) -> Result<Option<Changes>, AppError> {
+        let store = self.stores
+            .get(shard)
+            .ok_or_else(|| AppError::invalid("shard", format!("shard {} doesn't exist", shard)))?;
+        let promoted = self.promoted.lock().expect("Failed to acquire lock");
+        let changed = match promoted.get(&shard) {
+            Some(changed) => changed,
+            None => return Ok(None)
+        };
+        let mut jobs = Vec::new();
+        let mut removed = Vec::new();
+        for (tenant, id) in changed.iter() {
+            match store.get(tenant, id) {
+                Some(job) => jobs.push(job),
+                None => removed.push((tenant.clone(), id.clone()))
+            }
+        }
+        Ok(Some((jobs, removed)))
+    }
+
+    pub fn migrations(&self) -> Vec<Migration> {
+        self.migrations.lock().expect("Failed to acquire lock").clone()
+    }
+
+    // Starts moving the shards to another node. The shards are migrating
+    // until all of their jobs are on the target: new jobs go to the target
+    // right away, while the stored ones are sent in batches. A failed
+    // migration leaves the shards migrating, and is resumed by starting it
+    // again.
+    pub async fn migrate(
+        self: &Arc<Self>,
+        range: ShardRange,
+        target: &str
+    ) -> Result<Migration, AppError> {
+        if range.end >= SHARD_COUNT {
+            return Err(AppError::invalid("shards", format!("shard {} doesn't exist", range.end)));
+        }
+        let client = self.nodes
+            .get(target)
+            .cloned()
+            .ok_or_else(|| AppError::invalid("target", format!("unknown node {}", target)))?;
+        let mut shards = self.shards.write().await;
+        let mut migrations = self.migrations.lock().expect("Failed to acquire lock");
+        if migrations.iter().any(|migration| migration.state == MigrationState::Running) {
+            return Err(AppError::invalid("shards", "another migration is running"));
+        }
+        for index in range.start..=range.end {
+            let movable = match &shards[index] {
+                Shard::Local(_) => true,
+                Shard::Migrating(_, to) => Arc::ptr_eq(to, &client),
+                Shard::Remote(_) => false
+            };
+            if !movable {
+                let message = format!("shard {} isn't stored on this node", index);
+                return Err(AppError::invalid("shards", message));
+            }
+        }
+        for index in range.start..=range.end {
+            shards[index] = Shard::Migrating(self.stores[index].clone(), client.clone());
+        }
+        let migration = Migration {
+            id: Uuid::new_v4().to_string(),
+            shards: range.to_string(),
+            target: target.to_owned(),
+            state: MigrationState::Running,
+            moved: 0,
+            error: None
+        };
+        migrations.push(migration.clone());
+        info!("Migrating shards {} to {}", range, target);
+
+        let cluster = self.clone();
+        let id = migration.id.clone();
+        tokio::spawn(async move {
+            let (state, error) = match cluster.transfer(&id, range, client).await {
+                Ok(()) => (MigrationState::Completed, None),
+                Err(err) => {
+                    error!("Migration of shards {} failed - {}", range, err);
+                    (MigrationState::Failed, Some(err.to_string()))
+                }
+            };
+            cluster.update_migration(&id, |migration| {
+                migration.state = state;
+                migration.error = error;
+            });
+        });
+        Ok(migration)
+    }
+
+    fn update_migration<F>(&self, id: &str, update: F)
+    where F: FnOnce(&mut Migration) {
+        let mut migrations = self.migrations.lock().expect("Failed to acquire lock");
+        if let Some(migration) = migrations.iter_mut().find(|migration| migration.id == id) {
+            update(migration);
+        }
+    }
+
+    // Sends the stored jobs of the shards to the target, then hands the
+    // shards over once none are left. The last jobs are swept within the
+    // fence of the stores, which only holds up the writes to those shards.
+    async fn transfer(
+        &self,
+        id: &str,
+        range: ShardRange,
+        client: Arc<NodeClient>
+    ) -> Result<(), AppError> {
+        while self.send_batch(id, range, &client).await? > 0 {}
+        let mut stores: Vec<Arc<Store>> = Vec::new();
+        for store in &self.stores[range.start..=range.end] {
+            if !stores.iter().any(|seen| Arc::ptr_eq(seen, store)) {
+                stores.push(store.clone());
+            }
+        }
+        let mut fences = Vec::new();
+        for store in &stores {
+            fences.push(store.fence().write().await);
+        }
+        while self.send_batch(id, range, &client).await? > 0 {}
+        self.recall(range, &client).await?;
+        drop(fences);
+
+        // nothing is written to the stores of the shards from then on, as
+        // the writes only put back what they took.
+        let target = self.node_of(&client).expect("The target isn't part of the cluster");
+        match &self.raft {
+            Some(raft) => {
+                let shards = (range.start..=range.end).collect();
+                raft.propose(Command::Assign { shards, node: target }).await?;
+            },
+            None => {
+                for store in &self.stores[range.start..=range.end] {
+                    store.set_moved_to(&target);
+                }
+            }
+        }
+        let mut shards = self.shards.write().await;
+        for shard in shards.iter_mut().take(range.end + 1).skip(range.start) {
+            *shard = Shard::Remote(client.clone());
+        }
+        self.migrated
+            .lock()
+            .expect("Failed to acquire lock")
+            .extend(range.start..=range.end);
+        info!("Migrated shards {}", range);
+        Ok(())
+    }
+
+    // Jobs are taken out of the store before they are sent, so they can't
+    // fire on both nodes, and put back when the target can't be reached.
+    // The target may have stored them anyway, so they are remembered until
+    // they're sent again or recalled.
+    async fn send_batch(
+        &self,
+        id: &str,
+        range: ShardRange,
+        client: &NodeClient
+    ) -> Result<usize, AppError> {
+        for index in range.start..=range.end {
+            let store = &self.stores[index];
+            let batch = store.take(MIGRATION_BATCH, |job| Cluster::job_shard(job) == index);
+            if batch.is_empty() {
+                continue;
+            }
+            let count = batch.len();
+            let result = client.push_batch(batch.clone()).await;
+            let mut unsent = self.unsent.lock().expect("Failed to acquire lock");
+            for job in &batch {
+                let key = (job.tenant.clone(), job.id.clone());
+                match &result {
+                    Ok(()) => unsent.remove(&key),
+                    Err(_) => unsent.insert(key, job.clone())
+                };
+            }
+            drop(unsent);
+            if let Err(err) = result {
+                store.push_batch(batch);
+                return Err(err);
+            }
+            self.update_migration(id, |migration| migration.moved += count as u64);
+            return Ok(count);
+        }
+        Ok(0)
+    }
+
+    // Jobs of the shards the target may have stored when they failed to be
+    // sent, and which weren't sent again since, fired or were removed on
+    // this node. They're removed from the target unless it was sent a newer
+    // version since.
+    async fn recall(&self, range: ShardRange, client: &NodeClient) -> Result<(), AppError> {
+        let recalled: Vec<(QueueKey, Job)> = self.unsent
+            .lock()
+            .expect("Failed to acquire lock")
+            .iter()
+            .filter(|(_, job)| range.contains(Cluster::job_shard(job)))
+            .map(|(key, job)| (key.clone(), job.clone()))
+            .collect();
+        for ((tenant, id), job) in recalled {
+            let stored = client.get(&tenant, &id).await?;
+            if stored.is_some_and(|stored| Cluster::same_version(&stored, &job)) {
+                client.remove(&tenant, &id).await?;
+            }
+            self.unsent.lock().expect("Failed to acquire lock").remove(&(tenant, id));
+        }
+        Ok(())
+    }
+
+    // Timestamps are sent to other nodes in milliseconds.
+    fn same_version(a: &Job, b: &Job) -> bool {
+        a.timestamp.as_millis() == b.timestamp.as_millis()
+            && a.method == b.method
+            && a.url == b.url
+            && a.body == b.body
+            && a.schedule == b.schedule
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::schema::{MisfirePolicy, PeerState, DEFAULT_TENANT};
+    use crate::config::{EgressConfig, GossipConfig, NodeConfig, QuotaConfig, ShardRange};
+    use crate::node::server::NodeServer;
+    use std::collections::BTreeMap;
     use uuid::Uuid;
     use std::time::{UNIX_EPOCH, SystemTime, Duration};
-    use std::thread::{JoinHandle, spawn};
+    use net2::TcpBuilder;
+    use tokio::net::TcpListener;
 
     fn random_job() -> Job {
         let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + Duration::from_millis(10000);
@@ -68,8 +1809,895 @@ mod test {
             body: "{}".to_owned(),
             timestamp,
             id: Uuid::new_v4().to_string(),
-            schedule: None
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
+        }
+    }
+
+    fn open(path: &str) -> Arc<Store> {
+        Arc::new(Store::new(sled::open(path).unwrap().open_tree("jobs").unwrap()))
+    }
+
+    // every shard is stored in the same store.
+    fn shared(store: &Arc<Store>) -> Vec<Arc<Store>> {
+        vec![store.clone(); SHARD_COUNT]
+    }
+
+    fn node(id: &str, address: &str, shards: &str) -> NodeConfig {
+        NodeConfig {
+            id: id.to_owned(),
+            address: address.to_owned(),
+            shards: shards.split(',').filter(|range| !range.is_empty()).map(|range| range.parse().unwrap()).collect()
+        }
+    }
+
+    fn config(id: &str, nodes: Vec<NodeConfig>) -> ClusterConfig {
+        ClusterConfig {
+            node_id: Some(id.to_owned()),
+            nodes,
+            ..ClusterConfig::default()
+        }
+    }
+
+    async fn start(stores: Vec<Arc<Store>>, log: Option<Tree>, hints: Option<Tree>, config: &ClusterConfig) -> Arc<Cluster> {
+        let quotas = Arc::new(Quotas::new(&QuotaConfig::default()));
+        let egress = Arc::new(Egress::new(&EgressConfig::default()));
+        Cluster::start(stores, log, hints, quotas, egress, config).await
+    }
+
+    // a job for one of the shards of b, which owns 64-126.
+    fn remote_job() -> Job {
+        loop {
+            let job = random_job();
+            if Cluster::job_shard(&job) > 63 {
+                break job;
+            }
+        }
+    }
+
+    // A port picked by the system, so tests running at the same time never
+    // share one. Connections to it are refused until a node is served on it.
+    struct Port(TcpBuilder);
+
+    impl Port {
+        fn new() -> Port {
+            Port::at("127.0.0.1:0")
+        }
+
+        // the port of a node which stopped, to serve it again.
+        fn at(address: &str) -> Port {
+            let socket = TcpBuilder::new_v4().unwrap();
+            socket.reuse_address(true).unwrap();
+            socket.bind(address).unwrap();
+            Port(socket)
+        }
+
+        fn url(&self) -> String {
+            format!("http://{}", self.0.local_addr().unwrap())
+        }
+
+        async fn serve(self, cluster: Arc<Cluster>) -> NodeServer {
+            let listener = TcpListener::from_std(self.0.listen(128).unwrap()).unwrap();
+            NodeServer::serve(listener, cluster).await
+        }
+    }
+
+    #[test]
+    fn owners() {
+        let mut config = ClusterConfig {
+            node_id: Some("a".to_owned()),
+            bind: None,
+            address: None,
+            gossip: None,
+            replicas: 0,
+            raft: false,
+            handoff: false,
+            tls: None,
+            token: None,
+            nodes: vec![node("a", "http://a", "0-63"), node("b", "http://b", "64-125,126")]
+        };
+        let owners = Cluster::owners(&config).unwrap();
+        assert_eq!(owners[63], 0);
+        assert_eq!(owners[64], 1);
+        assert_eq!(owners[126], 1);
+
+        config.nodes[1] = node("b", "http://b", "60-126");
+        assert_eq!(Cluster::owners(&config).unwrap_err(), "shard 60 is owned by a and b");
+        config.nodes[1] = node("b", "http://b", "64-100");
+        assert_eq!(Cluster::owners(&config).unwrap_err(), "shard 101 has no owner");
+        config.nodes[1] = node("b", "http://b", "64-127");
+        assert!(Cluster::owners(&config).is_err());
+        assert!("64-10".parse::<ShardRange>().is_err());
+    }
+
+    #[test]
+    fn ring() {
+        // pinned, so a change of the hash doesn't go unnoticed.
+        assert_eq!(stable_hash(&["default", "job-1"]), 13531297201717309168);
+        assert_eq!(shard_index("default", "job-1"), 79);
+
+        let mut config = ClusterConfig {
+            node_id: Some("a".to_owned()),
+            bind: None,
+            address: None,
+            gossip: None,
+            replicas: 0,
+            raft: false,
+            handoff: false,
+            tls: None,
+            token: None,
+            nodes: ["a", "b", "c"]
+                .iter()
+                .map(|id| NodeConfig {
+                    id: id.to_string(),
+                    address: format!("http://{}", id),
+                    shards: vec![]
+                })
+                .collect()
+        };
+        let owners = Cluster::owners(&config).unwrap();
+        for node in 0..3 {
+            assert!(owners.iter().filter(|owner| **owner == node).count() > 10);
+        }
+        // the order of the nodes doesn't matter.
+        config.nodes.reverse();
+        let reversed: Vec<usize> = Cluster::owners(&config).unwrap().iter().map(|owner| 2 - owner).collect();
+        assert_eq!(owners, reversed);
+        config.nodes.reverse();
+
+        // a new node only takes shards, the others keep theirs.
+        config.nodes.push(NodeConfig {
+            id: "d".to_owned(),
+            address: "http://d".to_owned(),
+            shards: vec![]
+        });
+        let grown = Cluster::owners(&config).unwrap();
+        let moved = owners.iter().zip(&grown).filter(|(before, after)| before != after).count();
+        assert!(grown.iter().zip(&owners).all(|(after, before)| after == before || *after == 3));
+        assert!(moved > 10 && moved < 60, "{} shards moved", moved);
+    }
+
+    #[tokio::test]
+    async fn remote_shards() {
+        let data_dir = ".test/".to_owned() + &Uuid::new_v4().to_string();
+        let local = open(&(data_dir.clone() + "/a"));
+        let remote = open(&(data_dir.clone() + "/b"));
+        let port = Port::new();
+        let config = config("a", vec![node("a", "http://unused", "0-63"), node("b", &port.url(), "64-126")]);
+        let server = port.serve(start(shared(&remote), None, None, &ClusterConfig::default()).await).await;
+        let cluster = start(shared(&local), None, None, &config).await;
+
+        let jobs: Vec<Job> = (0..20).map(|_| random_job()).collect();
+        for job in &jobs {
+            cluster.push(job.clone()).await.unwrap();
+        }
+        let filter = JobFilter::default().in_tenant(DEFAULT_TENANT);
+        let (local_jobs, remote_jobs) = (local.list(&filter), remote.list(&filter));
+        assert_eq!(local_jobs.len() + remote_jobs.len(), 20);
+        // with 20 jobs, both nodes are all but certain to get some.
+        assert!(!local_jobs.is_empty() && !remote_jobs.is_empty());
+        assert_eq!(cluster.list(&filter).await.unwrap().len(), 20);
+        // timestamps sent to other nodes are in milliseconds.
+        for job in &jobs {
+            let found = cluster.get(DEFAULT_TENANT, &job.id).await.unwrap().unwrap();
+            assert_eq!(found.url, job.url);
         }
+        let remote_id = &remote_jobs[0].id;
+        assert!(cluster.remove(DEFAULT_TENANT, remote_id).await.unwrap().is_some());
+        assert!(remote.get(DEFAULT_TENANT, remote_id).is_none());
+        assert_eq!(cluster.usage(DEFAULT_TENANT).await.unwrap().pending, 19);
+
+        server.stop();
+        tokio::fs::remove_dir_all(&data_dir).await.expect("Error removing directory");
+    }
+
+    #[tokio::test]
+    async fn quotas() {
+        let data_dir = ".test/".to_owned() + &Uuid::new_v4().to_string();
+        let store = open(&(data_dir.clone() + "/a"));
+        let egress = Arc::new(Egress::new(&EgressConfig::default()));
+        let limits = QuotaConfig { max_pending: Some(5), ..QuotaConfig::default() };
+        let quotas = Arc::new(Quotas::new(&limits));
+        let single = ClusterConfig::default();
+        let cluster = Cluster::start(shared(&store), None, None, quotas, egress.clone(), &single).await;
+
+        // concurrent pushes can't both take the last of the quota.
+        let pushed = join_all((0..20).map(|_| cluster.push(random_job()))).await;
+        assert_eq!(pushed.iter().filter(|result| result.is_ok()).count(), 5);
+        assert_eq!(cluster.usage(DEFAULT_TENANT).await.unwrap().pending, 5);
+        // jobs removed since they were counted make room right away.
+        let removed = store.list(&JobFilter::default().in_tenant(DEFAULT_TENANT))[0].id.clone();
+        cluster.remove(DEFAULT_TENANT, &removed).await.unwrap();
+        cluster.push(random_job()).await.unwrap();
+        assert!(cluster.push(random_job()).await.is_err());
+
+        // jobs b can't store don't take a create.
+        let limits = QuotaConfig { max_creates_per_minute: Some(1), ..QuotaConfig::default() };
+        let quotas = Arc::new(Quotas::new(&limits));
+        let port = Port::new();
+        let nodes = vec![node("a", "http://unused", "0-63"), node("b", &port.url(), "64-126")];
+        let cluster = Cluster::start(shared(&store), None, None, quotas.clone(), egress, &config("a", nodes)).await;
+        assert!(cluster.push(remote_job()).await.is_err());
+        assert_eq!(quotas.report(DEFAULT_TENANT, JobUsage::default()).creates_per_minute.used, 0);
+        tokio::fs::remove_dir_all(&data_dir).await.expect("Error removing directory");
+    }
+
+    #[tokio::test]
+    async fn readiness() {
+        let data_dir = ".test/".to_owned() + &Uuid::new_v4().to_string();
+        let local = open(&(data_dir.clone() + "/a"));
+        // connections are accepted by the system but never answered.
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", silent.local_addr().unwrap());
+        let config = config("a", vec![node("a", "http://unused", "0-63"), node("b", &address, "64-126")]);
+        let cluster = start(shared(&local), None, None, &config).await;
+
+        let checks = timeout(Duration::from_secs(5), cluster.readiness()).await.expect("readiness hung");
+        let node = checks.iter().find(|check| check.name == format!("node {}", address)).unwrap();
+        assert!(!node.is_ok());
+        assert!(checks.iter().filter(|check| check.name != node.name).all(HealthCheck::is_ok));
+        tokio::fs::remove_dir_all(&data_dir).await.expect("Error removing directory");
+    }
+
+    #[tokio::test]
+    async fn migration() {
+        let data_dir = ".test/".to_owned() + &Uuid::new_v4().to_string();
+        let db = sled::open(data_dir.clone() + "/a").unwrap();
+        let locals = Store::open_shards(&db, SHARD_COUNT);
+        let remote = open(&(data_dir.clone() + "/b"));
+        let port = Port::new();
+        // the target doesn't own any shard yet.
+        let config = config("a", vec![node("a", "http://unused", "0-126"), node("b", &port.url(), "")]);
+        let server = port.serve(start(shared(&remote), None, None, &ClusterConfig::default()).await).await;
+        let cluster = start(locals.clone(), None, None, &config).await;
+        let jobs: Vec<Job> = (0..20).map(|_| random_job()).collect();
+        for job in &jobs {
+            cluster.push(job.clone()).await.unwrap();
+        }
+
+        let range: ShardRange = "0-63".parse().unwrap();
+        assert!(cluster.migrate(range, "c").await.is_err());
+        assert!(cluster.migrate("0-127".parse().unwrap(), "b").await.is_err());
+        let migration = cluster.migrate(range, "b").await.unwrap();
+        assert_eq!(migration.shards, "0-63");
+        let mut migrations = cluster.migrations();
+        for _ in 0..50 {
+            if migrations[0].state != MigrationState::Running {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+            migrations = cluster.migrations();
+        }
+        assert_eq!(migrations[0].state, MigrationState::Completed);
+
+        let moved: Vec<&Job> = jobs.iter().filter(|job| range.contains(Cluster::job_shard(job))).collect();
+        assert_eq!(migrations[0].moved, moved.len() as u64);
+        let filter = JobFilter::default().in_tenant(DEFAULT_TENANT);
+        assert_eq!(remote.list(&filter).len(), moved.len());
+        let stored: usize = locals.iter().map(|store| store.list(&filter).len()).sum();
+        assert_eq!(stored, 20 - moved.len());
+        assert!(locals[..64].iter().all(|store| store.queue_size() == 0));
+        for job in &jobs {
+            let found = cluster.get(DEFAULT_TENANT, &job.id).await.unwrap().unwrap();
+            assert_eq!(found.url, job.url);
+        }
+        assert_eq!(cluster.list(&filter).await.unwrap().len(), 20);
+        // the shards are no longer stored here.
+        let err = cluster.migrate(range, "b").await.unwrap_err();
+        assert_eq!(err.to_string(), "Invalid shards: shard 0 isn't stored on this node");
+        let job = (0..100)
+            .map(|_| random_job())
+            .find(|job| range.contains(Cluster::job_shard(job)))
+            .unwrap();
+        cluster.push(job.clone()).await.unwrap();
+        assert!(remote.get(DEFAULT_TENANT, &job.id).is_some());
+        assert!(matches!(cluster.serving(DEFAULT_TENANT, &job.id).await, Shard::Remote(_)));
+
+        // jobs the target stored although sending them failed are recalled,
+        // unless they were updated since.
+        let stale = Job { id: "stale".to_owned(), ..job.clone() };
+        let updated = Job { id: "updated".to_owned(), ..job.clone() };
+        remote.push_batch(vec![stale.clone(), Job { body: "{\"updated\":true}".to_owned(), ..updated.clone() }]);
+        for unsent in vec![stale, updated] {
+            let key = (unsent.tenant.clone(), unsent.id.clone());
+            cluster.unsent.lock().unwrap().insert(key, unsent);
+        }
+        let every = "0-126".parse().unwrap();
+        cluster.recall(every, &cluster.nodes["b"]).await.unwrap();
+        assert!(remote.get(DEFAULT_TENANT, "stale").is_none());
+        assert!(remote.get(DEFAULT_TENANT, "updated").is_some());
+        assert!(cluster.unsent.lock().unwrap().is_empty());
+
+        // the shards stay with the target once the node restarts.
+        drop(cluster);
+        let cluster = start(Store::open_shards(&db, SHARD_COUNT), None, None, &config).await;
+        assert!(matches!(cluster.serving(DEFAULT_TENANT, &job.id).await, Shard::Remote(_)));
+        let statuses = cluster.shard_statuses().await;
+        assert!(statuses[..64].iter().all(|status| status.state == ShardState::Remote));
+        assert_eq!(cluster.get(DEFAULT_TENANT, &job.id).await.unwrap().unwrap().url, job.url);
+
+        server.stop();
+        tokio::fs::remove_dir_all(&data_dir).await.expect("Error removing directory");
+    }
+
+    // a job for one of the shards of a, which owns 0-42.
+    fn owned_by_a() -> Job {
+        loop {
+            let job = random_job();
+            if Cluster::job_shard(&job) < 43 {
+                break job;
+            }
+        }
+    }
+
+    async fn promoted(cluster: &Cluster) -> Vec<usize> {
+        let statuses = cluster.shard_statuses().await;
+        (0..43).filter(|index| statuses[*index].state == ShardState::Local).collect()
+    }
+
+    #[tokio::test]
+    async fn replication() {
+        let data_dir = ".test/".to_owned() + &Uuid::new_v4().to_string();
+        let ports = [Port::new(), Port::new(), Port::new()];
+        let a_address = ports[0].0.local_addr().unwrap().to_string();
+        let mut config = config("b", vec![
+            node("a", &ports[0].url(), "0-42"),
+            node("b", &ports[1].url(), "43-84"),
+            node("c", &ports[2].url(), "85-126")
+        ]);
+        config.replicas = 1;
+        let [a_port, b_port, c_port] = ports;
+        let b_stores = Store::open_shards(&sled::open(data_dir.clone() + "/b").unwrap(), SHARD_COUNT);
+        let b = start(b_stores.clone(), None, None, &config).await;
+        let b_server = b_port.serve(b.clone()).await;
+
+        // alone, b can't tell whether a is down or b is cut off, so it
+        // neither takes the shards of a over nor fires its own.
+        tokio::time::delay_for(Duration::from_millis(4500)).await;
+        assert!(promoted(&b).await.is_empty());
+        assert!(b.firing_stores().await.is_empty());
+
+        // with c, most nodes agree a is down.
+        config.node_id = Some("c".to_owned());
+        let c_stores = Store::open_shards(&sled::open(data_dir.clone() + "/c").unwrap(), SHARD_COUNT);
+        let c = start(c_stores, None, None, &config).await;
+        let c_server = c_port.serve(c.clone()).await;
+        for _ in 0..100 {
+            if promoted(&b).await.len() == 43 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        assert_eq!(promoted(&b).await.len(), 43);
+        assert!(promoted(&c).await.is_empty());
+        let jobs: Vec<Job> = (0..10).map(|_| owned_by_a()).collect();
+        for job in &jobs {
+            b.push(job.clone()).await.unwrap();
+        }
+        b.remove(DEFAULT_TENANT, &jobs[0].id).await.unwrap().unwrap();
+
+        // once back, a takes the jobs changed meanwhile back before serving
+        // its shards again.
+        config.node_id = Some("a".to_owned());
+        let a_stores = Store::open_shards(&sled::open(data_dir.clone() + "/a").unwrap(), SHARD_COUNT);
+        let a = start(a_stores.clone(), None, None, &config).await;
+        let mut a_server = a_port.serve(a.clone()).await;
+        for _ in 0..100 {
+            if promoted(&b).await.is_empty() && b.view().promoted.is_empty() {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        assert!(promoted(&b).await.is_empty());
+        assert!(b.view().promoted.is_empty());
+        let filter = JobFilter::default().in_tenant(DEFAULT_TENANT);
+        let stored: usize = a_stores.iter().map(|store| store.list(&filter).len()).sum();
+        assert_eq!(stored, 9);
+        assert!(a.get(DEFAULT_TENANT, &jobs[0].id).await.unwrap().is_none());
+        for job in &jobs[1..] {
+            assert_eq!(a.get(DEFAULT_TENANT, &job.id).await.unwrap().unwrap().url, job.url);
+        }
+        // replicas aren't listed twice.
+        assert_eq!(a.list(&filter).await.unwrap().len(), 9);
+        assert_eq!(b.local_stores().await.len(), 42);
+
+        // a keeps running while cut off, and stops firing its shards once
+        // the answers of the nodes which still heard from it are too old.
+        a_server.stop();
+        for _ in 0..100 {
+            if promoted(&b).await.len() == 43 && a.firing_stores().await.is_empty() {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        assert_eq!(promoted(&b).await.len(), 43);
+        assert!(a.firing_stores().await.is_empty());
+        let job = owned_by_a();
+        b.push(job.clone()).await.unwrap();
+        b.remove(DEFAULT_TENANT, &jobs[1].id).await.unwrap().unwrap();
+        // a still sends requests to b while it's cut off.
+        assert_eq!(a.get(DEFAULT_TENANT, &job.id).await.unwrap().unwrap().url, job.url);
+
+        a_server = Port::at(&a_address).serve(a.clone()).await;
+        for _ in 0..100 {
+            if promoted(&b).await.is_empty() && !a.firing_stores().await.is_empty() {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        assert!(promoted(&b).await.is_empty());
+        assert!(!a.firing_stores().await.is_empty());
+        let stored: usize = a_stores.iter().map(|store| store.list(&filter).len()).sum();
+        assert_eq!(stored, 9);
+        assert!(a_stores[Cluster::job_shard(&job)].get(DEFAULT_TENANT, &job.id).is_some());
+        assert!(a_stores[Cluster::job_shard(&jobs[1])].get(DEFAULT_TENANT, &jobs[1].id).is_none());
+
+        a_server.stop();
+        b_server.stop();
+        c_server.stop();
+        tokio::fs::remove_dir_all(&data_dir).await.expect("Error removing directory");
+    }
+
+    #[tokio::test]
+    async fn partition() {
+        let data_dir = ".test/".to_owned() + &Uuid::new_v4().to_string();
+        let ids = ["a", "b", "c"];
+        let ports: Vec<Port> = ids.iter().map(|_| Port::new()).collect();
+        let addresses: Vec<String> = ports.iter().map(|port| port.0.local_addr().unwrap().to_string()).collect();
+        let nodes = vec![
+            node("a", &ports[0].url(), "0-42"),
+            node("b", &ports[1].url(), "43-84"),
+            node("c", &ports[2].url(), "85-126")
+        ];
+        let mut config = ClusterConfig { replicas: 1, ..config("a", nodes) };
+        let mut clusters = Vec::new();
+        let mut servers = Vec::new();
+        let mut stores = Vec::new();
+        for (id, port) in ids.iter().zip(ports) {
+            config.node_id = Some(id.to_string());
+            let shards = Store::open_shards(&sled::open(format!("{}/{}", data_dir, id)).unwrap(), SHARD_COUNT);
+            let cluster = start(shards.clone(), None, None, &config).await;
+            servers.push(port.serve(cluster.clone()).await);
+            clusters.push(cluster);
+            stores.push(shards);
+        }
+        let (a, b) = (&clusters[0], &clusters[1]);
+        // shard 0 is owned by a, and b is its replica.
+        let fires = |cluster: &Arc<Cluster>, store: &Arc<Store>| {
+            let (cluster, store) = (cluster.clone(), store.clone());
+            async move { cluster.firing_stores().await.iter().any(|firing| Arc::ptr_eq(firing, &store)) }
+        };
+        for _ in 0..100 {
+            if fires(a, &stores[0][0]).await {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        assert!(fires(a, &stores[0][0]).await);
+
+        // a is cut off both ways. At no point do both fire the shard.
+        servers.remove(0).stop();
+        for client in a.nodes.values() {
+            client.set_host("http://127.0.0.1:1").await;
+        }
+        let mut b_fired = false;
+        for _ in 0..300 {
+            let (a_fires, b_fires) = (fires(a, &stores[0][0]).await, fires(b, &stores[1][0]).await);
+            assert!(!(a_fires && b_fires), "both nodes fire the shard");
+            if b_fires {
+                b_fired = true;
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+        assert!(b_fired);
+
+        // nor once the partition heals and a takes the shard back.
+        for (id, client) in &a.nodes {
+            let position = ids.iter().position(|known| known == id).unwrap();
+            client.set_host(&format!("http://{}", addresses[position])).await;
+        }
+        servers.push(Port::at(&addresses[0]).serve(a.clone()).await);
+        let mut a_fired = false;
+        for _ in 0..300 {
+            let (a_fires, b_fires) = (fires(a, &stores[0][0]).await, fires(b, &stores[1][0]).await);
+            assert!(!(a_fires && b_fires), "both nodes fire the shard");
+            if a_fires && promoted(b).await.is_empty() {
+                a_fired = true;
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+        assert!(a_fired);
+
+        for server in servers {
+            server.stop();
+        }
+        tokio::fs::remove_dir_all(&data_dir).await.expect("Error removing directory");
+    }
+
+    #[tokio::test]
+    async fn raft_adoption() {
+        let data_dir = ".test/".to_owned() + &Uuid::new_v4().to_string();
+        let ids = ["a", "b", "c"];
+        let ports: Vec<Port> = ids.iter().map(|_| Port::new()).collect();
+        let ranges = ["0-42", "43-84", "85-126"];
+        let nodes = ids.iter().zip(&ports).zip(&ranges).map(|((id, port), range)| node(id, &port.url(), range)).collect();
+        let mut config = ClusterConfig { raft: true, ..config("a", nodes) };
+        let mut nodes = Vec::new();
+        for (id, port) in ids.iter().zip(ports) {
+            config.node_id = Some(id.to_string());
+            let db = sled::open(format!("{}/{}", data_dir, id)).unwrap();
+            let stores = Store::open_shards(&db, SHARD_COUNT);
+            let log = Some(db.open_tree("raft").unwrap());
+            let cluster = start(stores.clone(), log, None, &config).await;
+            let server = port.serve(cluster.clone()).await;
+            nodes.push((cluster, server, stores));
+        }
+        let fires = |cluster: &Arc<Cluster>, store: &Arc<Store>| {
+            let (cluster, store) = (cluster.clone(), store.clone());
+            async move { cluster.firing_stores().await.iter().any(|firing| Arc::ptr_eq(firing, &store)) }
+        };
+        // a fires shard 0 once it hears from the leader.
+        let (a, _, a_stores) = &nodes[0];
+        for _ in 0..100 {
+            if fires(a, &a_stores[0]).await {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+        assert!(fires(a, &a_stores[0]).await);
+
+        // a stops firing the shard as soon as its log hands it over, not
+        // once it polls the log next.
+        let proposer = nodes[1].0.clone();
+        let proposed = tokio::spawn(async move {
+            let command = Command::Assign { shards: vec![0], node: "b".to_owned() };
+            proposer.raft().unwrap().propose(command).await.unwrap();
+        });
+        let mut handed_over = false;
+        for _ in 0..5000 {
+            if a.raft().unwrap().map().0.owners[0] == "b" {
+                assert!(!fires(a, &a_stores[0]).await);
+                handed_over = true;
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        assert!(handed_over);
+        proposed.await.unwrap();
+
+        for (_, server, _) in nodes {
+            server.stop();
+        }
+        tokio::fs::remove_dir_all(&data_dir).await.expect("Error removing directory");
+    }
+
+    #[tokio::test]
+    async fn raft_failover() {
+        let data_dir = ".test/".to_owned() + &Uuid::new_v4().to_string();
+        let ids = ["a", "b", "c"];
+        let ports: Vec<Port> = ids.iter().map(|_| Port::new()).collect();
+        let nodes = ids.iter().zip(&ports).map(|(id, port)| node(id, &port.url(), "")).collect();
+        let mut config = ClusterConfig { replicas: 1, raft: true, ..config("a", nodes) };
+        let mut nodes = Vec::new();
+        for (id, port) in ids.iter().zip(ports) {
+            config.node_id = Some(id.to_string());
+            let db = sled::open(format!("{}/{}", data_dir, id)).unwrap();
+            let stores = Store::open_shards(&db, SHARD_COUNT);
+            let log = Some(db.open_tree("raft").unwrap());
+            let cluster = start(stores, log, None, &config).await;
+            let server = port.serve(cluster.clone()).await;
+            nodes.push((cluster, server));
+        }
+        let mut leader = None;
+        for _ in 0..50 {
+            let leaders: Vec<Option<String>> = nodes
+                .iter()
+                .map(|(cluster, _)| cluster.raft().unwrap().leader())
+                .collect();
+            if leaders[0].is_some() && leaders.iter().all(|leader| leader == &leaders[0]) {
+                leader = leaders[0].clone();
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        let leader = leader.expect("No leader was elected");
+        let (cluster, server) = nodes.remove(ids.iter().position(|id| *id == leader).unwrap());
+
+        // a follower passes the command on to the leader.
+        let follower = nodes[0].0.clone();
+        let command = Command::Assign { shards: vec![0], node: follower.node_id.clone() };
+        follower.raft().unwrap().propose(command).await.unwrap();
+        assert_eq!(cluster.raft().unwrap().map().0.owners[0], follower.node_id);
+        let jobs: Vec<Job> = (0..20).map(|_| random_job()).collect();
+        for job in &jobs {
+            follower.push(job.clone()).await.unwrap();
+        }
+        // jobs of the leader reach its replica.
+        tokio::time::delay_for(Duration::from_millis(1000)).await;
+
+        server.stop();
+        drop(cluster);
+        let remaining = |statuses: &[ShardStatus]| {
+            statuses.iter().all(|status| status.node.as_ref() != Some(&leader))
+        };
+        let mut statuses = follower.shard_statuses().await;
+        for _ in 0..150 {
+            if remaining(&statuses) {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+            statuses = follower.shard_statuses().await;
+        }
+        assert!(remaining(&statuses));
+        assert!(follower.raft().unwrap().map().0.owners.iter().all(|owner| owner != &leader));
+        for job in &jobs {
+            let found = follower.get(DEFAULT_TENANT, &job.id).await.unwrap().unwrap();
+            assert_eq!(found.url, job.url);
+        }
+        // only the log hands shards over, the heartbeats never do.
+        for (cluster, _) in &nodes {
+            assert!(cluster.view().promoted.is_empty());
+        }
+
+        for (_, server) in nodes {
+            server.stop();
+        }
+        tokio::fs::remove_dir_all(&data_dir).await.expect("Error removing directory");
+    }
+
+    #[tokio::test]
+    async fn gossip() {
+        let data_dir = ".test/".to_owned() + &Uuid::new_v4().to_string();
+        // the addresses are only known through gossip.
+        let mut config = config("a", vec![node("a", "", "0-63"), node("b", "", "64-126")]);
+        let mut nodes: Vec<(Arc<Cluster>, NodeServer, Arc<Store>)> = Vec::new();
+        for id in ["a", "b"].iter() {
+            let port = Port::new();
+            config.node_id = Some(id.to_string());
+            config.address = Some(port.url());
+            config.gossip = Some(GossipConfig {
+                bind: "127.0.0.1:0".to_owned(),
+                advertise: None,
+                seed: nodes.first().map(|(a, _, _)| a.gossip.as_ref().unwrap().address().to_string())
+            });
+            let store = open(&format!("{}/{}", data_dir, id));
+            let cluster = start(shared(&store), None, None, &config).await;
+            let server = port.serve(cluster.clone()).await;
+            nodes.push((cluster, server, store));
+        }
+        let (b, b_server, b_store) = nodes.pop().unwrap();
+        let (a, a_server, _) = nodes.pop().unwrap();
+
+        let job = remote_job();
+        let mut pushed = a.push(job.clone()).await;
+        for _ in 0..50 {
+            if pushed.is_ok() {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+            pushed = a.push(job.clone()).await;
+        }
+        pushed.unwrap();
+        assert!(b_store.get(DEFAULT_TENANT, &job.id).is_some());
+
+        b_server.stop();
+        drop(b);
+        let job = remote_job();
+        let mut pushed = a.push(job.clone()).await;
+        for _ in 0..100 {
+            if let Err(AppError::NodeUnreachable { message, .. }) = &pushed {
+                if message == "the node is dead" {
+                    break;
+                }
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+            pushed = a.push(job.clone()).await;
+        }
+        assert_eq!(pushed.unwrap_err().message(), "the node is dead");
+
+        a_server.stop();
+        tokio::fs::remove_dir_all(&data_dir).await.expect("Error removing directory");
+    }
+
+    #[tokio::test]
+    async fn gossip_addresses() {
+        let data_dir = ".test/".to_owned() + &Uuid::new_v4().to_string();
+        let mut config = config("a", vec![node("a", "", "0-63"), node("b", "", "64-126")]);
+        config.gossip = Some(GossipConfig { bind: "127.0.0.1:0".to_owned(), advertise: None, seed: None });
+
+        // the calls carrying the token can't be pointed elsewhere by gossip.
+        config.token = Some("secret".to_owned());
+        let security = Arc::new(NodeSecurity::new(&config));
+        let store = open(&format!("{}/assign", data_dir));
+        let refused = Cluster::assign(&shared(&store), &config, &security).err().unwrap();
+        assert!(refused.contains("without tls"));
+
+        // the addresses of the cluster file are kept.
+        config.token = None;
+        let pinned = "http://127.0.0.1:1";
+        config.nodes[1].address = pinned.to_owned();
+        let mut nodes: Vec<Arc<Cluster>> = Vec::new();
+        for id in ["a", "b"].iter() {
+            config.node_id = Some(id.to_string());
+            config.address = Some(Port::new().url());
+            config.gossip = Some(GossipConfig {
+                bind: "127.0.0.1:0".to_owned(),
+                advertise: None,
+                seed: nodes.first().map(|a| a.gossip.as_ref().unwrap().address().to_string())
+            });
+            let store = open(&format!("{}/{}", data_dir, id));
+            nodes.push(start(shared(&store), None, None, &config).await);
+        }
+        let a = &nodes[0];
+        let known = || a.gossip.as_ref().unwrap().members().0.iter().any(|member| member.id == "b");
+        for _ in 0..50 {
+            if known() {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        assert!(known());
+        tokio::time::delay_for(FOLLOW_INTERVAL * 3).await;
+        assert_eq!(a.nodes["b"].host(), pinned);
+
+        tokio::fs::remove_dir_all(&data_dir).await.expect("Error removing directory");
+    }
+
+    #[tokio::test]
+    async fn peer_health() {
+        let data_dir = ".test/".to_owned() + &Uuid::new_v4().to_string();
+        let port = Port::new();
+        let address = port.url();
+        let mut config = config("a", vec![node("a", "http://unused", "0-63"), node("b", &address, "64-126")]);
+        let store = open(&(data_dir.clone() + "/a"));
+        let a = start(shared(&store), None, None, &config).await;
+
+        // b isn't up, so it is down after a few heartbeats.
+        assert_eq!(a.node_statuses()[0].state, PeerState::Unknown);
+        for _ in 0..50 {
+            if a.node_statuses()[0].state == PeerState::Down {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        let status = &a.node_statuses()[0];
+        assert_eq!((status.node.as_str(), status.state), ("b", PeerState::Down));
+        assert!(status.failures >= 3 && status.error.is_some());
+        // a failed attempt puts off the next one, which fails without
+        // trying to reach b.
+        assert!(a.push(remote_job()).await.is_err());
+        match a.push(remote_job()).await {
+            Err(AppError::NodeUnreachable { node, message }) => {
+                assert_eq!(node, address);
+                assert!(message.starts_with("the node is down since"), "{}", message);
+                assert!(message.contains("retrying in"), "{}", message);
+            },
+            result => panic!("unexpected result {:?}", result)
+        }
+
+        // the connection is made again once b starts.
+        config.node_id = Some("b".to_owned());
+        let store = open(&(data_dir.clone() + "/b"));
+        let server = port.serve(start(shared(&store), None, None, &config).await).await;
+        for _ in 0..100 {
+            if a.node_statuses()[0].state == PeerState::Up {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        let status = &a.node_statuses()[0];
+        assert_eq!((status.state, status.failures, status.error.as_deref()), (PeerState::Up, 0, None));
+        a.push(remote_job()).await.unwrap();
+
+        server.stop();
+        tokio::fs::remove_dir_all(&data_dir).await.expect("Error removing directory");
+    }
+
+    #[tokio::test]
+    async fn handoff() {
+        let data_dir = ".test/".to_owned() + &Uuid::new_v4().to_string();
+        let port = Port::new();
+        let mut config = config("a", vec![node("a", "http://unused", "0-63"), node("b", &port.url(), "64-126")]);
+        config.handoff = true;
+        let db = sled::open(data_dir.clone() + "/a").unwrap();
+        let store = Arc::new(Store::new(db.open_tree("jobs").unwrap()));
+        let hints = Some(db.open_tree("hints").unwrap());
+        let limits = QuotaConfig { max_pending: Some(10), ..QuotaConfig::default() };
+        let quotas = Arc::new(Quotas::new(&limits));
+        let egress = Arc::new(Egress::new(&EgressConfig::default()));
+        let a = Cluster::start(shared(&store), None, hints, quotas, egress, &config).await;
+        let kept = a.hints.clone().unwrap();
+
+        // b isn't up, so its jobs are kept by a, counted against the quotas
+        // and fire there when due. Jobs which fired aren't handed off.
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let due = Job { timestamp: now - Duration::from_millis(100), ..remote_job() };
+        a.push(due.clone()).await.unwrap();
+        assert!(a.firing_stores().await.iter().any(|store| Arc::ptr_eq(store, &kept)));
+        assert_eq!(kept.next().unwrap().id, due.id);
+
+        let job = remote_job();
+        a.push(job.clone()).await.unwrap();
+        let batch = vec![remote_job(), remote_job()];
+        assert!(a.push_batch(batch.clone()).await.iter().all(Result::is_ok));
+        assert_eq!(kept.queue_size(), 3);
+        assert_eq!(a.usage(DEFAULT_TENANT).await.unwrap().pending, 3);
+        assert!(store.get(DEFAULT_TENANT, &job.id).is_none());
+
+        // the kept jobs are read and removed on a.
+        assert_eq!(a.get(DEFAULT_TENANT, &job.id).await.unwrap().unwrap().url, job.url);
+        let removed = remote_job();
+        a.push(removed.clone()).await.unwrap();
+        assert_eq!(a.remove(DEFAULT_TENANT, &removed.id).await.unwrap().unwrap().id, removed.id);
+        assert_eq!(kept.queue_size(), 3);
+
+        // so are jobs removed in batches or along with their tenant, while
+        // the ones b holds can't be.
+        let deleted = remote_job();
+        a.push(deleted.clone()).await.unwrap();
+        let ids = vec![deleted.id.clone(), remote_job().id];
+        let results = a.remove_batch(DEFAULT_TENANT, &ids).await;
+        assert_eq!(results[0].as_ref().unwrap().as_ref().unwrap().id, deleted.id);
+        assert!(matches!(results[1], Err(AppError::NodeUnreachable { .. })));
+        let cleared = loop {
+            let job = Job { tenant: "other".to_owned(), ..random_job() };
+            if Cluster::job_shard(&job) > 63 {
+                break job;
+            }
+        };
+        a.push(cleared.clone()).await.unwrap();
+        assert!(a.clear("other").await.is_err());
+        assert!(kept.get("other", &cleared.id).is_none());
+        assert_eq!(kept.queue_size(), 3);
+
+        // they're listed and counted without b.
+        let filter = JobFilter { tenant: Some(DEFAULT_TENANT.to_owned()), ..JobFilter::default() };
+        let listed: Vec<String> = a.list(&filter).await.unwrap().into_iter().map(|job| job.id).collect();
+        assert!(listed.contains(&job.id));
+        assert!(batch.iter().all(|job| listed.contains(&job.id)));
+        assert_eq!(a.stats(&filter).await.unwrap().count, listed.len() as u64);
+
+        // once b is up, they're sent to it, unless b wrote them since.
+        config.node_id = Some("b".to_owned());
+        let b_store = open(&(data_dir.clone() + "/b"));
+        let b = start(shared(&b_store), None, None, &config).await;
+        b_store.push(Job { url: "newer".to_owned(), ..job.clone() });
+        let server = port.serve(b).await;
+        for _ in 0..100 {
+            if kept.queue_size() == 0 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        assert_eq!(kept.queue_size(), 0);
+        for job in &batch {
+            assert_eq!(b_store.get(DEFAULT_TENANT, &job.id).unwrap().url, job.url);
+        }
+        assert_eq!(b_store.get(DEFAULT_TENANT, &job.id).unwrap().url, "newer");
+        assert!(b_store.get(DEFAULT_TENANT, &due.id).is_none());
+        assert!(b_store.get(DEFAULT_TENANT, &removed.id).is_none());
+        assert!(b_store.get(DEFAULT_TENANT, &deleted.id).is_none());
+        assert!(b_store.get("other", &cleared.id).is_none());
+
+        // kept jobs replace the ones b holds, which are listed again.
+        let replaced = Job { url: "kept".to_owned(), ..batch[0].clone() };
+        a.keep(&kept, vec![replaced]);
+        let listed = a.list(&filter).await.unwrap();
+        let copies: Vec<&Job> = listed.iter().filter(|listed| listed.id == batch[0].id).collect();
+        assert_eq!(copies.len(), 1);
+        assert_eq!(copies[0].url, "kept");
+        assert_eq!(a.stats(&filter).await.unwrap().count, listed.len() as u64);
+        assert!(listed.iter().any(|listed| listed.id == batch[1].id));
+
+        server.stop();
+        tokio::fs::remove_dir_all(&data_dir).await.expect("Error removing directory");
     }
 
     //#[tokio::test]
@@ -0,0 +1,381 @@
+// Settings for a schedule-m8 instance. Defaults can be overridden through
+// environment variables when started from the binary.
+
+use std::convert::TryFrom;
+use std::env;
+use std::fmt::{Display, Formatter, Result as FormatResult};
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use serde::Deserialize;
+use crate::egress::Network;
+
+#[derive(Clone, Debug)]
+pub struct HistoryConfig {
+    // how many runs are kept for each job, the oldest are dropped first.
+    pub max_runs: usize,
+    // runs older than this are dropped, even for jobs which were deleted.
+    pub max_age: Duration
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            max_runs: 100,
+            max_age: Duration::from_secs(7 * 24 * 60 * 60)
+        }
+    }
+}
+
+// Where the keys verifying the bearer tokens are read from.
+#[derive(Clone, Debug)]
+pub enum JwtKey {
+    // file holding the shared secret of HS256 tokens.
+    Hs256Secret(PathBuf),
+    // PEM file holding the RSA public key of RS256 tokens.
+    Rs256PublicKey(PathBuf),
+    // JSON Web Key Set of RS256 keys, picked through the `kid` of the token.
+    Jwks(PathBuf)
+}
+
+#[derive(Clone, Debug)]
+pub struct JwtConfig {
+    pub key: JwtKey,
+    // checked against the `iss` and `aud` claims when set.
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    // claims holding the tenant of the caller and its permissions, which
+    // are scopes such as `read` or `write`.
+    pub tenant_claim: String,
+    pub permissions_claim: String
+}
+
+impl JwtConfig {
+    pub fn new(key: JwtKey) -> JwtConfig {
+        JwtConfig {
+            key,
+            issuer: None,
+            audience: None,
+            tenant_claim: "tenant".to_owned(),
+            permissions_claim: "permissions".to_owned()
+        }
+    }
+}
+
+// Limits applied to each tenant, there are none by default.
+#[derive(Clone, Debug, Default)]
+pub struct QuotaConfig {
+    // one-off jobs waiting to fire.
+    pub max_pending: Option<u64>,
+    // cron jobs.
+    pub max_recurring: Option<u64>,
+    // jobs created or replaced.
+    pub max_creates_per_minute: Option<u64>,
+    // callbacks over the limit are sent in the next minute.
+    pub max_callbacks_per_minute: Option<u64>
+}
+
+// Where callbacks can be sent. Hosts match their subdomains as well, and the
+// allow lists only apply when they aren't empty. Loopback, link-local and
+// private addresses are denied unless they are in `allowed_networks`.
+#[derive(Clone, Debug, Default)]
+pub struct EgressConfig {
+    pub allowed_hosts: Vec<String>,
+    pub denied_hosts: Vec<String>,
+    pub allowed_networks: Vec<Network>,
+    pub denied_networks: Vec<Network>,
+    pub allowed_ports: Vec<u16>,
+    pub denied_ports: Vec<u16>
+}
+
+// Shards owned by a node, such as `0-63` or `64`. Both ends are included.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ShardRange {
+    pub start: usize,
+    pub end: usize
+}
+
+impl FromStr for ShardRange {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<ShardRange, String> {
+        let invalid = || format!("invalid shard range `{}`", value);
+        let (start, end) = match value.split_once('-') {
+            Some((start, end)) => (start, end),
+            None => (value, value)
+        };
+        let start = start.trim().parse().map_err(|_| invalid())?;
+        let end = end.trim().parse().map_err(|_| invalid())?;
+        match start <= end {
+            true => Ok(ShardRange { start, end }),
+            false => Err(invalid())
+        }
+    }
+}
+
+impl ShardRange {
+    pub fn contains(&self, shard: usize) -> bool {
+        self.start <= shard && shard <= self.end
+    }
+}
+
+impl Display for ShardRange {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> FormatResult {
+        match self.start == self.end {
+            true => write!(formatter, "{}", self.start),
+            false => write!(formatter, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl TryFrom<String> for ShardRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<ShardRange, String> {
+        value.parse()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct NodeConfig {
+    pub id: String,
+    // grpc address of the node, such as `http://10.0.0.2:9001`, learned
+    // through gossip when left out.
+    #[serde(default)]
+    pub address: String,
+    // left out on every node to spread the shards over the ring.
+    #[serde(default)]
+    pub shards: Vec<ShardRange>
+}
+
+// Static membership of the cluster, which must be the same on every node.
+// Without nodes, all of the shards are stored locally.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ClusterConfig {
+    // which of the nodes this one is.
+    #[serde(default)]
+    pub node_id: Option<String>,
+    // address the grpc server listens on, it isn't started without it.
+    #[serde(default)]
+    pub bind: Option<String>,
+    // grpc address other nodes reach this one at, which gossip spreads,
+    // when it isn't listed along with the node.
+    #[serde(default)]
+    pub address: Option<String>,
+    // nodes find each other and detect failures through gossip when set.
+    #[serde(default)]
+    pub gossip: Option<GossipConfig>,
+    #[serde(default)]
+    pub nodes: Vec<NodeConfig>,
+    // number of other nodes holding a copy of each shard, the nodes
+    // following its owner in the list.
+    #[serde(default)]
+    pub replicas: usize,
+    // keeps the owner of each shard in a raft log shared by the nodes, so
+    // shards of unreachable nodes can be handed over safely.
+    #[serde(default)]
+    pub raft: bool,
+    // jobs for the shards of unreachable nodes are kept by this one until
+    // they are back, rather than refused.
+    #[serde(default)]
+    pub handoff: bool,
+    // the credentials of the node are read from the environment, rather
+    // than shared through the cluster file.
+    #[serde(skip)]
+    pub tls: Option<NodeTlsConfig>,
+    // secret every node sends along with its calls to the others.
+    #[serde(skip)]
+    pub token: Option<String>
+}
+
+// Certificates securing the calls between the nodes, in PEM files. Each node
+// presents its own to the others, which only accept the ones signed by the
+// authority.
+#[derive(Clone, Debug)]
+pub struct NodeTlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub ca: PathBuf,
+    // name the certificates are issued for, checked rather than the host of
+    // the node called since ip addresses can't be.
+    pub domain: Option<String>
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GossipConfig {
+    // udp address gossip is received on, such as `0.0.0.0:9002`.
+    pub bind: String,
+    // udp address other nodes send gossip to, when it differs from the bind
+    // address.
+    #[serde(default)]
+    pub advertise: Option<String>,
+    // gossip address of a node already in the cluster, left out on the first
+    // node.
+    #[serde(default)]
+    pub seed: Option<String>
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AuthConfig {
+    // key granted every scope, which is used to create the other keys. The
+    // api requires authentication when it or `jwt` is set.
+    pub admin_key: Option<String>,
+    pub jwt: Option<JwtConfig>
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub bind: String,
+    pub db_path: String,
+    pub history: HistoryConfig,
+    pub auth: AuthConfig,
+    pub quotas: QuotaConfig,
+    pub egress: EgressConfig,
+    pub cluster: ClusterConfig
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    env_opt(name).unwrap_or(default)
+}
+
+// Comma separated values, empty when the variable isn't set.
+fn env_list<T: FromStr>(name: &str) -> Vec<T> {
+    match env::var(name) {
+        Ok(values) => values
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(|value| {
+                value
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Invalid value for {}: {}", name, value))
+            })
+            .collect(),
+        Err(_) => Vec::new()
+    }
+}
+
+fn env_opt<T: FromStr>(name: &str) -> Option<T> {
+    env::var(name).ok().map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("Invalid value for {}", name))
+    })
+}
+
+impl Config {
+    pub fn new(bind: String, db_path: String) -> Config {
+        Config {
+            bind,
+            db_path,
+            history: HistoryConfig::default(),
+            auth: AuthConfig::default(),
+            quotas: QuotaConfig::default(),
+            egress: EgressConfig::default(),
+            cluster: ClusterConfig::default()
+        }
+    }
+
+    pub fn from_env() -> Config {
+        let mut default_dir = env::current_dir().unwrap();
+        default_dir.push(".data");
+        let bind = env::var("SCHEDULE_M8_BIND_ADDR")
+            .unwrap_or("0.0.0.0:8001".to_owned());
+        let db_path = env::var("SCHEDULE_M8_DATA_DIR")
+            .unwrap_or(default_dir.to_str().unwrap().to_owned());
+
+        let mut config = Config::new(bind, db_path);
+        config.history.max_runs = env_or(
+            "SCHEDULE_M8_HISTORY_MAX_RUNS",
+            config.history.max_runs
+        );
+        config.history.max_age = Duration::from_secs(env_or(
+            "SCHEDULE_M8_HISTORY_MAX_AGE_SECS",
+            config.history.max_age.as_secs()
+        ));
+        config.auth.admin_key = env::var("SCHEDULE_M8_ADMIN_KEY").ok();
+        config.auth.jwt = jwt_from_env();
+        config.quotas = QuotaConfig {
+            max_pending: env_opt("SCHEDULE_M8_QUOTA_MAX_PENDING"),
+            max_recurring: env_opt("SCHEDULE_M8_QUOTA_MAX_RECURRING"),
+            max_creates_per_minute: env_opt("SCHEDULE_M8_QUOTA_MAX_CREATES_PER_MINUTE"),
+            max_callbacks_per_minute: env_opt("SCHEDULE_M8_QUOTA_MAX_CALLBACKS_PER_MINUTE")
+        };
+        config.egress = EgressConfig {
+            allowed_hosts: env_list("SCHEDULE_M8_EGRESS_ALLOWED_HOSTS"),
+            denied_hosts: env_list("SCHEDULE_M8_EGRESS_DENIED_HOSTS"),
+            allowed_networks: env_list("SCHEDULE_M8_EGRESS_ALLOWED_NETWORKS"),
+            denied_networks: env_list("SCHEDULE_M8_EGRESS_DENIED_NETWORKS"),
+            allowed_ports: env_list("SCHEDULE_M8_EGRESS_ALLOWED_PORTS"),
+            denied_ports: env_list("SCHEDULE_M8_EGRESS_DENIED_PORTS")
+        };
+        config.cluster = cluster_from_env();
+        config
+    }
+}
+
+// The nodes are read from a JSON file shared by the cluster, while the id,
+// addresses, credentials and gossip of the node can be set through the
+// environment.
+fn cluster_from_env() -> ClusterConfig {
+    let mut cluster = match env::var("SCHEDULE_M8_CLUSTER_FILE") {
+        Ok(path) => {
+            let contents = fs::read_to_string(&path)
+                .unwrap_or_else(|err| panic!("Failed to read {}: {}", path, err));
+            serde_json::from_str(&contents)
+                .unwrap_or_else(|err| panic!("Invalid cluster file {}: {}", path, err))
+        },
+        Err(_) => ClusterConfig::default()
+    };
+    if let Ok(node_id) = env::var("SCHEDULE_M8_NODE_ID") {
+        cluster.node_id = Some(node_id);
+    }
+    if let Ok(bind) = env::var("SCHEDULE_M8_NODE_BIND_ADDR") {
+        cluster.bind = Some(bind);
+    }
+    if let Ok(address) = env::var("SCHEDULE_M8_NODE_ADDR") {
+        cluster.address = Some(address);
+    }
+    if let Ok(cert) = env::var("SCHEDULE_M8_NODE_TLS_CERT_FILE") {
+        let required = |name: &str| -> PathBuf {
+            env::var(name)
+                .unwrap_or_else(|_| panic!("{} is required along with a node certificate", name))
+                .into()
+        };
+        cluster.tls = Some(NodeTlsConfig {
+            cert: cert.into(),
+            key: required("SCHEDULE_M8_NODE_TLS_KEY_FILE"),
+            ca: required("SCHEDULE_M8_NODE_TLS_CA_FILE"),
+            domain: env::var("SCHEDULE_M8_NODE_TLS_DOMAIN").ok()
+        });
+    }
+    cluster.token = env::var("SCHEDULE_M8_NODE_TOKEN").ok();
+    if let Ok(bind) = env::var("SCHEDULE_M8_GOSSIP_BIND_ADDR") {
+        cluster.gossip = Some(GossipConfig {
+            bind,
+            advertise: env::var("SCHEDULE_M8_GOSSIP_ADVERTISE_ADDR").ok(),
+            seed: env::var("SCHEDULE_M8_GOSSIP_SEED").ok()
+        });
+    }
+    cluster
+}
+
+fn jwt_from_env() -> Option<JwtConfig> {
+    let key = if let Ok(path) = env::var("SCHEDULE_M8_JWT_SECRET_FILE") {
+        JwtKey::Hs256Secret(path.into())
+    } else if let Ok(path) = env::var("SCHEDULE_M8_JWT_PUBLIC_KEY_FILE") {
+        JwtKey::Rs256PublicKey(path.into())
+    } else if let Ok(path) = env::var("SCHEDULE_M8_JWT_JWKS_FILE") {
+        JwtKey::Jwks(path.into())
+    } else {
+        return None;
+    };
+    let mut jwt = JwtConfig::new(key);
+    jwt.issuer = env::var("SCHEDULE_M8_JWT_ISSUER").ok();
+    jwt.audience = env::var("SCHEDULE_M8_JWT_AUDIENCE").ok();
+    jwt.tenant_claim = env_or("SCHEDULE_M8_JWT_TENANT_CLAIM", jwt.tenant_claim);
+    jwt.permissions_claim = env_or("SCHEDULE_M8_JWT_PERMISSIONS_CLAIM", jwt.permissions_claim);
+    Some(jwt)
+}
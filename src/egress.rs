@@ -0,0 +1,339 @@
+// Rules on where callbacks can be sent, so that the scheduler can't be used
+// to reach internal services or cloud metadata endpoints. Urls are checked
+// when jobs are created, and the addresses their host resolves to are
+// checked again when the callback is sent.
+
+use std::fmt::{Display, Formatter, Result as FormatResult};
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::vec::IntoIter;
+use hyper::Uri;
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::service::Service;
+use crate::config::EgressConfig;
+use crate::error::AppError;
+
+// Loopback, link-local, private, multicast, broadcast, benchmarking and
+// unspecified ranges, along with the IPv6 prefixes which embed an IPv4
+// address, which are blocked unless they are part of an allowed network.
+const BLOCKED_NETWORKS: [&str; 16] = [
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "255.255.255.255/32",
+    "::/128",
+    "::1/128",
+    "64:ff9b::/96",
+    "2002::/16",
+    "fc00::/7",
+    "fe80::/10"
+];
+
+// A CIDR range such as `10.0.0.0/8`, or a single address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Network {
+    address: IpAddr,
+    prefix: u8
+}
+
+impl Network {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, normalize(address)) {
+            (IpAddr::V4(network), IpAddr::V4(address)) =>
+                masked(u32::from(network).into(), self.prefix, 32)
+                    == masked(u32::from(address).into(), self.prefix, 32),
+            (IpAddr::V6(network), IpAddr::V6(address)) =>
+                masked(u128::from(network), self.prefix, 128)
+                    == masked(u128::from(address), self.prefix, 128),
+            _ => false
+        }
+    }
+}
+
+fn masked(bits: u128, prefix: u8, width: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        _ => bits >> (width - prefix)
+    }
+}
+
+// IPv4 addresses mapped in IPv6 are checked as IPv4.
+fn normalize(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => address
+        },
+        v4 => v4
+    }
+}
+
+// The IPv4 address a NAT64 or 6to4 address is translated to, which is
+// checked as well so allowing those prefixes doesn't open the private ranges.
+fn embedded(address: IpAddr) -> Option<IpAddr> {
+    let v6 = match address {
+        IpAddr::V6(v6) => v6,
+        IpAddr::V4(_) => return None
+    };
+    let bits = u128::from(v6);
+    let v4 = match v6.segments() {
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] => bits as u32,
+        [0x2002, ..] => (bits >> 80) as u32,
+        _ => return None
+    };
+    Some(IpAddr::V4(v4.into()))
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Network, String> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None)
+        };
+        let address: IpAddr = address
+            .parse()
+            .map_err(|_| format!("invalid address in `{}`", value))?;
+        let width = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= width)
+                .ok_or_else(|| format!("invalid prefix in `{}`", value))?,
+            None => width
+        };
+        Ok(Network { address, prefix })
+    }
+}
+
+impl Display for Network {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> FormatResult {
+        write!(formatter, "{}/{}", self.address, self.prefix)
+    }
+}
+
+pub struct Egress {
+    config: EgressConfig,
+    blocked: Vec<Network>
+}
+
+impl Egress {
+    pub fn new(config: &EgressConfig) -> Egress {
+        let mut blocked: Vec<Network> = BLOCKED_NETWORKS
+            .iter()
+            .map(|network| network.parse().expect("Invalid blocked network"))
+            .collect();
+        blocked.extend(config.denied_networks.iter().cloned());
+        Egress {
+            config: config.clone(),
+            blocked
+        }
+    }
+
+    // Checks the host and port of the url, along with its address when the
+    // host is one.
+    pub fn check_url(&self, url: &str) -> Result<(), AppError> {
+        let uri: Uri = url.parse().map_err(|err| AppError::invalid("url", err))?;
+        let host = uri
+            .host()
+            .ok_or_else(|| AppError::invalid("url", "must have a host"))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .trim_end_matches('.')
+            .to_ascii_lowercase();
+        let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+            Some("https") => 443,
+            _ => 80
+        });
+        let allowed_ports = &self.config.allowed_ports;
+        if (!allowed_ports.is_empty() && !allowed_ports.contains(&port))
+            || self.config.denied_ports.contains(&port) {
+            return Err(AppError::invalid("url", format!("port {} is not allowed", port)));
+        }
+        let matches = |patterns: &[String]| {
+            patterns.iter().any(|pattern| host_matches(pattern, &host))
+        };
+        let allowed_hosts = &self.config.allowed_hosts;
+        if matches(&self.config.denied_hosts)
+            || (!allowed_hosts.is_empty() && !matches(allowed_hosts)) {
+            return Err(AppError::invalid("url", format!("host {} is not allowed", host)));
+        }
+        match host.parse::<IpAddr>() {
+            Ok(address) => self.check_address(address),
+            Err(_) => Ok(())
+        }
+    }
+
+    pub fn check_address(&self, address: IpAddr) -> Result<(), AppError> {
+        let blocked = |address: IpAddr| {
+            self.blocked.iter().any(|network| network.contains(address))
+                && !self.config.allowed_networks.iter().any(|network| network.contains(address))
+        };
+        match blocked(address) || embedded(address).is_some_and(blocked) {
+            true => Err(AppError::invalid("url", format!("address {} is not allowed", address))),
+            false => Ok(())
+        }
+    }
+}
+
+// Patterns match the host itself and its subdomains.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+    host == pattern || host.ends_with(&format!(".{}", pattern))
+}
+
+// Resolves the hosts of the callbacks, failing when one of the addresses
+// isn't allowed. Since the connection is made to the addresses checked, the
+// host can't resolve to another one in the meantime.
+#[derive(Clone)]
+pub struct EgressResolver {
+    egress: Arc<Egress>,
+    resolver: GaiResolver
+}
+
+impl EgressResolver {
+    pub fn new(egress: Arc<Egress>) -> EgressResolver {
+        EgressResolver {
+            egress,
+            resolver: GaiResolver::new()
+        }
+    }
+}
+
+impl Service<Name> for EgressResolver {
+    type Response = IntoIter<IpAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<IntoIter<IpAddr>, io::Error>> + Send>>;
+
+    fn poll_ready(&mut self, context: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.resolver.poll_ready(context)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let egress = self.egress.clone();
+        let resolving = self.resolver.call(name);
+        Box::pin(async move {
+            let addresses: Vec<IpAddr> = resolving.await?.collect();
+            for address in &addresses {
+                egress.check_address(*address).map_err(|err| {
+                    io::Error::new(io::ErrorKind::PermissionDenied, err.to_string())
+                })?;
+            }
+            Ok(addresses.into_iter())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn address(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn networks() {
+        let network: Network = "10.1.0.0/16".parse().unwrap();
+        assert!(network.contains(address("10.1.200.3")));
+        assert!(!network.contains(address("10.2.0.1")));
+        assert!(!network.contains(address("::1")));
+        assert!("0.0.0.0/0".parse::<Network>().unwrap().contains(address("8.8.8.8")));
+        assert!("fe80::/10".parse::<Network>().unwrap().contains(address("fe80::1")));
+        // a single address.
+        assert!("::1".parse::<Network>().unwrap().contains(address("::1")));
+        // IPv4 mapped in IPv6.
+        let loopback: Network = "127.0.0.0/8".parse().unwrap();
+        assert!(loopback.contains(address("::ffff:127.0.0.1")));
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("localhost/8".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn defaults() {
+        let egress = Egress::new(&EgressConfig::default());
+        assert!(egress.check_url("https://example.com/callback").is_ok());
+        assert!(egress.check_url("http://93.184.216.34:8080").is_ok());
+        for url in &[
+            "http://127.0.0.1:3000",
+            "http://169.254.169.254/latest/meta-data",
+            "http://10.0.0.4",
+            "http://192.168.1.1",
+            "http://[::1]:8080",
+            "http://[::ffff:127.0.0.1]"
+        ] {
+            let err = egress.check_url(url).unwrap_err();
+            assert!(err.to_string().starts_with("Invalid url: address"), "{}", url);
+        }
+        assert!(egress.check_address(address("172.31.0.1")).is_err());
+        assert!(egress.check_address(address("8.8.8.8")).is_ok());
+        for blocked in &["224.0.0.1", "255.255.255.255", "198.19.0.1", "64:ff9b::808:808", "2002:808:808::1"] {
+            assert!(egress.check_address(address(blocked)).is_err(), "{}", blocked);
+        }
+    }
+
+    #[test]
+    fn embedded_addresses() {
+        assert_eq!(embedded(address("64:ff9b::a00:1")), Some(address("10.0.0.1")));
+        assert_eq!(embedded(address("2002:7f00:1::1")), Some(address("127.0.0.1")));
+        assert_eq!(embedded(address("2001:db8::1")), None);
+        assert_eq!(embedded(address("10.0.0.1")), None);
+
+        // allowing the prefixes still checks the address they embed.
+        let egress = Egress::new(&EgressConfig {
+            allowed_networks: vec!["64:ff9b::/96".parse().unwrap(), "2002::/16".parse().unwrap()],
+            ..EgressConfig::default()
+        });
+        assert!(egress.check_address(address("64:ff9b::808:808")).is_ok());
+        assert!(egress.check_address(address("2002:808:808::1")).is_ok());
+        assert!(egress.check_address(address("64:ff9b::a9fe:a9fe")).is_err());
+        assert!(egress.check_address(address("2002:a00:1::1")).is_err());
+    }
+
+    #[test]
+    fn rules() {
+        let egress = Egress::new(&EgressConfig {
+            allowed_hosts: vec![],
+            denied_hosts: vec!["internal.example.com".to_owned()],
+            allowed_networks: vec!["10.1.0.0/16".parse().unwrap()],
+            denied_networks: vec!["8.8.8.0/24".parse().unwrap()],
+            allowed_ports: vec![80, 443],
+            denied_ports: vec![]
+        });
+        assert!(egress.check_url("http://10.1.0.5").is_ok());
+        assert!(egress.check_url("http://10.2.0.5").is_err());
+        assert!(egress.check_url("http://8.8.8.8").is_err());
+        assert_eq!(
+            egress.check_url("http://example.com:8080").unwrap_err().to_string(),
+            "Invalid url: port 8080 is not allowed"
+        );
+        assert!(egress.check_url("https://api.internal.example.com").is_err());
+        assert!(egress.check_url("https://INTERNAL.example.com.").is_err());
+        assert!(egress.check_url("https://notinternal.example.com").is_ok());
+
+        let egress = Egress::new(&EgressConfig {
+            allowed_hosts: vec!["example.com".to_owned()],
+            denied_ports: vec![25],
+            ..EgressConfig::default()
+        });
+        assert!(egress.check_url("https://hooks.example.com").is_ok());
+        assert!(egress.check_url("https://example.org").is_err());
+        assert!(egress.check_url("http://example.com:25").is_err());
+    }
+}
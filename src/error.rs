@@ -1,30 +1,109 @@
 // This contains all of the top level errors in the application
 
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt::{Display, Formatter, Result as FormatResult};
 
 use serde_json::Error as SerdeError;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum AppError {
-    ValidationError,
+    // the field is the part of the request at fault, when known.
+    ValidationError { field: Option<String>, message: String },
     // this is likely a bug...
-    UnexpectedError,
+    UnexpectedError(String),
     // internal shard rpc calls failed
-    NodeUnreachable,
-    RpcDeserializationError,
+    NodeUnreachable { node: String, message: String },
+    RpcDeserializationError(String),
     // fallback error if unable to parse the grpc status
-    UnexpectedRpcError(String)
+    UnexpectedRpcError(String),
+    // missing or unknown credentials
+    Unauthorized(String),
+    // the credentials don't grant the scope required by the request
+    Forbidden { scope: String },
+    // the tenant reached one of its quotas
+    QuotaExceeded { quota: String, limit: u64 }
+}
+
+impl AppError {
+    pub fn invalid<M: Display>(field: &str, message: M) -> AppError {
+        AppError::ValidationError {
+            field: Some(field.to_owned()),
+            message: message.to_string()
+        }
+    }
+
+    // Stable identifier of the kind of error, for clients to match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::ValidationError { .. } => "validation_error",
+            AppError::UnexpectedError(_) => "unexpected_error",
+            AppError::NodeUnreachable { .. } => "node_unreachable",
+            AppError::RpcDeserializationError(_) => "rpc_deserialization_error",
+            AppError::UnexpectedRpcError(_) => "unexpected_rpc_error",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden { .. } => "forbidden",
+            AppError::QuotaExceeded { .. } => "quota_exceeded"
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            AppError::ValidationError { message, .. } => message,
+            AppError::UnexpectedError(message) => message,
+            AppError::NodeUnreachable { message, .. } => message,
+            AppError::RpcDeserializationError(message) => message,
+            AppError::UnexpectedRpcError(message) => message,
+            AppError::Unauthorized(message) => message,
+            AppError::Forbidden { .. } => "missing scope",
+            AppError::QuotaExceeded { .. } => "quota exceeded"
+        }
+    }
+
+    // The context of the error besides its message.
+    pub fn details(&self) -> BTreeMap<String, String> {
+        let mut details = BTreeMap::new();
+        match self {
+            AppError::ValidationError { field: Some(field), .. } => {
+                details.insert("field".to_owned(), field.clone());
+            },
+            AppError::NodeUnreachable { node, .. } => {
+                details.insert("node".to_owned(), node.clone());
+            },
+            AppError::Forbidden { scope } => {
+                details.insert("scope".to_owned(), scope.clone());
+            },
+            AppError::QuotaExceeded { quota, limit } => {
+                details.insert("quota".to_owned(), quota.clone());
+                details.insert("limit".to_owned(), limit.to_string());
+            },
+            _ => {}
+        }
+        details
+    }
 }
 
 impl Display for AppError {
     fn fmt(&self, formatter: &mut Formatter<'_>) -> FormatResult {
         match &*self {
-            AppError::ValidationError => write!(formatter, "ValidationError"),
-            AppError::UnexpectedError => write!(formatter, "UnexpectedError"),
-            AppError::NodeUnreachable => write!(formatter, "NodeUnreachable"),
-            AppError::RpcDeserializationError => write!(formatter, "RpcDeserializationError"),
-            AppError::UnexpectedRpcError(message) => write!(formatter, "UnexpectedRpcError - {}", message)
+            AppError::ValidationError { field: Some(field), message } =>
+                write!(formatter, "Invalid {}: {}", field, message),
+            AppError::ValidationError { field: None, message } =>
+                write!(formatter, "Invalid request: {}", message),
+            AppError::UnexpectedError(message) =>
+                write!(formatter, "Unexpected error: {}", message),
+            AppError::NodeUnreachable { node, message } =>
+                write!(formatter, "Node {} is unreachable: {}", node, message),
+            AppError::RpcDeserializationError(message) =>
+                write!(formatter, "Invalid rpc message: {}", message),
+            AppError::UnexpectedRpcError(message) =>
+                write!(formatter, "Unexpected rpc error: {}", message),
+            AppError::Unauthorized(message) =>
+                write!(formatter, "Unauthorized: {}", message),
+            AppError::Forbidden { scope } =>
+                write!(formatter, "Forbidden: requires the {} scope", scope),
+            AppError::QuotaExceeded { quota, limit } =>
+                write!(formatter, "Quota exceeded: {} is limited to {}", quota, limit)
         }
     }
 }
@@ -32,17 +111,29 @@ impl Display for AppError {
 impl Error for AppError {
     fn description(&self) -> &str {
         match &*self {
-            AppError::ValidationError => "ValidationError",
-            AppError::UnexpectedError => "UnexpectedError",
-            AppError::NodeUnreachable => "NodeUnreachable",
-            AppError::RpcDeserializationError => "RpcDeserializationError",
-            AppError::UnexpectedRpcError(_) => "UnexpectedRpcError"
+            AppError::ValidationError { .. } => "ValidationError",
+            AppError::UnexpectedError(_) => "UnexpectedError",
+            AppError::NodeUnreachable { .. } => "NodeUnreachable",
+            AppError::RpcDeserializationError(_) => "RpcDeserializationError",
+            AppError::UnexpectedRpcError(_) => "UnexpectedRpcError",
+            AppError::Unauthorized(_) => "Unauthorized",
+            AppError::Forbidden { .. } => "Forbidden",
+            AppError::QuotaExceeded { .. } => "QuotaExceeded"
         }
     }
 }
 
 impl From<SerdeError> for AppError {
-    fn from(_error: SerdeError) -> AppError {
-        AppError::ValidationError
+    fn from(error: SerdeError) -> AppError {
+        AppError::ValidationError {
+            field: None,
+            message: error.to_string()
+        }
+    }
+}
+
+impl From<hyper::Error> for AppError {
+    fn from(error: hyper::Error) -> AppError {
+        AppError::UnexpectedError(error.to_string())
     }
 }
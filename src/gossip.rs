@@ -0,0 +1,550 @@
+// SWIM membership of the cluster over UDP. Every period a node probes one of
+// the others, asks a few more to probe it when it doesn't answer, then
+// suspects it and declares it dead once the suspicion isn't refuted in time.
+// What a node learns about the members travels along with the messages it
+// sends anyway, and a node joins by pinging a seed.
+
+use crate::config::GossipConfig;
+use futures::channel::oneshot;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::net::udp::SendHalf;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+const PROBE_INTERVAL: Duration = Duration::from_millis(500);
+
+const PROBE_TIMEOUT: Duration = Duration::from_millis(200);
+
+// Members asked to probe a member which didn't answer.
+const INDIRECT_PROBES: usize = 3;
+
+// Time a suspect member has to refute the suspicion before it is dead.
+const SUSPECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Updates sent along with each message, besides the sender itself.
+const PIGGYBACKED: usize = 8;
+
+const MAX_PACKET: usize = 65_507;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum MemberState {
+    Alive,
+    Suspect,
+    Dead
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Member {
+    pub id: String,
+    // grpc address of the node.
+    pub address: String,
+    // udp address gossip reaches the node at.
+    pub gossip: SocketAddr,
+    // raised by the node itself to refute suspicions about it, which
+    // outdates them.
+    pub incarnation: u64,
+    pub state: MemberState
+}
+
+// Each message carries the sender first, followed by the updates it spreads.
+#[derive(Serialize, Deserialize, Debug)]
+enum Message {
+    Ping { seq: u64, updates: Vec<Member> },
+    Ack { seq: u64, updates: Vec<Member> },
+    // asks the node to probe the target on behalf of the sender.
+    PingReq { seq: u64, target: SocketAddr, updates: Vec<Member> }
+}
+
+// What an acknowledgement is waited on for.
+enum Waiter {
+    Probe(oneshot::Sender<()>),
+    // passed on to the node which asked for the probe, unless it comes too
+    // late.
+    Relay { requester: SocketAddr, seq: u64, expires: Instant }
+}
+
+struct State {
+    me: Member,
+    // other members, along with when their state last changed.
+    members: HashMap<String, (Member, Instant)>,
+    // updates still to be spread, with the number of messages left to carry
+    // each of them.
+    updates: Vec<(Member, usize)>,
+    seq: u64,
+    // members left to probe in the current round.
+    round: Vec<String>,
+    // raised whenever a member changes.
+    version: u64
+}
+
+impl State {
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    // Updates are carried by a number of messages growing with the log of
+    // the size of the cluster, which spreads them to every member with high
+    // probability.
+    fn spread(&mut self, member: Member) {
+        let size = self.members.len() + 2;
+        let transmits = 3 * (usize::BITS - size.leading_zeros()) as usize;
+        self.updates.retain(|(update, _)| update.id != member.id);
+        self.updates.push((member, transmits));
+    }
+
+    fn piggyback(&mut self) -> Vec<Member> {
+        self.updates.sort_by(|(_, left), (_, right)| right.cmp(left));
+        let mut updates = vec![self.me.clone()];
+        for (update, transmits) in self.updates.iter_mut().take(PIGGYBACKED) {
+            updates.push(update.clone());
+            *transmits -= 1;
+        }
+        self.updates.retain(|(_, transmits)| *transmits > 0);
+        updates
+    }
+
+    // Every member, for a node which just joined.
+    fn everyone(&self) -> Vec<Member> {
+        let mut updates = vec![self.me.clone()];
+        updates.extend(self.members.values().map(|(member, _)| member.clone()));
+        updates
+    }
+
+    // Newer incarnations win, and for the same incarnation a member which
+    // isn't alive only comes back with a new incarnation, which it raises
+    // once it hears what is said about it.
+    fn merge(&mut self, update: Member) {
+        if update.id == self.me.id {
+            if update.state != MemberState::Alive && update.incarnation >= self.me.incarnation {
+                self.me.incarnation = update.incarnation + 1;
+                info!("Refuting suspicion with incarnation {}", self.me.incarnation);
+                let me = self.me.clone();
+                self.spread(me);
+            }
+            return;
+        }
+        let newer = match self.members.get(&update.id) {
+            None => true,
+            Some((known, _)) if update.incarnation != known.incarnation =>
+                update.incarnation > known.incarnation,
+            Some((known, _)) => matches!(
+                (update.state, known.state),
+                (MemberState::Suspect, MemberState::Alive) | (MemberState::Dead, MemberState::Alive)
+                    | (MemberState::Dead, MemberState::Suspect)
+            )
+        };
+        if newer {
+            let changed = self.members.get(&update.id).map_or(true, |(known, _)| known.state != update.state);
+            if changed {
+                info!("Node {} is {:?}", update.id, update.state);
+            }
+            self.members.insert(update.id.clone(), (update.clone(), Instant::now()));
+            self.spread(update);
+            self.version += 1;
+        }
+    }
+
+    // What the node says about a member which isn't alive, for the member
+    // to refute when it's wrong, as it outlives the spreading of the rumour.
+    fn rumour(&self, id: Option<&str>) -> Option<Member> {
+        id.and_then(|id| self.members.get(id))
+            .map(|(member, _)| member)
+            .filter(|member| member.state != MemberState::Alive)
+            .cloned()
+    }
+
+    // Marks the member with the state, as long as the node didn't learn of
+    // a newer incarnation in the meantime.
+    fn mark(&mut self, id: &str, incarnation: u64, state: MemberState) {
+        if let Some((member, _)) = self.members.get(id) {
+            if member.incarnation == incarnation && member.state != state {
+                let mut update = member.clone();
+                update.state = state;
+                self.merge(update);
+            }
+        }
+    }
+}
+
+pub struct Gossip {
+    sender: tokio::sync::Mutex<SendHalf>,
+    seed: Option<SocketAddr>,
+    state: Mutex<State>,
+    pending: Mutex<HashMap<u64, Waiter>>,
+    // cuts the node off from the others while set.
+    #[cfg(test)]
+    muted: std::sync::atomic::AtomicBool
+}
+
+fn random(bound: usize) -> usize {
+    (Uuid::new_v4().as_u128() % bound as u128) as usize
+}
+
+impl Gossip {
+    // Incarnations start at the time the node starts, so a restarted node
+    // outdates what was said about it before.
+    pub async fn start(id: &str, address: &str, config: &GossipConfig) -> Arc<Gossip> {
+        let bind: SocketAddr = config.bind.parse().expect("Invalid gossip bind address");
+        let socket = UdpSocket::bind(bind).await.expect("Failed to bind gossip socket");
+        let bound = socket.local_addr().expect("Failed to read gossip address");
+        let advertise: SocketAddr = match &config.advertise {
+            Some(advertise) => advertise.parse().expect("Invalid gossip advertise address"),
+            None => bound
+        };
+        let seed = config.seed
+            .as_ref()
+            .map(|seed| seed.parse().expect("Invalid gossip seed address"));
+        let (mut receiver, sender) = socket.split();
+        let incarnation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis() as u64;
+        let me = Member {
+            id: id.to_owned(),
+            address: address.to_owned(),
+            gossip: advertise,
+            incarnation,
+            state: MemberState::Alive
+        };
+        let gossip = Arc::new(Gossip {
+            sender: tokio::sync::Mutex::new(sender),
+            seed,
+            state: Mutex::new(State {
+                me,
+                members: HashMap::new(),
+                updates: Vec::new(),
+                seq: 0,
+                round: Vec::new(),
+                version: 0
+            }),
+            pending: Mutex::new(HashMap::new()),
+            #[cfg(test)]
+            muted: std::sync::atomic::AtomicBool::new(false)
+        });
+        info!("Gossip listening on {}", bound);
+
+        let received = Arc::downgrade(&gossip);
+        tokio::spawn(async move {
+            let mut buffer = vec![0u8; MAX_PACKET];
+            loop {
+                let (size, from) = match receiver.recv_from(&mut buffer).await {
+                    Ok(received) => received,
+                    // errors of earlier sends are reported here as well.
+                    Err(_) => continue
+                };
+                let gossip = match received.upgrade() {
+                    Some(gossip) => gossip,
+                    None => break
+                };
+                match rmp_serde::from_slice(&buffer[..size]) {
+                    Ok(message) => gossip.receive(message, from).await,
+                    Err(err) => warn!("Invalid gossip from {} - {}", from, err)
+                }
+            }
+        });
+        let probed = Arc::downgrade(&gossip);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PROBE_INTERVAL);
+            loop {
+                interval.tick().await;
+                match probed.upgrade() {
+                    Some(gossip) => gossip.probe().await,
+                    None => break
+                }
+            }
+        });
+        gossip
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        self.state.lock().expect("Failed to acquire lock")
+    }
+
+    // Other members the node knows of, along with a version raised whenever
+    // one of them changes.
+    // The address other nodes gossip with this one on.
+    #[cfg(test)]
+    pub fn address(&self) -> SocketAddr {
+        self.lock().me.gossip
+    }
+
+    pub fn members(&self) -> (Vec<Member>, u64) {
+        let state = self.lock();
+        let members = state.members.values().map(|(member, _)| member.clone()).collect();
+        (members, state.version)
+    }
+
+    async fn send(&self, message: &Message, to: SocketAddr) {
+        let serialized = rmp_serde::to_vec(message).expect("Failed to serialize gossip");
+        if let Err(err) = self.sender.lock().await.send_to(&serialized, &to).await {
+            debug!("Failed to send gossip to {} - {}", to, err);
+        }
+    }
+
+    #[cfg(test)]
+    fn mute(&self, muted: bool) {
+        self.muted.store(muted, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    async fn receive(&self, message: Message, from: SocketAddr) {
+        #[cfg(test)]
+        if self.muted.load(std::sync::atomic::Ordering::SeqCst) {
+            return;
+        }
+        match message {
+            Message::Ping { seq, updates } => {
+                let updates = {
+                    let mut state = self.lock();
+                    let sender = updates.first().map(|sender| sender.id.clone());
+                    let joined = sender.as_ref().is_some_and(|sender| !state.members.contains_key(sender));
+                    for update in updates {
+                        state.merge(update);
+                    }
+                    match joined {
+                        true => state.everyone(),
+                        false => {
+                            let mut updates = state.piggyback();
+                            updates.extend(state.rumour(sender.as_deref()));
+                            updates
+                        }
+                    }
+                };
+                self.send(&Message::Ack { seq, updates }, from).await;
+            },
+            Message::Ack { seq, updates } => {
+                let waiter = {
+                    let mut state = self.lock();
+                    for update in updates {
+                        state.merge(update);
+                    }
+                    self.pending.lock().expect("Failed to acquire lock").remove(&seq)
+                };
+                match waiter {
+                    Some(Waiter::Probe(acked)) => {
+                        let _ = acked.send(());
+                    },
+                    Some(Waiter::Relay { requester, seq, .. }) => {
+                        let updates = self.lock().piggyback();
+                        self.send(&Message::Ack { seq, updates }, requester).await;
+                    },
+                    None => {}
+                }
+            },
+            Message::PingReq { seq, target, updates } => {
+                let (own, updates) = {
+                    let mut state = self.lock();
+                    for update in updates {
+                        state.merge(update);
+                    }
+                    (state.next_seq(), state.piggyback())
+                };
+                let relay = Waiter::Relay { requester: from, seq, expires: Instant::now() + PROBE_INTERVAL };
+                self.pending.lock().expect("Failed to acquire lock").insert(own, relay);
+                self.send(&Message::Ping { seq: own, updates }, target).await;
+            }
+        }
+    }
+
+    // Waits for the acknowledgement of the sequence number.
+    async fn acked(&self, seq: u64, wait: Duration, receiver: oneshot::Receiver<()>) -> bool {
+        let acked = matches!(timeout(wait, receiver).await, Ok(Ok(())));
+        self.pending.lock().expect("Failed to acquire lock").remove(&seq);
+        acked
+    }
+
+    async fn probe(&self) {
+        #[cfg(test)]
+        if self.muted.load(std::sync::atomic::Ordering::SeqCst) {
+            return;
+        }
+        let now = Instant::now();
+        self.pending
+            .lock()
+            .expect("Failed to acquire lock")
+            .retain(|_, waiter| !matches!(waiter, Waiter::Relay { expires, .. } if *expires <= now));
+        let (target, dead, helpers, seq, updates) = {
+            let mut state = self.lock();
+            let expired: Vec<(String, u64)> = state.members
+                .values()
+                .filter(|(member, since)| {
+                    member.state == MemberState::Suspect && now.duration_since(*since) >= SUSPECT_TIMEOUT
+                })
+                .map(|(member, _)| (member.id.clone(), member.incarnation))
+                .collect();
+            for (id, incarnation) in expired {
+                state.mark(&id, incarnation, MemberState::Dead);
+            }
+            let living: Vec<Member> = state.members
+                .values()
+                .map(|(member, _)| member.clone())
+                .filter(|member| member.state != MemberState::Dead)
+                .collect();
+            if state.round.is_empty() {
+                let mut round: Vec<String> = living.iter().map(|member| member.id.clone()).collect();
+                for index in (1..round.len()).rev() {
+                    round.swap(index, random(index + 1));
+                }
+                state.round = round;
+            }
+            let target = state.round.pop().and_then(|id| living.iter().find(|member| member.id == id).cloned());
+            // dead members are pinged now and then along with the rumour of
+            // their death, which those still running refute with a new
+            // incarnation, bringing them back.
+            let dead: Vec<Member> = state.members
+                .values()
+                .filter(|(member, _)| member.state == MemberState::Dead)
+                .map(|(member, _)| member.clone())
+                .collect();
+            let dead = match dead.is_empty() {
+                true => None,
+                false => Some(dead[random(dead.len())].clone())
+            };
+            let mut helpers: Vec<SocketAddr> = living
+                .iter()
+                .filter(|member| Some(&member.id) != target.as_ref().map(|target| &target.id))
+                .map(|member| member.gossip)
+                .collect();
+            while helpers.len() > INDIRECT_PROBES {
+                helpers.swap_remove(random(helpers.len()));
+            }
+            (target, dead, helpers, state.next_seq(), state.piggyback())
+        };
+        if let Some(dead) = dead {
+            let seq = self.lock().next_seq();
+            let address = dead.gossip;
+            let mut updates = updates.clone();
+            updates.push(dead);
+            self.send(&Message::Ping { seq, updates }, address).await;
+        }
+        let target = match (target, self.seed) {
+            (Some(target), _) => target,
+            // nodes which don't know of any other join through the seed.
+            (None, Some(seed)) => {
+                self.send(&Message::Ping { seq, updates }, seed).await;
+                return;
+            },
+            (None, None) => return
+        };
+
+        let (acked, receiver) = oneshot::channel();
+        self.pending.lock().expect("Failed to acquire lock").insert(seq, Waiter::Probe(acked));
+        self.send(&Message::Ping { seq, updates: updates.clone() }, target.gossip).await;
+        if self.acked(seq, PROBE_TIMEOUT, receiver).await {
+            return;
+        }
+        let (acked, receiver) = oneshot::channel();
+        self.pending.lock().expect("Failed to acquire lock").insert(seq, Waiter::Probe(acked));
+        for helper in helpers {
+            let request = Message::PingReq { seq, target: target.gossip, updates: updates.clone() };
+            self.send(&request, helper).await;
+        }
+        if !self.acked(seq, PROBE_INTERVAL - PROBE_TIMEOUT, receiver).await {
+            debug!("Node {} didn't answer", target.id);
+            self.lock().mark(&target.id, target.incarnation, MemberState::Suspect);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // the system picks the ports, so tests running at the same time don't
+    // take each other's.
+    fn config(seed: Option<&Gossip>) -> GossipConfig {
+        GossipConfig {
+            bind: "127.0.0.1:0".to_owned(),
+            advertise: None,
+            seed: seed.map(|seed| seed.address().to_string())
+        }
+    }
+
+    fn states(gossip: &Gossip) -> HashMap<String, MemberState> {
+        gossip.members().0.into_iter().map(|member| (member.id, member.state)).collect()
+    }
+
+    async fn wait_for<F: Fn() -> bool>(condition: F) -> bool {
+        for _ in 0..100 {
+            if condition() {
+                return true;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn membership() {
+        let a = Gossip::start("a", "http://a", &config(None)).await;
+        let b = Gossip::start("b", "http://b", &config(Some(&a))).await;
+        let c = Gossip::start("c", "http://c", &config(Some(&a))).await;
+
+        // b and c only know of the seed at first.
+        assert!(wait_for(|| states(&b).len() == 2 && states(&c).len() == 2).await);
+        let (members, _) = b.members();
+        let learned = members.iter().find(|member| member.id == "c").unwrap();
+        assert_eq!(learned.address, "http://c");
+        assert_eq!(learned.gossip, c.address());
+
+        // c is cut off without restarting, so it comes back with the
+        // incarnation it was found dead with until it hears of its death.
+        c.mute(true);
+        assert!(wait_for(|| {
+            states(&a).get("c") == Some(&MemberState::Dead) && states(&b).get("c") == Some(&MemberState::Dead)
+        }).await);
+        assert_eq!(states(&a).get("b"), Some(&MemberState::Alive));
+        c.mute(false);
+        assert!(wait_for(|| {
+            states(&a).get("c") == Some(&MemberState::Alive) && states(&b).get("c") == Some(&MemberState::Alive)
+        }).await);
+        assert!(c.members().0.iter().all(|member| member.state == MemberState::Alive));
+    }
+
+    #[test]
+    fn merge() {
+        let member = |incarnation, state| Member {
+            id: "b".to_owned(),
+            address: "http://b".to_owned(),
+            gossip: "127.0.0.1:1".parse().unwrap(),
+            incarnation,
+            state
+        };
+        let mut state = State {
+            me: Member { id: "a".to_owned(), ..member(5, MemberState::Alive) },
+            members: HashMap::new(),
+            updates: Vec::new(),
+            seq: 0,
+            round: Vec::new(),
+            version: 0
+        };
+        state.merge(member(1, MemberState::Alive));
+        state.merge(member(1, MemberState::Suspect));
+        assert_eq!(state.members["b"].0.state, MemberState::Suspect);
+        // the same incarnation doesn't clear the suspicion, a newer one does.
+        state.merge(member(1, MemberState::Alive));
+        assert_eq!(state.members["b"].0.state, MemberState::Suspect);
+        state.merge(member(2, MemberState::Alive));
+        assert_eq!(state.members["b"].0.state, MemberState::Alive);
+        state.merge(member(2, MemberState::Dead));
+        state.merge(member(1, MemberState::Alive));
+        state.merge(member(2, MemberState::Alive));
+        assert_eq!(state.members["b"].0.state, MemberState::Dead);
+        assert_eq!(state.version, 4);
+        // the dead member hears of it and refutes it.
+        assert_eq!(state.rumour(Some("b")).unwrap().state, MemberState::Dead);
+        state.merge(member(3, MemberState::Alive));
+        assert_eq!(state.members["b"].0.state, MemberState::Alive);
+        assert!(state.rumour(Some("b")).is_none());
+
+        // suspicions about the node itself are refuted.
+        state.merge(Member { id: "a".to_owned(), ..member(5, MemberState::Suspect) });
+        assert_eq!(state.me.incarnation, 6);
+        assert!(state.piggyback().iter().all(|update| update.id != "a" || update.incarnation == 6));
+    }
+}
@@ -0,0 +1,84 @@
+// Liveness and readiness reporting, for `/healthz` and `/readyz`.
+
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// The scheduler ticks every 500ms, so this leaves room for a few slow
+// callbacks before the process is reported as stuck.
+const SCHEDULER_STALE: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Clone, Debug)]
+pub struct HealthCheck {
+    pub name: String,
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>
+}
+
+impl HealthCheck {
+    pub fn ok(name: String, detail: Option<String>) -> HealthCheck {
+        HealthCheck { name, status: "ok", detail }
+    }
+
+    pub fn failing(name: String, detail: String) -> HealthCheck {
+        HealthCheck { name, status: "failing", detail: Some(detail) }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.status == "ok"
+    }
+}
+
+#[derive(Serialize)]
+pub struct HealthReport {
+    pub status: &'static str,
+    pub checks: Vec<HealthCheck>
+}
+
+impl HealthReport {
+    pub fn is_ok(&self) -> bool {
+        self.status == "ok"
+    }
+}
+
+impl From<Vec<HealthCheck>> for HealthReport {
+    fn from(checks: Vec<HealthCheck>) -> HealthReport {
+        let status = match checks.iter().all(HealthCheck::is_ok) {
+            true => "ok",
+            false => "failing"
+        };
+        HealthReport { status, checks }
+    }
+}
+
+pub struct Health {
+    started: Instant,
+    last_tick: Mutex<Option<Instant>>
+}
+
+impl Health {
+    pub fn new() -> Health {
+        Health {
+            started: Instant::now(),
+            last_tick: Mutex::new(None)
+        }
+    }
+
+    // Called by the scheduler loop on every iteration.
+    pub fn tick(&self) {
+        *self.last_tick.lock().expect("Failed to acquire lock") = Some(Instant::now());
+    }
+
+    pub fn liveness(&self) -> HealthReport {
+        let last_tick = *self.last_tick.lock().expect("Failed to acquire lock");
+        // the scheduler gets the same delay to tick for the first time.
+        let elapsed = last_tick.unwrap_or(self.started).elapsed();
+        let detail = format!("last tick {}ms ago", elapsed.as_millis());
+        let check = match elapsed < SCHEDULER_STALE {
+            true => HealthCheck::ok("scheduler".to_owned(), Some(detail)),
+            false => HealthCheck::failing("scheduler".to_owned(), detail)
+        };
+        HealthReport::from(vec![check])
+    }
+}
@@ -1,2 +1,14 @@
 
 pub const KEYSPACE_QUEUE: [u8; 2] = [0u8, 0u8];
+// label index entries, keyed by label key, label value and job id.
+pub const KEYSPACE_LABEL: [u8; 2] = [0u8, 1u8];
+// group index entries, keyed by group and job id.
+pub const KEYSPACE_GROUP: [u8; 2] = [0u8, 2u8];
+// execution history, keyed by job id and the time the job fired.
+pub const KEYSPACE_HISTORY: [u8; 2] = [0u8, 3u8];
+// node the shard of the tree was migrated to, when it was.
+pub const KEYSPACE_MOVED: [u8; 2] = [0u8, 4u8];
+// raft log entries keyed by index, in the tree of the log.
+pub const KEYSPACE_RAFT_LOG: [u8; 2] = [1u8, 0u8];
+// current term and vote of the node, in the tree of the log.
+pub const KEYSPACE_RAFT_TERM: [u8; 2] = [1u8, 1u8];
@@ -1,5 +1,7 @@
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate lazy_static;
 extern crate serde_derive;
 extern crate serde;
 extern crate serde_json;
@@ -13,10 +15,14 @@ extern crate priority_queue;
 extern crate hyper;
 extern crate futures;
 extern crate bytes;
+extern crate form_urlencoded;
 extern crate cron;
+extern crate prometheus;
+extern crate sha2;
+extern crate jsonwebtoken;
 
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
 use hyper::{
     Server,
@@ -27,9 +33,26 @@ use futures::channel::oneshot;
 
 mod error;
 
+pub mod config;
+use crate::config::Config;
+
+pub mod auth;
+use crate::auth::Auth;
+
+pub mod egress;
+use crate::egress::Egress;
+
 mod keyspace;
 
 pub mod schema;
+mod selector;
+mod validation;
+
+mod metrics;
+mod health;
+mod quota;
+use crate::quota::Quotas;
+use crate::health::Health;
 
 mod scheduler;
 use scheduler::Scheduler;
@@ -41,12 +64,17 @@ mod api;
 use crate::api::handle_request;
 
 mod shard;
+mod raft;
+mod gossip;
 mod cluster;
 mod node;
 use crate::cluster::Cluster;
+use crate::schema::SHARD_COUNT;
+use crate::node::server::NodeServer;
 
 pub struct ScheduleM8 {
     scheduler: Scheduler,
+    node_server: Option<NodeServer>,
     close_sender: oneshot::Sender<()>,
     closed_receiver: oneshot::Receiver<()>
 }
@@ -54,20 +82,34 @@ pub struct ScheduleM8 {
 type GenericError = Box<dyn std::error::Error + Send + Sync>;
 
 impl ScheduleM8 {
-    pub async fn start(bind: String, db_path: String) -> ScheduleM8 {
-        info!("Opening store at location: {}", db_path);
-        let tree = sled::open(&db_path).expect("Failed to open database");
-        let store = Arc::new(Store::new(tree));
-        let cluster = Arc::new(Cluster::start(store.clone()).await);
-        let scheduler = Scheduler::start(store.clone());
-
-        let address: SocketAddr = bind.parse().unwrap();
-
+    pub async fn start(config: Config) -> ScheduleM8 {
+        info!("Opening store at location: {}", config.db_path);
+        let tree = sled::open(&config.db_path).expect("Failed to open database");
+        // kept apart from the jobs so clearing them leaves the keys alone.
+        let keys = tree.open_tree("api_keys").expect("Failed to open api keys");
+        let auth = Arc::new(Auth::new(&config.auth, keys));
+        let health = Arc::new(Health::new());
+        // the api listens while the queue is rebuilt, reporting it isn't
+        // ready until the cluster is there to take the requests.
+        let started: Arc<RwLock<Option<Arc<Cluster>>>> = Arc::new(RwLock::new(None));
+
+        let address: SocketAddr = config.bind.parse().unwrap();
+
+        let service_started = started.clone();
+        let service_health = health.clone();
         let make_svc = make_service_fn(move |_| {
-            let service_cluster = cluster.clone();
+            let service_started = service_started.clone();
+            let service_health = service_health.clone();
+            let service_auth = auth.clone();
             async {
                 Ok::<_, GenericError>(service_fn(move |req| {
-                    handle_request(service_cluster.clone(), req)
+                    let cluster = service_started.read().expect("Failed to acquire lock").clone();
+                    handle_request(
+                        cluster,
+                        service_health.clone(),
+                        service_auth.clone(),
+                        req
+                    )
                 }))
             }
         });
@@ -86,8 +128,43 @@ impl ScheduleM8 {
             closed_sender.send(()).unwrap();
         });
 
+        let db = tree.clone();
+        let stores = tokio::task::spawn_blocking(move || Store::open_shards(&db, SHARD_COUNT))
+            .await
+            .expect("Failed to rebuild the queue");
+        let quotas = Arc::new(Quotas::new(&config.quotas));
+        let egress = Arc::new(Egress::new(&config.egress));
+        let log = tree.open_tree("raft").expect("Failed to open raft log");
+        let hints = tree.open_tree("hints").expect("Failed to open hinted jobs");
+        let cluster = Cluster::start(
+            stores.clone(),
+            Some(log),
+            Some(hints),
+            quotas.clone(),
+            egress.clone(),
+            &config.cluster
+        ).await;
+        // other nodes reach the shards of this one through it.
+        let node_server = match &config.cluster.bind {
+            Some(bind) => {
+                let address: SocketAddr = bind.parse().expect("Invalid node bind address");
+                info!("Node server listening on {}", address);
+                Some(NodeServer::start(address, cluster.clone()).await)
+            },
+            None => None
+        };
+        let scheduler = Scheduler::start(
+            cluster.clone(),
+            config.history.clone(),
+            health,
+            quotas,
+            egress
+        );
+        *started.write().expect("Failed to acquire lock") = Some(cluster);
+
         ScheduleM8 {
             scheduler,
+            node_server,
             close_sender,
             closed_receiver
         }
@@ -95,6 +172,9 @@ impl ScheduleM8 {
 
     pub fn stop(self) {
         self.scheduler.stop();
+        if let Some(node_server) = self.node_server {
+            node_server.stop();
+        }
         self.close_sender.send(()).unwrap();
     }
 
@@ -6,7 +6,7 @@ extern crate futures;
 extern crate tokio;
 
 use schedule_m8::ScheduleM8;
-use std::env;
+use schedule_m8::config::Config;
 use env_logger::Env;
 
 #[tokio::main]
@@ -15,14 +15,10 @@ async fn main() {
         Env::default().default_filter_or("info")
     ).init();
 
-    let mut default_dir = std::env::current_dir().unwrap();
-    default_dir.push(".data");
-    let bind = env::var("SCHEDULE_M8_BIND_ADDR")
-        .unwrap_or("0.0.0.0:8001".to_owned());
-    let db_path = env::var("SCHEDULE_M8_DATA_DIR")
-        .unwrap_or(default_dir.to_str().unwrap().to_owned());
+    let config = Config::from_env();
+    let bind = config.bind.clone();
 
-    let schedule_m8 = ScheduleM8::start(bind.clone(), db_path).await;
+    let schedule_m8 = ScheduleM8::start(config).await;
     info!("Listening on {}", bind);
     schedule_m8.forever().await;
 }
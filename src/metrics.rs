@@ -0,0 +1,74 @@
+// Prometheus metrics, exposed by the api on `/metrics`.
+
+use prometheus::{
+    Encoder,
+    HistogramVec,
+    Histogram,
+    IntCounterVec,
+    IntGauge,
+    TextEncoder,
+    register_histogram,
+    register_histogram_vec,
+    register_int_counter_vec,
+    register_int_gauge
+};
+
+lazy_static! {
+    pub static ref QUEUE_SIZE: IntGauge = register_int_gauge!(
+        "schedule_m8_queue_size",
+        "Number of jobs waiting to fire on this node."
+    ).unwrap();
+
+    pub static ref FIRE_LATENESS: Histogram = register_histogram!(
+        "schedule_m8_fire_lateness_seconds",
+        "Delay between the time a job was scheduled for and the time it fired.",
+        vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0]
+    ).unwrap();
+
+    pub static ref CALLBACK_DURATION: HistogramVec = register_histogram_vec!(
+        "schedule_m8_callback_duration_seconds",
+        "Time taken by the callbacks to respond.",
+        &["host"]
+    ).unwrap();
+
+    // the status is "error" when no response was received.
+    pub static ref CALLBACKS: IntCounterVec = register_int_counter_vec!(
+        "schedule_m8_callbacks_total",
+        "Callbacks sent, by host and response status.",
+        &["host", "status"]
+    ).unwrap();
+
+    // callbacks held back until the next minute by the tenant's quota.
+    pub static ref CALLBACKS_THROTTLED: IntCounterVec = register_int_counter_vec!(
+        "schedule_m8_callbacks_throttled_total",
+        "Callbacks delayed by the callback quota, by tenant.",
+        &["tenant"]
+    ).unwrap();
+
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "schedule_m8_http_requests_total",
+        "Api requests, by method, route and response status.",
+        &["method", "route", "status"]
+    ).unwrap();
+
+    pub static ref RPC_DURATION: HistogramVec = register_histogram_vec!(
+        "schedule_m8_rpc_duration_seconds",
+        "Time taken by the calls to other nodes.",
+        &["method"]
+    ).unwrap();
+
+    // the status is the grpc status code of the response.
+    pub static ref RPC_CALLS: IntCounterVec = register_int_counter_vec!(
+        "schedule_m8_rpc_calls_total",
+        "Calls to other nodes, by method and status.",
+        &["method", "status"]
+    ).unwrap();
+}
+
+pub fn encode() -> Vec<u8> {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("Failed to encode metrics");
+    buffer
+}
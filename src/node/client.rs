@@ -1,48 +1,371 @@
 use crate::error::AppError;
-use crate::schema::Job;
-use tonic::transport::Channel;
+use crate::schema::{Job, JobFilter, JobRun, JobStats, JobUsage, NodeStatus, PeerState};
+use crate::metrics;
+use crate::health::HealthCheck;
+use crate::raft::{AppendRequest, AppendResponse, Command, Vote, VoteRequest};
+use crate::cluster::View;
+use crate::store::{Changes, QueueKey};
+use tonic::{Response, Status};
+use tonic::transport::{Channel, Endpoint};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::time::timeout;
 
 use super::convert::*;
+use super::security::NodeSecurity;
 
 use super::grpc::node_client::NodeClient as RpcClient;
 
 use super::grpc;
 
+// Calls failing in a row before the node is considered down.
+const FAILED_CALLS: u32 = 3;
+
+// Wait before connecting again to a node which is down, doubled with each
+// attempt failing.
+const RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+// Nodes which don't accept a connection or answer a call in time count as
+// unreachable, so a node which hangs doesn't hold its callers.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(1);
+// `/readyz` answers in time even when a node doesn't.
+const READINESS_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct Health {
+    state: PeerState,
+    // when the node entered its state.
+    since: SystemTime,
+    failures: u32,
+    error: Option<String>,
+    // calls fail without reaching the node before then, while it is down.
+    retry_at: Instant
+}
+
+impl Health {
+    fn new(state: PeerState) -> Health {
+        Health {
+            state,
+            since: SystemTime::now(),
+            failures: 0,
+            error: None,
+            retry_at: Instant::now()
+        }
+    }
+}
+
 pub struct NodeClient {
-    rpc_client: RpcClient<Channel>
+    // learned through gossip when it isn't in the cluster file.
+    host: RwLock<String>,
+    // connected on first use when the node couldn't be reached before.
+    rpc_client: Mutex<Option<RpcClient<Channel>>>,
+    // set once gossip finds the node dead, so no request is sent to it.
+    dead: AtomicBool,
+    health: RwLock<Health>,
+    security: Arc<NodeSecurity>
 }
 
 impl NodeClient {
-    pub async fn connect(host: &str) -> Result<NodeClient, AppError> {
-        let rpc_client = RpcClient::connect(host.to_owned())
-            .await
-            .map_err(|err| {
-                error!("\n\n\nERROR RpcClient::connect err - {}", err);
-                AppError::NodeUnreachable
-            })?;
-        Ok(NodeClient {
-            rpc_client
-        })
+    // Client of a node which might not be up yet, the connection is made by
+    // the first call.
+    pub fn lazy(host: &str, security: Arc<NodeSecurity>) -> NodeClient {
+        NodeClient {
+            host: RwLock::new(host.to_owned()),
+            rpc_client: Mutex::new(None),
+            dead: AtomicBool::new(false),
+            health: RwLock::new(Health::new(PeerState::Unknown)),
+            security
+        }
+    }
+
+    async fn dial(host: &str, security: &NodeSecurity) -> Result<RpcClient<Channel>, AppError> {
+        let unreachable = |message: String| {
+            error!("{} - Failed to connect to node: {}", host, message);
+            AppError::NodeUnreachable {
+                node: host.to_owned(),
+                message
+            }
+        };
+        let mut endpoint = Endpoint::from_shared(host.to_owned())
+            .map_err(|err| unreachable(err.to_string()))?
+            .timeout(REQUEST_TIMEOUT);
+        if let Some(tls) = security.client_tls() {
+            endpoint = endpoint.tls_config(tls);
+        }
+        let channel = match timeout(CONNECT_TIMEOUT, endpoint.connect()).await {
+            Ok(connected) => connected.map_err(|err| unreachable(err.to_string()))?,
+            Err(_) => return Err(unreachable("the connection timed out".to_owned()))
+        };
+        Ok(RpcClient::with_interceptor(channel, security.client_interceptor()))
+    }
+
+    // Requests for jobs fail right away once gossip finds the node dead.
+    async fn rpc_client(&self) -> Result<RpcClient<Channel>, AppError> {
+        if self.dead.load(Ordering::Relaxed) {
+            return Err(AppError::NodeUnreachable {
+                node: self.host(),
+                message: "the node is dead".to_owned()
+            });
+        }
+        self.connection().await
+    }
+
+    // Calls to a node which is down fail right away until it is time to
+    // connect to it again. Raft calls are sent this way even to nodes gossip
+    // found dead, as they tell on their own whether the node is.
+    async fn connection(&self) -> Result<RpcClient<Channel>, AppError> {
+        {
+            let health = self.health.read().expect("Failed to acquire lock");
+            let now = Instant::now();
+            if health.state == PeerState::Down && health.retry_at > now {
+                return Err(AppError::NodeUnreachable {
+                    node: self.host(),
+                    message: format!(
+                        "the node is down since {}s, retrying in {}ms - {}",
+                        health.since.elapsed().unwrap_or_default().as_secs(),
+                        (health.retry_at - now).as_millis(),
+                        health.error.as_deref().unwrap_or("unknown error")
+                    )
+                });
+            }
+        }
+        self.connected().await
+    }
+
+    // Connection to the node, made when there is none. Calls made while it
+    // is being made make their own.
+    async fn connected(&self) -> Result<RpcClient<Channel>, AppError> {
+        let host = self.host();
+        if host.is_empty() {
+            return Err(AppError::NodeUnreachable {
+                node: host,
+                message: "the address of the node isn't known yet".to_owned()
+            });
+        }
+        if let Some(connected) = &*self.rpc_client.lock().expect("Failed to acquire lock") {
+            return Ok(connected.clone());
+        }
+        match NodeClient::dial(&host, &self.security).await {
+            Ok(connected) => {
+                let mut rpc_client = self.rpc_client.lock().expect("Failed to acquire lock");
+                // the node moved while it was connected to.
+                if self.host() == host {
+                    *rpc_client = Some(connected.clone());
+                }
+                Ok(connected)
+            },
+            Err(err) => {
+                self.failed(&err);
+                Err(err)
+            }
+        }
+    }
+
+    fn disconnect(&self) {
+        *self.rpc_client.lock().expect("Failed to acquire lock") = None;
+    }
+
+    // Waits for the rpc call, recording its duration and status, and
+    // whether the node was reached.
+    async fn observe<T>(
+        &self,
+        method: &str,
+        call: impl Future<Output = Result<Response<T>, Status>>
+    ) -> Result<T, AppError> {
+        let answer = self.measure(method, call).await?;
+        self.succeeded();
+        answer
+    }
+
+    // Waits for the rpc call, recording its duration and status, and fails
+    // with the errors of the calls which didn't reach the node. Statuses
+    // without error details didn't come from the node service, which means
+    // the node couldn't be reached, and the connection is made again by the
+    // next call.
+    async fn measure<T>(
+        &self,
+        method: &str,
+        call: impl Future<Output = Result<Response<T>, Status>>
+    ) -> Result<Result<T, AppError>, AppError> {
+        let started = Instant::now();
+        let result = call.await;
+        metrics::RPC_DURATION
+            .with_label_values(&[method])
+            .observe(started.elapsed().as_secs_f64());
+        let status = match &result {
+            Ok(_) => "Ok".to_owned(),
+            Err(status) => format!("{:?}", status.code())
+        };
+        metrics::RPC_CALLS.with_label_values(&[method, &status]).inc();
+        match result {
+            Ok(response) => Ok(Ok(response.into_inner())),
+            Err(status) if status.details().is_empty() => {
+                let err = AppError::NodeUnreachable {
+                    node: self.host(),
+                    message: status.message().to_owned()
+                };
+                self.disconnect();
+                self.failed(&err);
+                Err(err)
+            },
+            Err(status) => Ok(Err(AppError::from(status)))
+        }
+    }
+
+    fn succeeded(&self) {
+        let mut health = self.health.write().expect("Failed to acquire lock");
+        if health.state == PeerState::Down {
+            info!("{} - Node is up again", self.host());
+        }
+        if health.state != PeerState::Up {
+            *health = Health::new(PeerState::Up);
+        }
+        health.failures = 0;
+        health.error = None;
+    }
+
+    // The node is down after enough failures in a row, and each failure
+    // from then on puts off connecting to it again a little more.
+    fn failed(&self, err: &AppError) {
+        let mut health = self.health.write().expect("Failed to acquire lock");
+        health.failures += 1;
+        health.error = Some(err.message().to_owned());
+        if health.failures < FAILED_CALLS {
+            return;
+        }
+        if health.state != PeerState::Down {
+            warn!("{} - Node is down: {}", self.host(), err.message());
+            health.state = PeerState::Down;
+            health.since = SystemTime::now();
+        }
+        let attempts = (health.failures - FAILED_CALLS).min(16);
+        let delay = (RECONNECT_DELAY * 2u32.pow(attempts)).min(MAX_RECONNECT_DELAY);
+        health.retry_at = Instant::now() + delay;
+    }
+
+    pub fn status(&self, node: &str) -> NodeStatus {
+        let health = self.health.read().expect("Failed to acquire lock");
+        let dead = self.dead.load(Ordering::Relaxed);
+        NodeStatus {
+            node: node.to_owned(),
+            address: self.host(),
+            state: match dead {
+                true => PeerState::Down,
+                false => health.state
+            },
+            since: health.since.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            failures: health.failures,
+            error: match dead {
+                true => Some("the node is dead".to_owned()),
+                false => health.error.clone()
+            }
+        }
+    }
+
+    // Whether enough calls to the node failed in a row, which heartbeats
+    // make sure happens once it stops answering.
+    pub fn is_down(&self) -> bool {
+        self.health.read().expect("Failed to acquire lock").state == PeerState::Down
+    }
+
+    // Whether the last call to the node succeeded.
+    pub fn is_up(&self) -> bool {
+        let health = self.health.read().expect("Failed to acquire lock");
+        health.state == PeerState::Up && !self.dead.load(Ordering::Relaxed)
+    }
+
+    pub fn host(&self) -> String {
+        self.host.read().expect("Failed to acquire lock").clone()
+    }
+
+    // Points the client at the address gossip found the node at, which is
+    // connected to on the next call.
+    pub async fn set_host(&self, host: &str) {
+        if self.host() == host {
+            return;
+        }
+        let mut rpc_client = self.rpc_client.lock().expect("Failed to acquire lock");
+        *self.host.write().expect("Failed to acquire lock") = host.to_owned();
+        *rpc_client = None;
+        *self.health.write().expect("Failed to acquire lock") = Health::new(PeerState::Unknown);
+    }
+
+    pub fn set_dead(&self, dead: bool) {
+        self.dead.store(dead, Ordering::Relaxed);
     }
 
     pub async fn push(&self, job: Job) -> Result<(), AppError> {
-        let mut rpc_client = self.rpc_client.clone();
-        rpc_client
-            .push(grpc::Job::from(job))
-            .await
-            .map_err(AppError::from)?;
+        let mut rpc_client = self.rpc_client().await?;
+        self.observe("push", rpc_client.push(grpc::Job::from(job))).await?;
+
+        Ok(())
+    }
+
+    pub async fn get(&self, tenant: &str, id: &str) -> Result<Option<Job>, AppError> {
+        let mut rpc_client = self.rpc_client().await?;
+
+        let result = self.observe(
+            "get",
+            rpc_client.get(grpc::Id { id: id.to_owned(), tenant: tenant.to_owned() })
+        ).await?;
+
+        result.job.map(Job::try_from).transpose()
+    }
+
+    pub async fn push_batch(&self, jobs: Vec<Job>) -> Result<(), AppError> {
+        let mut rpc_client = self.rpc_client().await?;
+        self.observe(
+            "push_batch",
+            rpc_client.push_batch(grpc::Jobs::from(jobs))
+        ).await?;
+
+        Ok(())
+    }
 
+    pub async fn hand_off(&self, jobs: Vec<(Job, Duration)>) -> Result<(), AppError> {
+        let mut rpc_client = self.rpc_client().await?;
+        let handed_off = grpc::HandedOff {
+            ages: jobs.iter().map(|(_, age)| age.as_millis() as u64).collect(),
+            jobs: jobs.into_iter().map(|(job, _)| grpc::Job::from(job)).collect()
+        };
+        self.observe("hand_off", rpc_client.hand_off(handed_off)).await?;
         Ok(())
     }
 
-    pub async fn remove(&self, id: &str) -> Result<Option<Job>, AppError> {
-        let mut rpc_client = self.rpc_client.clone();
+    // Only the removed jobs are sent back, so they are matched back to the
+    // requested ids.
+    pub async fn remove_batch(
+        &self,
+        tenant: &str,
+        ids: &[String]
+    ) -> Result<Vec<Option<Job>>, AppError> {
+        let mut rpc_client = self.rpc_client().await?;
 
-        let result = rpc_client
-            .remove(grpc::Id { id: id.to_owned() })
-            .await
-            .map_err(AppError::from)?
-            .into_inner();
+        let result = self.observe(
+            "remove_batch",
+            rpc_client.remove_batch(grpc::Ids { ids: ids.to_vec(), tenant: tenant.to_owned() })
+        ).await?;
+
+        let mut removed: HashMap<String, Job> = Vec::try_from(result)?
+            .into_iter()
+            .map(|job: Job| (job.id.clone(), job))
+            .collect();
+        Ok(ids.iter().map(|id| removed.remove(id)).collect())
+    }
+
+    pub async fn remove(&self, tenant: &str, id: &str) -> Result<Option<Job>, AppError> {
+        let mut rpc_client = self.rpc_client().await?;
+
+        let result = self.observe(
+            "remove",
+            rpc_client.remove(grpc::Id { id: id.to_owned(), tenant: tenant.to_owned() })
+        ).await?;
 
         match result.job {
             None => Ok(None),
@@ -50,12 +373,204 @@ impl NodeClient {
         }
     }
 
-    pub async fn clear(&self) -> Result<(), AppError> {
-        let mut rpc_client = self.rpc_client.clone();
-        rpc_client
-            .clear(grpc:: Empty { })
-            .await
-            .map_err(AppError::from)?;
+    pub async fn pause(&self, tenant: &str, id: &str) -> Result<Option<Job>, AppError> {
+        let mut rpc_client = self.rpc_client().await?;
+
+        let result = self.observe(
+            "pause",
+            rpc_client.pause(grpc::Id { id: id.to_owned(), tenant: tenant.to_owned() })
+        ).await?;
+
+        result.job.map(Job::try_from).transpose()
+    }
+
+    pub async fn resume(&self, tenant: &str, id: &str) -> Result<Option<Job>, AppError> {
+        let mut rpc_client = self.rpc_client().await?;
+
+        let result = self.observe(
+            "resume",
+            rpc_client.resume(grpc::Id { id: id.to_owned(), tenant: tenant.to_owned() })
+        ).await?;
+
+        result.job.map(Job::try_from).transpose()
+    }
+
+    pub async fn pause_matching(&self, filter: &JobFilter) -> Result<Vec<Job>, AppError> {
+        let mut rpc_client = self.rpc_client().await?;
+        let result = self.observe(
+            "pause_matching",
+            rpc_client.pause_matching(grpc::Filter::from(filter))
+        ).await?;
+        Vec::try_from(result)
+    }
+
+    pub async fn resume_matching(&self, filter: &JobFilter) -> Result<Vec<Job>, AppError> {
+        let mut rpc_client = self.rpc_client().await?;
+        let result = self.observe(
+            "resume_matching",
+            rpc_client.resume_matching(grpc::Filter::from(filter))
+        ).await?;
+        Vec::try_from(result)
+    }
+
+    pub async fn list(&self, filter: &JobFilter) -> Result<Vec<Job>, AppError> {
+        let mut rpc_client = self.rpc_client().await?;
+        let result = self.observe(
+            "list",
+            rpc_client.list(grpc::Filter::from(filter))
+        ).await?;
+        Vec::try_from(result)
+    }
+
+    pub async fn remove_matching(&self, filter: &JobFilter) -> Result<Vec<Job>, AppError> {
+        let mut rpc_client = self.rpc_client().await?;
+        let result = self.observe(
+            "remove_matching",
+            rpc_client.remove_matching(grpc::Filter::from(filter))
+        ).await?;
+        Vec::try_from(result)
+    }
+
+    pub async fn stats(&self, filter: &JobFilter) -> Result<JobStats, AppError> {
+        let mut rpc_client = self.rpc_client().await?;
+        let result = self.observe(
+            "stats",
+            rpc_client.stats(grpc::Filter::from(filter))
+        ).await?;
+        Ok(JobStats::from(result))
+    }
+
+    pub async fn runs(&self, tenant: &str, id: &str) -> Result<Vec<JobRun>, AppError> {
+        let mut rpc_client = self.rpc_client().await?;
+        let result = self.observe(
+            "runs",
+            rpc_client.runs(grpc::Id { id: id.to_owned(), tenant: tenant.to_owned() })
+        ).await?;
+        Ok(result.runs.into_iter().map(JobRun::from).collect())
+    }
+
+    pub async fn usage(&self, tenant: &str) -> Result<JobUsage, AppError> {
+        let mut rpc_client = self.rpc_client().await?;
+        let tenant = grpc::Tenant { tenant: tenant.to_owned() };
+        let result = self.observe("usage", rpc_client.usage(tenant)).await?;
+        Ok(JobUsage::from(result))
+    }
+
+    // Sends the jobs of a shard to a node holding a replica of it.
+    pub async fn replicate(
+        &self,
+        shard: usize,
+        jobs: Vec<Job>,
+        removed: Vec<(String, String)>,
+        replace: bool
+    ) -> Result<(), AppError> {
+        let mut rpc_client = self.rpc_client().await?;
+        let replica = grpc::Replica {
+            shard: shard as u32,
+            jobs: jobs.into_iter().map(grpc::Job::from).collect(),
+            removed: removed
+                .into_iter()
+                .map(|(tenant, id)| grpc::Id { id, tenant })
+                .collect(),
+            replace
+        };
+        self.observe("replicate", rpc_client.replicate(replica)).await?;
+        Ok(())
+    }
+
+    // Jobs changed on a shard since the node took it over, if it did, along
+    // with the keys of the erased ones.
+    pub async fn snapshot(&self, shard: usize) -> Result<Option<Changes>, AppError> {
+        let mut rpc_client = self.rpc_client().await?;
+        let request = grpc::Shard { shard: shard as u32 };
+        let result = self.observe("snapshot", rpc_client.snapshot(request)).await?;
+        match result.promoted {
+            true => Ok(Some((
+                result.jobs.into_iter().map(Job::try_from).collect::<Result<_, _>>()?,
+                result.removed.into_iter().map(QueueKey::from).collect()
+            ))),
+            false => Ok(None)
+        }
+    }
+
+    pub async fn request_vote(&self, request: VoteRequest) -> Result<Vote, AppError> {
+        let mut rpc_client = self.connection().await?;
+        let request = grpc::VoteRequest::from(request);
+        let result = self.observe("request_vote", rpc_client.request_vote(request)).await?;
+        Ok(Vote::from(result))
+    }
+
+    pub async fn append_entries(&self, request: AppendRequest) -> Result<AppendResponse, AppError> {
+        let mut rpc_client = self.connection().await?;
+        let request = grpc::AppendRequest::from(request);
+        let result = self.observe("append_entries", rpc_client.append_entries(request)).await?;
+        Ok(AppendResponse::from(result))
+    }
+
+    // Passes a command on to the node, which is the leader of the raft log.
+    pub async fn propose(&self, command: &Command) -> Result<(), AppError> {
+        let mut rpc_client = self.connection().await?;
+        let proposal = grpc::Proposal {
+            command: rmp_serde::to_vec(command).expect("Failed to serialize raft command")
+        };
+        self.observe("propose", rpc_client.propose(proposal)).await?;
+        Ok(())
+    }
+
+    // Sent on behalf of the node with the given id to the node expected at
+    // the address, the answer is the view of the node called. Heartbeats are
+    // sent even while the node is down, as they find out when it's back,
+    // and a node answering with another id is at the wrong address, which
+    // counts as a failure.
+    pub async fn heartbeat(&self, from: &str, to: &str) -> Result<View, AppError> {
+        let heartbeat = grpc::NodeId { node: from.to_owned() };
+        let call = async {
+            let mut rpc_client = self.connected().await?;
+            self.measure("heartbeat", rpc_client.heartbeat(heartbeat)).await
+        };
+        let message = match timeout(HEARTBEAT_TIMEOUT, call).await {
+            Ok(Ok(Ok(view))) if view.node == to => {
+                self.succeeded();
+                return Ok(View {
+                    node: view.node,
+                    down: view.down,
+                    promoted: view.promoted.into_iter().map(|shard| shard as usize).collect()
+                });
+            },
+            Ok(Ok(Ok(view))) => format!("the node answered as {}", view.node),
+            Ok(Ok(Err(err))) => {
+                self.succeeded();
+                return Err(err);
+            },
+            // failures to reach the node are already counted.
+            Ok(Err(err)) => return Err(err),
+            Err(_) => "the heartbeat timed out".to_owned()
+        };
+        let err = AppError::NodeUnreachable { node: self.host(), message };
+        self.disconnect();
+        self.failed(&err);
+        Err(err)
+    }
+
+    pub async fn ping(&self) -> Result<(), AppError> {
+        let mut rpc_client = self.rpc_client().await?;
+        self.observe("ping", rpc_client.ping(grpc::Empty { })).await?;
+        Ok(())
+    }
+
+    pub async fn readiness(&self) -> HealthCheck {
+        let name = format!("node {}", self.host());
+        match timeout(READINESS_TIMEOUT, self.ping()).await {
+            Ok(Ok(())) => HealthCheck::ok(name, None),
+            Ok(Err(err)) => HealthCheck::failing(name, err.to_string()),
+            Err(_) => HealthCheck::failing(name, format!("no answer within {}ms", READINESS_TIMEOUT.as_millis()))
+        }
+    }
+
+    pub async fn clear(&self, tenant: &str) -> Result<(), AppError> {
+        let mut rpc_client = self.rpc_client().await?;
+        let tenant = grpc::Tenant { tenant: tenant.to_owned() };
+        self.observe("clear", rpc_client.clear(tenant)).await?;
         Ok(())
     }
 }
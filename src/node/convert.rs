@@ -1,8 +1,10 @@
 // type conversions between the grpc and internal types
 use tonic::{ Status, Code };
 use std::time::Duration;
-use crate::schema::Job;
+use crate::schema::{Job, JobFilter, JobRun, JobStats, JobUsage, MisfirePolicy, DEFAULT_TENANT};
 use crate::error::AppError;
+use crate::store::QueueKey;
+use crate::raft::{AppendRequest, AppendResponse, Entry, Vote, VoteRequest};
 use super::grpc;
 
 use prost::Message;
@@ -40,25 +42,66 @@ fn internal_method(method: i32) -> String {
     conversion.to_owned()
 }
 
+fn rpc_misfire(misfire: MisfirePolicy) -> i32 {
+    match misfire {
+        MisfirePolicy::Skip => 0,
+        MisfirePolicy::FireOnce => 1
+    }
+}
+
+fn internal_misfire(misfire: i32) -> MisfirePolicy {
+    match misfire {
+        1 => MisfirePolicy::FireOnce,
+        _ => MisfirePolicy::Skip
+    }
+}
+
+// Nodes which don't know about tenants send an empty one, meaning the
+// default tenant.
+fn internal_tenant(tenant: String) -> String {
+    match tenant.is_empty() {
+        true => DEFAULT_TENANT.to_owned(),
+        false => tenant
+    }
+}
+
 impl From <Status> for AppError {
     fn from(status: Status) -> AppError {
         match grpc::AppError::decode(status.details()) {
             Err(_) => {
                 AppError::UnexpectedRpcError(status.message().to_owned())
             },
-            Ok(decoded) => {
+            Ok(mut decoded) => {
+                let message = decoded.message;
                 match decoded.code {
-                    1 => AppError::ValidationError,
-                    2 => AppError::UnexpectedError,
-                    3 => AppError::NodeUnreachable,
-                    4 => AppError::RpcDeserializationError,
+                    1 => AppError::ValidationError {
+                        field: decoded.details.remove("field"),
+                        message
+                    },
+                    2 => AppError::UnexpectedError(message),
+                    3 => AppError::NodeUnreachable {
+                        node: decoded.details.remove("node").unwrap_or_default(),
+                        message
+                    },
+                    4 => AppError::RpcDeserializationError(message),
                     5 => {
-                        error!("app_error - {}", decoded.message);
-                        AppError::UnexpectedRpcError(decoded.message)
+                        error!("app_error - {}", message);
+                        AppError::UnexpectedRpcError(message)
+                    },
+                    6 => AppError::Unauthorized(message),
+                    7 => AppError::Forbidden {
+                        scope: decoded.details.remove("scope").unwrap_or_default()
+                    },
+                    8 => AppError::QuotaExceeded {
+                        quota: decoded.details.remove("quota").unwrap_or_default(),
+                        limit: decoded.details
+                            .get("limit")
+                            .and_then(|limit| limit.parse().ok())
+                            .unwrap_or_default()
                     },
                     _ => {
                         error!("app_error - invalid rpc error response");
-                        AppError::RpcDeserializationError
+                        AppError::RpcDeserializationError(status.message().to_owned())
                     }
                 }
             }
@@ -75,7 +118,15 @@ impl From <Job> for grpc::Job {
             url: job.url,
             body: job.body,
             has_schedule: job.schedule.is_some(),
-            schedule: job.schedule.unwrap_or("".to_owned())
+            schedule: job.schedule.unwrap_or("".to_owned()),
+            paused: job.paused,
+            misfire: rpc_misfire(job.misfire),
+            labels: job.labels.into_iter().collect(),
+            has_group: job.group.is_some(),
+            group: job.group.unwrap_or_default(),
+            has_name: job.name.is_some(),
+            name: job.name.unwrap_or_default(),
+            tenant: job.tenant
         }
     }
 }
@@ -93,43 +144,296 @@ impl TryFrom <grpc::Job> for Job {
             schedule: match rpc_job.has_schedule {
                 true => Some(rpc_job.schedule),
                 false => None
-            }
+            },
+            paused: rpc_job.paused,
+            misfire: internal_misfire(rpc_job.misfire),
+            labels: rpc_job.labels.into_iter().collect(),
+            group: match rpc_job.has_group {
+                true => Some(rpc_job.group),
+                false => None
+            },
+            name: match rpc_job.has_name {
+                true => Some(rpc_job.name),
+                false => None
+            },
+            tenant: internal_tenant(rpc_job.tenant)
         })
     }
 }
 
-impl From<AppError> for Status {
-    fn from(app_error: AppError) -> Status {
-        let mut grpc_error = grpc::AppError::default();
-        let message = app_error.to_string();
+impl From <grpc::Id> for QueueKey {
+    fn from(rpc_id: grpc::Id) -> QueueKey {
+        (internal_tenant(rpc_id.tenant), rpc_id.id)
+    }
+}
 
-        let code = match app_error {
-            AppError::ValidationError => {
-                grpc_error.code = 1;
-                Code::InvalidArgument
+// The tenant along with the ids of its jobs.
+impl From <grpc::Ids> for (String, Vec<String>) {
+    fn from(rpc_ids: grpc::Ids) -> (String, Vec<String>) {
+        (internal_tenant(rpc_ids.tenant), rpc_ids.ids)
+    }
+}
+
+impl From <grpc::Tenant> for String {
+    fn from(rpc_tenant: grpc::Tenant) -> String {
+        internal_tenant(rpc_tenant.tenant)
+    }
+}
+
+impl From <&JobFilter> for grpc::Filter {
+    fn from(filter: &JobFilter) -> grpc::Filter {
+        grpc::Filter {
+            has_url: filter.url.is_some(),
+            url: filter.url.clone().unwrap_or_default(),
+            has_selector: filter.selector.is_some(),
+            selector: filter.selector
+                .as_ref()
+                .map(|selector| selector.to_string())
+                .unwrap_or_default(),
+            has_group: filter.group.is_some(),
+            group: filter.group.clone().unwrap_or_default(),
+            has_tenant: filter.tenant.is_some(),
+            tenant: filter.tenant.clone().unwrap_or_default()
+        }
+    }
+}
+
+impl TryFrom <grpc::Filter> for JobFilter {
+    type Error = AppError;
+
+    fn try_from(rpc_filter: grpc::Filter) -> Result<JobFilter, AppError> {
+        Ok(JobFilter {
+            url: match rpc_filter.has_url {
+                true => Some(rpc_filter.url),
+                false => None
             },
-            AppError::UnexpectedError => {
-                grpc_error.code = 2;
-                Code::Unknown
+            selector: match rpc_filter.has_selector {
+                true => Some(rpc_filter.selector.parse()?),
+                false => None
             },
-            AppError::NodeUnreachable => {
-                grpc_error.code = 3;
-                Code::Unavailable
+            group: match rpc_filter.has_group {
+                true => Some(rpc_filter.group),
+                false => None
+            },
+            tenant: match rpc_filter.has_tenant {
+                true => Some(rpc_filter.tenant),
+                false => None
+            }
+        })
+    }
+}
+
+impl From <JobStats> for grpc::JobStats {
+    fn from(stats: JobStats) -> grpc::JobStats {
+        grpc::JobStats {
+            count: stats.count,
+            paused: stats.paused,
+            has_next_fire: stats.next_fire.is_some(),
+            next_fire: stats.next_fire.unwrap_or_default()
+        }
+    }
+}
+
+impl From <grpc::JobStats> for JobStats {
+    fn from(rpc_stats: grpc::JobStats) -> JobStats {
+        JobStats {
+            count: rpc_stats.count,
+            paused: rpc_stats.paused,
+            next_fire: match rpc_stats.has_next_fire {
+                true => Some(rpc_stats.next_fire),
+                false => None
+            }
+        }
+    }
+}
+
+impl From <JobUsage> for grpc::JobUsage {
+    fn from(usage: JobUsage) -> grpc::JobUsage {
+        grpc::JobUsage {
+            pending: usage.pending,
+            recurring: usage.recurring
+        }
+    }
+}
+
+impl From <grpc::JobUsage> for JobUsage {
+    fn from(rpc_usage: grpc::JobUsage) -> JobUsage {
+        JobUsage {
+            pending: rpc_usage.pending,
+            recurring: rpc_usage.recurring
+        }
+    }
+}
+
+impl From <JobRun> for grpc::JobRun {
+    fn from(run: JobRun) -> grpc::JobRun {
+        grpc::JobRun {
+            scheduled: run.scheduled.as_millis() as u64,
+            fired: run.fired.as_millis() as u64,
+            latency: run.latency.as_millis() as u64,
+            has_status: run.status.is_some(),
+            status: run.status.map(u32::from).unwrap_or_default(),
+            has_error: run.error.is_some(),
+            error: run.error.unwrap_or_default(),
+            has_body: run.body.is_some(),
+            body: run.body.unwrap_or_default()
+        }
+    }
+}
+
+impl From <grpc::JobRun> for JobRun {
+    fn from(rpc_run: grpc::JobRun) -> JobRun {
+        JobRun {
+            scheduled: Duration::from_millis(rpc_run.scheduled),
+            fired: Duration::from_millis(rpc_run.fired),
+            latency: Duration::from_millis(rpc_run.latency),
+            status: match rpc_run.has_status {
+                true => Some(rpc_run.status as u16),
+                false => None
             },
-            AppError::RpcDeserializationError => {
-                grpc_error.code = 4;
-                Code::InvalidArgument
+            error: match rpc_run.has_error {
+                true => Some(rpc_run.error),
+                false => None
             },
-            AppError::UnexpectedRpcError(message) => {
-                grpc_error.message = message.clone();
-                grpc_error.code = 5;
-                Code::Unknown
+            body: match rpc_run.has_body {
+                true => Some(rpc_run.body),
+                false => None
             }
+        }
+    }
+}
+
+impl From <Vec<Job>> for grpc::Jobs {
+    fn from(jobs: Vec<Job>) -> grpc::Jobs {
+        grpc::Jobs {
+            jobs: jobs.into_iter().map(grpc::Job::from).collect()
+        }
+    }
+}
+
+impl TryFrom <grpc::Jobs> for Vec<Job> {
+    type Error = AppError;
+
+    fn try_from(rpc_jobs: grpc::Jobs) -> Result<Vec<Job>, AppError> {
+        rpc_jobs.jobs.into_iter().map(Job::try_from).collect()
+    }
+}
+
+impl From <VoteRequest> for grpc::VoteRequest {
+    fn from(request: VoteRequest) -> grpc::VoteRequest {
+        grpc::VoteRequest {
+            term: request.term,
+            candidate: request.candidate,
+            last_index: request.last_index,
+            last_term: request.last_term,
+            bootstrap: request.bootstrap
+        }
+    }
+}
+
+impl From <grpc::VoteRequest> for VoteRequest {
+    fn from(rpc_request: grpc::VoteRequest) -> VoteRequest {
+        VoteRequest {
+            term: rpc_request.term,
+            candidate: rpc_request.candidate,
+            last_index: rpc_request.last_index,
+            last_term: rpc_request.last_term,
+            bootstrap: rpc_request.bootstrap
+        }
+    }
+}
+
+impl From <Vote> for grpc::Vote {
+    fn from(vote: Vote) -> grpc::Vote {
+        grpc::Vote { term: vote.term, granted: vote.granted }
+    }
+}
+
+impl From <grpc::Vote> for Vote {
+    fn from(rpc_vote: grpc::Vote) -> Vote {
+        Vote { term: rpc_vote.term, granted: rpc_vote.granted }
+    }
+}
+
+impl From <AppendRequest> for grpc::AppendRequest {
+    fn from(request: AppendRequest) -> grpc::AppendRequest {
+        grpc::AppendRequest {
+            term: request.term,
+            leader: request.leader,
+            prev_index: request.prev_index,
+            prev_term: request.prev_term,
+            entries: request.entries
+                .iter()
+                .map(|entry| rmp_serde::to_vec(entry).expect("Failed to serialize raft entry"))
+                .collect(),
+            commit: request.commit,
+            bootstrap: request.bootstrap
+        }
+    }
+}
+
+impl TryFrom <grpc::AppendRequest> for AppendRequest {
+    type Error = AppError;
+
+    fn try_from(rpc_request: grpc::AppendRequest) -> Result<AppendRequest, AppError> {
+        let entries = rpc_request.entries
+            .iter()
+            .map(|entry| rmp_serde::from_slice::<Entry>(entry))
+            .collect::<Result<_, _>>()
+            .map_err(|err| AppError::RpcDeserializationError(err.to_string()))?;
+        Ok(AppendRequest {
+            term: rpc_request.term,
+            leader: rpc_request.leader,
+            prev_index: rpc_request.prev_index,
+            prev_term: rpc_request.prev_term,
+            entries,
+            commit: rpc_request.commit,
+            bootstrap: rpc_request.bootstrap
+        })
+    }
+}
+
+impl From <AppendResponse> for grpc::AppendResponse {
+    fn from(response: AppendResponse) -> grpc::AppendResponse {
+        grpc::AppendResponse {
+            term: response.term,
+            success: response.success,
+            last_index: response.last_index
+        }
+    }
+}
+
+impl From <grpc::AppendResponse> for AppendResponse {
+    fn from(rpc_response: grpc::AppendResponse) -> AppendResponse {
+        AppendResponse {
+            term: rpc_response.term,
+            success: rpc_response.success,
+            last_index: rpc_response.last_index
+        }
+    }
+}
+
+impl From<AppError> for Status {
+    fn from(app_error: AppError) -> Status {
+        let (code, grpc_code) = match app_error {
+            AppError::ValidationError { .. } => (Code::InvalidArgument, 1),
+            AppError::UnexpectedError(_) => (Code::Unknown, 2),
+            AppError::NodeUnreachable { .. } => (Code::Unavailable, 3),
+            AppError::RpcDeserializationError(_) => (Code::InvalidArgument, 4),
+            AppError::UnexpectedRpcError(_) => (Code::Unknown, 5),
+            AppError::Unauthorized(_) => (Code::Unauthenticated, 6),
+            AppError::Forbidden { .. } => (Code::PermissionDenied, 7),
+            AppError::QuotaExceeded { .. } => (Code::ResourceExhausted, 8)
+        };
+        let grpc_error = grpc::AppError {
+            code: grpc_code,
+            message: app_error.message().to_owned(),
+            details: app_error.details().into_iter().collect()
         };
         let mut encoded = Vec::new();
         grpc_error.encode(&mut encoded)
             .expect("Failed to allocate buffer to write error");
 
-        Status::with_details(code, message, bytes::Bytes::from(encoded))
+        Status::with_details(code, app_error.to_string(), bytes::Bytes::from(encoded))
     }
 }
@@ -2,18 +2,31 @@ mod grpc;
 mod convert;
 pub mod client;
 pub mod server;
+pub mod security;
 
 #[cfg(test)]
 mod test {
-    use crate::schema::Job;
+    use crate::schema::{Job, JobFilter, JobRun, JobUsage, MisfirePolicy, DEFAULT_TENANT};
+    use crate::config::{ClusterConfig, EgressConfig, HistoryConfig, NodeTlsConfig, QuotaConfig};
+    use crate::egress::Egress;
+    use crate::quota::Quotas;
+    use crate::metrics;
+    use crate::error::AppError;
+    use super::grpc;
+    use super::grpc::node_client::NodeClient as RpcClient;
     use crate::cluster::Cluster;
+    use crate::schema::SHARD_COUNT;
     use crate::store::Store;
     use crate::node::server::NodeServer;
     use crate::node::client::NodeClient;
+    use crate::node::security::NodeSecurity;
     use std::sync::Arc;
     use sled::Db;
     use std::time::{UNIX_EPOCH, SystemTime, Duration};
     use uuid::Uuid;
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
 
     fn random_host() -> String {
         use rand::prelude::*;
@@ -22,19 +35,48 @@ mod test {
         "127.0.0.1:".to_owned() + &port.to_string()
     }
 
+    fn authority() -> Certificate {
+        let mut params = CertificateParams::new(Vec::new());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        Certificate::from_params(params).unwrap()
+    }
+
+    // Certificate of a node signed by the authority, written along with its
+    // key and the certificate of the authority.
+    async fn credentials(dir: &str, name: &str, authority: &Certificate) -> NodeTlsConfig {
+        let params = CertificateParams::new(vec!["node.test".to_owned()]);
+        let certificate = Certificate::from_params(params).unwrap();
+        let path = |extension: &str| PathBuf::from(format!("{}/{}.{}", dir, name, extension));
+        tokio::fs::create_dir_all(dir).await.unwrap();
+        tokio::fs::write(path("pem"), certificate.serialize_pem_with_signer(authority).unwrap()).await.unwrap();
+        tokio::fs::write(path("key"), certificate.serialize_private_key_pem()).await.unwrap();
+        tokio::fs::write(path("ca"), authority.serialize_pem().unwrap()).await.unwrap();
+        NodeTlsConfig {
+            cert: path("pem"),
+            key: path("key"),
+            ca: path("ca"),
+            domain: Some("node.test".to_owned())
+        }
+    }
+
     macro_rules! node_test {
-        ($name:ident $test:expr) => {
+        ($name:ident |$client:ident, $store:ident| $test:expr) => {
             #[tokio::test]
             async fn $name() {
                 let data_dir = ".test/".to_owned() + &Uuid::new_v4().to_string();
                 let db = Db::open(data_dir.clone()).unwrap();
-                let store = Arc::new(Store::new(db));
-                store.clear();
-                let cluster = Arc::new(Cluster::start(store.clone()).await);
+                let $store = Arc::new(Store::new(db.open_tree("jobs").unwrap()));
+                $store.clear(DEFAULT_TENANT);
+                let quotas = Arc::new(Quotas::new(&QuotaConfig::default()));
+                let egress = Arc::new(Egress::new(&EgressConfig::default()));
+                let config = ClusterConfig::default();
+                // every shard shares the store, so tests can look at it.
+                let stores = vec![$store.clone(); SHARD_COUNT];
+                let cluster = Cluster::start(stores, None, None, quotas, egress, &config).await;
                 let host = random_host();
                 let server = NodeServer::start(host.parse().unwrap(), cluster).await;
                 let client_url = String::from("http://") + &host;
-                let client = NodeClient::connect(&client_url).await.unwrap();
+                let $client = NodeClient::lazy(&client_url, Arc::default());
 
                 $test
 
@@ -44,7 +86,7 @@ mod test {
         }
     }
 
-    node_test!(push {
+    node_test!(push |client, store| {
         let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
 
         client.push(Job {
@@ -53,14 +95,20 @@ mod test {
             body: "{}".to_owned(),
             timestamp: now - Duration::from_millis(100),
             id: "yolo".to_owned(),
-            schedule: None
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
         }).await.unwrap();
 
         let job = store.next().unwrap();
         assert!(job.id == "yolo");
     });
 
-    node_test!(remove {
+    node_test!(remove |client, store| {
         let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
         let id = "test";
         client.push(Job {
@@ -69,15 +117,21 @@ mod test {
             body: "{}".to_owned(),
             timestamp: now - Duration::from_millis(1000),
             id: id.to_owned(),
-            schedule: None
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
         }).await.unwrap();
-        let job = client.remove(id).await.unwrap().unwrap();
+        let job = client.remove(DEFAULT_TENANT, id).await.unwrap().unwrap();
         assert!(job.method == "POST");
         assert!(job.id == id);
-        assert_eq!(store.remove(id), None);
+        assert_eq!(store.remove(DEFAULT_TENANT, id), None);
     });
 
-    node_test!(clear {
+    node_test!(clear |client, store| {
         let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
         let id = "test";
         let job = Job {
@@ -86,12 +140,215 @@ mod test {
             body: "{}".to_owned(),
             timestamp: now - Duration::from_millis(1000),
             id: id.to_owned(),
-            schedule: None
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
         };
 
         client.push(job.clone()).await.unwrap();
 
-        client.clear().await.unwrap();
-        assert_eq!(store.remove(&job.id), None);
+        client.clear(DEFAULT_TENANT).await.unwrap();
+        assert_eq!(store.remove(DEFAULT_TENANT, &job.id), None);
+    });
+
+    node_test!(pause |client, store| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let id = "test";
+        client.push(Job {
+            method: "POST".to_owned(),
+            url: "1".to_owned(),
+            body: "{}".to_owned(),
+            timestamp: now - Duration::from_millis(1000),
+            id: id.to_owned(),
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
+        }).await.unwrap();
+        let job = client.pause(DEFAULT_TENANT, id).await.unwrap().unwrap();
+        assert!(job.paused);
+        assert_eq!(store.next(), None);
+
+        let filter = JobFilter { url: Some("1".to_owned()), ..JobFilter::default() };
+        let jobs = client.resume_matching(&filter).await.unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(store.next().unwrap().id, id);
+    });
+
+    node_test!(batch |client, store| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let jobs = vec!["a", "b"].into_iter().map(|id| Job {
+            method: "POST".to_owned(),
+            url: "1".to_owned(),
+            body: "{}".to_owned(),
+            timestamp: now - Duration::from_millis(1000),
+            id: id.to_owned(),
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
+        }).collect();
+        client.push_batch(jobs).await.unwrap();
+
+        let ids = vec!["missing".to_owned(), "b".to_owned()];
+        let removed = client.remove_batch(DEFAULT_TENANT, &ids).await.unwrap();
+        assert!(removed[0].is_none());
+        assert_eq!(removed[1].as_ref().unwrap().id, "b");
+        assert_eq!(store.next().unwrap().id, "a");
+        assert_eq!(store.next(), None);
+    });
+
+    node_test!(list |client, store| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let mut labels = BTreeMap::new();
+        labels.insert("customer".to_owned(), "42".to_owned());
+        client.push(Job {
+            method: "POST".to_owned(),
+            url: "1".to_owned(),
+            body: "{}".to_owned(),
+            timestamp: now + Duration::from_millis(1000),
+            id: "test".to_owned(),
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels,
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
+        }).await.unwrap();
+
+        let filter = JobFilter {
+            selector: Some("customer=42".parse().unwrap()),
+            ..JobFilter::default()
+        };
+        let jobs = client.list(&filter).await.unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].labels.get("customer").unwrap(), "42");
+
+        let removed = client.remove_matching(&filter).await.unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(store.remove(DEFAULT_TENANT, "test"), None);
+    });
+
+    node_test!(runs |client, store| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let run = JobRun {
+            scheduled: Duration::from_millis(now.as_millis() as u64 - 10),
+            fired: Duration::from_millis(now.as_millis() as u64),
+            latency: Duration::from_millis(5),
+            status: Some(500),
+            error: None,
+            body: Some("failed".to_owned())
+        };
+        store.record_run(DEFAULT_TENANT, "test", &run, &HistoryConfig::default());
+
+        assert_eq!(client.runs(DEFAULT_TENANT, "test").await.unwrap(), vec![run]);
+        assert!(client.runs(DEFAULT_TENANT, "other").await.unwrap().is_empty());
+        assert!(metrics::RPC_CALLS.with_label_values(&["runs", "Ok"]).get() >= 2);
     });
+
+    node_test!(usage |client, store| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        store.push(Job {
+            method: "POST".to_owned(),
+            url: "1".to_owned(),
+            body: "{}".to_owned(),
+            timestamp: now + Duration::from_millis(1000),
+            id: "test".to_owned(),
+            schedule: Some("0 0 0 1 1 *".to_owned()),
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
+        });
+
+        let usage = client.usage(DEFAULT_TENANT).await.unwrap();
+        assert_eq!(usage, JobUsage { pending: 0, recurring: 1 });
+        assert_eq!(client.usage("acme").await.unwrap(), JobUsage::default());
+    });
+
+    node_test!(ping |client, store| {
+        client.ping().await.unwrap();
+        let check = client.readiness().await;
+        assert!(check.is_ok());
+        assert!(store.readiness().iter().all(|check| check.is_ok()));
+    });
+
+    node_test!(error_details |client, store| {
+        // the node client can't send an invalid filter.
+        let mut rpc_client = RpcClient::connect(client.host()).await.unwrap();
+        let filter = grpc::Filter {
+            has_selector: true,
+            selector: "=42".to_owned(),
+            ..grpc::Filter::default()
+        };
+        let status = rpc_client.list(filter).await.unwrap_err();
+        assert_eq!(status.message(), "Invalid selector: invalid requirement `=42`");
+        match AppError::from(status) {
+            AppError::ValidationError { field, message } => {
+                assert_eq!(field.as_deref(), Some("selector"));
+                assert_eq!(message, "invalid requirement `=42`");
+            },
+            err => panic!("unexpected error {}", err)
+        }
+        assert_eq!(store.queue_size(), 0);
+    });
+
+    #[tokio::test]
+    async fn tls() {
+        let data_dir = ".test/".to_owned() + &Uuid::new_v4().to_string();
+        let db = sled::open(data_dir.clone()).unwrap();
+        let store = Arc::new(Store::new(db.open_tree("jobs").unwrap()));
+        let trusted = authority();
+        let config = ClusterConfig {
+            tls: Some(credentials(&data_dir, "node", &trusted).await),
+            token: Some("secret".to_owned()),
+            ..ClusterConfig::default()
+        };
+        let quotas = Arc::new(Quotas::new(&QuotaConfig::default()));
+        let egress = Arc::new(Egress::new(&EgressConfig::default()));
+        let stores = vec![store.clone(); SHARD_COUNT];
+        let cluster = Cluster::start(stores, None, None, quotas, egress, &config).await;
+        let host = random_host();
+        let server = NodeServer::start(host.parse().unwrap(), cluster).await;
+        let url = String::from("http://") + &host;
+        let client = |config: &ClusterConfig| NodeClient::lazy(&url, Arc::new(NodeSecurity::new(config)));
+
+        client(&config).ping().await.unwrap();
+        client(&config).clear(DEFAULT_TENANT).await.unwrap();
+        // the token is checked once the certificate is.
+        let guessed = ClusterConfig { token: Some("guess".to_owned()), ..config.clone() };
+        match client(&guessed).ping().await {
+            Err(AppError::Unauthorized(message)) => assert_eq!(message, "invalid node token"),
+            result => panic!("unexpected result {:?}", result)
+        }
+        let missing = ClusterConfig { token: None, ..config.clone() };
+        match client(&missing).ping().await {
+            Err(AppError::Unauthorized(message)) => assert_eq!(message, "missing node token"),
+            result => panic!("unexpected result {:?}", result)
+        }
+        // without a certificate, or with one the authority didn't sign, the
+        // node can't be reached.
+        let plain = ClusterConfig { tls: None, ..config.clone() };
+        assert!(matches!(client(&plain).ping().await, Err(AppError::NodeUnreachable { .. })));
+        let mut forged = credentials(&(data_dir.clone() + "/forged"), "node", &authority()).await;
+        forged.ca = config.tls.as_ref().unwrap().ca.clone();
+        let forged = ClusterConfig { tls: Some(forged), ..config.clone() };
+        assert!(matches!(client(&forged).ping().await, Err(AppError::NodeUnreachable { .. })));
+
+        server.stop();
+        tokio::fs::remove_dir_all(&data_dir).await.expect("Error removing directory");
+    }
 }
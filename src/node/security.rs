@@ -0,0 +1,101 @@
+use crate::config::ClusterConfig;
+use crate::error::AppError;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+use tonic::{Interceptor, Request, Status};
+use tonic::metadata::MetadataValue;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
+
+// Credentials the nodes authenticate each other with, read once at startup.
+// Without them, calls between the nodes are sent in plain text and anyone
+// reaching the node server can make them.
+#[derive(Clone, Default)]
+pub struct NodeSecurity {
+    tls: Option<Tls>,
+    token: Option<String>
+}
+
+#[derive(Clone)]
+struct Tls {
+    identity: Identity,
+    ca: Certificate,
+    domain: Option<String>
+}
+
+fn read_pem(path: &Path) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|err| panic!("Failed to read {}: {}", path.display(), err))
+}
+
+// Tokens are compared through their hash, so the time it takes doesn't tell
+// how much of the token was right.
+fn hash(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+impl NodeSecurity {
+    pub fn new(config: &ClusterConfig) -> NodeSecurity {
+        NodeSecurity {
+            tls: config.tls.as_ref().map(|tls| Tls {
+                identity: Identity::from_pem(read_pem(&tls.cert), read_pem(&tls.key)),
+                ca: Certificate::from_pem(read_pem(&tls.ca)),
+                domain: tls.domain.clone()
+            }),
+            token: config.token.clone()
+        }
+    }
+
+    // The calling nodes must present a certificate signed by the authority.
+    pub fn server_tls(&self) -> Option<ServerTlsConfig> {
+        self.tls.as_ref().map(|tls| {
+            ServerTlsConfig::new()
+                .identity(tls.identity.clone())
+                .client_ca_root(tls.ca.clone())
+        })
+    }
+
+    pub fn client_tls(&self) -> Option<ClientTlsConfig> {
+        self.tls.as_ref().map(|tls| {
+            let config = ClientTlsConfig::new()
+                .identity(tls.identity.clone())
+                .ca_certificate(tls.ca.clone());
+            match &tls.domain {
+                Some(domain) => config.domain_name(domain.clone()),
+                None => config
+            }
+        })
+    }
+
+    // Sends the token along with each call to the other nodes.
+    pub fn client_interceptor(&self) -> Interceptor {
+        let token = self.token.as_ref().map(|token| {
+            MetadataValue::from_str(&format!("Bearer {}", token)).expect("Invalid node token")
+        });
+        Interceptor::new(move |mut request: Request<()>| {
+            if let Some(token) = &token {
+                request.metadata_mut().insert("authorization", token.clone());
+            }
+            Ok(request)
+        })
+    }
+
+    // Refuses the calls which don't carry the token, when there is one.
+    pub fn server_interceptor(&self) -> Interceptor {
+        let expected = self.token.as_deref().map(hash);
+        Interceptor::new(move |request: Request<()>| {
+            let expected = match &expected {
+                Some(expected) => expected,
+                None => return Ok(request)
+            };
+            let sent = request.metadata()
+                .get("authorization")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "));
+            match sent.map(hash) {
+                Some(hashed) if &hashed == expected => Ok(request),
+                Some(_) => Err(Status::from(AppError::Unauthorized("invalid node token".to_owned()))),
+                None => Err(Status::from(AppError::Unauthorized("missing node token".to_owned())))
+            }
+        })
+    }
+}
@@ -1,17 +1,25 @@
 use std::sync::Arc;
 use std::net::SocketAddr;
+use std::time::Duration;
 use crate::cluster::Cluster;
+use crate::error::AppError;
+use crate::raft::{AppendRequest, Command, VoteRequest};
 use tonic::{Request, Response, Status};
 use tonic::transport::Server;
 use futures::channel::oneshot;
-use tokio::time::interval;
-use std::time::Duration;
+use futures::stream;
+use tokio::net::TcpListener;
 
 use super::grpc::node_server::{Node, NodeServer as GrpcNodeServer};
 use super::grpc;
-use crate::schema::Job;
+use crate::schema::{Job, JobFilter, JobStats, JobUsage};
+use crate::store::QueueKey;
 use super::convert::*;
 
+// Serves the shards of this node. The calling node already picked the shard,
+// so requests only involve the local stores, unless the shard is being or was
+// migrated to another node, in which case they are passed on to it. Stores
+// holding replicas of the shards of other nodes are left out.
 pub struct NodeService {
     cluster: Arc<Cluster>
 }
@@ -20,62 +28,277 @@ pub struct NodeService {
 impl Node for NodeService {
     async fn push(&self, request: Request<grpc::Job>) -> Result<Response<grpc::Job>, Status> {
         let job = Job::try_from(request.into_inner())?;
-        self.cluster.push(job.clone()).await?;
+        let shard = self.cluster.serving(&job.tenant, &job.id).await;
+        shard.push(job.clone()).await?;
         Ok(Response::new(grpc::Job::from(job)))
     }
 
+    async fn get(&self, request: Request<grpc::Id>) -> Result<Response<grpc::JobResponse>, Status> {
+        let (tenant, id) = QueueKey::from(request.into_inner());
+        let shard = self.cluster.serving(&tenant, &id).await;
+        let job = shard.get(&tenant, &id).await?.map(grpc::Job::from);
+        Ok(Response::new(grpc::JobResponse { job }))
+    }
+
+    async fn list(&self, request: Request<grpc::Filter>) -> Result<Response<grpc::Jobs>, Status> {
+        let filter = JobFilter::try_from(request.into_inner())?;
+        let jobs: Vec<Job> = self.cluster.local_stores().await.iter().flat_map(|store| store.list(&filter)).collect();
+        Ok(Response::new(grpc::Jobs::from(jobs)))
+    }
+
+    async fn remove_matching(&self, request: Request<grpc::Filter>) -> Result<Response<grpc::Jobs>, Status> {
+        let filter = JobFilter::try_from(request.into_inner())?;
+        let jobs: Vec<Job> = self.cluster.local_stores().await
+            .iter()
+            .flat_map(|store| store.remove_matching(&filter))
+            .collect();
+        Ok(Response::new(grpc::Jobs::from(jobs)))
+    }
+
+    async fn stats(&self, request: Request<grpc::Filter>) -> Result<Response<grpc::JobStats>, Status> {
+        let filter = JobFilter::try_from(request.into_inner())?;
+        let mut stats = JobStats::default();
+        for store in &self.cluster.local_stores().await {
+            stats.merge(store.stats(&filter));
+        }
+        Ok(Response::new(grpc::JobStats::from(stats)))
+    }
+
+    async fn runs(&self, request: Request<grpc::Id>) -> Result<Response<grpc::JobRuns>, Status> {
+        let (tenant, id) = QueueKey::from(request.into_inner());
+        let shard = self.cluster.serving(&tenant, &id).await;
+        let runs = shard.runs(&tenant, &id).await?;
+        Ok(Response::new(grpc::JobRuns {
+            runs: runs.into_iter().map(grpc::JobRun::from).collect()
+        }))
+    }
+
+    async fn usage(&self, request: Request<grpc::Tenant>) -> Result<Response<grpc::JobUsage>, Status> {
+        let tenant = String::from(request.into_inner());
+        let mut usage = JobUsage::default();
+        for store in &self.cluster.local_stores().await {
+            usage.merge(store.usage(&tenant));
+        }
+        Ok(Response::new(grpc::JobUsage::from(usage)))
+    }
+
+    async fn replicate(&self, request: Request<grpc::Replica>) -> Result<Response<grpc::Empty>, Status> {
+        let replica = request.into_inner();
+        let jobs = replica.jobs
+            .into_iter()
+            .map(Job::try_from)
+            .collect::<Result<Vec<Job>, _>>()?;
+        let removed = replica.removed.into_iter().map(QueueKey::from).collect();
+        self.cluster.apply_replica(replica.shard as usize, jobs, removed, replica.replace)?;
+        Ok(Response::new(grpc::Empty { }))
+    }
+
+    async fn snapshot(&self, request: Request<grpc::Shard>) -> Result<Response<grpc::ShardSnapshot>, Status> {
+        let shard = request.into_inner().shard as usize;
+        let snapshot = match self.cluster.snapshot(shard)? {
+            Some((jobs, removed)) => grpc::ShardSnapshot {
+                promoted: true,
+                jobs: jobs.into_iter().map(grpc::Job::from).collect(),
+                removed: removed
+                    .into_iter()
+                    .map(|(tenant, id)| grpc::Id { id, tenant })
+                    .collect()
+            },
+            None => grpc::ShardSnapshot { promoted: false, jobs: vec![], removed: vec![] }
+        };
+        Ok(Response::new(snapshot))
+    }
+
+    async fn request_vote(&self, request: Request<grpc::VoteRequest>) -> Result<Response<grpc::Vote>, Status> {
+        let raft = self.cluster.raft()?;
+        let vote = raft.vote(VoteRequest::from(request.into_inner()));
+        Ok(Response::new(grpc::Vote::from(vote)))
+    }
+
+    async fn append_entries(
+        &self,
+        request: Request<grpc::AppendRequest>
+    ) -> Result<Response<grpc::AppendResponse>, Status> {
+        let raft = self.cluster.raft()?;
+        let response = raft.append(AppendRequest::try_from(request.into_inner())?);
+        // the node was started with the wrong cluster file.
+        if let (true, Err(err)) = (raft.diverged(), &response) {
+            error!("Stopping, {}", err);
+            std::process::exit(1);
+        }
+        Ok(Response::new(grpc::AppendResponse::from(response?)))
+    }
+
+    async fn propose(&self, request: Request<grpc::Proposal>) -> Result<Response<grpc::Empty>, Status> {
+        let raft = self.cluster.raft()?;
+        let command: Command = rmp_serde::from_slice(&request.into_inner().command)
+            .map_err(|err| AppError::RpcDeserializationError(err.to_string()))?;
+        raft.propose(command).await?;
+        Ok(Response::new(grpc::Empty { }))
+    }
+
+    async fn heartbeat(&self, request: Request<grpc::NodeId>) -> Result<Response<grpc::View>, Status> {
+        debug!("Heartbeat from {}", request.into_inner().node);
+        let view = self.cluster.view();
+        Ok(Response::new(grpc::View {
+            node: view.node,
+            down: view.down,
+            promoted: view.promoted.into_iter().map(|shard| shard as u32).collect()
+        }))
+    }
+
+    async fn ping(&self, _request: Request<grpc::Empty>) -> Result<Response<grpc::Empty>, Status> {
+        Ok(Response::new(grpc::Empty { }))
+    }
+
+    async fn hand_off(&self, request: Request<grpc::HandedOff>) -> Result<Response<grpc::Empty>, Status> {
+        let handed_off = request.into_inner();
+        let jobs = handed_off.jobs
+            .into_iter()
+            .map(Job::try_from)
+            .collect::<Result<Vec<Job>, _>>()?;
+        let keys = jobs.iter().map(|job| (job.tenant.as_str(), job.id.as_str()));
+        let groups = self.cluster.serving_groups(keys).await;
+        let mut jobs: Vec<Option<(Job, Duration)>> = jobs
+            .into_iter()
+            .zip(handed_off.ages)
+            .map(|(job, age)| Some((job, Duration::from_millis(age))))
+            .collect();
+        for (shard, positions) in groups {
+            let portion = positions
+                .into_iter()
+                .filter_map(|position| jobs[position].take())
+                .collect();
+            shard.hand_off(portion).await?;
+        }
+        Ok(Response::new(grpc::Empty { }))
+    }
+
+    async fn push_batch(&self, request: Request<grpc::Jobs>) -> Result<Response<grpc::Empty>, Status> {
+        let jobs: Vec<Job> = Vec::try_from(request.into_inner())?;
+        let keys = jobs.iter().map(|job| (job.tenant.as_str(), job.id.as_str()));
+        let groups = self.cluster.serving_groups(keys).await;
+        let mut jobs: Vec<Option<Job>> = jobs.into_iter().map(Some).collect();
+        for (shard, positions) in groups {
+            let portion = positions
+                .into_iter()
+                .filter_map(|position| jobs[position].take())
+                .collect();
+            shard.push_batch(portion).await?;
+        }
+        Ok(Response::new(grpc::Empty { }))
+    }
+
+    async fn remove_batch(&self, request: Request<grpc::Ids>) -> Result<Response<grpc::Jobs>, Status> {
+        let (tenant, ids) = <(String, Vec<String>)>::from(request.into_inner());
+        let keys = ids.iter().map(|id| (tenant.as_str(), id.as_str()));
+        let groups = self.cluster.serving_groups(keys).await;
+        let mut removed: Vec<Job> = Vec::new();
+        for (shard, positions) in groups {
+            let portion: Vec<String> = positions
+                .into_iter()
+                .map(|position| ids[position].clone())
+                .collect();
+            removed.extend(shard.remove_batch(&tenant, &portion).await?.into_iter().flatten());
+        }
+        Ok(Response::new(grpc::Jobs::from(removed)))
+    }
+
     async fn remove(&self, request: Request<grpc::Id>) -> Result<Response<grpc::RemoveResponse>, Status> {
-        let id = request.into_inner().id;
-        let job = self.cluster.remove(&id).await?.map(grpc::Job::from);
+        let (tenant, id) = QueueKey::from(request.into_inner());
+        let shard = self.cluster.serving(&tenant, &id).await;
+        let job = shard.remove(&tenant, &id).await?.map(grpc::Job::from);
         Ok(Response::new(grpc::RemoveResponse{ job: job }))
     }
 
-    async fn clear(&self, _request: Request<grpc::Empty>) -> Result<Response<grpc::Empty>, Status> {
-        self.cluster.clear().await?;
+    async fn clear(&self, request: Request<grpc::Tenant>) -> Result<Response<grpc::Empty>, Status> {
+        let tenant = String::from(request.into_inner());
+        for store in &self.cluster.local_stores().await {
+            store.clear(&tenant);
+        }
         Ok(Response::new(grpc::Empty { }))
     }
+
+    async fn pause(&self, request: Request<grpc::Id>) -> Result<Response<grpc::JobResponse>, Status> {
+        let (tenant, id) = QueueKey::from(request.into_inner());
+        let shard = self.cluster.serving(&tenant, &id).await;
+        let job = shard.pause(&tenant, &id).await?.map(grpc::Job::from);
+        Ok(Response::new(grpc::JobResponse { job }))
+    }
+
+    async fn resume(&self, request: Request<grpc::Id>) -> Result<Response<grpc::JobResponse>, Status> {
+        let (tenant, id) = QueueKey::from(request.into_inner());
+        let shard = self.cluster.serving(&tenant, &id).await;
+        let job = shard.resume(&tenant, &id).await?.map(grpc::Job::from);
+        Ok(Response::new(grpc::JobResponse { job }))
+    }
+
+    async fn pause_matching(&self, request: Request<grpc::Filter>) -> Result<Response<grpc::Jobs>, Status> {
+        let filter = JobFilter::try_from(request.into_inner())?;
+        let jobs: Vec<Job> = self.cluster.local_stores().await
+            .iter()
+            .flat_map(|store| store.pause_matching(&filter))
+            .collect();
+        Ok(Response::new(grpc::Jobs::from(jobs)))
+    }
+
+    async fn resume_matching(&self, request: Request<grpc::Filter>) -> Result<Response<grpc::Jobs>, Status> {
+        let filter = JobFilter::try_from(request.into_inner())?;
+        let jobs: Vec<Job> = self.cluster.local_stores().await
+            .iter()
+            .flat_map(|store| store.resume_matching(&filter))
+            .collect();
+        Ok(Response::new(grpc::Jobs::from(jobs)))
+    }
 }
 
 pub struct NodeServer {
-    close_sender: oneshot::Sender<()>,
-    closed_receiver: oneshot::Receiver<()>
+    close_sender: oneshot::Sender<()>
 }
 
 impl NodeServer {
+    // Calls are only served over TLS, or with the token, when the cluster
+    // is configured with them.
     pub async fn start(addr: SocketAddr, cluster: Arc<Cluster>) -> NodeServer {
-        let service = NodeService {
-            cluster
-        };
+        let listener = TcpListener::bind(addr).await.expect("Failed to bind node address");
+        NodeServer::serve(listener, cluster).await
+    }
+
+    // Serves on a listener which is already bound, which lets tests pick a
+    // free port before the addresses of the nodes are configured.
+    pub async fn serve(listener: TcpListener, cluster: Arc<Cluster>) -> NodeServer {
+        let security = cluster.security();
+        let service = GrpcNodeServer::with_interceptor(
+            NodeService { cluster },
+            security.server_interceptor()
+        );
         let (close_sender, close_receiver) = oneshot::channel::<()>();
-        let (closed_sender, closed_receiver) = oneshot::channel::<()>();
 
         let close_future = async {
             close_receiver.await.unwrap();
         };
-        let serve = Server::builder()
-            .add_service(GrpcNodeServer::new(service))
-            .serve_with_shutdown(addr, close_future);
+        let incoming = stream::unfold(listener, |mut listener| async move {
+            let accepted = listener.accept().await.map(|(stream, _)| stream);
+            Some((accepted, listener))
+        });
+        let mut builder = Server::builder();
+        if let Some(tls) = security.server_tls() {
+            builder = builder.tls_config(tls);
+        }
+        let serve = builder
+            .add_service(service)
+            .serve_with_incoming_shutdown(incoming, close_future);
 
         tokio::spawn(async move {
             serve.await.unwrap();
-            closed_sender.send(()).unwrap();
         });
 
-        // for some reason, this is the only way to make sure when the `start`
-        // future completes that the server is listening on the port...
-        interval(Duration::from_millis(100)).tick().await;
-
         NodeServer {
-            close_sender,
-            closed_receiver
+            close_sender
         }
     }
 
     pub fn stop(self) {
         self.close_sender.send(()).unwrap();
     }
-
-    pub async fn forever(self) {
-        self.closed_receiver.await.unwrap();
-    }
 }
@@ -0,0 +1,198 @@
+// Limits on what each tenant can store and send. The job counts come from
+// the shards, while the rates are counted by each node in fixed windows of a
+// minute.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::config::QuotaConfig;
+use crate::error::AppError;
+use crate::schema::JobUsage;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+// Number of events of each tenant in the current minute.
+struct RateWindow {
+    windows: Mutex<HashMap<String, (Instant, u64)>>
+}
+
+impl RateWindow {
+    fn new() -> RateWindow {
+        RateWindow {
+            windows: Mutex::new(HashMap::new())
+        }
+    }
+
+    // Counts the event unless the tenant already reached the limit.
+    fn acquire(&self, tenant: &str, limit: Option<u64>) -> bool {
+        let mut windows = self.windows.lock().expect("Failed to acquire lock");
+        let window = windows.entry(tenant.to_owned()).or_insert((Instant::now(), 0));
+        if window.0.elapsed() >= WINDOW {
+            *window = (Instant::now(), 0);
+        }
+        match limit {
+            Some(limit) if window.1 >= limit => false,
+            _ => {
+                window.1 += 1;
+                true
+            }
+        }
+    }
+
+    // Takes back an event of the current minute.
+    fn release(&self, tenant: &str) {
+        let mut windows = self.windows.lock().expect("Failed to acquire lock");
+        if let Some(window) = windows.get_mut(tenant) {
+            if window.0.elapsed() < WINDOW {
+                window.1 = window.1.saturating_sub(1);
+            }
+        }
+    }
+
+    fn used(&self, tenant: &str) -> u64 {
+        let windows = self.windows.lock().expect("Failed to acquire lock");
+        match windows.get(tenant) {
+            Some((started, used)) if started.elapsed() < WINDOW => *used,
+            _ => 0
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct QuotaUsage {
+    pub used: u64,
+    pub limit: Option<u64>
+}
+
+#[derive(Serialize, Debug)]
+pub struct QuotaReport {
+    pub tenant: String,
+    pub pending: QuotaUsage,
+    pub recurring: QuotaUsage,
+    pub creates_per_minute: QuotaUsage,
+    pub callbacks_per_minute: QuotaUsage
+}
+
+pub struct Quotas {
+    config: QuotaConfig,
+    creates: RateWindow,
+    callbacks: RateWindow
+}
+
+impl Quotas {
+    pub fn new(config: &QuotaConfig) -> Quotas {
+        Quotas {
+            config: config.clone(),
+            creates: RateWindow::new(),
+            callbacks: RateWindow::new()
+        }
+    }
+
+    // Whether the number of jobs is limited, in which case pushing a job
+    // requires the usage of its tenant.
+    pub fn limits_jobs(&self) -> bool {
+        self.config.max_pending.is_some() || self.config.max_recurring.is_some()
+    }
+
+    pub fn check_usage(&self, usage: &JobUsage) -> Result<(), AppError> {
+        exceeds("max_pending", usage.pending, self.config.max_pending)?;
+        exceeds("max_recurring", usage.recurring, self.config.max_recurring)
+    }
+
+    pub fn acquire_create(&self, tenant: &str) -> Result<(), AppError> {
+        let limit = self.config.max_creates_per_minute;
+        match self.creates.acquire(tenant, limit) {
+            true => Ok(()),
+            false => Err(AppError::QuotaExceeded {
+                quota: "max_creates_per_minute".to_owned(),
+                limit: limit.unwrap_or_default()
+            })
+        }
+    }
+
+    // Gives back the create of a job which couldn't be stored.
+    pub fn release_create(&self, tenant: &str) {
+        self.creates.release(tenant);
+    }
+
+    // False when the callback has to wait for the next minute.
+    pub fn acquire_callback(&self, tenant: &str) -> bool {
+        self.callbacks.acquire(tenant, self.config.max_callbacks_per_minute)
+    }
+
+    pub fn report(&self, tenant: &str, usage: JobUsage) -> QuotaReport {
+        QuotaReport {
+            tenant: tenant.to_owned(),
+            pending: QuotaUsage { used: usage.pending, limit: self.config.max_pending },
+            recurring: QuotaUsage { used: usage.recurring, limit: self.config.max_recurring },
+            creates_per_minute: QuotaUsage {
+                used: self.creates.used(tenant),
+                limit: self.config.max_creates_per_minute
+            },
+            callbacks_per_minute: QuotaUsage {
+                used: self.callbacks.used(tenant),
+                limit: self.config.max_callbacks_per_minute
+            }
+        }
+    }
+}
+
+fn exceeds(quota: &str, used: u64, limit: Option<u64>) -> Result<(), AppError> {
+    match limit {
+        Some(limit) if used > limit => Err(AppError::QuotaExceeded {
+            quota: quota.to_owned(),
+            limit
+        }),
+        _ => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limits() {
+        let quotas = Quotas::new(&QuotaConfig {
+            max_pending: Some(2),
+            max_recurring: None,
+            max_creates_per_minute: Some(1),
+            max_callbacks_per_minute: Some(2)
+        });
+        assert!(quotas.check_usage(&JobUsage { pending: 2, recurring: 1000 }).is_ok());
+        assert_eq!(
+            quotas.check_usage(&JobUsage { pending: 3, recurring: 0 }).unwrap_err().to_string(),
+            "Quota exceeded: max_pending is limited to 2"
+        );
+
+        assert!(quotas.acquire_create("acme").is_ok());
+        assert!(quotas.acquire_create("acme").is_err());
+        quotas.release_create("acme");
+        assert!(quotas.acquire_create("acme").is_ok());
+        assert!(quotas.acquire_create("acme").is_err());
+        // each tenant has its own window.
+        assert!(quotas.acquire_create("globex").is_ok());
+
+        assert!(quotas.acquire_callback("acme"));
+        assert!(quotas.acquire_callback("acme"));
+        assert!(!quotas.acquire_callback("acme"));
+
+        let report = quotas.report("acme", JobUsage { pending: 1, recurring: 0 });
+        assert_eq!(report.pending, QuotaUsage { used: 1, limit: Some(2) });
+        assert_eq!(report.creates_per_minute, QuotaUsage { used: 1, limit: Some(1) });
+        assert_eq!(report.callbacks_per_minute, QuotaUsage { used: 2, limit: Some(2) });
+        assert_eq!(quotas.report("initech", JobUsage::default()).callbacks_per_minute.used, 0);
+    }
+
+    #[test]
+    fn unlimited() {
+        let quotas = Quotas::new(&QuotaConfig::default());
+        assert!(!quotas.limits_jobs());
+        for _ in 0..100 {
+            assert!(quotas.acquire_create("acme").is_ok());
+            assert!(quotas.acquire_callback("acme"));
+        }
+        assert_eq!(quotas.report("acme", JobUsage::default()).creates_per_minute.used, 100);
+    }
+}
@@ -0,0 +1,748 @@
+// Raft log holding the nodes of the cluster and the owner of each shard, so
+// every node agrees on who owns a shard and ownership only changes through
+// entries committed by a majority of the nodes.
+
+use crate::error::AppError;
+use crate::keyspace::{KEYSPACE_RAFT_LOG, KEYSPACE_RAFT_TERM};
+use crate::node::client::NodeClient;
+use futures::future::join_all;
+use serde::{Serialize, Deserialize};
+use sled::Tree;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use sha2::{Digest, Sha256};
+use tokio::time::timeout;
+use uuid::Uuid;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+
+// Followers which don't hear from a leader for a random time between the
+// two start an election.
+const ELECTION_TIMEOUT_MIN: u64 = 500;
+const ELECTION_TIMEOUT_MAX: u64 = 1000;
+
+const RPC_TIMEOUT: Duration = Duration::from_millis(300);
+
+// Time a node keeps firing the jobs of its shards without hearing from the
+// leader, or as the leader without hearing from a majority. Shards are only
+// handed over to another node once this ran out on the previous owner.
+const LEASE: Duration = Duration::from_secs(1);
+
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Entries sent to a follower at once.
+const APPEND_BATCH: usize = 100;
+
+// Nodes of the cluster along with the owner of each shard, as agreed upon
+// by the committed entries of the log.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ClusterMap {
+    // id and address of each node, which are the members of the raft group.
+    pub nodes: Vec<(String, String)>,
+    // id of the owner of each shard.
+    pub owners: Vec<String>
+}
+
+impl ClusterMap {
+    fn apply(&mut self, command: &Command) {
+        match command {
+            Command::Bootstrap(map) => *self = map.clone(),
+            Command::Noop => {},
+            Command::Assign { shards, node } => {
+                for shard in shards {
+                    if let Some(owner) = self.owners.get_mut(*shard) {
+                        *owner = node.clone();
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Command {
+    // first entry of every log, built from the cluster file by each node.
+    Bootstrap(ClusterMap),
+    // appended by each leader once elected, which commits the entries of the
+    // previous terms along with it.
+    Noop,
+    Assign { shards: Vec<usize>, node: String }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub term: u64,
+    pub command: Command
+}
+
+#[derive(Clone, Debug)]
+pub struct VoteRequest {
+    pub term: u64,
+    pub candidate: String,
+    pub last_index: u64,
+    pub last_term: u64,
+    // fingerprint of the first entry of the log, see `fingerprint`.
+    pub bootstrap: u64
+}
+
+#[derive(Clone, Debug)]
+pub struct Vote {
+    pub term: u64,
+    pub granted: bool
+}
+
+#[derive(Clone, Debug)]
+pub struct AppendRequest {
+    pub term: u64,
+    pub leader: String,
+    pub prev_index: u64,
+    pub prev_term: u64,
+    pub entries: Vec<Entry>,
+    pub commit: u64,
+    pub bootstrap: u64
+}
+
+#[derive(Clone, Debug)]
+pub struct AppendResponse {
+    pub term: u64,
+    pub success: bool,
+    pub last_index: u64
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader
+}
+
+struct State {
+    term: u64,
+    voted_for: Option<String>,
+    role: Role,
+    leader: Option<String>,
+    // entry at index 1 first, as indexes start at 1.
+    log: Vec<Entry>,
+    commit: usize,
+    applied: usize,
+    map: ClusterMap,
+    // index of the next entry to send to each follower, and of the last one
+    // known to match the log of the leader.
+    next: HashMap<String, usize>,
+    matched: HashMap<String, usize>,
+    // when the requests last answered by each follower were sent, starting
+    // with the election of the leader.
+    acked: HashMap<String, Instant>,
+    // last append from the leader which left the map up to date.
+    contact: Option<Instant>,
+    deadline: Instant,
+    // fingerprint of the first entry, which the other members must share.
+    bootstrap: u64
+}
+
+impl State {
+    fn last(&self) -> (usize, u64) {
+        (self.log.len(), self.log.last().map(|entry| entry.term).unwrap_or(0))
+    }
+
+    fn term_at(&self, index: usize) -> u64 {
+        match index {
+            0 => 0,
+            index => self.log[index - 1].term
+        }
+    }
+
+    fn apply(&mut self) {
+        while self.applied < self.commit {
+            self.applied += 1;
+            let command = self.log[self.applied - 1].command.clone();
+            self.map.apply(&command);
+        }
+    }
+}
+
+pub struct Raft {
+    id: String,
+    // clients of the other members, by id.
+    peers: HashMap<String, Arc<NodeClient>>,
+    // term, vote and entries, kept so a restarted node doesn't vote twice in
+    // a term or lose committed entries.
+    tree: Tree,
+    state: Mutex<State>,
+    // set once a leader with another cluster file was followed first.
+    diverged: AtomicBool
+}
+
+fn election_deadline() -> Instant {
+    let spread = ELECTION_TIMEOUT_MAX - ELECTION_TIMEOUT_MIN;
+    let timeout = ELECTION_TIMEOUT_MIN + (Uuid::new_v4().as_u128() % spread as u128) as u64;
+    Instant::now() + Duration::from_millis(timeout)
+}
+
+fn encode<T: Serialize>(item: &T) -> Vec<u8> {
+    rmp_serde::to_vec(item).expect("Failed to serialize raft state")
+}
+
+// The first entry isn't sent between the nodes, each builds it from its own
+// cluster file, so the requests carry its hash to tell the files apart.
+fn fingerprint(entry: &Entry) -> u64 {
+    let digest = Sha256::digest(encode(entry));
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+fn log_key(index: usize) -> Vec<u8> {
+    let mut key = KEYSPACE_RAFT_LOG.to_vec();
+    key.extend(&(index as u64).to_be_bytes());
+    key
+}
+
+impl Raft {
+    // Every node starts its log with the same map, built from the cluster
+    // file, which counts as committed. Since it is never sent between the
+    // nodes, the node refuses to start when the file changed since its log
+    // was created, and later refuses to take part in elections or follow a
+    // leader whose file is different. The members are the nodes of the file,
+    // so they can't change either.
+    pub fn start(
+        id: &str,
+        tree: Tree,
+        peers: HashMap<String, Arc<NodeClient>>,
+        initial: ClusterMap
+    ) -> Result<Arc<Raft>, String> {
+        let (term, voted_for): (u64, Option<String>) = tree
+            .get(KEYSPACE_RAFT_TERM)
+            .expect("Failed to read raft state")
+            .map(|serialized| {
+                rmp_serde::from_slice(&serialized).expect("Failed to deserialize raft state")
+            })
+            .unwrap_or((0, None));
+        let mut log: Vec<Entry> = tree
+            .scan_prefix(KEYSPACE_RAFT_LOG)
+            .values()
+            .map(|serialized| {
+                let serialized = serialized.expect("Failed to read raft log");
+                rmp_serde::from_slice(&serialized).expect("Failed to deserialize raft log")
+            })
+            .collect();
+        if log.is_empty() {
+            let bootstrap = Entry { term: 0, command: Command::Bootstrap(initial.clone()) };
+            tree.insert(log_key(1), encode(&bootstrap)).expect("Failed to write raft log");
+            log.push(bootstrap);
+        } else if log[0].command != Command::Bootstrap(initial.clone()) {
+            return Err(
+                "the nodes or owners of the cluster file changed since the raft log was created, \
+                restore it or remove the raft tree of every node".to_owned()
+            );
+        }
+        let bootstrap = fingerprint(&log[0]);
+        let mut state = State {
+            term,
+            voted_for,
+            role: Role::Follower,
+            leader: None,
+            log,
+            commit: 1,
+            applied: 0,
+            map: initial,
+            next: HashMap::new(),
+            matched: HashMap::new(),
+            acked: HashMap::new(),
+            contact: None,
+            deadline: election_deadline(),
+            bootstrap
+        };
+        state.apply();
+        let raft = Arc::new(Raft {
+            id: id.to_owned(),
+            peers,
+            tree,
+            state: Mutex::new(state),
+            diverged: AtomicBool::new(false)
+        });
+
+        let ticked = Arc::downgrade(&raft);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                interval.tick().await;
+                match ticked.upgrade() {
+                    Some(raft) => raft.tick().await,
+                    None => break
+                }
+            }
+        });
+        Ok(raft)
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        self.state.lock().expect("Failed to acquire lock")
+    }
+
+    fn quorum(&self) -> usize {
+        (self.peers.len() + 1) / 2 + 1
+    }
+
+    fn save_term(&self, state: &State) {
+        self.tree
+            .insert(KEYSPACE_RAFT_TERM, encode(&(state.term, &state.voted_for)))
+            .expect("Failed to write raft state");
+        self.tree.flush().expect("Failed to write raft state");
+    }
+
+    fn save_entries(&self, first: usize, entries: &[Entry]) {
+        for (offset, entry) in entries.iter().enumerate() {
+            self.tree
+                .insert(log_key(first + offset), encode(entry))
+                .expect("Failed to write raft log");
+        }
+        self.tree.flush().expect("Failed to write raft log");
+    }
+
+    // Drops the entries from the index on, which conflict with the leader.
+    fn truncate(&self, state: &mut State, index: usize) {
+        for removed in index..=state.log.len() {
+            self.tree.remove(log_key(removed)).expect("Failed to write raft log");
+        }
+        state.log.truncate(index - 1);
+    }
+
+    fn step_down(&self, state: &mut State, term: u64) {
+        if state.role == Role::Leader {
+            info!("Stepping down as the leader of term {}", state.term);
+        }
+        state.term = term;
+        state.voted_for = None;
+        state.role = Role::Follower;
+        state.leader = None;
+        state.deadline = election_deadline();
+        self.save_term(state);
+    }
+
+    // Committed map, along with the number of entries it was built from.
+    pub fn map(&self) -> (ClusterMap, usize) {
+        let state = self.lock();
+        (state.map.clone(), state.applied)
+    }
+
+    pub fn leader(&self) -> Option<String> {
+        self.lock().leader.clone()
+    }
+
+    pub fn diverged(&self) -> bool {
+        self.diverged.load(Ordering::SeqCst)
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.lock().role == Role::Leader
+    }
+
+    // Whether the node can still rely on the map to be current, as the
+    // leader heard from a majority or as a follower heard from the leader
+    // within the lease.
+    pub fn has_lease(&self) -> bool {
+        let state = self.lock();
+        let now = Instant::now();
+        match state.role {
+            Role::Leader => {
+                let acked = state.acked
+                    .values()
+                    .filter(|acked| now.duration_since(**acked) < LEASE)
+                    .count();
+                acked + 1 >= self.quorum()
+            },
+            _ => state.contact.is_some_and(|contact| now.duration_since(contact) < LEASE)
+        }
+    }
+
+    // Members the leader hasn't heard from for the duration, counting from
+    // its election.
+    pub fn unreachable(&self, duration: Duration) -> Vec<String> {
+        let state = self.lock();
+        if state.role != Role::Leader {
+            return Vec::new();
+        }
+        let now = Instant::now();
+        state.acked
+            .iter()
+            .filter(|(_, acked)| now.duration_since(**acked) >= duration)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    // Appends the command to the log of the leader and waits for it to be
+    // committed. Followers pass the command on to the leader.
+    pub async fn propose(&self, command: Command) -> Result<(), AppError> {
+        let appended = {
+            let mut state = self.lock();
+            match state.role {
+                Role::Leader => {
+                    let entry = Entry { term: state.term, command: command.clone() };
+                    self.save_entries(state.log.len() + 1, &[entry.clone()]);
+                    state.log.push(entry);
+                    Ok((state.log.len(), state.term))
+                },
+                _ => Err(state.leader.clone())
+            }
+        };
+        let (index, term) = match appended {
+            Ok(appended) => appended,
+            Err(Some(leader)) => return match self.peers.get(&leader) {
+                Some(client) => client.propose(&command).await,
+                None => Err(Raft::no_leader())
+            },
+            Err(None) => return Err(Raft::no_leader())
+        };
+        self.broadcast().await;
+        let started = Instant::now();
+        while started.elapsed() < PROPOSE_TIMEOUT {
+            {
+                let state = self.lock();
+                if state.log.len() < index || state.term_at(index) != term {
+                    break;
+                }
+                if state.commit >= index {
+                    return Ok(());
+                }
+            }
+            tokio::time::delay_for(HEARTBEAT_INTERVAL / 2).await;
+        }
+        Err(AppError::UnexpectedError(format!("entry {} of term {} wasn't committed", index, term)))
+    }
+
+    fn no_leader() -> AppError {
+        AppError::NodeUnreachable {
+            node: "leader".to_owned(),
+            message: "the cluster has no leader".to_owned()
+        }
+    }
+
+    async fn tick(&self) {
+        let (leading, campaigning) = {
+            let state = self.lock();
+            (state.role == Role::Leader, state.role != Role::Leader && Instant::now() >= state.deadline)
+        };
+        if leading {
+            self.broadcast().await;
+        } else if campaigning {
+            self.campaign().await;
+        }
+    }
+
+    async fn campaign(&self) {
+        let request = {
+            let mut state = self.lock();
+            state.term += 1;
+            state.role = Role::Candidate;
+            state.voted_for = Some(self.id.clone());
+            state.leader = None;
+            state.deadline = election_deadline();
+            self.save_term(&state);
+            let (last_index, last_term) = state.last();
+            VoteRequest {
+                term: state.term,
+                candidate: self.id.clone(),
+                last_index: last_index as u64,
+                last_term,
+                bootstrap: state.bootstrap
+            }
+        };
+        debug!("Starting the election of term {}", request.term);
+        let votes = join_all(self.peers.values().map(|peer| {
+            timeout(RPC_TIMEOUT, peer.request_vote(request.clone()))
+        })).await;
+        {
+            let mut state = self.lock();
+            let mut granted = 1;
+            for vote in votes.into_iter().flatten().flatten() {
+                if vote.term > state.term {
+                    self.step_down(&mut state, vote.term);
+                    return;
+                }
+                if vote.granted {
+                    granted += 1;
+                }
+            }
+            if state.role != Role::Candidate || state.term != request.term || granted < self.quorum() {
+                return;
+            }
+            info!("Elected as the leader of term {}", state.term);
+            state.role = Role::Leader;
+            state.leader = Some(self.id.clone());
+            let next = state.log.len() + 1;
+            let now = Instant::now();
+            for peer in self.peers.keys() {
+                state.next.insert(peer.clone(), next);
+                state.matched.insert(peer.clone(), 0);
+                state.acked.insert(peer.clone(), now);
+            }
+            let noop = Entry { term: state.term, command: Command::Noop };
+            self.save_entries(next, &[noop.clone()]);
+            state.log.push(noop);
+        }
+        self.broadcast().await;
+    }
+
+    // Sends the entries each follower is missing, which doubles as the
+    // heartbeat of the leader, then commits the entries stored by a
+    // majority.
+    async fn broadcast(&self) {
+        let (term, requests) = {
+            let mut state = self.lock();
+            if state.role != Role::Leader {
+                return;
+            }
+            self.advance(&mut state);
+            let requests: Vec<(String, AppendRequest)> = self.peers
+                .keys()
+                .map(|peer| {
+                    let next = state.next[peer];
+                    let end = state.log.len().min(next - 1 + APPEND_BATCH);
+                    let request = AppendRequest {
+                        term: state.term,
+                        leader: self.id.clone(),
+                        prev_index: (next - 1) as u64,
+                        prev_term: state.term_at(next - 1),
+                        entries: state.log[next - 1..end].to_vec(),
+                        commit: state.commit as u64,
+                        bootstrap: state.bootstrap
+                    };
+                    (peer.clone(), request)
+                })
+                .collect();
+            (state.term, requests)
+        };
+        let responses = join_all(requests.into_iter().map(|(peer, request)| async move {
+            let sent = Instant::now();
+            let last = request.prev_index as usize + request.entries.len();
+            let response = timeout(RPC_TIMEOUT, self.peers[&peer].append_entries(request)).await;
+            (peer, sent, last, response)
+        })).await;
+
+        let mut state = self.lock();
+        for (peer, sent, last, response) in responses {
+            let response = match response {
+                Ok(Ok(response)) => response,
+                Ok(Err(err)) => {
+                    debug!("Failed to append entries to {}: {}", peer, err);
+                    continue;
+                },
+                Err(_) => continue
+            };
+            if response.term > state.term {
+                self.step_down(&mut state, response.term);
+                return;
+            }
+            if state.role != Role::Leader || state.term != term {
+                return;
+            }
+            state.acked.insert(peer.clone(), sent);
+            if response.success {
+                state.matched.insert(peer.clone(), last);
+                state.next.insert(peer, last + 1);
+            } else {
+                let next = state.next[&peer].saturating_sub(1).min(response.last_index as usize + 1);
+                state.next.insert(peer, next.max(1));
+            }
+        }
+        self.advance(&mut state);
+    }
+
+    // Commits the last entry of the current term stored by a majority,
+    // along with every entry before it.
+    fn advance(&self, state: &mut State) {
+        for index in (state.commit + 1..=state.log.len()).rev() {
+            let stored = 1 + state.matched.values().filter(|matched| **matched >= index).count();
+            if state.term_at(index) == state.term && stored >= self.quorum() {
+                state.commit = index;
+                break;
+            }
+        }
+        state.apply();
+    }
+
+    pub fn vote(&self, request: VoteRequest) -> Vote {
+        let mut state = self.lock();
+        if request.bootstrap != state.bootstrap {
+            warn!("Refused to vote for {}, whose cluster file is different", request.candidate);
+            return Vote { term: state.term, granted: false };
+        }
+        if request.term > state.term {
+            self.step_down(&mut state, request.term);
+        }
+        let (last_index, last_term) = state.last();
+        let current = request.last_term > last_term
+            || (request.last_term == last_term && request.last_index as usize >= last_index);
+        let available = state.voted_for.as_ref().map_or(true, |voted| voted == &request.candidate);
+        let granted = request.term == state.term && current && available;
+        if granted {
+            state.voted_for = Some(request.candidate);
+            state.deadline = election_deadline();
+            self.save_term(&state);
+        }
+        Vote { term: state.term, granted }
+    }
+
+    // Leaders are elected by nodes sharing their cluster file, so a node
+    // whose file is different is the one at fault. Unless it already
+    // followed a leader, it's marked as diverged so it stops.
+    pub fn append(&self, request: AppendRequest) -> Result<AppendResponse, AppError> {
+        let mut state = self.lock();
+        if request.bootstrap != state.bootstrap {
+            let message = format!("the cluster file is different from the one of {}", request.leader);
+            if state.contact.is_none() {
+                self.diverged.store(true, Ordering::SeqCst);
+            }
+            return Err(AppError::invalid("cluster", message));
+        }
+        if request.term < state.term {
+            return Ok(AppendResponse { term: state.term, success: false, last_index: state.log.len() as u64 });
+        }
+        if request.term > state.term {
+            self.step_down(&mut state, request.term);
+        }
+        state.role = Role::Follower;
+        state.leader = Some(request.leader);
+        state.deadline = election_deadline();
+
+        let prev = request.prev_index as usize;
+        if prev > state.log.len() || state.term_at(prev) != request.prev_term {
+            let last_index = state.log.len().min(prev.saturating_sub(1)) as u64;
+            return Ok(AppendResponse { term: state.term, success: false, last_index });
+        }
+        let last = prev + request.entries.len();
+        for (offset, entry) in request.entries.into_iter().enumerate() {
+            let index = prev + offset + 1;
+            if index <= state.log.len() {
+                if state.term_at(index) == entry.term {
+                    continue;
+                }
+                self.truncate(&mut state, index);
+            }
+            self.save_entries(index, &[entry.clone()]);
+            state.log.push(entry);
+        }
+        let commit = (request.commit as usize).min(last);
+        if commit > state.commit {
+            state.commit = commit;
+        }
+        state.apply();
+        // the map can only be relied on once it holds what the leader
+        // committed.
+        if state.commit >= request.commit as usize {
+            state.contact = Some(Instant::now());
+        }
+        Ok(AppendResponse { term: state.term, success: true, last_index: last as u64 })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn lead(raft: &Raft) {
+        for _ in 0..30 {
+            if raft.is_leader() {
+                return;
+            }
+            tokio::time::delay_for(HEARTBEAT_INTERVAL).await;
+        }
+        panic!("No leader was elected");
+    }
+
+    #[tokio::test]
+    async fn restart() {
+        let data_dir = ".test/".to_owned() + &Uuid::new_v4().to_string();
+        let db = sled::open(&data_dir).unwrap();
+        let initial = ClusterMap {
+            nodes: vec![("a".to_owned(), "http://a".to_owned())],
+            owners: vec!["a".to_owned(); 3]
+        };
+        let raft = Raft::start("a", db.open_tree("raft").unwrap(), HashMap::new(), initial.clone()).unwrap();
+        assert_eq!(raft.map(), (initial.clone(), 1));
+        lead(&raft).await;
+        assert!(raft.has_lease());
+        let command = Command::Assign { shards: vec![1], node: "b".to_owned() };
+        raft.propose(command).await.unwrap();
+        assert_eq!(raft.map().0.owners, vec!["a", "b", "a"]);
+        drop(raft);
+
+        // committed entries come back once elected again.
+        let raft = Raft::start("a", db.open_tree("raft").unwrap(), HashMap::new(), initial.clone()).unwrap();
+        assert_eq!(raft.map().0, initial);
+        lead(&raft).await;
+        assert_eq!(raft.map().0.owners, vec!["a", "b", "a"]);
+        // a candidate missing the entries of the last term isn't voted for,
+        // even in a later term.
+        let term = raft.lock().term;
+        let (last_index, last_term) = raft.lock().last();
+        let stale = VoteRequest {
+            term: term + 1,
+            candidate: "b".to_owned(),
+            last_index: last_index as u64 + 5,
+            last_term: last_term - 1,
+            bootstrap: raft.lock().bootstrap
+        };
+        let vote = raft.vote(stale.clone());
+        assert_eq!(vote.term, term + 1);
+        assert!(!vote.granted);
+        let current = VoteRequest { term: term + 2, last_index: last_index as u64, last_term, ..stale };
+        assert!(raft.vote(current).granted);
+        drop(raft);
+
+        // the log is kept from another cluster file, whether its owners or
+        // its members changed.
+        let other = ClusterMap { owners: vec!["a".to_owned(); 4], ..initial.clone() };
+        assert!(Raft::start("a", db.open_tree("raft").unwrap(), HashMap::new(), other).is_err());
+        let mut joined = initial.clone();
+        joined.nodes.push(("b".to_owned(), "http://b".to_owned()));
+        assert!(Raft::start("a", db.open_tree("raft").unwrap(), HashMap::new(), joined).is_err());
+
+        drop(db);
+        tokio::fs::remove_dir_all(&data_dir).await.expect("Error removing directory");
+    }
+
+    #[tokio::test]
+    async fn cluster_files() {
+        let data_dir = ".test/".to_owned() + &Uuid::new_v4().to_string();
+        let db = sled::open(&data_dir).unwrap();
+        let initial = ClusterMap {
+            nodes: vec![("a".to_owned(), "http://a".to_owned()), ("b".to_owned(), "http://b".to_owned())],
+            owners: vec!["a".to_owned(); 3]
+        };
+        let raft = Raft::start("a", db.open_tree("raft").unwrap(), HashMap::new(), initial.clone()).unwrap();
+        let other = Entry {
+            term: 0,
+            command: Command::Bootstrap(ClusterMap { owners: vec!["b".to_owned(); 3], ..initial })
+        };
+
+        // candidates and leaders with another cluster file are refused.
+        assert!(!raft.vote(VoteRequest {
+            term: 5,
+            candidate: "b".to_owned(),
+            last_index: 10,
+            last_term: 4,
+            bootstrap: fingerprint(&other)
+        }).granted);
+        let append = AppendRequest {
+            term: 5,
+            leader: "b".to_owned(),
+            prev_index: 1,
+            prev_term: 0,
+            entries: vec![],
+            commit: 1,
+            bootstrap: fingerprint(&other)
+        };
+        assert!(raft.append(append).is_err());
+        assert_eq!(raft.lock().term, 0);
+        // not having followed a leader yet, the node is the one at fault.
+        assert!(raft.diverged());
+
+        drop(raft);
+        drop(db);
+        tokio::fs::remove_dir_all(&data_dir).await.expect("Error removing directory");
+    }
+}
@@ -1,27 +1,43 @@
 
-use crate::schema::Job;
-use std::time::Duration;
-use crate::store::Store;
+use crate::schema::{Job, JobRun, MAX_RUN_BODY, next_occurrence};
+use crate::config::HistoryConfig;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::cluster::Cluster;
+use crate::store::{Popped, Store};
+use crate::metrics;
+use crate::health::Health;
+use crate::quota::Quotas;
+use crate::egress::{Egress, EgressResolver};
 use std::sync::Arc;
-use chrono::Utc;
-use std::str::FromStr;
 
 use futures::channel::oneshot;
 
-use hyper::{Client, Method, header, Request};
+use hyper::{Body, Client, Method, Response, header, Request, Uri};
+use hyper::client::HttpConnector;
+use hyper::body::HttpBody;
+
+// how often the runs past their retention are dropped.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
 
 pub struct Scheduler {
     stop_sender: oneshot::Sender<()>
 }
 
 impl Scheduler {
-    pub fn start(store: Arc<Store>) -> Scheduler {
+    pub fn start(
+        cluster: Arc<Cluster>,
+        history: HistoryConfig,
+        health: Arc<Health>,
+        quotas: Arc<Quotas>,
+        egress: Arc<Egress>
+    ) -> Scheduler {
         let (sender, mut receiver) = futures::channel::oneshot::channel::<()>();
         let mut interval = tokio::time::interval(Duration::from_millis(500));
         let scheduler = Scheduler {
             stop_sender: sender
         };
         tokio::spawn(async move {
+            let mut pruned = Instant::now();
             loop {
                 interval.tick().await;
                 match receiver.try_recv() {
@@ -29,7 +45,18 @@ impl Scheduler {
                     Ok(Some(())) => break,
                     Ok(None) => {}
                 }
-                Scheduler::send_ready(&store).await;
+                health.tick();
+                // each shard has a queue of its own, and only the node serving
+                // a shard fires its jobs.
+                for store in &cluster.firing_stores().await {
+                    Scheduler::send_ready(store, &history, &quotas, &egress).await;
+                }
+                if pruned.elapsed() >= PRUNE_INTERVAL {
+                    for store in &cluster.stores() {
+                        store.prune_runs(&history);
+                    }
+                    pruned = Instant::now();
+                }
             }
         });
 
@@ -40,52 +67,126 @@ impl Scheduler {
         self.stop_sender.send(()).expect("Failed to stop scheduler");
     }
 
-    async fn send_ready(store: &Arc<Store>) {
+    // Jobs of tenants over their callback quota are held as they were, so
+    // they fire on a later tick once the quota allows it. Cron jobs are put
+    // back with their next occurrence as they fire, within the fence of the
+    // store so they can't land after the final sweep of a migration.
+    async fn send_ready(
+        store: &Arc<Store>,
+        history: &HistoryConfig,
+        quotas: &Quotas,
+        egress: &Arc<Egress>
+    ) {
         loop {
-            let next = store.next();
+            let fenced = store.fence().read().await;
+            let next = store.next_with(|item| {
+                if !quotas.acquire_callback(&item.tenant) {
+                    metrics::CALLBACKS_THROTTLED.with_label_values(&[&item.tenant]).inc();
+                    return Popped::Hold;
+                }
+                Popped::Fire(item.schedule.as_ref().map(|schedule| Job {
+                    timestamp: next_occurrence(schedule).expect("No next schedule found"),
+                    ..item.clone()
+                }))
+            });
+            drop(fenced);
             match next {
-                Some(item) => {
-                    if let Some(schedule) = &item.schedule {
-                        let timestamp = cron::Schedule::from_str(schedule)
-                            .expect("Failed to parse pattern")
-                            .upcoming(Utc)
-                            .next()
-                            .expect("No next schedule found")
-                            .timestamp_millis();
-                        let callback = Job {
-                            method: item.method.clone(),
-                            timestamp: Duration::from_millis(timestamp as u64),
-                            url: item.url.clone(),
-                            body: item.body.clone(),
-                            id: item.id.clone(),
-                            schedule: Some(schedule.to_owned())
-                        };
-                        store.push(callback);
-                    }
-                    Scheduler::send_callback(item).await;
-                },
+                Some(item) => Scheduler::send_callback(store, history, egress, item).await,
                 None => break
             }
         }
+        store.release_held();
     }
 
-    async fn send_callback(callback: Job) {
+    async fn send_callback(
+        store: &Store,
+        history: &HistoryConfig,
+        egress: &Arc<Egress>,
+        callback: Job
+    ) {
+        let fired = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Error getting system time");
+        let lateness = fired.checked_sub(callback.timestamp).unwrap_or_default();
+        metrics::FIRE_LATENESS.observe(lateness.as_secs_f64());
+        let mut run = JobRun {
+            scheduled: callback.timestamp,
+            fired,
+            latency: Duration::default(),
+            status: None,
+            error: None,
+            body: None
+        };
+
+        // jobs are validated when created, but the ones stored by previous
+        // versions might not be.
+        let uri: Uri = match callback.url.parse() {
+            Ok(uri) => uri,
+            Err(e) => {
+                error!("{} - Invalid callback url: {}", callback.url, e);
+                run.error = Some(format!("Invalid callback url: {}", e));
+                store.record_run(&callback.tenant, &callback.id, &run, history);
+                return;
+            }
+        };
+        let host = uri.host().unwrap_or("").to_owned();
+        // the rules might have changed since the job was created, and the
+        // addresses of the host are only known now.
+        if let Err(err) = egress.check_url(&callback.url) {
+            error!("{} - Callback blocked: {}", callback.url, err);
+            metrics::CALLBACKS.with_label_values(&[&host, "blocked"]).inc();
+            run.error = Some(err.to_string());
+            store.record_run(&callback.tenant, &callback.id, &run, history);
+            return;
+        }
+
         let mut request = Request::new(hyper::Body::from(callback.body));
         let method = &callback.method.as_bytes();
 
         *request.method_mut() = Method::from_bytes(method).unwrap_or(Method::POST);
-        *request.uri_mut() = callback.url.parse().expect("Invalid callback url");
+        *request.uri_mut() = uri;
         request.headers_mut().insert(
             header::CONTENT_TYPE,
             header::HeaderValue::from_static("application/json")
         );
 
-        let client = Client::new();
+        let connector = HttpConnector::new_with_resolver(EgressResolver::new(egress.clone()));
+        let client = Client::builder().build::<_, Body>(connector);
 
+        let started = Instant::now();
         let result = client.request(request).await;
+        run.latency = started.elapsed();
+        metrics::CALLBACK_DURATION
+            .with_label_values(&[&host])
+            .observe(run.latency.as_secs_f64());
+
+        match result {
+            Ok(response) => {
+                run.status = Some(response.status().as_u16());
+                metrics::CALLBACKS
+                    .with_label_values(&[&host, response.status().as_str()])
+                    .inc();
+                run.body = Some(Scheduler::read_body(response).await);
+            },
+            Err(e) => {
+                error!("{} - Failed to send callback: {}", callback.url, e);
+                metrics::CALLBACKS.with_label_values(&[&host, "error"]).inc();
+                run.error = Some(e.to_string());
+            }
+        }
+        store.record_run(&callback.tenant, &callback.id, &run, history);
+    }
 
-        if let Err(e) = result {
-            error!("{} - Failed to send callback: {}", callback.url, e);
+    // Reads the start of the response body, up to `MAX_RUN_BODY` bytes.
+    async fn read_body(mut response: Response<Body>) -> String {
+        let mut body: Vec<u8> = Vec::new();
+        while body.len() < MAX_RUN_BODY {
+            match response.data().await {
+                Some(Ok(chunk)) => body.extend(chunk.iter()),
+                _ => break
+            }
         }
+        body.truncate(MAX_RUN_BODY);
+        String::from_utf8_lossy(&body).into_owned()
     }
 }
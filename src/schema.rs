@@ -8,6 +8,15 @@ use chrono::Utc;
 use std::convert::TryFrom;
 use crate::error::AppError;
 use std::str::FromStr;
+use std::collections::BTreeMap;
+use crate::selector::{LabelSelector, validate_labels};
+use crate::validation::{validate_job, validate_tenant};
+use crate::auth::{ApiKey, Scope};
+use crate::config::ShardRange;
+use sha2::{Digest, Sha256};
+
+// hashing is not implemented to handle beyond that value.
+pub const SHARD_COUNT: usize = 127;
 
 // This type is the internal structure used by the scheduler.
 #[derive(Eq, Clone, Debug, Serialize, Deserialize)]
@@ -17,9 +26,229 @@ pub struct Job {
     pub body: String,
     pub timestamp: Duration,
     pub id: String,
-    pub schedule: Option<String>
+    pub schedule: Option<String>,
+    // paused jobs are kept in storage but are never queued for dispatch.
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(default)]
+    pub misfire: MisfirePolicy,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub name: Option<String>,
+    // namespace of the job, ids are only unique within a tenant.
+    #[serde(default = "default_tenant")]
+    pub tenant: String
+}
+
+// Tenant of the jobs created without one, including the jobs stored before
+// there were tenants.
+pub const DEFAULT_TENANT: &str = "default";
+
+fn default_tenant() -> String {
+    DEFAULT_TENANT.to_owned()
+}
+
+// What to do with a cron job which should have fired while it was paused.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MisfirePolicy {
+    // skip the missed executions and wait for the next one.
+    #[default]
+    Skip,
+    // fire once as soon as the job is resumed.
+    FireOnce
+}
+
+impl Job {
+    pub fn in_tenant(self, tenant: &str) -> Job {
+        Job {
+            tenant: tenant.to_owned(),
+            ..self
+        }
+    }
+
+    // Timestamp the job should be queued at when it is resumed.
+    pub fn resume_timestamp(&self, now: Duration) -> Result<Duration, AppError> {
+        if self.timestamp > now {
+            return Ok(self.timestamp);
+        }
+        match (&self.schedule, self.misfire) {
+            (Some(schedule), MisfirePolicy::Skip) => next_occurrence(schedule),
+            _ => Ok(now)
+        }
+    }
 }
 
+// Separates the group from the name in the id of a named job.
+const NAME_SEPARATOR: &str = "::_";
+
+// Jobs with a group and a name use them as their natural key, which makes
+// creating them again replace the existing job instead of adding another one.
+pub fn named_id(group: &str, name: &str) -> String {
+    group.to_owned() + NAME_SEPARATOR + name
+}
+
+fn job_id(group: &Option<String>, name: &Option<String>) -> Result<String, AppError> {
+    match (group, name) {
+        // the group is part of the group index keys.
+        (Some(group), _) if group.is_empty() || group.contains('\0') =>
+            Err(AppError::invalid("group", "must be non-empty and without NUL characters")),
+        // otherwise two pairs could make the same id.
+        (Some(group), _) if group.contains(NAME_SEPARATOR) =>
+            Err(AppError::invalid("group", format!("must not contain `{}`", NAME_SEPARATOR))),
+        (_, Some(name)) if name.is_empty() =>
+            Err(AppError::invalid("name", "must not be empty")),
+        (_, Some(name)) if name.contains(NAME_SEPARATOR) =>
+            Err(AppError::invalid("name", format!("must not contain `{}`", NAME_SEPARATOR))),
+        (Some(group), Some(name)) => Ok(named_id(group, name)),
+        (None, Some(_)) => Err(AppError::invalid("name", "requires a group")),
+        (_, None) => Ok(Uuid::new_v4().to_string())
+    }
+}
+
+// Computes the next time a cron schedule will fire.
+pub fn next_occurrence(schedule: &str) -> Result<Duration, AppError> {
+    let timestamp = cron::Schedule::from_str(schedule)
+        .map_err(|err| AppError::invalid("schedule", err))?
+        .upcoming(Utc)
+        .next()
+        .ok_or_else(|| AppError::invalid("schedule", "never fires"))?
+        .timestamp_millis();
+    Ok(Duration::from_millis(timestamp as u64))
+}
+
+// Selects the jobs affected by operations which apply to more than one job.
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub struct JobFilter {
+    // prefix of the callback url.
+    pub url: Option<String>,
+    pub selector: Option<LabelSelector>,
+    pub group: Option<String>,
+    // set from the credentials of the request, never from its body.
+    #[serde(skip)]
+    pub tenant: Option<String>
+}
+
+impl JobFilter {
+    pub fn matches(&self, job: &Job) -> bool {
+        let url_matches = match &self.url {
+            Some(url) => job.url.starts_with(url),
+            None => true
+        };
+        let labels_match = match &self.selector {
+            Some(selector) => selector.matches(&job.labels),
+            None => true
+        };
+        let group_matches = match &self.group {
+            Some(group) => job.group.as_ref() == Some(group),
+            None => true
+        };
+        let tenant_matches = match &self.tenant {
+            Some(tenant) => &job.tenant == tenant,
+            None => true
+        };
+        url_matches && labels_match && group_matches && tenant_matches
+    }
+
+    // Whether the filter matches every job of the tenant.
+    pub fn is_empty(&self) -> bool {
+        self.url.is_none() && self.selector.is_none() && self.group.is_none()
+    }
+
+    pub fn group(group: &str) -> JobFilter {
+        JobFilter {
+            group: Some(group.to_owned()),
+            ..JobFilter::default()
+        }
+    }
+
+    pub fn in_tenant(self, tenant: &str) -> JobFilter {
+        JobFilter {
+            tenant: Some(tenant.to_owned()),
+            ..self
+        }
+    }
+}
+
+// Summary of the jobs matching a filter.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct JobStats {
+    pub count: u64,
+    pub paused: u64,
+    // timestamp of the next job to fire, in milliseconds.
+    pub next_fire: Option<u64>
+}
+
+impl JobStats {
+    pub fn add(&mut self, job: &Job) {
+        self.count += 1;
+        if job.paused {
+            self.paused += 1;
+        } else {
+            let timestamp = job.timestamp.as_millis() as u64;
+            self.next_fire = Some(self.next_fire.map_or(timestamp, |next| next.min(timestamp)));
+        }
+    }
+
+    pub fn merge(&mut self, other: JobStats) {
+        self.count += other.count;
+        self.paused += other.paused;
+        self.next_fire = match (self.next_fire, other.next_fire) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b)
+        };
+    }
+}
+
+// Jobs of a tenant counted against its quotas.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+pub struct JobUsage {
+    // one-off jobs waiting to fire.
+    pub pending: u64,
+    pub recurring: u64
+}
+
+impl JobUsage {
+    pub fn add(&mut self, job: &Job) {
+        match job.schedule {
+            Some(_) => self.recurring += 1,
+            None => self.pending += 1
+        }
+    }
+
+    pub fn remove(&mut self, job: &Job) {
+        match job.schedule {
+            Some(_) => self.recurring = self.recurring.saturating_sub(1),
+            None => self.pending = self.pending.saturating_sub(1)
+        }
+    }
+
+    pub fn merge(&mut self, other: JobUsage) {
+        self.pending += other.pending;
+        self.recurring += other.recurring;
+    }
+}
+
+// A single delivery attempt of a job.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct JobRun {
+    // when the job was meant to fire.
+    pub scheduled: Duration,
+    // when the callback was actually sent.
+    pub fired: Duration,
+    pub latency: Duration,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    // start of the response body, see `MAX_RUN_BODY`.
+    pub body: Option<String>
+}
+
+// Only this many bytes of the callback responses are kept in the history.
+pub const MAX_RUN_BODY: usize = 1024;
+
 impl Ord for Job {
     fn cmp(&self, other: &Job) -> Ordering {
         other.timestamp.cmp(&self.timestamp)
@@ -78,36 +307,52 @@ impl TryFrom<V1CronJob> for Job {
         // replace non-standard "?" with just a "*"
         let schedule_pattern = v1.schedule.replace("?", "*");
         let timestamp = cron::Schedule::from_str(&schedule_pattern)
-            .map_err(|_| AppError::ValidationError)?
+            .map_err(|err| AppError::invalid("schedule", err))?
             .upcoming(Utc)
             .next()
-            .ok_or(AppError::ValidationError)?;
+            .ok_or_else(|| AppError::invalid("schedule", "never fires"))?;
 
-        let id = v1.group.clone() + "::_" + &v1.name;
-        Ok(
-            Job {
-                method: "POST".to_owned(),
-                timestamp: Duration::from_millis(timestamp.timestamp_millis() as u64),
-                url: v1.url,
-                body: v1.payload,
-                id,
-                schedule: Some(schedule_pattern.to_owned())
-            }
-        )
+        let id = named_id(&v1.group, &v1.name);
+        let job = Job {
+            method: "POST".to_owned(),
+            timestamp: Duration::from_millis(timestamp.timestamp_millis() as u64),
+            url: v1.url,
+            body: v1.payload,
+            id,
+            schedule: Some(schedule_pattern.to_owned()),
+            paused: false,
+            misfire: MisfirePolicy::default(),
+            labels: BTreeMap::new(),
+            group: Some(v1.group),
+            name: Some(v1.name),
+            tenant: DEFAULT_TENANT.to_owned()
+        };
+        validate_job(&job)?;
+        Ok(job)
     }
 }
 
-impl From<V1Job> for Job {
-    fn from(v1: V1Job) -> Job {
+impl TryFrom<V1Job> for Job {
+    type Error = AppError;
+
+    fn try_from(v1: V1Job) -> Result<Job, AppError> {
         let id = Uuid::new_v4().to_string();
-        Job {
+        let job = Job {
             method: "POST".to_owned(),
             timestamp: Duration::from_millis(v1.timestamp),
             url: v1.url + "?key=" + &id,
             body: v1.payload,
             id,
-            schedule: None
-        }
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::default(),
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
+        };
+        validate_job(&job)?;
+        Ok(job)
     }
 }
 
@@ -116,7 +361,11 @@ pub struct V2Job {
     pub method: Option<String>,
     pub url: String,
     pub body: String,
-    pub timestamp: u64
+    pub timestamp: u64,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    pub group: Option<String>,
+    pub name: Option<String>
 }
 
 #[derive(Serialize)]
@@ -125,7 +374,13 @@ pub struct V2JobResponse <'a> {
     pub url: &'a str,
     pub body: &'a str,
     pub timestamp: u64,
-    pub id: &'a str
+    pub id: &'a str,
+    pub paused: bool,
+    pub labels: &'a BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<&'a str>
 }
 
 #[derive(Deserialize)]
@@ -133,7 +388,12 @@ pub struct V2CronJob {
     pub method: Option<String>,
     pub url: String,
     pub body: String,
-    pub schedule: String
+    pub schedule: String,
+    pub misfire: Option<MisfirePolicy>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    pub group: Option<String>,
+    pub name: Option<String>
 }
 
 #[derive(Serialize)]
@@ -142,7 +402,199 @@ pub struct V2CronJobResponse<'a> {
     pub method: &'a str,
     pub url: &'a str,
     pub body: &'a str,
-    pub schedule: &'a str
+    pub schedule: &'a str,
+    pub misfire: MisfirePolicy,
+    pub paused: bool,
+    pub labels: &'a BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<&'a str>
+}
+
+// Lists the jobs affected by an operation on a `JobFilter`.
+#[derive(Serialize, Deserialize)]
+pub struct V2JobIds {
+    pub ids: Vec<String>
+}
+
+impl <'a> From<&'a [Job]> for V2JobIds {
+    fn from(jobs: &'a [Job]) -> V2JobIds {
+        V2JobIds {
+            ids: jobs.iter().map(|job| job.id.clone()).collect()
+        }
+    }
+}
+
+// Either a one-off or a cron job, as accepted when creating batches of jobs
+// or declaring a named job.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum V2BatchJob {
+    Cron(V2CronJob),
+    Job(V2Job)
+}
+
+impl V2BatchJob {
+    // Names the job, as done when it is declared through its group and
+    // name instead of through the body.
+    pub fn named(self, group: &str, name: &str) -> V2BatchJob {
+        match self {
+            V2BatchJob::Cron(v2) => V2BatchJob::Cron(V2CronJob {
+                group: Some(group.to_owned()),
+                name: Some(name.to_owned()),
+                ..v2
+            }),
+            V2BatchJob::Job(v2) => V2BatchJob::Job(V2Job {
+                group: Some(group.to_owned()),
+                name: Some(name.to_owned()),
+                ..v2
+            })
+        }
+    }
+}
+
+impl TryFrom<V2BatchJob> for Job {
+    type Error = AppError;
+
+    fn try_from(item: V2BatchJob) -> Result<Job, AppError> {
+        match item {
+            V2BatchJob::Cron(v2) => Job::try_from(v2),
+            V2BatchJob::Job(v2) => Job::try_from(v2)
+        }
+    }
+}
+
+// Body of the error responses.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct V2Error {
+    pub code: String,
+    pub message: String,
+    pub details: BTreeMap<String, String>
+}
+
+impl From<&AppError> for V2Error {
+    fn from(error: &AppError) -> V2Error {
+        V2Error {
+            code: error.code().to_owned(),
+            message: error.to_string(),
+            details: error.details()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct V2ApiKeyRequest {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    // confines the key to the tenant.
+    #[serde(default)]
+    pub tenant: Option<String>
+}
+
+impl V2ApiKeyRequest {
+    pub fn validate(&self) -> Result<(), AppError> {
+        if self.name.is_empty() {
+            return Err(AppError::invalid("name", "must not be empty"));
+        }
+        if self.scopes.is_empty() {
+            return Err(AppError::invalid("scopes", "must not be empty"));
+        }
+        match &self.tenant {
+            Some(tenant) => validate_tenant(tenant),
+            None => Ok(())
+        }
+    }
+}
+
+// The key is only returned when it is created.
+#[derive(Serialize, Deserialize)]
+pub struct V2CreatedApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    pub key: String
+}
+
+#[derive(Deserialize)]
+pub struct V2MigrationRequest {
+    // a shard or a range of shards, such as `0-63`.
+    pub shards: ShardRange,
+    // id of the node receiving the shards.
+    pub target: String
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationState {
+    Running,
+    Completed,
+    Failed
+}
+
+// Progress of moving shards to another node.
+#[derive(Serialize, Clone, Debug)]
+pub struct Migration {
+    pub id: String,
+    pub shards: String,
+    pub target: String,
+    pub state: MigrationState,
+    // jobs sent to the target so far.
+    pub moved: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShardState {
+    Local,
+    Remote,
+    Migrating
+}
+
+#[derive(Serialize, Debug)]
+pub struct ShardStatus {
+    pub shard: usize,
+    pub state: ShardState,
+    // node owning the shard, or receiving it when it is migrating.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    // jobs of the shard stored by this node.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jobs: Option<JobStats>
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerState {
+    // not called yet.
+    Unknown,
+    Up,
+    Down
+}
+
+// Health of another node, as seen by this one.
+#[derive(Serialize, Debug)]
+pub struct NodeStatus {
+    pub node: String,
+    pub address: String,
+    pub state: PeerState,
+    // when the node entered its state, in milliseconds since the epoch.
+    pub since: u64,
+    // calls failed in a row.
+    pub failures: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>
+}
+
+// Outcome of a single item in a batch request.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct V2BatchResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<V2Error>
 }
 
 impl TryFrom<V2Job> for Job {
@@ -150,19 +602,24 @@ impl TryFrom<V2Job> for Job {
 
     fn try_from(v2: V2Job) -> Result<Job, AppError> {
         let method = v2.method.unwrap_or_else(|| "POST".to_owned());
-        hyper::Method::from_bytes(&method.as_bytes())
-            .map_err(|_| AppError::ValidationError)?;
+        validate_labels(&v2.labels)?;
 
-        Ok(
-            Job {
-                method: method,
-                timestamp: Duration::from_millis(v2.timestamp),
-                body: v2.body,
-                url: v2.url,
-                id: Uuid::new_v4().to_string(),
-                schedule: None
-            }
-        )
+        let job = Job {
+            method: method,
+            timestamp: Duration::from_millis(v2.timestamp),
+            body: v2.body,
+            url: v2.url,
+            id: job_id(&v2.group, &v2.name)?,
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::default(),
+            labels: v2.labels,
+            group: v2.group,
+            name: v2.name,
+            tenant: DEFAULT_TENANT.to_owned()
+        };
+        validate_job(&job)?;
+        Ok(job)
     }
 }
 
@@ -174,6 +631,10 @@ impl <'a> From<&'a Job> for V2JobResponse<'a> {
             url: &job.url,
             body: &job.body,
             timestamp: job.timestamp.as_millis() as u64,
+            paused: job.paused,
+            labels: &job.labels,
+            group: job.group.as_deref(),
+            name: job.name.as_deref()
         }
     }
 }
@@ -183,25 +644,25 @@ impl TryFrom<V2CronJob> for Job {
 
     fn try_from(v2: V2CronJob) -> Result<Job, AppError> {
         let method = v2.method.unwrap_or_else(|| "POST".to_owned());
-        hyper::Method::from_bytes(&method.as_bytes())
-            .map_err(|_| AppError::ValidationError)?;
 
-        let timestamp = cron::Schedule::from_str(&v2.schedule)
-            .map_err(|_| AppError::ValidationError)?
-            .upcoming(Utc)
-            .next()
-            .ok_or(AppError::ValidationError)?
-            .timestamp_millis();
-        Ok(
-            Job {
-                method: method,
-                timestamp: Duration::from_millis(timestamp as u64),
-                body: v2.body,
-                url: v2.url,
-                id: Uuid::new_v4().to_string(),
-                schedule: None
-            }
-        )
+        validate_labels(&v2.labels)?;
+        let timestamp = next_occurrence(&v2.schedule)?;
+        let job = Job {
+            method: method,
+            timestamp,
+            body: v2.body,
+            url: v2.url,
+            id: job_id(&v2.group, &v2.name)?,
+            schedule: Some(v2.schedule),
+            paused: false,
+            misfire: v2.misfire.unwrap_or_default(),
+            labels: v2.labels,
+            group: v2.group,
+            name: v2.name,
+            tenant: DEFAULT_TENANT.to_owned()
+        };
+        validate_job(&job)?;
+        Ok(job)
     }
 }
 
@@ -212,7 +673,63 @@ impl <'a> From<&'a Job> for V2CronJobResponse<'a> {
             method: &job.method,
             url: &job.url,
             body: &job.body,
-            schedule: &job.schedule.as_ref().unwrap()
+            schedule: &job.schedule.as_ref().unwrap(),
+            misfire: job.misfire,
+            paused: job.paused,
+            labels: &job.labels,
+            group: job.group.as_deref(),
+            name: job.name.as_deref()
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct V2GroupStats<'a> {
+    pub group: &'a str,
+    #[serde(flatten)]
+    pub stats: JobStats
+}
+
+// Representation of any job, picking the cron format when it has a schedule.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum V2JobView<'a> {
+    Cron(V2CronJobResponse<'a>),
+    Job(V2JobResponse<'a>)
+}
+
+impl <'a> From<&'a Job> for V2JobView<'a> {
+    fn from(job: &'a Job) -> V2JobView<'a> {
+        match job.schedule {
+            Some(_) => V2JobView::Cron(V2CronJobResponse::from(job)),
+            None => V2JobView::Job(V2JobResponse::from(job))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct V2JobRun {
+    pub scheduled: u64,
+    pub fired: u64,
+    // in milliseconds.
+    pub latency: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>
+}
+
+impl From<JobRun> for V2JobRun {
+    fn from(run: JobRun) -> V2JobRun {
+        V2JobRun {
+            scheduled: run.scheduled.as_millis() as u64,
+            fired: run.fired.as_millis() as u64,
+            latency: run.latency.as_millis() as u64,
+            status: run.status,
+            error: run.error,
+            body: run.body
         }
     }
 }
@@ -243,3 +760,43 @@ fn cron_year_deserialize() {
     let v1_cron: V1CronJob = serde_json::from_str(body).unwrap();
     let _job = Job::try_from(v1_cron).unwrap();
 }
+
+#[test]
+fn v2_named_deserialize() {
+    let body = r#"{
+        "url": "http://example.com/callback",
+        "body": "",
+        "schedule": "0 0 4 * * *",
+        "group": "reports",
+        "name": "daily"
+    }"#;
+    let v2_cron: V2CronJob = serde_json::from_str(body).unwrap();
+    let job = Job::try_from(v2_cron).unwrap();
+    assert_eq!(job.id, named_id("reports", "daily"));
+
+    let body = r#"{"url": "", "body": "", "timestamp": 0, "name": "daily"}"#;
+    let v2_job: V2Job = serde_json::from_str(body).unwrap();
+    assert!(Job::try_from(v2_job).is_err());
+}
+
+// Ids are only unique within a tenant, so both pick the shard.
+pub fn shard_index(tenant: &str, id: &str) -> usize {
+    (stable_hash(&[tenant, id]) % SHARD_COUNT as u64) as usize
+}
+
+// First 8 bytes, big endian, of the SHA-256 of the parts separated by NUL
+// bytes. Unlike the hashers of the standard library, it is the same for every
+// build and platform, which all of the nodes must agree on.
+pub fn stable_hash(parts: &[&str]) -> u64 {
+    let mut hasher = Sha256::new();
+    for (index, part) in parts.iter().enumerate() {
+        if index > 0 {
+            hasher.update(b"\0");
+        }
+        hasher.update(part.as_bytes());
+    }
+    let digest = hasher.finalize();
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(bytes)
+}
@@ -0,0 +1,163 @@
+// Label selectors, in the same spirit as the kubernetes ones. A selector is a
+// comma separated list of requirements which must all be met:
+// - `key=value` (or `key==value`), the label is set to the value.
+// - `key!=value`, the label is missing or set to another value.
+// - `key`, the label is set.
+// - `!key`, the label is missing.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter, Result as FormatResult};
+use std::str::FromStr;
+use serde::{Serialize, Deserialize};
+use crate::error::AppError;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Requirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    Exists(String),
+    NotExists(String)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct LabelSelector {
+    requirements: Vec<Requirement>
+}
+
+fn valid_key(key: &str) -> bool {
+    !key.is_empty() && !key.contains(|c| "=!,\0".contains(c))
+}
+
+fn valid_value(value: &str) -> bool {
+    !value.contains(|c| ",\0".contains(c))
+}
+
+// Labels are stored as part of the index keys, so the characters used as
+// separators aren't allowed.
+pub fn validate_labels(labels: &BTreeMap<String, String>) -> Result<(), AppError> {
+    for (key, value) in labels {
+        if !valid_key(key) || !valid_value(value) {
+            return Err(AppError::invalid("labels", format!("invalid label `{}`", key)));
+        }
+    }
+    Ok(())
+}
+
+impl LabelSelector {
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.requirements.iter().all(|requirement| match requirement {
+            Requirement::Equals(key, value) => labels.get(key) == Some(value),
+            Requirement::NotEquals(key, value) => labels.get(key) != Some(value),
+            Requirement::Exists(key) => labels.contains_key(key),
+            Requirement::NotExists(key) => !labels.contains_key(key)
+        })
+    }
+
+    // Selectors without requirements match every job.
+    pub fn is_empty(&self) -> bool {
+        self.requirements.is_empty()
+    }
+
+    // A label which all matching jobs must have, which can be looked up in
+    // the label index instead of scanning every job.
+    pub fn indexed_label(&self) -> Option<(&str, &str)> {
+        self.requirements.iter().find_map(|requirement| match requirement {
+            Requirement::Equals(key, value) => Some((key.as_str(), value.as_str())),
+            _ => None
+        })
+    }
+}
+
+impl FromStr for LabelSelector {
+    type Err = AppError;
+
+    fn from_str(selector: &str) -> Result<LabelSelector, AppError> {
+        let mut requirements = Vec::new();
+        for term in selector.split(',').map(str::trim).filter(|term| !term.is_empty()) {
+            let requirement = if let Some(index) = term.find("!=") {
+                let (key, value) = (term[..index].trim(), term[index + 2..].trim());
+                Requirement::NotEquals(key.to_owned(), value.to_owned())
+            } else if let Some(index) = term.find('=') {
+                let value = term[index + 1..].trim_start_matches('=').trim();
+                Requirement::Equals(term[..index].trim().to_owned(), value.to_owned())
+            } else if let Some(key) = term.strip_prefix('!') {
+                Requirement::NotExists(key.trim().to_owned())
+            } else {
+                Requirement::Exists(term.to_owned())
+            };
+            let valid = match &requirement {
+                Requirement::Equals(key, value) | Requirement::NotEquals(key, value) =>
+                    valid_key(key) && valid_value(value),
+                Requirement::Exists(key) | Requirement::NotExists(key) => valid_key(key)
+            };
+            if !valid {
+                let message = format!("invalid requirement `{}`", term);
+                return Err(AppError::invalid("selector", message));
+            }
+            requirements.push(requirement);
+        }
+        Ok(LabelSelector { requirements })
+    }
+}
+
+impl TryFrom<String> for LabelSelector {
+    type Error = AppError;
+
+    fn try_from(selector: String) -> Result<LabelSelector, AppError> {
+        LabelSelector::from_str(&selector)
+    }
+}
+
+impl Display for LabelSelector {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> FormatResult {
+        let terms: Vec<String> = self.requirements.iter().map(|requirement| match requirement {
+            Requirement::Equals(key, value) => format!("{}={}", key, value),
+            Requirement::NotEquals(key, value) => format!("{}!={}", key, value),
+            Requirement::Exists(key) => key.to_owned(),
+            Requirement::NotExists(key) => format!("!{}", key)
+        }).collect();
+        write!(formatter, "{}", terms.join(","))
+    }
+}
+
+impl From<LabelSelector> for String {
+    fn from(selector: LabelSelector) -> String {
+        selector.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parse() {
+        let selector: LabelSelector = "env = prod, customer!=42,feature,!beta,tier==1"
+            .parse()
+            .unwrap();
+        assert_eq!(selector.to_string(), "env=prod,customer!=42,feature,!beta,tier=1");
+        assert_eq!(selector.indexed_label(), Some(("env", "prod")));
+        assert!("=prod".parse::<LabelSelector>().is_err());
+        assert!("!".parse::<LabelSelector>().is_err());
+    }
+
+    #[test]
+    fn matches() {
+        let selector: LabelSelector = "env=prod,customer!=42,feature,!beta".parse().unwrap();
+        assert!(selector.matches(&labels(&[("env", "prod"), ("feature", "")])));
+        assert!(selector.matches(&labels(&[("env", "prod"), ("feature", "x"), ("customer", "1")])));
+        assert!(!selector.matches(&labels(&[("env", "prod"), ("feature", "x"), ("customer", "42")])));
+        assert!(!selector.matches(&labels(&[("env", "prod"), ("feature", "x"), ("beta", "")])));
+        assert!(!selector.matches(&labels(&[("env", "dev"), ("feature", "x")])));
+        assert!("".parse::<LabelSelector>().unwrap().matches(&labels(&[])));
+    }
+}
@@ -1,9 +1,12 @@
 use crate::error::AppError;
 use crate::store::Store;
-use crate::schema::Job;
+use crate::schema::{Job, JobFilter, JobRun, JobStats, JobUsage};
 use crate::node::client::NodeClient;
+use crate::health::HealthCheck;
 use std::sync::Arc;
+use std::time::Duration;
 
+#[derive(Clone)]
 pub enum Shard {
     Local(Arc<Store>),
     Remote(Arc<NodeClient>),
@@ -19,25 +22,239 @@ impl Shard {
         match self {
             Shard::Local(store) => Ok(store.push(job)),
             Shard::Remote(client) => client.push(job).await,
-            Shard::Migrating(store, client) => client.push(job).await
+            // the stored copy is dropped so it can't fire on this node as
+            // well, and restored when the target can't be reached.
+            Shard::Migrating(store, client) => {
+                let _fenced = store.fence().read().await;
+                let previous = store.remove(&job.tenant, &job.id);
+                let pushed = client.push(job).await;
+                if let (Err(_), Some(previous)) = (&pushed, previous) {
+                    store.push(previous);
+                }
+                pushed
+            }
+        }
+    }
+    pub async fn get(&self, tenant: &str, id: &str) -> Result<Option<Job>, AppError> {
+        match self {
+            Shard::Local(store) => Ok(store.get(tenant, id)),
+            Shard::Remote(client) => client.get(tenant, id).await,
+            Shard::Migrating(store, client) => match store.get(tenant, id) {
+                Some(job) => Ok(Some(job)),
+                None => client.get(tenant, id).await
+            }
+        }
+    }
+    pub async fn push_batch(&self, jobs: Vec<Job>) -> Result<(), AppError> {
+        match self {
+            Shard::Local(store) => {
+                store.push_batch(jobs);
+                Ok(())
+            },
+            Shard::Remote(client) => client.push_batch(jobs).await,
+            Shard::Migrating(store, client) => {
+                let _fenced = store.fence().read().await;
+                let previous: Vec<Job> = jobs
+                    .iter()
+                    .filter_map(|job| store.remove(&job.tenant, &job.id))
+                    .collect();
+                let pushed = client.push_batch(jobs).await;
+                if pushed.is_err() && !previous.is_empty() {
+                    store.push_batch(previous);
+                }
+                pushed
+            }
+        }
+    }
+    // Jobs kept by another node while this shard couldn't be reached, with
+    // how long ago each was kept, which don't replace newer writes.
+    pub async fn hand_off(&self, jobs: Vec<(Job, Duration)>) -> Result<(), AppError> {
+        match self {
+            Shard::Local(store) => {
+                store.hand_off(jobs);
+                Ok(())
+            },
+            Shard::Remote(client) => client.hand_off(jobs).await,
+            Shard::Migrating(store, client) => {
+                let _fenced = store.fence().read().await;
+                let jobs = store.untouched(jobs);
+                let previous: Vec<Job> = jobs
+                    .iter()
+                    .filter_map(|(job, _)| store.remove(&job.tenant, &job.id))
+                    .collect();
+                let handed_off = client.hand_off(jobs).await;
+                if handed_off.is_err() && !previous.is_empty() {
+                    store.push_batch(previous);
+                }
+                handed_off
+            }
+        }
+    }
+    pub async fn remove(&self, tenant: &str, id: &str) -> Result<Option<Job>, AppError> {
+        match self {
+            Shard::Local(store) => Ok(store.remove(tenant, id)),
+            Shard::Remote(client) => client.remove(tenant, id).await,
+            Shard::Migrating(store, client) => {
+                let local_result = store.remove(tenant, id);
+                let remote_result = client.remove(tenant, id).await?;
+                Ok(local_result.or(remote_result))
+            }
+        }
+    }
+    pub async fn remove_batch(&self, tenant: &str, ids: &[String]) -> Result<Vec<Option<Job>>, AppError> {
+        match self {
+            Shard::Local(store) => Ok(store.remove_batch(tenant, ids)),
+            Shard::Remote(client) => client.remove_batch(tenant, ids).await,
+            Shard::Migrating(store, client) => {
+                let local_result = store.remove_batch(tenant, ids);
+                let remote_result = client.remove_batch(tenant, ids).await?;
+                Ok(
+                    local_result
+                        .into_iter()
+                        .zip(remote_result)
+                        .map(|(local, remote)| local.or(remote))
+                        .collect()
+                )
+            }
+        }
+    }
+    pub async fn pause(&self, tenant: &str, id: &str) -> Result<Option<Job>, AppError> {
+        match self {
+            Shard::Local(store) => Ok(store.pause(tenant, id)),
+            Shard::Remote(client) => client.pause(tenant, id).await,
+            Shard::Migrating(store, client) => {
+                let local_result = store.pause(tenant, id);
+                let remote_result = client.pause(tenant, id).await?;
+                Ok(local_result.or(remote_result))
+            }
         }
     }
-    pub async fn remove(&self, id: &str) -> Result<Option<Job>, AppError> {
+    pub async fn resume(&self, tenant: &str, id: &str) -> Result<Option<Job>, AppError> {
         match self {
-            Shard::Local(store) => Ok(store.remove(id)),
-            Shard::Remote(client) => client.remove(id).await,
+            Shard::Local(store) => Ok(store.resume(tenant, id)),
+            Shard::Remote(client) => client.resume(tenant, id).await,
             Shard::Migrating(store, client) => {
-                let local_result = store.remove(id);
-                let remote_result = client.remove(id).await?;
+                let local_result = store.resume(tenant, id);
+                let remote_result = client.resume(tenant, id).await?;
                 Ok(local_result.or(remote_result))
             }
         }
     }
-    pub async fn clear(&self) -> Result<(), AppError> {
+    pub async fn pause_matching(&self, filter: &JobFilter) -> Result<Vec<Job>, AppError> {
+        match self {
+            Shard::Local(store) => Ok(store.pause_matching(filter)),
+            Shard::Remote(client) => client.pause_matching(filter).await,
+            Shard::Migrating(store, client) => {
+                let mut jobs = store.pause_matching(filter);
+                jobs.extend(client.pause_matching(filter).await?);
+                Ok(jobs)
+            }
+        }
+    }
+    pub async fn resume_matching(&self, filter: &JobFilter) -> Result<Vec<Job>, AppError> {
+        match self {
+            Shard::Local(store) => Ok(store.resume_matching(filter)),
+            Shard::Remote(client) => client.resume_matching(filter).await,
+            Shard::Migrating(store, client) => {
+                let mut jobs = store.resume_matching(filter);
+                jobs.extend(client.resume_matching(filter).await?);
+                Ok(jobs)
+            }
+        }
+    }
+    pub async fn list(&self, filter: &JobFilter) -> Result<Vec<Job>, AppError> {
+        match self {
+            Shard::Local(store) => Ok(store.list(filter)),
+            Shard::Remote(client) => client.list(filter).await,
+            Shard::Migrating(store, client) => {
+                let mut jobs = store.list(filter);
+                jobs.extend(client.list(filter).await?);
+                Ok(jobs)
+            }
+        }
+    }
+    pub async fn remove_matching(&self, filter: &JobFilter) -> Result<Vec<Job>, AppError> {
+        match self {
+            Shard::Local(store) => Ok(store.remove_matching(filter)),
+            Shard::Remote(client) => client.remove_matching(filter).await,
+            Shard::Migrating(store, client) => {
+                let mut jobs = store.remove_matching(filter);
+                jobs.extend(client.remove_matching(filter).await?);
+                Ok(jobs)
+            }
+        }
+    }
+    pub async fn stats(&self, filter: &JobFilter) -> Result<JobStats, AppError> {
+        match self {
+            Shard::Local(store) => Ok(store.stats(filter)),
+            Shard::Remote(client) => client.stats(filter).await,
+            Shard::Migrating(store, client) => {
+                let mut stats = store.stats(filter);
+                stats.merge(client.stats(filter).await?);
+                Ok(stats)
+            }
+        }
+    }
+    pub async fn runs(&self, tenant: &str, id: &str) -> Result<Vec<JobRun>, AppError> {
+        match self {
+            Shard::Local(store) => Ok(store.runs(tenant, id)),
+            Shard::Remote(client) => client.runs(tenant, id).await,
+            Shard::Migrating(store, client) => {
+                let mut runs = store.runs(tenant, id);
+                runs.extend(client.runs(tenant, id).await?);
+                runs.sort_by(|a, b| b.fired.cmp(&a.fired));
+                Ok(runs)
+            }
+        }
+    }
+    pub async fn usage(&self, tenant: &str) -> Result<JobUsage, AppError> {
+        match self {
+            Shard::Local(store) => Ok(store.usage(tenant)),
+            Shard::Remote(client) => client.usage(tenant).await,
+            Shard::Migrating(store, client) => {
+                let mut usage = store.usage(tenant);
+                usage.merge(client.usage(tenant).await?);
+                Ok(usage)
+            }
+        }
+    }
+    pub async fn readiness(&self) -> Vec<HealthCheck> {
+        match self {
+            Shard::Local(store) => store.readiness(),
+            Shard::Remote(client) => vec![client.readiness().await],
+            Shard::Migrating(store, client) => {
+                let mut checks = store.readiness();
+                checks.push(client.readiness().await);
+                checks
+            }
+        }
+    }
+    // Number of jobs queued on this node for the shard.
+    pub fn queue_size(&self) -> usize {
+        match self {
+            Shard::Local(store) | Shard::Migrating(store, _) => store.queue_size(),
+            Shard::Remote(_) => 0
+        }
+    }
+    // Whether both shards are backed by the same store or node, in which
+    // case operations spanning all shards only need to visit one of them.
+    pub fn same_backend(&self, other: &Shard) -> bool {
+        match (self, other) {
+            (Shard::Local(a), Shard::Local(b)) => Arc::ptr_eq(a, b),
+            (Shard::Remote(a), Shard::Remote(b)) => Arc::ptr_eq(a, b),
+            (Shard::Migrating(a, c), Shard::Migrating(b, d)) =>
+                Arc::ptr_eq(a, b) && Arc::ptr_eq(c, d),
+            _ => false
+        }
+    }
+    pub async fn clear(&self, tenant: &str) -> Result<(), AppError> {
         match self {
-            Shard::Local(store) => Ok(store.clear()),
-            Shard::Remote(client) => client.clear().await,
-            _ => Ok(()) // no need to handle this invariant
+            Shard::Local(store) => Ok(store.clear(tenant)),
+            Shard::Remote(client) => client.clear(tenant).await,
+            Shard::Migrating(store, client) => {
+                store.clear(tenant);
+                client.clear(tenant).await
+            }
         }
     }
 }
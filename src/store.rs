@@ -1,102 +1,837 @@
-use sled::Db;
+use sled::{Batch, Db, Tree};
 use rmp_serde::Serializer;
 use priority_queue::PriorityQueue;
-use crate::schema::Job;
+use crate::schema::{shard_index, Job, JobFilter, JobRun, JobStats, JobUsage, DEFAULT_TENANT};
+use crate::config::HistoryConfig;
+use crate::health::HealthCheck;
 use serde::Serialize;
-use std::time::{UNIX_EPOCH, Duration, SystemTime};
-use std::sync::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::time::{UNIX_EPOCH, Duration, Instant, SystemTime};
+use std::sync::{Arc, Mutex};
 
-use crate::keyspace::KEYSPACE_QUEUE;
+use crate::keyspace::{KEYSPACE_QUEUE, KEYSPACE_LABEL, KEYSPACE_GROUP, KEYSPACE_HISTORY, KEYSPACE_MOVED};
+
+// Keyspaces holding the jobs and their indexes, the ones dropped when the
+// jobs are cleared.
+const JOB_KEYSPACES: [[u8; 2]; 3] = [KEYSPACE_QUEUE, KEYSPACE_LABEL, KEYSPACE_GROUP];
+
+// How long the writes of a job are remembered once touches are tracked.
+// Jobs handed off by other nodes only lose against the writes made in
+// that time.
+const TOUCHED_TTL: Duration = Duration::from_secs(60);
+
+// Jobs are queued by tenant and id.
+pub type QueueKey = (String, String);
+
+// Jobs which changed, split into the stored ones and the keys of the erased
+// ones.
+pub type Changes = (Vec<Job>, Vec<QueueKey>);
+
+// What happens to a due job popped from the queue.
+pub enum Popped {
+    // the job fires, replaced by its next occurrence when there is one.
+    Fire(Option<Job>),
+    // the job stays stored until it is queued again by `release_held`.
+    Hold
+}
+
+// When jobs were last written or erased, forgotten after `TOUCHED_TTL`.
+struct Touched {
+    keys: HashMap<QueueKey, Instant>,
+    pruned: Instant
+}
 
 pub struct Store {
-    queue: Mutex<PriorityQueue<String, Duration>>,
-    tree: Db
+    queue: Mutex<PriorityQueue<QueueKey, Duration>>,
+    // jobs stored for each tenant, kept up to date as jobs are written and
+    // erased so quotas don't require a scan.
+    usage: Mutex<HashMap<String, JobUsage>>,
+    // jobs taken out of the queue until the next tick.
+    held: Mutex<Vec<QueueKey>>,
+    // jobs written or erased since the changes were last taken, only kept
+    // once tracking is turned on.
+    changes: Mutex<Option<HashSet<QueueKey>>>,
+    // when jobs were last written or erased, only kept once tracking is
+    // turned on.
+    touched: Mutex<Option<Touched>>,
+    // held by the writes which may put jobs back while the shard of the
+    // store is migrating, and by the final sweep of the migration on its
+    // own, so none land after it.
+    fence: tokio::sync::RwLock<()>,
+    tree: Tree
 }
 
 impl Store {
-    pub fn new(tree: Db) -> Self {
+    // Opens a store for each shard, each in its own tree of the database so
+    // the jobs of a shard can be listed, moved or cleared on their own.
+    pub fn open_shards(db: &Db, count: usize) -> Vec<Arc<Store>> {
+        let trees: Vec<Tree> = (0..count)
+            .map(|shard| db.open_tree(format!("shard-{}", shard)).expect("Failed to open shard"))
+            .collect();
+        Store::split(db, &trees);
+        trees.into_iter().map(|tree| Arc::new(Store::new(tree))).collect()
+    }
+
+    // Jobs and runs used to share the default tree, they are moved to the
+    // tree of their shard. The trees are written before the jobs are
+    // removed from the default one, so an interrupted split is done again.
+    fn split(db: &Db, trees: &[Tree]) {
+        Store::migrate(db);
+        let mut batches: Vec<Batch> = trees.iter().map(|_| Batch::default()).collect();
+        let mut moved = Batch::default();
+        for entry in db.scan_prefix(KEYSPACE_QUEUE) {
+            let (key, serialized) = entry.expect("Failed to extract from store");
+            let item: Job = rmp_serde::decode::from_slice(&serialized)
+                .expect("Failed to deserialize from store");
+            let shard = shard_index(&item.tenant, &item.id) % trees.len();
+            batches[shard].insert(key.clone(), serialized);
+            moved.remove(key);
+        }
+        // history keys hold the tenant and the id, followed by a separator
+        // and the time the job fired.
+        for entry in db.scan_prefix(KEYSPACE_HISTORY) {
+            let (key, serialized) = entry.expect("Failed to extract from store");
+            let owner = &key[KEYSPACE_HISTORY.len()..key.len() - 17];
+            let separator = owner.iter().position(|byte| *byte == 0).unwrap_or(owner.len());
+            let tenant = String::from_utf8_lossy(&owner[..separator]);
+            let id = String::from_utf8_lossy(&owner[(separator + 1).min(owner.len())..]);
+            let shard = shard_index(&tenant, &id) % trees.len();
+            batches[shard].insert(key.clone(), serialized);
+            moved.remove(key);
+        }
+        // the indexes are rebuilt in each tree by `new`.
+        let index = db.scan_prefix(KEYSPACE_LABEL).keys()
+            .chain(db.scan_prefix(KEYSPACE_GROUP).keys());
+        for key in index {
+            moved.remove(key.expect("Failed to extract from store"));
+        }
+        for (tree, batch) in trees.iter().zip(batches) {
+            tree.apply_batch(batch).expect("Failed to split store");
+        }
+        db.apply_batch(moved).expect("Failed to split store");
+    }
+
+    pub fn new(tree: Tree) -> Self {
+        Store::migrate(&tree);
         let mut queue = PriorityQueue::new();
+        let mut usage: HashMap<String, JobUsage> = HashMap::new();
+        // index entries are written again so jobs stored before an index
+        // existed can be found through it.
+        let mut index = Batch::default();
         for serialized in tree.scan_prefix(KEYSPACE_QUEUE).values() {
             let item: Job = rmp_serde::decode::from_slice(
                 &serialized.expect("Failed to extract from store")
             ).expect("Failed to deserialize from store");
-            let priority = item.timestamp.clone();
-            queue.push(item.id.clone(), priority);
+            for index_key in Store::index_keys(&item) {
+                index.insert(index_key, vec![]);
+            }
+            usage.entry(item.tenant.clone()).or_default().add(&item);
+            if !item.paused {
+                let priority = item.timestamp.clone();
+                queue.push(Store::queue_key(&item), priority);
+            }
         }
+        tree.apply_batch(index).expect("Failed to write indexes");
         Store {
             queue: Mutex::new(queue),
+            usage: Mutex::new(usage),
+            held: Mutex::new(Vec::new()),
+            changes: Mutex::new(None),
+            touched: Mutex::new(None),
+            fence: tokio::sync::RwLock::new(()),
             tree: tree
         }
     }
 
-    fn db_key(id: &str) -> Box<[u8]> {
-        let bytes = id.as_bytes();
-        let mut key: Vec<u8> = Vec::with_capacity(KEYSPACE_QUEUE.len() + bytes.len());
-        key.extend(KEYSPACE_QUEUE.iter());
-        key.extend(bytes);
-        key.into_boxed_slice()
+    // Jobs and runs stored before there were tenants are moved to the
+    // default tenant. Since their index entries can't be told apart from
+    // the new ones, the indexes are dropped and rebuilt by `new`.
+    fn migrate(tree: &Tree) {
+        let mut batch = Batch::default();
+        let mut migrated = false;
+        for entry in tree.scan_prefix(KEYSPACE_QUEUE) {
+            let (key, serialized) = entry.expect("Failed to extract from store");
+            let item: Job = rmp_serde::decode::from_slice(&serialized)
+                .expect("Failed to deserialize from store");
+            let db_key = Store::db_key(&item.tenant, &item.id);
+            if key.as_ref() != db_key.as_slice() {
+                batch.remove(key);
+                batch.insert(db_key, serialized);
+                migrated = true;
+            }
+        }
+        if migrated {
+            let index = tree.scan_prefix(KEYSPACE_LABEL).keys()
+                .chain(tree.scan_prefix(KEYSPACE_GROUP).keys());
+            for key in index {
+                batch.remove(key.expect("Failed to extract from store"));
+            }
+        }
+        // the job id is followed by a single separator in the old history
+        // keys, and by the time the job fired.
+        for entry in tree.scan_prefix(KEYSPACE_HISTORY) {
+            let (key, serialized) = entry.expect("Failed to extract from store");
+            let id = &key[KEYSPACE_HISTORY.len()..key.len() - 17];
+            if !id.contains(&0) {
+                let mut history_key = Store::history_prefix(
+                    DEFAULT_TENANT,
+                    &String::from_utf8_lossy(id)
+                );
+                history_key.extend(&key[key.len() - 16..]);
+                batch.remove(key);
+                batch.insert(history_key, serialized);
+            }
+        }
+        tree.apply_batch(batch).expect("Failed to migrate store");
     }
 
-    pub fn next(&self) -> Option<Job> {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Error getting system time");
-        let mut queue = self.queue.lock().expect("Failed to acquire lock");
+    // Every key starts with the tenant, so the data of a tenant can be
+    // scanned and cleared on its own.
+    fn tenant_prefix(keyspace: &[u8], tenant: &str) -> Vec<u8> {
+        let mut prefix: Vec<u8> = Vec::with_capacity(keyspace.len() + tenant.len() + 1);
+        prefix.extend(keyspace);
+        prefix.extend(tenant.as_bytes());
+        prefix.push(0);
+        prefix
+    }
 
-        let has_next = queue
-            .peek()
-            .map(|(_, timestamp)| timestamp.lt(&now) || timestamp.eq(&now))
-            .unwrap_or(false);
+    fn db_key(tenant: &str, id: &str) -> Vec<u8> {
+        let mut key = Store::tenant_prefix(&KEYSPACE_QUEUE, tenant);
+        key.extend(id.as_bytes());
+        key
+    }
 
-        if has_next {
-            queue.pop().map(|(uuid, _)| {
-                let bytes = self.tree.remove(Store::db_key(&uuid))
-                    .expect("Failed to remove item from tree")
-                    .expect("Item in queue does not exist in persistence layer");
+    pub fn queue_key(item: &Job) -> QueueKey {
+        (item.tenant.clone(), item.id.clone())
+    }
 
-                let item: Job = rmp_serde::decode::from_slice(&bytes)
-                    .expect("Failed to deserialize from store");
+    fn now() -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Error getting system time")
+    }
 
-                self.tree.remove(&uuid.as_bytes()).expect("Failed to remove item from tree");
-                item
-            })
-        } else {
-            None
+    // The queue has no direct removal, so bump the item to the top first.
+    fn dequeue(queue: &mut PriorityQueue<QueueKey, Duration>, key: &QueueKey) {
+        if queue.get_priority(key).is_some() {
+            queue.change_priority(key, Duration::new(u64::MAX, 0));
+            queue.pop();
         }
     }
 
-    pub fn push(&self, item: Job) {
-        let priority = item.timestamp.clone();
+    fn encode<T: Serialize>(item: &T) -> Vec<u8> {
         let mut buffer = Vec::new();
         item
             .serialize(&mut Serializer::new(&mut buffer))
             .expect("Failed to serialize callback");
-        self
-            .queue
-            .lock()
-            .expect("Failed to acquire lock")
-            .push(item.id.clone(), priority);
-        self.tree.insert(Store::db_key(&item.id), buffer).unwrap();
+        buffer
     }
 
-    pub fn remove(&self, id: &str) -> Option<Job> {
-        let serialized = self
-            .tree
-            .remove(Store::db_key(id))
-            .expect("Failed to remove callback from storage");
+    fn label_prefix(tenant: &str, key: &str, value: &str) -> Vec<u8> {
+        let mut prefix = Store::tenant_prefix(&KEYSPACE_LABEL, tenant);
+        prefix.extend(key.as_bytes());
+        prefix.push(0);
+        prefix.extend(value.as_bytes());
+        prefix.push(0);
+        prefix
+    }
 
-        serialized.map(|data| {
-            let item: Job = rmp_serde::decode::from_slice(&data).unwrap();
-            let mut queue = self.queue.lock().expect("Failed to acquire lock");
-            queue.change_priority(&item.id, Duration::new(std::u64::MAX, 0));
-            queue.pop();
+    fn group_prefix(tenant: &str, group: &str) -> Vec<u8> {
+        let mut prefix = Store::tenant_prefix(&KEYSPACE_GROUP, tenant);
+        prefix.extend(group.as_bytes());
+        prefix.push(0);
+        prefix
+    }
+
+    fn history_prefix(tenant: &str, id: &str) -> Vec<u8> {
+        let mut prefix = Store::tenant_prefix(&KEYSPACE_HISTORY, tenant);
+        prefix.extend(id.as_bytes());
+        prefix.push(0);
+        prefix
+    }
+
+    // History keys end with the time the job fired, so the runs of a job
+    // are sorted from oldest to newest.
+    fn history_key(tenant: &str, id: &str, run: &JobRun) -> Vec<u8> {
+        let mut key = Store::history_prefix(tenant, id);
+        key.extend(&run.fired.as_nanos().to_be_bytes());
+        key
+    }
+
+    fn history_fired(key: &[u8]) -> Duration {
+        let mut nanos = [0u8; 16];
+        nanos.copy_from_slice(&key[key.len() - 16..]);
+        let nanos = u128::from_be_bytes(nanos);
+        Duration::new((nanos / 1_000_000_000) as u64, (nanos % 1_000_000_000) as u32)
+    }
+
+    // Keys of the secondary index entries pointing to the job.
+    fn index_keys(item: &Job) -> Vec<Vec<u8>> {
+        let mut index_keys: Vec<Vec<u8>> = item.labels
+            .iter()
+            .map(|(key, value)| Store::label_prefix(&item.tenant, key, value))
+            .collect();
+        if let Some(group) = &item.group {
+            index_keys.push(Store::group_prefix(&item.tenant, group));
+        }
+        for index_key in index_keys.iter_mut() {
+            index_key.extend(item.id.as_bytes());
+        }
+        index_keys
+    }
+
+    // Reads the jobs of the index entries of the tenant starting with the
+    // prefix.
+    fn indexed<'a>(&'a self, tenant: &'a str, prefix: Vec<u8>) -> impl Iterator<Item = Job> + 'a {
+        let ids: Vec<String> = self.tree
+            .scan_prefix(&prefix)
+            .keys()
+            .map(|index_key| {
+                let index_key = index_key.expect("Failed to extract from store");
+                String::from_utf8_lossy(&index_key[prefix.len()..]).into_owned()
+            })
+            .collect();
+        ids.into_iter().filter_map(move |id| self.read(tenant, &id))
+    }
+
+    // Adds the job to the batch, replacing the index entries of the
+    // version of the job currently stored.
+    fn stage_write(&self, batch: &mut Batch, item: &Job) {
+        self.changed(Store::queue_key(item));
+        let mut usage = self.usage.lock().expect("Failed to acquire lock");
+        let tenant_usage = usage.entry(item.tenant.clone()).or_default();
+        if let Some(previous) = self.read(&item.tenant, &item.id) {
+            for index_key in Store::index_keys(&previous) {
+                batch.remove(index_key);
+            }
+            tenant_usage.remove(&previous);
+        }
+        for index_key in Store::index_keys(item) {
+            batch.insert(index_key, vec![]);
+        }
+        tenant_usage.add(item);
+        batch.insert(Store::db_key(&item.tenant, &item.id), Store::encode(item));
+    }
+
+    fn stage_erase(&self, batch: &mut Batch, item: &Job) {
+        self.changed(Store::queue_key(item));
+        for index_key in Store::index_keys(item) {
+            batch.remove(index_key);
+        }
+        batch.remove(Store::db_key(&item.tenant, &item.id));
+        let mut usage = self.usage.lock().expect("Failed to acquire lock");
+        if let Some(tenant_usage) = usage.get_mut(&item.tenant) {
+            tenant_usage.remove(item);
+        }
+    }
+
+    fn write(&self, item: &Job) {
+        let mut batch = Batch::default();
+        self.stage_write(&mut batch, item);
+        self.tree.apply_batch(batch).expect("Failed to write callback");
+    }
+
+    fn erase(&self, item: &Job) {
+        let mut batch = Batch::default();
+        self.stage_erase(&mut batch, item);
+        self.tree.apply_batch(batch).expect("Failed to remove callback from storage");
+    }
+
+    fn read(&self, tenant: &str, id: &str) -> Option<Job> {
+        self.tree
+            .get(Store::db_key(tenant, id))
+            .expect("Failed to read callback from storage")
+            .map(|data| {
+                rmp_serde::decode::from_slice(&data)
+                    .expect("Failed to deserialize from store")
+            })
+    }
+
+    fn scan(&self, prefix: Vec<u8>) -> impl Iterator<Item = Job> {
+        self.tree.scan_prefix(prefix).values().map(|serialized| {
+            rmp_serde::decode::from_slice(
+                &serialized.expect("Failed to extract from store")
+            ).expect("Failed to deserialize from store")
+        })
+    }
+
+    // Jobs matching the filter. When the filter is on a group or requires a
+    // label, only the jobs in the matching index of the tenant are looked
+    // at. Without a tenant, the jobs of every tenant are.
+    fn find(&self, filter: &JobFilter) -> Vec<Job> {
+        let label = filter.selector.as_ref().and_then(|selector| selector.indexed_label());
+        let tenant = filter.tenant.as_deref();
+        let candidates: Box<dyn Iterator<Item = Job>> = match (tenant, &filter.group, label) {
+            (Some(tenant), Some(group), _) =>
+                Box::new(self.indexed(tenant, Store::group_prefix(tenant, group))),
+            (Some(tenant), None, Some((key, value))) =>
+                Box::new(self.indexed(tenant, Store::label_prefix(tenant, key, value))),
+            (Some(tenant), None, None) =>
+                Box::new(self.scan(Store::tenant_prefix(&KEYSPACE_QUEUE, tenant))),
+            (None, _, _) => Box::new(self.scan(KEYSPACE_QUEUE.to_vec()))
+        };
+        candidates.filter(|item| filter.matches(item)).collect()
+    }
+
+    #[cfg(test)]
+    pub fn next(&self) -> Option<Job> {
+        self.next_with(|_| Popped::Fire(None))
+    }
+
+    // Pops the next due job which `decide` fires. The job replacing it is
+    // stored while the queue is still locked, so the job can't be seen
+    // missing in between.
+    pub fn next_with<F>(&self, mut decide: F) -> Option<Job>
+    where F: FnMut(&Job) -> Popped {
+        let now = Store::now();
+        let mut queue = self.queue.lock().expect("Failed to acquire lock");
+
+        loop {
+            let has_next = queue
+                .peek()
+                .map(|(_, timestamp)| timestamp.lt(&now) || timestamp.eq(&now))
+                .unwrap_or(false);
+            if !has_next {
+                return None;
+            }
+            let ((tenant, uuid), _) = queue.pop()?;
+            let item = self.read(&tenant, &uuid)
+                .expect("Item in queue does not exist in persistence layer");
+            match decide(&item) {
+                Popped::Hold => {
+                    self.held.lock().expect("Failed to acquire lock").push((tenant, uuid));
+                },
+                Popped::Fire(rescheduled) => {
+                    self.erase(&item);
+
+                    self.tree.remove(&uuid.as_bytes()).expect("Failed to remove item from tree");
+                    if let Some(rescheduled) = rescheduled {
+                        Store::enqueue(&mut queue, &rescheduled);
+                        self.write(&rescheduled);
+                    }
+                    return Some(item);
+                }
+            }
+        }
+    }
+
+    // Queues the held jobs again, unless they were removed since.
+    pub fn release_held(&self) {
+        let mut queue = self.queue.lock().expect("Failed to acquire lock");
+        let held: Vec<QueueKey> = self.held.lock().expect("Failed to acquire lock").drain(..).collect();
+        for (tenant, id) in held {
+            if let Some(item) = self.read(&tenant, &id) {
+                Store::enqueue(&mut queue, &item);
+            }
+        }
+    }
+
+    fn enqueue(queue: &mut PriorityQueue<QueueKey, Duration>, item: &Job) {
+        if item.paused {
+            Store::dequeue(queue, &Store::queue_key(item));
+        } else {
+            queue.push(Store::queue_key(item), item.timestamp);
+        }
+    }
+
+    pub fn push(&self, item: Job) {
+        let mut queue = self.queue.lock().expect("Failed to acquire lock");
+        Store::enqueue(&mut queue, &item);
+        self.write(&item);
+    }
+
+    // Writes all of the jobs in a single batch. When an id is repeated the
+    // last job wins, as each write is staged against the stored version.
+    pub fn push_batch(&self, items: Vec<Job>) {
+        let mut seen = HashSet::new();
+        let items: Vec<Job> = items
+            .into_iter()
+            .rev()
+            .filter(|item| seen.insert(Store::queue_key(item)))
+            .collect();
+        let mut queue = self.queue.lock().expect("Failed to acquire lock");
+        let mut batch = Batch::default();
+        for item in &items {
+            self.stage_write(&mut batch, item);
+        }
+        self.tree.apply_batch(batch).expect("Failed to write batch");
+        for item in &items {
+            Store::enqueue(&mut queue, item);
+        }
+    }
+
+    // Removes all of the jobs in a single batch, returning the removed job
+    // for each id if it existed. A repeated id was already removed by its
+    // first occurrence.
+    pub fn remove_batch(&self, tenant: &str, ids: &[String]) -> Vec<Option<Job>> {
+        let mut queue = self.queue.lock().expect("Failed to acquire lock");
+        let mut seen = HashSet::new();
+        let removed: Vec<Option<Job>> = ids
+            .iter()
+            .map(|id| match seen.insert(id) {
+                true => self.read(tenant, id),
+                false => None
+            })
+            .collect();
+        let mut batch = Batch::default();
+        for item in removed.iter().flatten() {
+            self.stage_erase(&mut batch, item);
+        }
+        self.tree.apply_batch(batch).expect("Failed to remove batch");
+        for item in removed.iter().flatten() {
+            Store::dequeue(&mut queue, &Store::queue_key(item));
+        }
+        removed
+    }
+
+    pub fn remove(&self, tenant: &str, id: &str) -> Option<Job> {
+        let mut queue = self.queue.lock().expect("Failed to acquire lock");
+        let item = self.read(tenant, id)?;
+        self.erase(&item);
+        Store::dequeue(&mut queue, &Store::queue_key(&item));
+        Some(item)
+    }
+
+    pub fn get(&self, tenant: &str, id: &str) -> Option<Job> {
+        self.read(tenant, id)
+    }
+
+    pub fn list(&self, filter: &JobFilter) -> Vec<Job> {
+        self.find(filter)
+    }
+
+    pub fn stats(&self, filter: &JobFilter) -> JobStats {
+        let mut stats = JobStats::default();
+        for item in self.find(filter) {
+            stats.add(&item);
+        }
+        stats
+    }
+
+    pub fn remove_matching(&self, filter: &JobFilter) -> Vec<Job> {
+        let mut queue = self.queue.lock().expect("Failed to acquire lock");
+        let removed = self.find(filter);
+        let mut batch = Batch::default();
+        for item in &removed {
+            self.stage_erase(&mut batch, item);
+        }
+        self.tree.apply_batch(batch).expect("Failed to remove batch");
+        for item in &removed {
+            Store::dequeue(&mut queue, &Store::queue_key(item));
+        }
+        removed
+    }
+
+    // Removes up to `limit` jobs of every tenant accepted by the predicate.
+    // Jobs can't be fired or rescheduled while they are looked up.
+    pub fn take<F>(&self, limit: usize, predicate: F) -> Vec<Job>
+    where F: Fn(&Job) -> bool {
+        let mut queue = self.queue.lock().expect("Failed to acquire lock");
+        let taken: Vec<Job> = self.scan(KEYSPACE_QUEUE.to_vec())
+            .filter(|item| predicate(item))
+            .take(limit)
+            .collect();
+        let mut batch = Batch::default();
+        for item in &taken {
+            self.stage_erase(&mut batch, item);
+        }
+        self.tree.apply_batch(batch).expect("Failed to remove batch");
+        for item in &taken {
+            Store::dequeue(&mut queue, &Store::queue_key(item));
+        }
+        taken
+    }
+
+    // Keeps the job in storage but takes it out of the queue.
+    pub fn pause(&self, tenant: &str, id: &str) -> Option<Job> {
+        let mut queue = self.queue.lock().expect("Failed to acquire lock");
+        self.read(tenant, id).map(|mut item| {
+            if !item.paused {
+                item.paused = true;
+                Store::dequeue(&mut queue, &Store::queue_key(&item));
+                self.write(&item);
+            }
             item
         })
     }
 
-    pub fn clear(&self) {
-        self.tree.clear().expect("Failed to clear storage");
-        self.queue.lock().expect("Failed to acquire lock").clear();
+    // Puts a paused job back in the queue. Cron jobs which missed their
+    // execution while paused are handled according to their misfire policy.
+    pub fn resume(&self, tenant: &str, id: &str) -> Option<Job> {
+        let mut queue = self.queue.lock().expect("Failed to acquire lock");
+        self.read(tenant, id).map(|mut item| {
+            if item.paused {
+                self.enqueue_resumed(&mut queue, &mut item);
+            }
+            item
+        })
+    }
+
+    pub fn pause_matching(&self, filter: &JobFilter) -> Vec<Job> {
+        let mut queue = self.queue.lock().expect("Failed to acquire lock");
+        self.find(filter)
+            .into_iter()
+            .filter(|item| !item.paused)
+            .map(|mut item| {
+                item.paused = true;
+                Store::dequeue(&mut queue, &Store::queue_key(&item));
+                self.write(&item);
+                item
+            })
+            .collect()
+    }
+
+    pub fn resume_matching(&self, filter: &JobFilter) -> Vec<Job> {
+        let mut queue = self.queue.lock().expect("Failed to acquire lock");
+        self.find(filter)
+            .into_iter()
+            .filter(|item| item.paused)
+            .map(|mut item| {
+                self.enqueue_resumed(&mut queue, &mut item);
+                item
+            })
+            .collect()
+    }
+
+    fn enqueue_resumed(&self, queue: &mut PriorityQueue<QueueKey, Duration>, item: &mut Job) {
+        item.paused = false;
+        match item.resume_timestamp(Store::now()) {
+            Ok(timestamp) => item.timestamp = timestamp,
+            Err(_) => error!("{} - Failed to compute next schedule on resume", item.id)
+        }
+        queue.push(Store::queue_key(item), item.timestamp);
+        self.write(item);
+    }
+
+    // Records the run and drops the runs of the job which are no longer
+    // retained.
+    pub fn record_run(&self, tenant: &str, id: &str, run: &JobRun, history: &HistoryConfig) {
+        if history.max_runs == 0 {
+            return;
+        }
+        let mut batch = Batch::default();
+        batch.insert(Store::history_key(tenant, id, run), Store::encode(run));
+        let keys: Vec<_> = self.tree
+            .scan_prefix(Store::history_prefix(tenant, id))
+            .keys()
+            .map(|key| key.expect("Failed to extract from store"))
+            .collect();
+        let expired = Store::now().checked_sub(history.max_age).unwrap_or_default();
+        // the run being recorded takes one of the slots.
+        let excess = (keys.len() + 1).saturating_sub(history.max_runs);
+        for (index, key) in keys.iter().enumerate() {
+            if index < excess || Store::history_fired(key) < expired {
+                batch.remove(key);
+            }
+        }
+        self.tree.apply_batch(batch).expect("Failed to write run");
+    }
+
+    // Runs of the job, starting with the most recent one.
+    pub fn runs(&self, tenant: &str, id: &str) -> Vec<JobRun> {
+        self.tree
+            .scan_prefix(Store::history_prefix(tenant, id))
+            .values()
+            .rev()
+            .map(|serialized| {
+                rmp_serde::decode::from_slice(
+                    &serialized.expect("Failed to extract from store")
+                ).expect("Failed to deserialize from store")
+            })
+            .collect()
+    }
+
+    // Drops the runs which are too old, including the ones of jobs which
+    // were deleted since.
+    pub fn prune_runs(&self, history: &HistoryConfig) {
+        let expired = Store::now().checked_sub(history.max_age).unwrap_or_default();
+        let mut batch = Batch::default();
+        for key in self.tree.scan_prefix(KEYSPACE_HISTORY).keys() {
+            let key = key.expect("Failed to extract from store");
+            if Store::history_fired(&key) < expired {
+                batch.remove(key);
+            }
+        }
+        self.tree.apply_batch(batch).expect("Failed to prune runs");
+    }
+
+    // The store only exists once the queue is rebuilt, so what's left to
+    // check is that the database still answers.
+    pub fn readiness(&self) -> Vec<HealthCheck> {
+        let queue = HealthCheck::ok(
+            "queue".to_owned(),
+            Some(format!("{} jobs queued", self.queue_size()))
+        );
+        vec![self.check(), queue]
+    }
+
+    // Whether the tree can be read.
+    pub fn check(&self) -> HealthCheck {
+        match self.tree.get(KEYSPACE_QUEUE) {
+            Ok(_) => HealthCheck::ok("store".to_owned(), None),
+            Err(err) => HealthCheck::failing("store".to_owned(), err.to_string())
+        }
+    }
+
+    pub fn usage(&self, tenant: &str) -> JobUsage {
+        self.usage
+            .lock()
+            .expect("Failed to acquire lock")
+            .get(tenant)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn fence(&self) -> &tokio::sync::RwLock<()> {
+        &self.fence
+    }
+
+    // Node the shard of the store was migrated to, which serves it from then
+    // on.
+    pub fn moved_to(&self) -> Option<String> {
+        self.tree
+            .get(KEYSPACE_MOVED)
+            .expect("Failed to read from store")
+            .map(|node| String::from_utf8_lossy(&node).into_owned())
+    }
+
+    pub fn set_moved_to(&self, node: &str) {
+        self.tree.insert(KEYSPACE_MOVED, node.as_bytes()).expect("Failed to write to store");
+        self.tree.flush().expect("Failed to flush store");
+    }
+
+    pub fn queue_size(&self) -> usize {
+        self.queue.lock().expect("Failed to acquire lock").len()
+    }
+
+    // Starts keeping the jobs which change, so they can be sent to the
+    // replicas of the shard.
+    pub fn track_changes(&self) {
+        let mut changes = self.changes.lock().expect("Failed to acquire lock");
+        if changes.is_none() {
+            *changes = Some(HashSet::new());
+        }
+    }
+
+    // Starts remembering when each job was last written or erased, so jobs
+    // handed off by other nodes don't overwrite newer ones.
+    pub fn track_touches(&self) {
+        let mut touched = self.touched.lock().expect("Failed to acquire lock");
+        if touched.is_none() {
+            *touched = Some(Touched { keys: HashMap::new(), pruned: Instant::now() });
+        }
+    }
+
+    fn changed(&self, key: QueueKey) {
+        if let Some(touched) = self.touched.lock().expect("Failed to acquire lock").as_mut() {
+            let now = Instant::now();
+            if now.duration_since(touched.pruned) >= TOUCHED_TTL {
+                touched.keys.retain(|_, at| now.duration_since(*at) < TOUCHED_TTL);
+                touched.pruned = now;
+            }
+            touched.keys.insert(key.clone(), now);
+        }
+        if let Some(changes) = self.changes.lock().expect("Failed to acquire lock").as_mut() {
+            changes.insert(key);
+        }
+    }
+
+    // Leaves out the jobs written or erased here since another node kept
+    // them, each given with how long ago it was kept.
+    pub fn untouched(&self, jobs: Vec<(Job, Duration)>) -> Vec<(Job, Duration)> {
+        let touched = self.touched.lock().expect("Failed to acquire lock");
+        let touched = match touched.as_ref() {
+            Some(touched) => touched,
+            None => return jobs
+        };
+        jobs
+            .into_iter()
+            .filter(|(job, age)| touched.keys
+                .get(&Store::queue_key(job))
+                .map_or(true, |at| at.elapsed() >= *age))
+            .collect()
+    }
+
+    // Stores the jobs another node kept while this one couldn't be reached,
+    // unless they changed here since.
+    pub fn hand_off(&self, jobs: Vec<(Job, Duration)>) {
+        let jobs: Vec<Job> = self.untouched(jobs).into_iter().map(|(job, _)| job).collect();
+        if !jobs.is_empty() {
+            self.push_batch(jobs);
+        }
+    }
+
+    // Jobs which changed since the last call, split into the stored ones and
+    // the keys of the erased ones.
+    pub fn take_changes(&self) -> Changes {
+        let keys: Vec<QueueKey> = match self.changes.lock().expect("Failed to acquire lock").as_mut() {
+            Some(changes) => changes.drain().collect(),
+            None => return (Vec::new(), Vec::new())
+        };
+        let mut jobs = Vec::new();
+        let mut removed = Vec::new();
+        for (tenant, id) in keys {
+            match self.read(&tenant, &id) {
+                Some(item) => jobs.push(item),
+                None => removed.push((tenant, id))
+            }
+        }
+        (jobs, removed)
+    }
+
+    // Applies the jobs sent by the node acting as the owner of the shard.
+    pub fn apply(&self, jobs: Vec<Job>, removed: &[QueueKey], replace: bool) {
+        if replace {
+            self.clear_all();
+        }
+        self.push_batch(jobs);
+        for (tenant, id) in removed {
+            self.remove(tenant, id);
+        }
+    }
+
+    // Drops every job of every tenant. Their history is left for
+    // `prune_runs` to age out.
+    pub fn clear_all(&self) {
+        let mut queue = self.queue.lock().expect("Failed to acquire lock");
+        let mut batch = Batch::default();
+        for item in self.scan(KEYSPACE_QUEUE.to_vec()) {
+            self.changed(Store::queue_key(&item));
+        }
+        for keyspace in JOB_KEYSPACES.iter() {
+            for key in self.tree.scan_prefix(keyspace).keys() {
+                batch.remove(key.expect("Failed to extract from store"));
+            }
+        }
+        self.tree.apply_batch(batch).expect("Failed to clear storage");
+        self.usage.lock().expect("Failed to acquire lock").clear();
+        self.held.lock().expect("Failed to acquire lock").clear();
+        queue.clear();
+    }
+
+    // Drops the jobs of the tenant, leaving their history like `clear_all`.
+    pub fn clear(&self, tenant: &str) {
+        let mut queue = self.queue.lock().expect("Failed to acquire lock");
+        let mut batch = Batch::default();
+        for item in self.scan(Store::tenant_prefix(&KEYSPACE_QUEUE, tenant)) {
+            self.changed(Store::queue_key(&item));
+        }
+        for keyspace in JOB_KEYSPACES.iter() {
+            for key in self.tree.scan_prefix(Store::tenant_prefix(keyspace, tenant)).keys() {
+                batch.remove(key.expect("Failed to extract from store"));
+            }
+        }
+        self.tree.apply_batch(batch).expect("Failed to clear storage");
+        self.usage.lock().expect("Failed to acquire lock").remove(tenant);
+        let queued: Vec<QueueKey> = queue
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|(queued_tenant, _)| queued_tenant == tenant)
+            .cloned()
+            .collect();
+        for key in queued {
+            Store::dequeue(&mut queue, &key);
+        }
     }
 }
 
@@ -104,16 +839,23 @@ impl Store {
 mod test {
     use std::time::{UNIX_EPOCH, SystemTime, Duration};
     use uuid::Uuid;
-    use crate::schema::Job;
+    use crate::schema::{named_id, Job, MisfirePolicy};
+    use std::collections::BTreeMap;
     use std::thread;
     use std::sync::Arc;
     use super::*;
 
+    // Each test has a database of its own, with the jobs in a tree of it.
+    fn open(name: &str) -> Tree {
+        let db = sled::open(format!(".test/{}", name)).expect("Failed to open store");
+        db.open_tree("jobs").expect("Failed to open tree")
+    }
+
     #[test]
     fn duplicates() {
-        let tree = sled::open(".test/duplicates").expect("Failed to open store");
+        let tree = open("duplicates");
         let store = Store::new(tree);
-        store.clear();
+        store.clear(DEFAULT_TENANT);
         let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
 
         store.push(Job {
@@ -122,7 +864,13 @@ mod test {
             body: "{}".to_owned(),
             timestamp: now - Duration::from_millis(200),
             id: Uuid::new_v4().to_string(),
-            schedule: None
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
         });
         store.push(Job {
             method: "POST".to_owned(),
@@ -130,7 +878,13 @@ mod test {
             body: "{}".to_owned(),
             timestamp: now - Duration::from_millis(100),
             id: Uuid::new_v4().to_string(),
-            schedule: None
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
         });
         store.push(Job {
             method: "POST".to_owned(),
@@ -138,7 +892,13 @@ mod test {
             body: "{}".to_owned(),
             timestamp: now - Duration::from_millis(200),
             id: Uuid::new_v4().to_string(),
-            schedule: None
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
         });
 
         assert_eq!(store.next().unwrap().url, "2");
@@ -148,9 +908,9 @@ mod test {
 
     #[test]
     fn remove() {
-        let tree = sled::open(".test/remove").expect("Failed to open store");
+        let tree = open("remove");
         let store = Store::new(tree);
-        store.clear();
+        store.clear(DEFAULT_TENANT);
         let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
         let id = Uuid::new_v4().to_string();
         store.push(Job {
@@ -159,7 +919,13 @@ mod test {
             body: "{}".to_owned(),
             timestamp: now - Duration::from_millis(100),
             id: id.clone(),
-            schedule: None
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
         });
 
         store.push(Job {
@@ -168,9 +934,15 @@ mod test {
             body: "{}".to_owned(),
             timestamp: now - Duration::from_millis(100),
             id: Uuid::new_v4().to_string(),
-            schedule: None
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
         });
-        store.remove(&id);
+        store.remove(DEFAULT_TENANT, &id);
         assert_eq!(store.next().unwrap().url, "2");
         assert!(store.next().is_none());
     }
@@ -178,9 +950,9 @@ mod test {
     #[test]
     fn resume() {
         {
-            let tree = sled::open(".test/resume").expect("Failed to open store");
+            let tree = open("resume");
             let store = Store::new(tree);
-            store.clear();
+            store.clear(DEFAULT_TENANT);
             let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
             store.push(Job {
                 method: "POST".to_owned(),
@@ -188,11 +960,17 @@ mod test {
                 body: "{}".to_owned(),
                 timestamp: now - Duration::from_millis(100),
                 id: Uuid::new_v4().to_string(),
-                schedule: None
+                schedule: None,
+                paused: false,
+                misfire: MisfirePolicy::Skip,
+                labels: BTreeMap::new(),
+                group: None,
+                name: None,
+                tenant: DEFAULT_TENANT.to_owned()
             });
         }
 
-        let tree = sled::open(".test/resume").expect("Failed to open store");
+        let tree = open("resume");
         let store = Store::new(tree);
         assert_eq!(store.next().unwrap().url, "1");
         assert_eq!(store.next(), None);
@@ -200,9 +978,9 @@ mod test {
 
     #[test]
     fn not_has_next() {
-        let tree = sled::open(".test/not_has_next").expect("Failed to open store");
+        let tree = open("not_has_next");
         let store = Store::new(tree);
-        store.clear();
+        store.clear(DEFAULT_TENANT);
         let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
 
         store.push(Job {
@@ -211,16 +989,544 @@ mod test {
             body: "{}".to_owned(),
             timestamp: now + Duration::from_millis(100),
             id: Uuid::new_v4().to_string(),
-            schedule: None
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
         });
 
         assert_eq!(store.next(), None);
     }
 
 
+    #[test]
+    fn pause_resume() {
+        let tree = open("pause_resume");
+        let store = Store::new(tree);
+        store.clear(DEFAULT_TENANT);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let id = Uuid::new_v4().to_string();
+        store.push(Job {
+            method: "POST".to_owned(),
+            url: "1".to_owned(),
+            body: "{}".to_owned(),
+            timestamp: now - Duration::from_millis(100),
+            id: id.clone(),
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
+        });
+
+        assert!(store.pause(DEFAULT_TENANT, &id).unwrap().paused);
+        assert_eq!(store.next(), None);
+        assert!(!store.resume(DEFAULT_TENANT, &id).unwrap().paused);
+        assert_eq!(store.next().unwrap().url, "1");
+        assert!(store.pause(DEFAULT_TENANT, &id).is_none());
+    }
+
+    #[test]
+    fn resume_cron_misfire() {
+        let tree = open("resume_cron_misfire");
+        let store = Store::new(tree);
+        store.clear(DEFAULT_TENANT);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let job = Job {
+            method: "POST".to_owned(),
+            url: "1".to_owned(),
+            body: "{}".to_owned(),
+            timestamp: now - Duration::from_millis(1000),
+            id: "skip".to_owned(),
+            schedule: Some("0 0 0 1 1 *".to_owned()),
+            paused: true,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
+        };
+        store.push(job.clone());
+        store.push(Job {
+            id: "fire_once".to_owned(),
+            misfire: MisfirePolicy::FireOnce,
+            ..job
+        });
+
+        let resumed = store.resume_matching(&JobFilter::default());
+        assert_eq!(resumed.len(), 2);
+        assert!(store.remove(DEFAULT_TENANT, "skip").unwrap().timestamp > now);
+        assert_eq!(store.next().unwrap().id, "fire_once");
+        assert_eq!(store.next(), None);
+    }
+
+    #[test]
+    fn batch() {
+        let tree = open("batch");
+        let store = Store::new(tree);
+        store.clear(DEFAULT_TENANT);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let job = || Job {
+            method: "POST".to_owned(),
+            url: "0".to_owned(),
+            body: "{}".to_owned(),
+            timestamp: now - Duration::from_millis(100),
+            id: "0".to_owned(),
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
+        };
+        let jobs: Vec<Job> = (0..3).map(|index| Job {
+            url: index.to_string(),
+            id: index.to_string(),
+            ..job()
+        }).collect();
+        store.push_batch(jobs);
+
+        let removed = store.remove_batch(DEFAULT_TENANT, &["1".to_owned(), "missing".to_owned()]);
+        assert_eq!(removed[0].as_ref().unwrap().id, "1");
+        assert!(removed[1].is_none());
+        assert!(store.next().is_some());
+        assert!(store.next().is_some());
+        assert_eq!(store.next(), None);
+
+        // the last job of a repeated id wins, leaving nothing of the others.
+        store.clear(DEFAULT_TENANT);
+        let grouped = |group: &str| Job {
+            group: Some(group.to_owned()),
+            ..job()
+        };
+        store.push_batch(vec![grouped("first"), grouped("second")]);
+        assert!(store.list(&JobFilter::group("first").in_tenant(DEFAULT_TENANT)).is_empty());
+        assert_eq!(store.list(&JobFilter::group("second").in_tenant(DEFAULT_TENANT)).len(), 1);
+        assert_eq!(store.usage(DEFAULT_TENANT).pending, 1);
+        let removed = store.remove_batch(DEFAULT_TENANT, &["0".to_owned(), "0".to_owned()]);
+        assert!(removed[0].is_some());
+        assert!(removed[1].is_none());
+        assert_eq!(store.usage(DEFAULT_TENANT).pending, 0);
+    }
+
+    #[test]
+    fn labels() {
+        let tree = open("labels");
+        let store = Store::new(tree);
+        store.clear(DEFAULT_TENANT);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let job = |id: &str, env: &str| Job {
+            method: "POST".to_owned(),
+            url: id.to_owned(),
+            body: "{}".to_owned(),
+            timestamp: now + Duration::from_millis(1000),
+            id: id.to_owned(),
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: vec![("env".to_owned(), env.to_owned())].into_iter().collect(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
+        };
+        store.push(job("1", "prod"));
+        store.push(job("2", "prod"));
+        store.push(job("3", "dev"));
+        // relabelling must drop the previous index entry.
+        store.push(job("2", "dev"));
+
+        let filter = |selector: &str| JobFilter {
+            selector: Some(selector.parse().unwrap()),
+            ..JobFilter::default()
+        };
+        let prod = store.list(&filter("env=prod"));
+        assert_eq!(prod.len(), 1);
+        assert_eq!(prod[0].id, "1");
+        assert_eq!(store.list(&filter("env!=prod")).len(), 2);
+
+        let removed = store.remove_matching(&filter("env=dev"));
+        assert_eq!(removed.len(), 2);
+        assert!(store.list(&filter("env=dev")).is_empty());
+        assert_eq!(store.tree.scan_prefix(KEYSPACE_LABEL).count(), 1);
+    }
+
+    #[test]
+    fn groups() {
+        let tree = open("groups");
+        let store = Store::new(tree);
+        store.clear(DEFAULT_TENANT);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let job = |id: &str, group: &str, delay: u64| Job {
+            method: "POST".to_owned(),
+            url: id.to_owned(),
+            body: "{}".to_owned(),
+            timestamp: now + Duration::from_millis(delay),
+            id: id.to_owned(),
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: Some(group.to_owned()),
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
+        };
+        store.push(job("1", "reports", 2000));
+        store.push(job("2", "reports", 1000));
+        store.push(job("3", "reminders", 1000));
+        store.pause(DEFAULT_TENANT, "2");
+
+        let stats = store.stats(&JobFilter::group("reports"));
+        assert_eq!(stats.count, 2);
+        assert_eq!(stats.paused, 1);
+        assert_eq!(stats.next_fire, Some((now + Duration::from_millis(2000)).as_millis() as u64));
+
+        assert_eq!(store.remove_matching(&JobFilter::group("reports")).len(), 2);
+        assert!(store.list(&JobFilter::group("reports")).is_empty());
+        assert_eq!(store.list(&JobFilter::group("reminders")).len(), 1);
+    }
+
+    #[test]
+    fn history() {
+        let tree = open("history");
+        tree.clear().unwrap();
+        let store = Store::new(tree);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let history = HistoryConfig {
+            max_runs: 2,
+            max_age: Duration::from_secs(60)
+        };
+        let run = |id: &str, age: u64| {
+            let fired = now - Duration::from_secs(age);
+            let run = JobRun {
+                scheduled: fired,
+                fired,
+                latency: Duration::from_millis(1),
+                status: Some(200),
+                error: None,
+                body: Some("{}".to_owned())
+            };
+            store.record_run(DEFAULT_TENANT, id, &run, &history);
+            run
+        };
+
+        // only the two most recent runs are kept.
+        run("1", 30);
+        let second = run("1", 20);
+        let third = run("1", 10);
+        assert_eq!(store.runs(DEFAULT_TENANT, "1"), vec![third, second]);
+
+        // runs are kept after the job is gone, until they expire.
+        run("2", 120);
+        let recent = run("2", 1);
+        assert_eq!(store.runs(DEFAULT_TENANT, "2"), vec![recent]);
+        run("3", 30);
+        let history = HistoryConfig {
+            max_runs: 2,
+            max_age: Duration::from_secs(15)
+        };
+        store.prune_runs(&history);
+        assert_eq!(store.runs(DEFAULT_TENANT, "1").len(), 1);
+        assert_eq!(store.runs(DEFAULT_TENANT, "2").len(), 1);
+        assert!(store.runs(DEFAULT_TENANT, "3").is_empty());
+
+        // clearing the jobs leaves their runs to expire.
+        store.clear(DEFAULT_TENANT);
+        store.clear_all();
+        assert_eq!(store.runs(DEFAULT_TENANT, "1").len(), 1);
+    }
+
+    #[test]
+    fn tenants() {
+        let tree = open("tenants");
+        let store = Store::new(tree);
+        store.clear("acme");
+        store.clear("globex");
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let job = |tenant: &str| Job {
+            method: "POST".to_owned(),
+            url: tenant.to_owned(),
+            body: "{}".to_owned(),
+            timestamp: now - Duration::from_millis(100),
+            id: named_id("reports", "daily"),
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: Some("reports".to_owned()),
+            name: Some("daily".to_owned()),
+            tenant: tenant.to_owned()
+        };
+        // the same id in two tenants is two different jobs.
+        store.push(job("acme"));
+        store.push(job("globex"));
+        assert_eq!(store.get("acme", &named_id("reports", "daily")).unwrap().url, "acme");
+        let reports = JobFilter::group("reports");
+        assert_eq!(store.list(&reports.clone().in_tenant("globex")).len(), 1);
+        assert_eq!(store.list(&reports).len(), 2);
+        assert!(store.get(DEFAULT_TENANT, &named_id("reports", "daily")).is_none());
+
+        store.clear("acme");
+        assert!(store.list(&JobFilter::default().in_tenant("acme")).is_empty());
+        assert_eq!(store.next().unwrap().tenant, "globex");
+        assert_eq!(store.next(), None);
+    }
+
+    #[test]
+    fn usage() {
+        let tree = open("usage");
+        let store = Store::new(tree);
+        store.clear("acme");
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let job = Job {
+            method: "POST".to_owned(),
+            url: "1".to_owned(),
+            body: "{}".to_owned(),
+            timestamp: now + Duration::from_secs(60),
+            id: "1".to_owned(),
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: "acme".to_owned()
+        };
+        store.push(job.clone());
+        // replacing a job doesn't count it twice.
+        store.push(job.clone());
+        store.push(Job {
+            id: "2".to_owned(),
+            schedule: Some("0 0 0 1 1 *".to_owned()),
+            ..job.clone()
+        });
+        assert_eq!(store.usage("acme"), JobUsage { pending: 1, recurring: 1 });
+        assert_eq!(store.usage(DEFAULT_TENANT), JobUsage::default());
+
+        store.pause("acme", "1");
+        store.remove("acme", "2");
+        assert_eq!(store.usage("acme"), JobUsage { pending: 1, recurring: 0 });
+        store.clear("acme");
+        assert_eq!(store.usage("acme"), JobUsage::default());
+    }
+
+    #[test]
+    fn take() {
+        let tree = open("take");
+        let store = Store::new(tree);
+        store.clear(DEFAULT_TENANT);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let job = Job {
+            method: "POST".to_owned(),
+            url: "1".to_owned(),
+            body: "{}".to_owned(),
+            timestamp: now - Duration::from_millis(100),
+            id: "1".to_owned(),
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
+        };
+        for id in &["1", "2", "3"] {
+            store.push(Job { id: id.to_string(), ..job.clone() });
+        }
+        let taken = store.take(1, |item| item.id != "1");
+        assert_eq!(taken.len(), 1);
+        assert!(store.get(DEFAULT_TENANT, &taken[0].id).is_none());
+        assert_eq!(store.take(10, |item| item.id != "1").len(), 1);
+        // taken jobs are out of the queue as well.
+        assert_eq!(store.next().unwrap().id, "1");
+        assert!(store.next().is_none());
+    }
+
+    #[test]
+    fn hand_off() {
+        let tree = open("hand-off");
+        let store = Store::new(tree);
+        store.clear(DEFAULT_TENANT);
+        store.track_touches();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let job = Job {
+            method: "POST".to_owned(),
+            url: "kept".to_owned(),
+            body: "{}".to_owned(),
+            timestamp: now + Duration::from_secs(60),
+            id: "updated".to_owned(),
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
+        };
+        store.push(Job { url: "newer".to_owned(), ..job.clone() });
+        store.push(Job { id: "removed".to_owned(), ..job.clone() });
+        store.remove(DEFAULT_TENANT, "removed");
+        let kept_a_while_ago = Duration::from_secs(10);
+        store.hand_off(vec![
+            (job.clone(), kept_a_while_ago),
+            (Job { id: "removed".to_owned(), ..job.clone() }, kept_a_while_ago),
+            (Job { id: "untouched".to_owned(), ..job.clone() }, kept_a_while_ago)
+        ]);
+
+        // the jobs written or removed since they were kept stay as they are.
+        assert_eq!(store.get(DEFAULT_TENANT, "updated").unwrap().url, "newer");
+        assert!(store.get(DEFAULT_TENANT, "removed").is_none());
+        assert_eq!(store.get(DEFAULT_TENANT, "untouched").unwrap().url, "kept");
+
+        // writes made before the jobs were kept are older.
+        thread::sleep(Duration::from_millis(20));
+        store.hand_off(vec![(job, Duration::from_millis(10))]);
+        assert_eq!(store.get(DEFAULT_TENANT, "updated").unwrap().url, "kept");
+    }
+
+    #[test]
+    fn held() {
+        let tree = open("held");
+        let store = Store::new(tree);
+        store.clear(DEFAULT_TENANT);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let job = Job {
+            method: "POST".to_owned(),
+            url: "1".to_owned(),
+            body: "{}".to_owned(),
+            timestamp: now - Duration::from_millis(100),
+            id: "held".to_owned(),
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: BTreeMap::new(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
+        };
+        store.push(job.clone());
+        store.push(Job { id: "removed".to_owned(), ..job.clone() });
+        assert!(store.next_with(|_| Popped::Hold).is_none());
+        // held jobs stay stored until they are released.
+        assert!(store.next().is_none());
+        assert!(store.get(DEFAULT_TENANT, "held").is_some());
+        store.remove(DEFAULT_TENANT, "removed");
+        store.release_held();
+        assert_eq!(store.queue_size(), 1);
+
+        let rescheduled = Job { timestamp: now + Duration::from_secs(60), ..job.clone() };
+        let fired = store.next_with(|_| Popped::Fire(Some(rescheduled.clone()))).unwrap();
+        assert_eq!(fired.id, "held");
+        assert_eq!(store.get(DEFAULT_TENANT, "held").unwrap().timestamp, rescheduled.timestamp);
+    }
+
+    #[test]
+    fn split() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let job = Job {
+            method: "POST".to_owned(),
+            url: "1".to_owned(),
+            body: "{}".to_owned(),
+            timestamp: now - Duration::from_millis(100),
+            id: "shared".to_owned(),
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: vec![("env".to_owned(), "prod".to_owned())].into_iter().collect(),
+            group: None,
+            name: None,
+            tenant: "acme".to_owned()
+        };
+        let run = JobRun {
+            scheduled: now,
+            fired: now,
+            latency: Duration::from_millis(1),
+            status: Some(200),
+            error: None,
+            body: None
+        };
+        // jobs as they were stored when every shard shared the default tree.
+        let db = sled::open(".test/split").expect("Failed to open store");
+        db.clear().unwrap();
+        let shared = Store::new((*db).clone());
+        shared.push(job.clone());
+        shared.record_run("acme", "shared", &run, &HistoryConfig::default());
+
+        let stores = Store::open_shards(&db, 127);
+        let shard = shard_index("acme", "shared");
+        assert_eq!(stores[shard].get("acme", "shared"), Some(job));
+        assert_eq!(stores[shard].runs("acme", "shared"), vec![run]);
+        let filter = JobFilter {
+            selector: Some("env=prod".parse().unwrap()),
+            ..JobFilter::default()
+        };
+        assert_eq!(stores[shard].list(&filter.in_tenant("acme")).len(), 1);
+        assert_eq!(db.len(), 0);
+        assert_eq!(stores.iter().map(|store| store.queue_size()).sum::<usize>(), 1);
+
+        stores[shard].clear_all();
+        assert!(stores[shard].get("acme", "shared").is_none());
+        assert_eq!(stores[shard].queue_size(), 0);
+    }
+
+    #[test]
+    fn migrate() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let job = Job {
+            method: "POST".to_owned(),
+            url: "1".to_owned(),
+            body: "{}".to_owned(),
+            timestamp: now - Duration::from_millis(100),
+            id: "legacy".to_owned(),
+            schedule: None,
+            paused: false,
+            misfire: MisfirePolicy::Skip,
+            labels: vec![("env".to_owned(), "prod".to_owned())].into_iter().collect(),
+            group: None,
+            name: None,
+            tenant: DEFAULT_TENANT.to_owned()
+        };
+        let run = JobRun {
+            scheduled: now,
+            fired: now,
+            latency: Duration::from_millis(1),
+            status: Some(200),
+            error: None,
+            body: None
+        };
+        // keys as they were written before there were tenants.
+        let tree = open("migrate");
+        tree.clear().unwrap();
+        let mut key = KEYSPACE_QUEUE.to_vec();
+        key.extend(b"legacy");
+        tree.insert(key, Store::encode(&job)).unwrap();
+        let mut key = KEYSPACE_LABEL.to_vec();
+        key.extend(b"env\0prod\0legacy");
+        tree.insert(key, vec![]).unwrap();
+        let mut key = KEYSPACE_HISTORY.to_vec();
+        key.extend(b"legacy\0");
+        key.extend(&run.fired.as_nanos().to_be_bytes());
+        tree.insert(key, Store::encode(&run)).unwrap();
+
+        let store = Store::new(tree);
+        let filter = JobFilter {
+            selector: Some("env=prod".parse().unwrap()),
+            ..JobFilter::default()
+        };
+        assert_eq!(store.list(&filter.in_tenant(DEFAULT_TENANT)).len(), 1);
+        assert_eq!(store.tree.scan_prefix(KEYSPACE_LABEL).count(), 1);
+        assert_eq!(store.runs(DEFAULT_TENANT, "legacy"), vec![run]);
+        assert_eq!(store.next().unwrap().id, "legacy");
+    }
+
     #[test]
     fn multi_threaded() {
-        let tree = sled::open(".test/multi_threaded").unwrap();
+        let tree = open("multi_threaded");
         let store = Store::new(tree);
         let store_arc = Arc::new(store);
         let clone = Arc::clone(&store_arc);
@@ -0,0 +1,142 @@
+// Checks applied to the jobs coming in through the api, so that what gets
+// stored can actually be sent once it fires.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hyper::Uri;
+use crate::error::AppError;
+use crate::schema::Job;
+
+pub const MAX_URL_LENGTH: usize = 2048;
+pub const MAX_TENANT_LENGTH: usize = 64;
+pub const MAX_BODY_SIZE: usize = 64 * 1024;
+// A job slightly in the past fires right away, further than that it's more
+// likely to be a mistake such as using seconds instead of milliseconds.
+pub const MAX_PAST: Duration = Duration::from_secs(60 * 60);
+pub const MAX_FUTURE: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+
+const SCHEMES: [&str; 2] = ["http", "https"];
+const METHODS: [&str; 7] = ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"];
+
+pub fn validate_url(url: &str) -> Result<(), AppError> {
+    if url.is_empty() {
+        return Err(AppError::invalid("url", "must not be empty"));
+    }
+    if url.len() > MAX_URL_LENGTH {
+        let message = format!("must be at most {} characters", MAX_URL_LENGTH);
+        return Err(AppError::invalid("url", message));
+    }
+    let uri: Uri = url.parse().map_err(|err| AppError::invalid("url", err))?;
+    match uri.scheme_str() {
+        Some(scheme) if SCHEMES.contains(&scheme) => {},
+        _ => return Err(AppError::invalid("url", "scheme must be http or https"))
+    }
+    match uri.host() {
+        Some(host) if !host.is_empty() => Ok(()),
+        _ => Err(AppError::invalid("url", "must have a host"))
+    }
+}
+
+pub fn validate_method(method: &str) -> Result<(), AppError> {
+    match METHODS.contains(&method) {
+        true => Ok(()),
+        false => {
+            let message = format!("must be one of {}", METHODS.join(", "));
+            Err(AppError::invalid("method", message))
+        }
+    }
+}
+
+pub fn validate_body(body: &str) -> Result<(), AppError> {
+    match body.len() <= MAX_BODY_SIZE {
+        true => Ok(()),
+        false => {
+            let message = format!("must be at most {} bytes", MAX_BODY_SIZE);
+            Err(AppError::invalid("body", message))
+        }
+    }
+}
+
+pub fn validate_timestamp(timestamp: Duration, now: Duration) -> Result<(), AppError> {
+    if timestamp + MAX_PAST < now {
+        let message = format!("must be at most {} seconds in the past", MAX_PAST.as_secs());
+        return Err(AppError::invalid("timestamp", message));
+    }
+    if timestamp > now + MAX_FUTURE {
+        return Err(AppError::invalid("timestamp", "must be at most 10 years in the future"));
+    }
+    Ok(())
+}
+
+// Tenants are part of the storage keys, where NUL ends them.
+pub fn validate_tenant(tenant: &str) -> Result<(), AppError> {
+    match !tenant.is_empty() && tenant.len() <= MAX_TENANT_LENGTH && !tenant.contains('\0') {
+        true => Ok(()),
+        false => {
+            let message = format!("must be 1 to {} characters without NUL", MAX_TENANT_LENGTH);
+            Err(AppError::invalid("tenant", message))
+        }
+    }
+}
+
+// The timestamp of cron jobs is computed from their schedule, so only the
+// one of regular jobs is checked.
+pub fn validate_job(job: &Job) -> Result<(), AppError> {
+    validate_url(&job.url)?;
+    validate_method(&job.method)?;
+    validate_body(&job.body)?;
+    if job.schedule.is_none() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Error getting system time");
+        validate_timestamp(job.timestamp, now)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn urls() {
+        assert!(validate_url("http://localhost:3000/callback?id=1").is_ok());
+        assert!(validate_url("https://example.com").is_ok());
+        assert_eq!(validate_url("").unwrap_err().to_string(), "Invalid url: must not be empty");
+        assert_eq!(
+            validate_url("ftp://example.com").unwrap_err().to_string(),
+            "Invalid url: scheme must be http or https"
+        );
+        assert!(validate_url("/callback").is_err());
+        assert!(validate_url("http://exa mple.com").is_err());
+        let long_url = "http://example.com/".to_owned() + &"a".repeat(MAX_URL_LENGTH);
+        assert!(validate_url(&long_url).is_err());
+    }
+
+    #[test]
+    fn timestamps() {
+        let now = Duration::from_secs(1_600_000_000);
+        assert!(validate_timestamp(now, now).is_ok());
+        assert!(validate_timestamp(now - Duration::from_secs(60), now).is_ok());
+        assert!(validate_timestamp(now - MAX_PAST - Duration::from_secs(1), now).is_err());
+        assert!(validate_timestamp(now + MAX_FUTURE + Duration::from_secs(1), now).is_err());
+        // seconds instead of milliseconds.
+        assert!(validate_timestamp(Duration::from_millis(1_600_000_000), now).is_err());
+    }
+
+    #[test]
+    fn methods_and_bodies() {
+        assert!(validate_method("PATCH").is_ok());
+        assert!(validate_method("CONNECT").is_err());
+        assert!(validate_method("post").is_err());
+        assert!(validate_body(&"a".repeat(MAX_BODY_SIZE)).is_ok());
+        assert!(validate_body(&"a".repeat(MAX_BODY_SIZE + 1)).is_err());
+    }
+
+    #[test]
+    fn tenants() {
+        assert!(validate_tenant("acme").is_ok());
+        assert!(validate_tenant("").is_err());
+        assert!(validate_tenant("ac\0me").is_err());
+        assert!(validate_tenant(&"a".repeat(MAX_TENANT_LENGTH + 1)).is_err());
+    }
+}
@@ -7,6 +7,7 @@ extern crate futures;
 
 use schedule_m8::schema::*;
 use schedule_m8::ScheduleM8;
+use schedule_m8::config::{Config, EgressConfig, JwtConfig, JwtKey};
 
 use hyper::{Client, Server, Body, Request, Response, Method};
 use hyper::service::{make_service_fn, service_fn};
@@ -15,6 +16,7 @@ use std::time::{UNIX_EPOCH, SystemTime, Duration};
 use std::sync::{Mutex, Arc};
 
 use bytes::buf::BufExt;
+use jsonwebtoken::{encode, EncodingKey, Header};
 use uuid::Uuid;
 
 fn random_port() -> u16 {
@@ -24,20 +26,32 @@ fn random_port() -> u16 {
 }
 
 macro_rules! test_case {
-    ($name:ident $test:expr) => {
+    ($name:ident |$client:ident, $app_port:ident, $server_port:ident, $requests:ident| $test:expr) => {
+        test_case!($name |$client, $app_port, $server_port, $requests| |_: &mut Config| {}, $test);
+    };
+    // the configuration can be changed before the app is started.
+    (
+        $name:ident |$client:ident, $app_port:ident, $server_port:ident, $requests:ident|
+        $configure:expr, $test:expr
+    ) => {
         #[tokio::test]
         async fn $name() {
-            let app_port = random_port();
+            let $app_port = random_port();
             let data_dir = ".test/".to_owned() + &Uuid::new_v4().to_string();
-            let app = ScheduleM8::start(
-                "0.0.0.0:".to_owned() + &app_port.to_string(),
+            let mut config = Config::new(
+                "0.0.0.0:".to_owned() + &$app_port.to_string(),
                 data_dir.clone()
-            ).await;
+            );
+            // the callbacks are sent to the test server.
+            config.egress.allowed_networks.push("127.0.0.0/8".parse().unwrap());
+            let configure = $configure;
+            configure(&mut config);
+            let app = ScheduleM8::start(config).await;
             
-            let client = Client::new();
+            let $client = Client::new();
 
-            let requests: Arc<Mutex<Vec<Request<Body>>>> = Arc::new(Mutex::new(Vec::new()));
-            let service_requests = requests.clone();
+            let $requests: Arc<Mutex<Vec<Request<Body>>>> = Arc::new(Mutex::new(Vec::new()));
+            let service_requests = $requests.clone();
             let service = make_service_fn(move|_| {
                 let requests = service_requests.clone();
                 async {
@@ -55,8 +69,8 @@ macro_rules! test_case {
                 }
             });
 
-            let server_port = random_port();
-            let server_address = ([0, 0, 0, 0], server_port).into();
+            let $server_port = random_port();
+            let server_address = ([0, 0, 0, 0], $server_port).into();
             let server = Server::bind(&server_address).serve(service);
             tokio::spawn(async move {
                 server.await.unwrap();
@@ -72,7 +86,7 @@ macro_rules! test_case {
     }
 }
 
-test_case!(create_callback {
+test_case!(create_callback |client, app_port, server_port, requests| {
     let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
     let callback = V1Job {
         payload: "{}".to_owned(),
@@ -95,7 +109,7 @@ test_case!(create_callback {
     assert_eq!(requests.len(), 1);
 });
 
-test_case!(cancel_callback {
+test_case!(cancel_callback |client, app_port, server_port, requests| {
     let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
 
     let callback = V1Job {
@@ -138,7 +152,7 @@ test_case!(cancel_callback {
     assert_eq!(requests.len(), 0);
 });
 
-test_case!(delete_triggered_callback {
+test_case!(delete_triggered_callback |client, app_port, server_port, requests| {
     let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
 
     let callback = V1Job {
@@ -182,7 +196,7 @@ test_case!(delete_triggered_callback {
     assert_eq!(requests.len(), 1);
 });
 
-test_case!(missing_id {
+test_case!(missing_id |client, app_port, server_port, requests| {
     let mut request = Request::new(
         Body::from("")
     );
@@ -195,3 +209,844 @@ test_case!(missing_id {
     let response = client.request(request).await.unwrap();
     assert_eq!(response.status(), 404);
 });
+
+test_case!(pause_resume_callback |client, app_port, server_port, requests| {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+
+    let callback = V1Job {
+        payload: "{}".to_owned(),
+        timestamp: (now + 1000) as u64,
+        url: "http://127.0.0.1:".to_owned() + &server_port.to_string() + "/test",
+    };
+
+    let mut request = Request::new(
+        Body::from(serde_json::to_string(&callback).unwrap())
+    );
+    *request.uri_mut() = (
+        "http://localhost:".to_owned() + &app_port.to_string() + "/scheduler/api"
+    ).parse().unwrap();
+    *request.method_mut() = Method::POST;
+    let response = client.request(request).await.unwrap();
+
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let key: V1JobKey = serde_json::from_reader(body.reader()).unwrap();
+
+    request = Request::new(Body::from(""));
+    *request.uri_mut() = (
+        "http://localhost:".to_owned() +
+            &app_port.to_string() +
+            "/api/job/" +
+            (&key.key.to_string()) +
+            "/pause"
+    ).parse().unwrap();
+    *request.method_mut() = Method::POST;
+    let response = client.request(request).await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    let mut interval = tokio::time::interval(Duration::from_millis(2000));
+    interval.tick().await;
+    interval.tick().await;
+    assert_eq!(requests.lock().unwrap().len(), 0);
+
+    request = Request::new(Body::from("{}"));
+    *request.uri_mut() = (
+        "http://localhost:".to_owned() + &app_port.to_string() + "/api/jobs/resume"
+    ).parse().unwrap();
+    *request.method_mut() = Method::POST;
+    let response = client.request(request).await.unwrap();
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let resumed: V2JobIds = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(resumed.ids, vec![key.key]);
+
+    interval.tick().await;
+    assert_eq!(requests.lock().unwrap().len(), 1);
+});
+
+test_case!(batch_callbacks |client, app_port, server_port, requests| {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let url = "http://127.0.0.1:".to_owned() + &server_port.to_string() + "/test";
+    let body = format!(
+        "{{\"url\": \"{}\", \"body\": \"{{}}\", \"timestamp\": {}}}\n\
+         {{\"url\": \"{}\", \"body\": \"{{}}\", \"timestamp\": {}}}\n\
+         {{\"url\": \"{}\"}}\n",
+        url, now + 1000, url, now + 1000, url
+    );
+
+    let mut request = Request::new(Body::from(body));
+    *request.uri_mut() = (
+        "http://localhost:".to_owned() + &app_port.to_string() + "/api/jobs:batch"
+    ).parse().unwrap();
+    *request.method_mut() = Method::POST;
+    request.headers_mut().insert("Content-Type", "application/x-ndjson".parse().unwrap());
+    let response = client.request(request).await.unwrap();
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let results: Vec<V2BatchResult> = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].status, 200);
+    assert_eq!(results[1].status, 200);
+    assert_eq!(results[2].status, 400);
+
+    let ids = vec![results[0].id.clone().unwrap(), "missing".to_owned()];
+    let mut request = Request::new(Body::from(serde_json::to_string(&ids).unwrap()));
+    *request.uri_mut() = (
+        "http://localhost:".to_owned() + &app_port.to_string() + "/api/jobs:batch"
+    ).parse().unwrap();
+    *request.method_mut() = Method::DELETE;
+    let response = client.request(request).await.unwrap();
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let results: Vec<V2BatchResult> = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(results[0].status, 204);
+    assert_eq!(results[1].status, 404);
+
+    let mut interval = tokio::time::interval(Duration::from_millis(2000));
+    interval.tick().await;
+    interval.tick().await;
+    assert_eq!(requests.lock().unwrap().len(), 1);
+});
+
+test_case!(label_selectors |client, app_port, server_port, requests| {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let url = "http://127.0.0.1:".to_owned() + &server_port.to_string() + "/test";
+    let jobs: Vec<serde_json::Value> = vec!["1", "2", "2"].into_iter().map(|customer| {
+        serde_json::json!({
+            "url": url,
+            "body": "{}",
+            "timestamp": (now + 1000) as u64,
+            "labels": { "customer": customer }
+        })
+    }).collect();
+
+    let mut request = Request::new(Body::from(serde_json::to_string(&jobs).unwrap()));
+    *request.uri_mut() = (
+        "http://localhost:".to_owned() + &app_port.to_string() + "/api/jobs:batch"
+    ).parse().unwrap();
+    *request.method_mut() = Method::POST;
+    client.request(request).await.unwrap();
+
+    let mut request = Request::new(Body::from(""));
+    *request.uri_mut() = (
+        "http://localhost:".to_owned() + &app_port.to_string() + "/api/jobs?selector=customer%3D2"
+    ).parse().unwrap();
+    *request.method_mut() = Method::GET;
+    let response = client.request(request).await.unwrap();
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let listed: Vec<serde_json::Value> = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(listed.len(), 2);
+    assert_eq!(listed[0]["labels"]["customer"], "2");
+
+    let mut request = Request::new(Body::from(""));
+    *request.uri_mut() = (
+        "http://localhost:".to_owned() + &app_port.to_string() + "/api/jobs?selector=customer%3D2"
+    ).parse().unwrap();
+    *request.method_mut() = Method::DELETE;
+    let response = client.request(request).await.unwrap();
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let removed: V2JobIds = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(removed.ids.len(), 2);
+
+    // empty values would match every job, so they're refused.
+    for (query, field) in [("url=", "url"), ("group=", "group"), ("selector=%2C", "selector")] {
+        let mut request = Request::new(Body::from(""));
+        *request.uri_mut() = (
+            "http://localhost:".to_owned() + &app_port.to_string() + "/api/jobs?" + query
+        ).parse().unwrap();
+        *request.method_mut() = Method::DELETE;
+        let response = client.request(request).await.unwrap();
+        assert_eq!(response.status(), 400);
+        let body = hyper::body::aggregate(response).await.unwrap();
+        let error: V2Error = serde_json::from_reader(body.reader()).unwrap();
+        assert_eq!(error.details.get("field").unwrap(), field);
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_millis(2000));
+    interval.tick().await;
+    interval.tick().await;
+    assert_eq!(requests.lock().unwrap().len(), 1);
+});
+
+test_case!(cron_job |client, app_port, server_port, requests| {
+    let base = "http://localhost:".to_owned() + &app_port.to_string();
+    let body = serde_json::json!({
+        "url": "http://127.0.0.1:".to_owned() + &server_port.to_string(),
+        "body": "{}",
+        "schedule": "0 0 4 * * *"
+    });
+    let mut request = Request::new(Body::from(body.to_string()));
+    *request.uri_mut() = (base.clone() + "/api/cron").parse().unwrap();
+    *request.method_mut() = Method::POST;
+    let response = client.request(request).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let created: serde_json::Value = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(created["schedule"], "0 0 4 * * *");
+
+    // the job is stored as a cron job, rather than firing once.
+    let response = client.get((base + "/api/jobs").parse().unwrap()).await.unwrap();
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let listed: Vec<serde_json::Value> = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0]["id"], created["id"]);
+    assert_eq!(listed[0]["schedule"], "0 0 4 * * *");
+    assert_eq!(requests.lock().unwrap().len(), 0);
+});
+
+test_case!(named_job_upsert |client, app_port, server_port, requests| {
+    let url = "http://127.0.0.1:".to_owned() + &server_port.to_string() + "/test";
+    let base = "http://localhost:".to_owned() + &app_port.to_string();
+    let job_url = base.clone() + "/api/groups/reports/jobs/daily";
+    let declare_at = |job_url: &str, schedule: &str| {
+        let body = serde_json::json!({
+            "url": url,
+            "body": "{}",
+            "schedule": schedule
+        });
+        let mut request = Request::new(Body::from(body.to_string()));
+        *request.uri_mut() = job_url.parse().unwrap();
+        *request.method_mut() = Method::PUT;
+        request
+    };
+    let declare = |schedule: &str| declare_at(&job_url, schedule);
+
+    let response = client.request(declare("0 0 4 * * *")).await.unwrap();
+    assert_eq!(response.status(), 201);
+    let response = client.request(declare("0 0 5 * * *")).await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    let mut request = Request::new(Body::from(""));
+    *request.uri_mut() = job_url.parse().unwrap();
+    *request.method_mut() = Method::GET;
+    let response = client.request(request).await.unwrap();
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let job: serde_json::Value = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(job["schedule"], "0 0 5 * * *");
+    assert_eq!(job["group"], "reports");
+    assert_eq!(job["name"], "daily");
+
+    let mut request = Request::new(Body::from(""));
+    *request.uri_mut() = (base.clone() + "/api/jobs").parse().unwrap();
+    *request.method_mut() = Method::GET;
+    let response = client.request(request).await.unwrap();
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let listed: Vec<serde_json::Value> = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(listed.len(), 1);
+
+    let mut request = Request::new(Body::from(""));
+    *request.uri_mut() = job_url.parse().unwrap();
+    *request.method_mut() = Method::DELETE;
+    let response = client.request(request).await.unwrap();
+    assert_eq!(response.status(), 204);
+
+    // segments of the path are decoded.
+    let encoded_url = base.clone() + "/api/groups/monthly%20reports/jobs/daily";
+    let response = client.request(declare_at(&encoded_url, "0 0 4 * * *")).await.unwrap();
+    assert_eq!(response.status(), 201);
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let job: serde_json::Value = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(job["group"], "monthly reports");
+
+    // the separator of the group and name in the id can't be part of them.
+    for (path, field) in &[("/api/groups/a::_b/jobs/c", "group"), ("/api/groups/a/jobs/b::_c", "name")] {
+        let response = client.request(declare_at(&(base.clone() + path), "0 0 4 * * *")).await.unwrap();
+        assert_eq!(response.status(), 400);
+        let body = hyper::body::aggregate(response).await.unwrap();
+        let error: V2Error = serde_json::from_reader(body.reader()).unwrap();
+        assert_eq!(error.details.get("field").unwrap(), field);
+    }
+    assert_eq!(requests.lock().unwrap().len(), 0);
+});
+
+test_case!(group_lifecycle |client, app_port, server_port, requests| {
+    let url = "http://127.0.0.1:".to_owned() + &server_port.to_string() + "/test";
+    let base = "http://localhost:".to_owned() + &app_port.to_string();
+    let call = |method: Method, path: &str, body: String| {
+        let mut request = Request::new(Body::from(body));
+        *request.uri_mut() = (base.clone() + path).parse().unwrap();
+        *request.method_mut() = method;
+        client.request(request)
+    };
+
+    let cron = V1CronJob {
+        schedule: "0 0 4 * * *".to_owned(),
+        payload: "{}".to_owned(),
+        name: "nightly".to_owned(),
+        group: "reports".to_owned(),
+        url: url.clone()
+    };
+    call(Method::POST, "/scheduler/api/cron", serde_json::to_string(&cron).unwrap()).await.unwrap();
+    let body = serde_json::json!({ "url": url, "body": "{}", "schedule": "0 0 5 * * *" });
+    call(Method::PUT, "/api/groups/reports/jobs/daily", body.to_string()).await.unwrap();
+
+    let response = call(Method::GET, "/api/groups/reports/jobs", "".to_owned()).await.unwrap();
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let listed: Vec<serde_json::Value> = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(listed.len(), 2);
+
+    let response = call(Method::POST, "/api/groups/reports/pause", "".to_owned()).await.unwrap();
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let paused: V2JobIds = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(paused.ids.len(), 2);
+
+    let response = call(Method::GET, "/api/groups/reports/stats", "".to_owned()).await.unwrap();
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let stats: serde_json::Value = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(stats["count"], 2);
+    assert_eq!(stats["paused"], 2);
+    assert_eq!(stats["next_fire"], serde_json::Value::Null);
+
+    let response = call(Method::DELETE, "/api/groups/reports", "".to_owned()).await.unwrap();
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let removed: V2JobIds = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(removed.ids.len(), 2);
+    assert_eq!(requests.lock().unwrap().len(), 0);
+});
+
+test_case!(job_runs |client, app_port, server_port, requests| {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let callback = V1Job {
+        payload: "{}".to_owned(),
+        timestamp: (now + 500) as u64,
+        url: "http://127.0.0.1:".to_owned() + &server_port.to_string() + "/test",
+    };
+
+    let mut request = Request::new(
+        Body::from(serde_json::to_string(&callback).unwrap())
+    );
+    *request.uri_mut() = (
+        "http://localhost:".to_owned() + &app_port.to_string() + "/scheduler/api"
+    ).parse().unwrap();
+    *request.method_mut() = Method::POST;
+    let response = client.request(request).await.unwrap();
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let key: V1JobKey = serde_json::from_reader(body.reader()).unwrap();
+
+    let mut interval = tokio::time::interval(Duration::from_millis(2000));
+    interval.tick().await;
+    interval.tick().await;
+    assert_eq!(requests.lock().unwrap().len(), 1);
+
+    // the job no longer exists once it fired, but its runs are kept.
+    let uri = "http://localhost:".to_owned() + &app_port.to_string() + "/api/job/" + &key.key;
+    let mut request = Request::new(Body::from(""));
+    *request.uri_mut() = uri.parse().unwrap();
+    *request.method_mut() = Method::DELETE;
+    let response = client.request(request).await.unwrap();
+    assert_eq!(response.status(), 404);
+
+    let response = client.get((uri + "/runs").parse().unwrap()).await.unwrap();
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let runs: Vec<serde_json::Value> = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(runs.len(), 1);
+    assert_eq!(runs[0]["status"], 200);
+    assert_eq!(runs[0]["body"], "{}");
+    assert_eq!(runs[0]["scheduled"], callback.timestamp);
+    assert!(runs[0]["fired"].as_u64().unwrap() >= callback.timestamp);
+});
+
+test_case!(metrics |client, app_port, server_port, requests| {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let callback = V1Job {
+        payload: "{}".to_owned(),
+        timestamp: (now + 500) as u64,
+        url: "http://127.0.0.1:".to_owned() + &server_port.to_string() + "/test",
+    };
+    let base = "http://localhost:".to_owned() + &app_port.to_string();
+
+    let mut request = Request::new(
+        Body::from(serde_json::to_string(&callback).unwrap())
+    );
+    *request.uri_mut() = (base.clone() + "/scheduler/api").parse().unwrap();
+    *request.method_mut() = Method::POST;
+    client.request(request).await.unwrap();
+    client.get((base.clone() + "/api/job/missing/runs").parse().unwrap()).await.unwrap();
+
+    let mut interval = tokio::time::interval(Duration::from_millis(2000));
+    interval.tick().await;
+    interval.tick().await;
+    assert_eq!(requests.lock().unwrap().len(), 1);
+
+    let response = client.get((base + "/metrics").parse().unwrap()).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body = hyper::body::to_bytes(response).await.unwrap();
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+    assert!(metrics.contains(
+        r#"schedule_m8_http_requests_total{method="POST",route="/scheduler/api",status="200"}"#
+    ));
+    assert!(metrics.contains(
+        r#"schedule_m8_http_requests_total{method="GET",route="/api/job/:id/runs",status="200"}"#
+    ));
+    assert!(metrics.contains(r#"schedule_m8_callbacks_total{host="127.0.0.1",status="200"}"#));
+    assert!(metrics.contains(r#"schedule_m8_callback_duration_seconds_count{host="127.0.0.1"}"#));
+    assert!(metrics.contains("schedule_m8_fire_lateness_seconds_count"));
+    assert!(metrics.contains("schedule_m8_queue_size 0"));
+});
+
+test_case!(health_checks |client, app_port, server_port, requests| {
+    let base = "http://localhost:".to_owned() + &app_port.to_string();
+    let mut interval = tokio::time::interval(Duration::from_millis(1000));
+    interval.tick().await;
+    interval.tick().await;
+
+    let response = client.get((base.clone() + "/healthz").parse().unwrap()).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let report: serde_json::Value = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(report["status"], "ok");
+    assert_eq!(report["checks"][0]["name"], "scheduler");
+
+    let response = client.get((base + "/readyz").parse().unwrap()).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let report: serde_json::Value = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(report["status"], "ok");
+    let names: Vec<&str> = report["checks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|check| check["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, vec!["store", "queue"]);
+    assert_eq!(requests.lock().unwrap().len(), 0);
+});
+
+test_case!(error_responses |client, app_port, server_port, requests| {
+    let base = "http://localhost:".to_owned() + &app_port.to_string();
+    let body = serde_json::json!({
+        "url": "http://127.0.0.1:".to_owned() + &server_port.to_string(),
+        "body": "{}",
+        "schedule": "every day"
+    });
+    let mut request = Request::new(Body::from(body.to_string()));
+    *request.uri_mut() = (base.clone() + "/api/cron").parse().unwrap();
+    *request.method_mut() = Method::POST;
+    let response = client.request(request).await.unwrap();
+    assert_eq!(response.status(), 400);
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let error: V2Error = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(error.code, "validation_error");
+    assert!(error.message.starts_with("Invalid schedule: "));
+    assert_eq!(error.details.get("field").unwrap(), "schedule");
+
+    let response = client.get((base.clone() + "/api/jobs?colour=red").parse().unwrap()).await.unwrap();
+    assert_eq!(response.status(), 400);
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let error: V2Error = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(error.details.get("field").unwrap(), "colour");
+
+    // without auth anyone could pick a tenant, so none can.
+    let mut request = Request::new(Body::empty());
+    *request.uri_mut() = (base.clone() + "/api/jobs").parse().unwrap();
+    request.headers_mut().insert("x-tenant", "acme".parse().unwrap());
+    let response = client.request(request).await.unwrap();
+    assert_eq!(response.status(), 403);
+
+    let response = client.get((base + "/api/nowhere").parse().unwrap()).await.unwrap();
+    assert_eq!(response.status(), 404);
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let error: V2Error = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(error.code, "not_found");
+    assert_eq!(requests.lock().unwrap().len(), 0);
+});
+
+test_case!(job_validation |client, app_port, server_port, requests| {
+    let base = "http://localhost:".to_owned() + &app_port.to_string();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    let url = "http://127.0.0.1:".to_owned() + &server_port.to_string() + "/test";
+    let cases = vec![
+        (serde_json::json!({ "url": "", "payload": "{}", "timestamp": now }), "url"),
+        (serde_json::json!({ "url": "localhost/test", "payload": "{}", "timestamp": now }), "url"),
+        (serde_json::json!({ "url": url, "payload": "{}", "timestamp": now / 1000 }), "timestamp"),
+        (serde_json::json!({ "url": url, "payload": "a".repeat(65 * 1024), "timestamp": now }), "body")
+    ];
+    for (body, field) in cases {
+        let mut request = Request::new(Body::from(body.to_string()));
+        *request.uri_mut() = (base.clone() + "/api/job").parse().unwrap();
+        *request.method_mut() = Method::POST;
+        let response = client.request(request).await.unwrap();
+        assert_eq!(response.status(), 400);
+        let body = hyper::body::aggregate(response).await.unwrap();
+        let error: V2Error = serde_json::from_reader(body.reader()).unwrap();
+        assert_eq!(error.details.get("field").unwrap(), field);
+    }
+
+    // oversized requests are refused whether their length is announced or not.
+    let oversized = || vec![b' '; 1024 * 1024];
+    let chunks: Vec<Result<Vec<u8>, std::io::Error>> = vec![Ok(oversized())];
+    for body in [Body::from(oversized()), Body::wrap_stream(futures::stream::iter(chunks))] {
+        let mut request = Request::new(body);
+        *request.uri_mut() = (base.clone() + "/api/job").parse().unwrap();
+        *request.method_mut() = Method::POST;
+        let response = client.request(request).await.unwrap();
+        assert_eq!(response.status(), 400);
+        let body = hyper::body::aggregate(response).await.unwrap();
+        let error: V2Error = serde_json::from_reader(body.reader()).unwrap();
+        assert!(error.message.starts_with("Invalid body: request must be at most"), "{}", error.message);
+    }
+
+    let body = serde_json::json!({ "url": url, "body": "{}", "schedule": "0 0 4 * * *", "method": "TRACE" });
+    let mut request = Request::new(Body::from(body.to_string()));
+    *request.uri_mut() = (base + "/api/cron").parse().unwrap();
+    *request.method_mut() = Method::POST;
+    let response = client.request(request).await.unwrap();
+    assert_eq!(response.status(), 400);
+    assert_eq!(requests.lock().unwrap().len(), 0);
+});
+
+test_case!(api_keys |client, app_port, server_port, requests| |config: &mut Config| {
+    config.auth.admin_key = Some("bootstrap".to_owned());
+}, {
+    let base = "http://localhost:".to_owned() + &app_port.to_string();
+    let call = |method: Method, path: &str, key: &str, body: String| {
+        let mut request = Request::new(Body::from(body));
+        *request.uri_mut() = (base.clone() + path).parse().unwrap();
+        *request.method_mut() = method;
+        request.headers_mut().insert("x-api-key", key.parse().unwrap());
+        client.request(request)
+    };
+
+    let response = client.get((base.clone() + "/api/jobs").parse().unwrap()).await.unwrap();
+    assert_eq!(response.status(), 401);
+    let response = client.get((base.clone() + "/healthz").parse().unwrap()).await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body = serde_json::json!({ "name": "ci", "scopes": ["read", "write"] });
+    let response = call(Method::POST, "/api/keys", "bootstrap", body.to_string()).await.unwrap();
+    assert_eq!(response.status(), 201);
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let created: serde_json::Value = serde_json::from_reader(body.reader()).unwrap();
+    let key = created["key"].as_str().unwrap().to_owned();
+    let id = created["id"].as_str().unwrap().to_owned();
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let callback = V1Job {
+        payload: "{}".to_owned(),
+        timestamp: (now + 60000) as u64,
+        url: "http://127.0.0.1:".to_owned() + &server_port.to_string() + "/test",
+    };
+    let body = serde_json::to_string(&callback).unwrap();
+    let response = call(Method::POST, "/api/job", &key, body).await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    // clearing everything requires the admin scope.
+    let response = call(Method::DELETE, "/api/job", &key, "".to_owned()).await.unwrap();
+    assert_eq!(response.status(), 403);
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let error: V2Error = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(error.details.get("scope").unwrap(), "admin");
+
+    // encoded paths require the scope of the route they're decoded to.
+    let body = serde_json::json!({ "name": "reader", "scopes": ["read"] });
+    let response = call(Method::POST, "/api/keys", "bootstrap", body.to_string()).await.unwrap();
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let created: serde_json::Value = serde_json::from_reader(body.reader()).unwrap();
+    let reader = created["key"].as_str().unwrap().to_owned();
+    let reader_id = created["id"].as_str().unwrap().to_owned();
+    let body = serde_json::json!({ "name": "admin", "scopes": ["admin"] }).to_string();
+    let encoded = vec![
+        (Method::GET, "/api/%6Beys", &reader, String::new()),
+        (Method::GET, "/api/%6Beys", &key, String::new()),
+        (Method::POST, "/api/%6Beys", &key, body),
+        (Method::DELETE, "/api/jo%62", &key, String::new())
+    ];
+    for (method, path, caller, body) in encoded {
+        let response = call(method, path, caller, body).await.unwrap();
+        assert_eq!(response.status(), 403, "{}", path);
+    }
+    let path = "/api/keys/".to_owned() + &reader_id;
+    let response = call(Method::DELETE, &path, "bootstrap", "".to_owned()).await.unwrap();
+    assert_eq!(response.status(), 204);
+    let response = call(Method::GET, "/api/jobs", &key, "".to_owned()).await.unwrap();
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let jobs: Vec<serde_json::Value> = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(jobs.len(), 1);
+
+    let response = call(Method::GET, "/api/keys", "bootstrap", "".to_owned()).await.unwrap();
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let keys: Vec<serde_json::Value> = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0]["name"], "ci");
+    assert!(keys[0].get("key").is_none());
+
+    let path = "/api/keys/".to_owned() + &id;
+    let response = call(Method::DELETE, &path, "bootstrap", "".to_owned()).await.unwrap();
+    assert_eq!(response.status(), 204);
+    let response = call(Method::GET, "/api/jobs", &key, "".to_owned()).await.unwrap();
+    assert_eq!(response.status(), 401);
+    assert_eq!(requests.lock().unwrap().len(), 0);
+});
+
+test_case!(jwt_tokens |client, app_port, server_port, requests| |config: &mut Config| {
+    std::fs::create_dir_all(".test").unwrap();
+    std::fs::write(".test/api_jwt_secret", "secret").unwrap();
+    let key = JwtKey::Hs256Secret(".test/api_jwt_secret".into());
+    config.auth.jwt = Some(JwtConfig::new(key));
+}, {
+    let base = "http://localhost:".to_owned() + &app_port.to_string();
+    let token = |permissions: &str| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let claims = serde_json::json!({
+            "sub": "billing",
+            "tenant": "acme",
+            "permissions": permissions,
+            "exp": now + 60
+        });
+        let key = EncodingKey::from_secret(b"secret");
+        encode(&Header::default(), &claims, &key).unwrap()
+    };
+    let call = |method: Method, path: &str, token: &str, body: String| {
+        let mut request = Request::new(Body::from(body));
+        *request.uri_mut() = (base.clone() + path).parse().unwrap();
+        *request.method_mut() = method;
+        let bearer = "Bearer ".to_owned() + token;
+        request.headers_mut().insert("authorization", bearer.parse().unwrap());
+        client.request(request)
+    };
+
+    let response = client.get((base.clone() + "/api/jobs").parse().unwrap()).await.unwrap();
+    assert_eq!(response.status(), 401);
+    let response = call(Method::GET, "/api/jobs", "not.a.token", "".to_owned()).await.unwrap();
+    assert_eq!(response.status(), 401);
+    let response = call(Method::GET, "/api/jobs", &token("read"), "".to_owned()).await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let callback = V1Job {
+        payload: "{}".to_owned(),
+        timestamp: (now + 60000) as u64,
+        url: "http://127.0.0.1:".to_owned() + &server_port.to_string() + "/test",
+    };
+    let body = serde_json::to_string(&callback).unwrap();
+    let response = call(Method::POST, "/api/job", &token("read"), body.clone()).await.unwrap();
+    assert_eq!(response.status(), 403);
+    let response = call(Method::POST, "/api/job", &token("read write"), body).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(requests.lock().unwrap().len(), 0);
+});
+
+test_case!(tenants |client, app_port, server_port, requests| |config: &mut Config| {
+    config.auth.admin_key = Some("bootstrap".to_owned());
+}, {
+    let base = "http://localhost:".to_owned() + &app_port.to_string();
+    let call = |method: Method, path: &str, key: &str, tenant: Option<&str>, body: String| {
+        let mut request = Request::new(Body::from(body));
+        *request.uri_mut() = (base.clone() + path).parse().unwrap();
+        *request.method_mut() = method;
+        request.headers_mut().insert("x-api-key", key.parse().unwrap());
+        if let Some(tenant) = tenant {
+            request.headers_mut().insert("x-tenant", tenant.parse().unwrap());
+        }
+        client.request(request)
+    };
+    let list = |key: String, tenant: Option<&'static str>| {
+        let request = call(Method::GET, "/api/jobs", &key, tenant, "".to_owned());
+        async move {
+            let body = hyper::body::aggregate(request.await.unwrap()).await.unwrap();
+            let jobs: Vec<serde_json::Value> = serde_json::from_reader(body.reader()).unwrap();
+            jobs
+        }
+    };
+
+    let mut keys = Vec::new();
+    for tenant in &["acme", "globex"] {
+        let scopes = ["read", "write"];
+        let body = serde_json::json!({ "name": tenant, "scopes": scopes, "tenant": tenant });
+        let response = call(Method::POST, "/api/keys", "bootstrap", None, body.to_string());
+        let body = hyper::body::aggregate(response.await.unwrap()).await.unwrap();
+        let created: serde_json::Value = serde_json::from_reader(body.reader()).unwrap();
+        assert_eq!(created["tenant"], *tenant);
+        keys.push(created["key"].as_str().unwrap().to_owned());
+    }
+    let (acme, globex) = (keys[0].clone(), keys[1].clone());
+
+    // the same named job is declared by both tenants.
+    let url = "http://127.0.0.1:".to_owned() + &server_port.to_string() + "/test";
+    for key in &[&acme, &globex] {
+        let body = serde_json::json!({ "url": url, "body": key, "schedule": "0 0 0 1 1 * *" });
+        let path = "/api/groups/reports/jobs/daily";
+        let response = call(Method::PUT, path, key, None, body.to_string()).await.unwrap();
+        assert_eq!(response.status(), 201);
+    }
+    let jobs = list(acme.clone(), None).await;
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0]["body"], acme.as_str());
+    // the key is confined to its tenant whatever the header says.
+    let jobs = list(acme.clone(), Some("globex")).await;
+    assert_eq!(jobs[0]["body"], acme.as_str());
+    // only admin keys pick a tenant.
+    let body = serde_json::json!({ "name": "reader", "scopes": ["read"] });
+    let response = call(Method::POST, "/api/keys", "bootstrap", None, body.to_string());
+    let body = hyper::body::aggregate(response.await.unwrap()).await.unwrap();
+    let created: serde_json::Value = serde_json::from_reader(body.reader()).unwrap();
+    let reader = created["key"].as_str().unwrap();
+    let response = call(Method::GET, "/api/jobs", reader, Some("acme"), "".to_owned());
+    assert_eq!(response.await.unwrap().status(), 403);
+
+    // clearing only drops the jobs of the tenant.
+    let response = call(Method::DELETE, "/api/job", "bootstrap", Some("acme"), "".to_owned());
+    assert_eq!(response.await.unwrap().status(), 204);
+    assert!(list(acme.clone(), None).await.is_empty());
+    assert_eq!(list(globex.clone(), None).await.len(), 1);
+    assert_eq!(list("bootstrap".to_owned(), Some("globex")).await.len(), 1);
+    assert!(list("bootstrap".to_owned(), None).await.is_empty());
+    assert_eq!(requests.lock().unwrap().len(), 0);
+});
+
+test_case!(quotas |client, app_port, server_port, requests| |config: &mut Config| {
+    config.quotas.max_pending = Some(2);
+    config.quotas.max_callbacks_per_minute = Some(1);
+}, {
+    let base = "http://localhost:".to_owned() + &app_port.to_string();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    let url = "http://127.0.0.1:".to_owned() + &server_port.to_string() + "/test";
+    let mut statuses = Vec::new();
+    for _ in 0..3 {
+        let body = serde_json::json!({ "url": url, "payload": "{}", "timestamp": now + 500 });
+        let mut request = Request::new(Body::from(body.to_string()));
+        *request.uri_mut() = (base.clone() + "/api/job").parse().unwrap();
+        *request.method_mut() = Method::POST;
+        statuses.push(client.request(request).await.unwrap());
+    }
+    assert!(statuses[0].status().is_success());
+    assert!(statuses[1].status().is_success());
+    let response = statuses.pop().unwrap();
+    assert_eq!(response.status(), 429);
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let error: V2Error = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(error.code, "quota_exceeded");
+    assert_eq!(error.details.get("quota").unwrap(), "max_pending");
+    assert_eq!(error.details.get("limit").unwrap(), "2");
+
+    // only one of the jobs fires this minute, the other one waits.
+    let mut interval = tokio::time::interval(Duration::from_millis(2000));
+    interval.tick().await;
+    interval.tick().await;
+    assert_eq!(requests.lock().unwrap().len(), 1);
+
+    let response = client.get((base + "/api/quota").parse().unwrap()).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let report: serde_json::Value = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(report["tenant"], DEFAULT_TENANT);
+    assert_eq!(report["pending"], serde_json::json!({ "used": 1, "limit": 2 }));
+    assert_eq!(report["recurring"], serde_json::json!({ "used": 0, "limit": null }));
+    assert_eq!(report["callbacks_per_minute"], serde_json::json!({ "used": 1, "limit": 1 }));
+    assert_eq!(report["creates_per_minute"]["used"], 2);
+});
+
+test_case!(callback_egress |client, app_port, server_port, requests| |config: &mut Config| {
+    config.egress = EgressConfig::default();
+}, {
+    let base = "http://localhost:".to_owned() + &app_port.to_string();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    let create = |url: String| {
+        let body = serde_json::json!({ "url": url, "payload": "{}", "timestamp": now + 500 });
+        let mut request = Request::new(Body::from(body.to_string()));
+        *request.uri_mut() = (base.clone() + "/api/job").parse().unwrap();
+        *request.method_mut() = Method::POST;
+        client.request(request)
+    };
+
+    let blocked = vec![
+        "http://127.0.0.1:".to_owned() + &server_port.to_string(),
+        "http://169.254.169.254/latest/meta-data".to_owned()
+    ];
+    for url in &blocked {
+        let response = create(url.clone()).await.unwrap();
+        assert_eq!(response.status(), 400);
+        let body = hyper::body::aggregate(response).await.unwrap();
+        let error: V2Error = serde_json::from_reader(body.reader()).unwrap();
+        assert_eq!(error.details.get("field").unwrap(), "url");
+    }
+
+    // the host is only known to be loopback once it is resolved.
+    let url = "http://localhost:".to_owned() + &server_port.to_string() + "/test";
+    let response = create(url).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let job: serde_json::Value = serde_json::from_reader(body.reader()).unwrap();
+
+    let mut interval = tokio::time::interval(Duration::from_millis(2000));
+    interval.tick().await;
+    interval.tick().await;
+    assert_eq!(requests.lock().unwrap().len(), 0);
+
+    let uri = base + "/api/job/" + job["id"].as_str().unwrap() + "/runs";
+    let response = client.get(uri.parse().unwrap()).await.unwrap();
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let runs: Vec<serde_json::Value> = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(runs.len(), 1);
+    assert!(runs[0]["status"].is_null());
+    assert!(runs[0]["error"].as_str().unwrap().contains("is not allowed"), "{}", runs[0]["error"]);
+});
+
+test_case!(shard_migrations |client, app_port, _server_port, _requests| {
+    let base = "http://localhost:".to_owned() + &app_port.to_string();
+    let uri = base + "/api/shards/migrations";
+    let response = client.get(uri.parse().unwrap()).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let migrations: Vec<serde_json::Value> = serde_json::from_reader(body.reader()).unwrap();
+    assert!(migrations.is_empty());
+
+    // a single node has nowhere to move its shards to.
+    let body = serde_json::json!({ "shards": "0-63", "target": "b" });
+    let mut request = Request::new(Body::from(body.to_string()));
+    *request.uri_mut() = uri.parse().unwrap();
+    *request.method_mut() = Method::POST;
+    let response = client.request(request).await.unwrap();
+    assert_eq!(response.status(), 400);
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let error: V2Error = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(error.details.get("field").unwrap(), "target");
+});
+
+test_case!(nodes |client, app_port, _server_port, _requests| {
+    let base = "http://localhost:".to_owned() + &app_port.to_string();
+    let response = client.get((base + "/api/nodes").parse().unwrap()).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let nodes: Vec<serde_json::Value> = serde_json::from_reader(body.reader()).unwrap();
+    // a single node has no other node to watch.
+    assert!(nodes.is_empty());
+});
+
+test_case!(shards |client, app_port, server_port, _requests| {
+    let base = "http://localhost:".to_owned() + &app_port.to_string();
+    let url = "http://127.0.0.1:".to_owned() + &server_port.to_string() + "/test";
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    let body = serde_json::json!({ "url": url, "payload": "{}", "timestamp": now + 60000 });
+    let mut request = Request::new(Body::from(body.to_string()));
+    *request.uri_mut() = (base.clone() + "/api/job").parse().unwrap();
+    *request.method_mut() = Method::POST;
+    let response = client.request(request).await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    let response = client.get((base.clone() + "/api/shards").parse().unwrap()).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let shards: Vec<serde_json::Value> = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(shards.len(), 127);
+    assert!(shards.iter().all(|shard| shard["state"] == "local"));
+    let stored: Vec<&serde_json::Value> = shards
+        .iter()
+        .filter(|shard| shard["jobs"]["count"] == 1)
+        .collect();
+    assert_eq!(stored.len(), 1);
+
+    // clearing the shard drops the job.
+    let shard = stored[0]["shard"].as_u64().unwrap();
+    let mut request = Request::new(Body::from(""));
+    *request.uri_mut() = format!("{}/api/shards/{}", base, shard).parse().unwrap();
+    *request.method_mut() = Method::DELETE;
+    let response = client.request(request).await.unwrap();
+    assert_eq!(response.status(), 204);
+    let response = client.get((base.clone() + "/api/jobs").parse().unwrap()).await.unwrap();
+    let body = hyper::body::aggregate(response).await.unwrap();
+    let jobs: Vec<serde_json::Value> = serde_json::from_reader(body.reader()).unwrap();
+    assert!(jobs.is_empty());
+
+    let mut request = Request::new(Body::from(""));
+    *request.uri_mut() = (base + "/api/shards/127").parse().unwrap();
+    *request.method_mut() = Method::DELETE;
+    let response = client.request(request).await.unwrap();
+    assert_eq!(response.status(), 400);
+});